@@ -0,0 +1,74 @@
+//! Benchmark of loading a synthetic week of hour files, as `--source disk:` and `bench
+//! --dataset` read them, in the CSV format and in the binary one
+
+use chrono::DateTime;
+use criterion::{criterion_group, criterion_main, Criterion};
+use rust_decimal::Decimal;
+use std::path::{Path, PathBuf};
+
+use interview::hour_file::{self, HourFormat};
+use interview::rng::SplitMix64;
+use interview::server::Fill;
+
+const FIRST_HOUR: i64 = 1700816400;
+const HOURS: i64 = 168;
+/// The mock source's default density
+const FILLS_PER_HOUR: i64 = 1500;
+
+/// Returns the fills of an hour: a random walk of prices around 58, spaced evenly
+fn synthetic_hour(rng: &mut SplitMix64, hour: i64) -> Vec<Fill> {
+    let mut price = Decimal::new(58_000, 3);
+    (0..FILLS_PER_HOUR)
+        .map(|i| {
+            price += Decimal::new(rng.below(11) as i64 - 5, 3);
+            Fill {
+                time: DateTime::from_timestamp_millis(
+                    hour * 1000 + 1 + i * 3_600_000 / FILLS_PER_HOUR,
+                )
+                .unwrap(),
+                direction: 1 + rng.below(2) as i32,
+                price,
+                quantity: Decimal::new(1 + rng.below(10_000) as i64, 4),
+                sequence_number: (hour * FILLS_PER_HOUR + i) as u64,
+                liquidity: None,
+                trade_id: None,
+                fee: None,
+                fee_currency: None,
+            }
+        })
+        .collect()
+}
+
+/// Writes the week in the format under the directory and returns the paths of its files
+fn write_week(dir: &Path, format: HourFormat) -> Vec<PathBuf> {
+    let mut rng = SplitMix64(0);
+    (0..HOURS)
+        .map(|i| {
+            let hour = FIRST_HOUR + i * 3600;
+            let path = hour_file::hour_path(dir, "BTC", hour, format);
+            hour_file::write(&path, "BTC", hour, &synthetic_hour(&mut rng, hour)).unwrap();
+            path
+        })
+        .collect()
+}
+
+fn load(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut group = c.benchmark_group("load 168 hour files of 1500 fills");
+    group.sample_size(10);
+    for format in [HourFormat::Csv, HourFormat::Binary] {
+        let paths = write_week(dir.path(), format);
+        group.bench_function(format.extension(), |b| {
+            b.iter(|| {
+                paths
+                    .iter()
+                    .map(|path| hour_file::read(path, false).unwrap().fills.len())
+                    .sum::<usize>()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, load);
+criterion_main!(benches);
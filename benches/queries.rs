@@ -0,0 +1,53 @@
+//! Benchmark of the query path over a generated workload against the mock source: the
+//! queries are answered by `run_queries`, as the `query` command answers its input, by a
+//! processor starting cold, and by one that has every hour of the workload cached already
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use std::io::{self, Cursor};
+
+use interview::clock::SystemClock;
+use interview::config::Config;
+use interview::mock::{MockConfig, MockSource};
+use interview::workload::{self, WorkloadConfig};
+use interview::{run_queries, Processor};
+
+/// The `bench` command's defaults over a day of hours, scaled down to keep samples short
+fn workload() -> Vec<String> {
+    workload::generate(&WorkloadConfig {
+        seed: 0,
+        queries: 2_000,
+        hours: (0..24).map(|i| 1700816400 + i * 3600).collect(),
+        hit_ratio: 0.9,
+    })
+}
+
+fn processor() -> Processor {
+    let source = Box::new(MockSource::new(MockConfig::default()));
+    Processor::with_source(Config::default(), source, Box::new(SystemClock))
+}
+
+fn input(queries: &[String]) -> Cursor<Vec<u8>> {
+    Cursor::new(queries.join("\n").into_bytes())
+}
+
+fn answer(c: &mut Criterion) {
+    let queries = workload();
+    let mut group = c.benchmark_group("run_queries 2k queries over 24 mock hours");
+    group.sample_size(10);
+    group.bench_function("cold cache", |b| {
+        b.iter_batched(
+            processor,
+            |mut processor| run_queries(&mut processor, input(&queries), &mut io::sink()).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+    let mut warm = processor();
+    run_queries(&mut warm, input(&queries), &mut io::sink()).unwrap();
+    group.bench_function("warm cache", |b| {
+        b.iter(|| run_queries(&mut warm, input(&queries), &mut io::sink()).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, answer);
+criterion_main!(benches);
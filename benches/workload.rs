@@ -0,0 +1,21 @@
+//! Microbenchmark of the workload generator behind the `bench` command, for tracking in CI
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use interview::workload::{self, WorkloadConfig};
+
+fn generate(c: &mut Criterion) {
+    // The defaults of the `bench` command over a week of hours
+    let config = WorkloadConfig {
+        seed: 0,
+        queries: 10_000,
+        hours: (0..168).map(|i| 1700816400 + i * 3600).collect(),
+        hit_ratio: 0.9,
+    };
+    c.bench_function("generate 10k queries over 168 hours", |b| {
+        b.iter(|| workload::generate(black_box(&config)))
+    });
+}
+
+criterion_group!(benches, generate);
+criterion_main!(benches);
@@ -0,0 +1,227 @@
+"""Python bindings to the query processor.
+
+Loads the library `cargo build --release --features python` builds
+(target/release/libinterview.so, .dylib on macOS) through its C interface, see
+src/ffi.rs. The library is found through the INTERVIEW_LIB environment variable,
+or on the system's library path otherwise.
+
+    from interview import Processor
+
+    processor = Processor(source="disk:fixtures/multi-hour-range/hours")
+    processor.query("C", 1700820000, 1700830000)  # 4509
+
+Calls into the library release the GIL; a Python fetch callback takes it back
+for as long as it runs.
+"""
+
+import ctypes
+import ctypes.util
+import json
+import os
+from datetime import datetime, timezone
+from decimal import Decimal, InvalidOperation
+
+__all__ = ["Processor", "ProcessorError", "Unavailable"]
+
+_OK = 0
+_FAILED = 1
+_UNAVAILABLE = 2
+
+_FETCH_CALLBACK = ctypes.CFUNCTYPE(
+    ctypes.c_int32,
+    ctypes.c_void_p,
+    ctypes.POINTER(ctypes.c_uint8),
+    ctypes.c_size_t,
+    ctypes.c_int64,
+    ctypes.c_int64,
+    ctypes.c_void_p,
+)
+
+
+class ProcessorError(Exception):
+    """A query or setting the processor rejected, with the processor's error"""
+
+
+class Unavailable(Exception):
+    """Raised by a fetch callback when the upstream is unavailable, as opposed to one
+    whose fills are bad"""
+
+
+def _load():
+    path = os.environ.get("INTERVIEW_LIB") or ctypes.util.find_library("interview")
+    if path is None:
+        raise ImportError(
+            "libinterview not found: build it with `cargo build --release --features python` "
+            "and point INTERVIEW_LIB at it"
+        )
+    lib = ctypes.CDLL(path)
+    lib.interview_new.argtypes = [
+        ctypes.c_char_p,
+        ctypes.c_size_t,
+        _FETCH_CALLBACK,
+        ctypes.c_void_p,
+    ]
+    lib.interview_new.restype = ctypes.c_void_p
+    lib.interview_free.argtypes = [ctypes.c_void_p]
+    lib.interview_free.restype = None
+    lib.interview_query.argtypes = [ctypes.c_void_p, ctypes.c_char_p, ctypes.c_size_t]
+    lib.interview_query.restype = ctypes.c_int32
+    lib.interview_fills_in_range.argtypes = [
+        ctypes.c_void_p,
+        ctypes.c_char_p,
+        ctypes.c_size_t,
+        ctypes.c_int64,
+        ctypes.c_int64,
+    ]
+    lib.interview_fills_in_range.restype = ctypes.c_int32
+    lib.interview_cache_stats.argtypes = [ctypes.c_void_p]
+    lib.interview_cache_stats.restype = ctypes.c_int32
+    lib.interview_result_ptr.argtypes = [ctypes.c_void_p]
+    lib.interview_result_ptr.restype = ctypes.c_void_p
+    lib.interview_result_len.argtypes = [ctypes.c_void_p]
+    lib.interview_result_len.restype = ctypes.c_size_t
+    lib.interview_error_ptr.argtypes = []
+    lib.interview_error_ptr.restype = ctypes.c_void_p
+    lib.interview_error_len.argtypes = []
+    lib.interview_error_len.restype = ctypes.c_size_t
+    lib.interview_sink_write.argtypes = [ctypes.c_void_p, ctypes.c_char_p, ctypes.c_size_t]
+    lib.interview_sink_write.restype = None
+    return lib
+
+
+_lib = _load()
+
+
+def _error():
+    return ProcessorError(
+        ctypes.string_at(_lib.interview_error_ptr(), _lib.interview_error_len()).decode()
+    )
+
+
+def _answer(text):
+    """Returns an answer as an int, a Decimal, None for NONE, or the text otherwise, e.g.
+    for the several answers of a multi-window line"""
+    if text == "NONE":
+        return None
+    try:
+        return int(text)
+    except ValueError:
+        pass
+    try:
+        return Decimal(text)
+    except InvalidOperation:
+        return text
+
+
+def _fill_json(fill):
+    """Returns a fill a fetch callback returned in the JSON the library reads"""
+    fill = dict(fill)
+    if isinstance(fill["time"], datetime):
+        fill["time"] = fill["time"].astimezone(timezone.utc).isoformat().replace("+00:00", "Z")
+    for field in ("price", "quantity", "fee"):
+        if fill.get(field) is not None:
+            fill[field] = str(fill[field])
+    return fill
+
+
+def _fill_dict(fill):
+    """Returns a fill of the library as a dict, its time a datetime and its amounts
+    Decimals"""
+    fill["time"] = datetime.fromisoformat(fill["time"])
+    for field in ("price", "quantity", "fee"):
+        if fill.get(field) is not None:
+            fill[field] = Decimal(fill[field])
+    return fill
+
+
+class Processor:
+    """A query processor and its cache.
+
+    Settings left out take the defaults of the command line flags: `cache_hours`
+    (--cache-hours), `source` (--source, e.g. "api" or "disk:DIR") and `symbol`
+    (--symbol). With `fetch`, a callable `fetch(symbol, start, end)` returning the fills
+    within (start, end] in Unix seconds as dicts with the fields of `fixture record`'s
+    JSON, fills are fetched from it instead of from `source`. It may raise `Unavailable`
+    for an upstream that is down; any other exception fails the fetch.
+    """
+
+    def __init__(self, cache_hours=None, source=None, symbol=None, fetch=None):
+        settings = {
+            name: value
+            for name, value in (("cache_hours", cache_hours), ("source", source), ("symbol", symbol))
+            if value is not None
+        }
+        config = json.dumps(settings).encode()
+        # Kept alive with the processor, which calls it
+        self._callback = _FETCH_CALLBACK(self._fetch_callback(fetch)) if fetch else _FETCH_CALLBACK()
+        self._handle = _lib.interview_new(config, len(config), self._callback, None)
+        if not self._handle:
+            raise _error()
+
+    @staticmethod
+    def _fetch_callback(fetch):
+        def callback(_user, symbol, symbol_len, start, end, sink):
+            try:
+                symbol = ctypes.string_at(symbol, symbol_len).decode()
+                written = json.dumps([_fill_json(fill) for fill in fetch(symbol, start, end)])
+                status = _OK
+            except Unavailable as e:
+                written, status = str(e), _UNAVAILABLE
+            except Exception as e:  # noqa: BLE001, the callback mustn't unwind into Rust
+                written, status = "{}: {}".format(type(e).__name__, e), _FAILED
+            written = written.encode()
+            _lib.interview_sink_write(sink, written, len(written))
+            return status
+
+        return callback
+
+    def close(self):
+        """Frees the processor; it can't be used after"""
+        if self._handle:
+            _lib.interview_free(self._handle)
+            self._handle = None
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *_):
+        self.close()
+
+    def __del__(self):
+        self.close()
+
+    def _result(self, status):
+        if status != _OK:
+            raise _error()
+        return ctypes.string_at(
+            _lib.interview_result_ptr(self._handle), _lib.interview_result_len(self._handle)
+        ).decode()
+
+    def query_line(self, line):
+        """Answers a query line, like a line of `query`'s input, as its text"""
+        line = line.encode()
+        return self._result(_lib.interview_query(self._handle, line, len(line)))
+
+    def query(self, kind, start, end, symbol=None, venue=None):
+        """Answers the query of type `kind` (e.g. "C", "V" or "WB") over (start, end] in
+        Unix seconds: an int, a Decimal, or None for a query without an answer (NONE)"""
+        tokens = [kind]
+        if venue is not None:
+            tokens.append("@" + venue)
+        if symbol is not None:
+            tokens.append(symbol)
+        tokens += [str(start), str(end)]
+        return _answer(self.query_line(" ".join(tokens)))
+
+    def fills_in_range(self, start, end, symbol=None):
+        """Returns the fills within (start, end] in Unix seconds through the cache, as a
+        query of the window sees them, as dicts in time order"""
+        symbol = (symbol or "").encode()
+        fills = self._result(
+            _lib.interview_fills_in_range(self._handle, symbol, len(symbol), start, end)
+        )
+        return [_fill_dict(fill) for fill in json.loads(fills)]
+
+    def cache_stats(self):
+        """Returns the cached hours, fills and bytes and the hit and API call counts"""
+        return json.loads(self._result(_lib.interview_cache_stats(self._handle)))
@@ -0,0 +1,125 @@
+"""Tests of the bindings, for pytest or run directly with the library built:
+
+    cargo build --features python
+    INTERVIEW_LIB=target/debug/libinterview.so python3 python/tests/test_processor.py
+"""
+
+import os
+import sys
+import threading
+from datetime import datetime, timezone
+from decimal import Decimal
+
+sys.path.insert(0, os.path.join(os.path.dirname(__file__), ".."))
+
+from interview import Processor, ProcessorError, Unavailable  # noqa: E402
+
+FIXTURE = os.path.join(os.path.dirname(__file__), "..", "..", "fixtures", "multi-hour-range")
+HOURS = "disk:" + os.path.join(FIXTURE, "hours")
+HOUR = 1700816400
+
+
+def fill(time, seq, price, quantity, direction=1):
+    return {
+        "time": datetime.fromtimestamp(time, timezone.utc),
+        "direction": direction,
+        "price": price,
+        "quantity": quantity,
+        "sequence_number": seq,
+    }
+
+
+def test_answers_match_the_fixture():
+    with Processor(source=HOURS) as processor:
+        assert processor.query("C", 1700820000, 1700830000) == 4509
+        stats = processor.cache_stats()
+        assert (stats["hours"], stats["cache_hits"], stats["api_calls"]) == (3, 0, 3)
+
+        # The hours of the first query are served from the cache
+        assert processor.query("V", 1700820000, 1700830000) == Decimal("1813528.229735")
+        assert processor.query("SD", 1700822000, 1700826000) == Decimal("0.21807807")
+        assert processor.query_line("E 1700820000 1700830000") == "1700820003 1700829995 5908"
+        stats = processor.cache_stats()
+        assert (stats["cache_hits"], stats["api_calls"]) == (8, 3)
+
+
+def test_fills_come_from_the_python_source():
+    calls = []
+
+    def fetch(symbol, start, end):
+        calls.append((symbol, start, end))
+        fills = [
+            fill(HOUR + 10, 1, "100.5", "2"),
+            fill(HOUR + 20, 2, Decimal("101"), "1", direction=2),
+        ]
+        return [f for f in fills if start <= f["time"].timestamp() < end]
+
+    processor = Processor(fetch=fetch, symbol="BTC")
+    assert processor.query("C", HOUR, HOUR + 3600) == 2
+    assert processor.query("V", HOUR, HOUR + 3600) == Decimal("302")
+    assert processor.query("WB", HOUR, HOUR + 15) == Decimal("100.5")
+    assert processor.query("WB", HOUR + 15, HOUR + 3600) is None
+    assert calls == [("BTC", HOUR, HOUR + 3600), ("BTC", HOUR + 3600, HOUR + 7200)]
+
+    fills = processor.fills_in_range(HOUR, HOUR + 15)
+    assert [f["sequence_number"] for f in fills] == [1]
+    assert fills[0]["price"] == Decimal("100.5")
+    assert fills[0]["time"].timestamp() == HOUR + 10
+
+
+def test_errors_are_raised():
+    def fetch(symbol, start, end):
+        raise ValueError("no fills for " + symbol)
+
+    processor = Processor(fetch=fetch)
+    try:
+        processor.query("C", HOUR, HOUR + 3600)
+        raise AssertionError("the failed fetch answered")
+    except ProcessorError as e:
+        assert "no fills for DEFAULT" in str(e), e
+
+    try:
+        processor.query("X", HOUR, HOUR + 3600)
+        raise AssertionError("the unknown query type answered")
+    except ProcessorError as e:
+        assert "X" in str(e), e
+
+    try:
+        Processor(source="nowhere:")
+        raise AssertionError("the invalid source was accepted")
+    except ProcessorError:
+        pass
+
+
+def test_an_unavailable_upstream_fails_the_query():
+    def fetch(symbol, start, end):
+        raise Unavailable("503")
+
+    processor = Processor(fetch=fetch)
+    try:
+        processor.query("C", HOUR, HOUR + 3600)
+        raise AssertionError("the unavailable upstream answered")
+    except ProcessorError as e:
+        assert "503" in str(e), e
+
+
+def test_processors_run_on_threads():
+    answers = []
+
+    def run():
+        with Processor(source=HOURS) as processor:
+            answers.append(processor.query("C", 1700820000, 1700830000))
+
+    threads = [threading.Thread(target=run) for _ in range(4)]
+    for thread in threads:
+        thread.start()
+    for thread in threads:
+        thread.join()
+    assert answers == [4509] * 4
+
+
+if __name__ == "__main__":
+    tests = [(name, test) for name, test in sorted(globals().items()) if name.startswith("test_")]
+    for name, test in tests:
+        test()
+        print("ok", name)
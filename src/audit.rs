@@ -0,0 +1,235 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::profile::{Phase, PhaseTimer};
+use crate::query::Query;
+
+/// What processing one query did, gathered while it runs
+#[derive(Debug, Default)]
+pub struct QueryTrace {
+    /// The parsed query, once parsing succeeded
+    pub parsed: Option<Query>,
+    /// The hours loaded, in the order they were loaded
+    pub hours: Vec<HourTrace>,
+    /// Time spent in each phase, when profiling
+    pub timer: PhaseTimer,
+}
+
+/// How one hour of a query was loaded
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct HourTrace {
+    pub hour: i64,
+    /// Whether the hour was served from the cache
+    pub hit: bool,
+    /// Time spent fetching the hour from the upstream, zero on a hit
+    #[serde(rename = "fetch_ms", serialize_with = "serialize_millis")]
+    pub fetch_time: Duration,
+    /// Whether the hour was fetched after it ended, so its fills are final
+    pub complete: bool,
+}
+
+/// Serializes a duration as fractional milliseconds
+fn serialize_millis<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
+/// One line of the audit log
+#[derive(Serialize)]
+struct Record<'a> {
+    index: u64,
+    query: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parsed: Option<ParsedRecord<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    hours: &'a [HourTrace],
+    #[serde(rename = "duration_ms", serialize_with = "serialize_millis")]
+    duration: Duration,
+    /// Milliseconds spent in each phase but output, which comes after the record
+    #[serde(rename = "phases_ms", skip_serializing_if = "Option::is_none")]
+    phases: Option<BTreeMap<&'static str, f64>>,
+}
+
+/// Fields of a parsed query in an audit log line
+#[derive(Serialize)]
+struct ParsedRecord<'a> {
+    kind: &'static str,
+    venue: &'a str,
+    symbol: &'a str,
+    /// (start_ms, end_ms] of each window, after its bounds and the future policy were applied
+    windows: Vec<(i64, i64)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    window_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    step_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    burst_gap_ms: Option<i64>,
+}
+
+/// Appends one JSON line per processed query to a file.
+/// Lines are buffered and never synced, so the log doesn't hold up result output;
+/// the first IO error disables the log with a warning instead of failing queries.
+pub struct AuditLog {
+    writer: Option<BufWriter<File>>,
+    /// Index of the next query, counting every processed query including failed ones
+    next_index: u64,
+}
+
+impl AuditLog {
+    /// Opens the file for appending, creating it if needed.
+    /// When it can't be opened, the log is disabled from the start.
+    pub fn open(path: &Path) -> Self {
+        let writer = match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Some(BufWriter::new(file)),
+            Err(e) => {
+                warn!("Audit log disabled: cannot open {}: {}", path.display(), e);
+                None
+            }
+        };
+        AuditLog {
+            writer,
+            next_index: 0,
+        }
+    }
+
+    /// Appends the record of a processed query
+    pub fn record(
+        &mut self,
+        query: &str,
+        trace: &QueryTrace,
+        result: &anyhow::Result<String>,
+        duration: Duration,
+    ) {
+        let index = self.next_index;
+        self.next_index += 1;
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+
+        let record = Record {
+            index,
+            query,
+            parsed: trace.parsed.as_ref().map(|parsed| ParsedRecord {
+                kind: parsed.kind.code(),
+                venue: &parsed.venue,
+                symbol: &parsed.symbol,
+                windows: parsed
+                    .windows
+                    .iter()
+                    .map(|window| (window.start_ms, window.end_ms))
+                    .collect(),
+                window_ms: parsed.series.map(|series| series.window_ms),
+                step_ms: parsed.series.map(|series| series.step_ms),
+                burst_gap_ms: parsed.burst_gap_ms,
+            }),
+            result: result.as_ref().ok().map(String::as_str),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+            hours: &trace.hours,
+            duration,
+            phases: trace.timer.is_enabled().then(|| {
+                trace
+                    .timer
+                    .times
+                    .iter()
+                    .filter(|&(name, _)| name != Phase::Output.name())
+                    .map(|(name, duration)| (name, duration.as_secs_f64() * 1000.0))
+                    .collect()
+            }),
+        };
+        let written = serde_json::to_writer(&mut *writer, &record)
+            .map_err(std::io::Error::from)
+            .and_then(|()| writer.write_all(b"\n"));
+        if let Err(e) = written {
+            warn!("Audit log disabled after a write error: {}", e);
+            self.writer = None;
+        }
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        if let Some(writer) = &mut self.writer {
+            if let Err(e) = writer.flush() {
+                warn!("Failed to flush the audit log: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::processor::Processor;
+    use crate::testing::{fill, MemorySource, TestClock, HOUR};
+    use serde_json::Value;
+
+    #[test]
+    fn log_lines_add_up_to_the_statistics() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let config = Config {
+            audit_log: Some(path.clone()),
+            ..Config::default()
+        };
+        let source = MemorySource::new(vec![
+            fill(HOUR + 60, 1, 1, "10", "1"),
+            fill(HOUR + 3600 + 60, 2, 2, "11", "2"),
+        ]);
+        let clock = Box::new(TestClock::at(HOUR + 86400));
+        let mut processor = Processor::with_source(config, Box::new(source), clock);
+        let queries = [
+            format!("C {} {}", HOUR, HOUR + 1800),
+            format!("V {} {}", HOUR, HOUR + 5400),
+            "X 1 2".to_string(),
+            format!("B {} {} {} {}", HOUR, HOUR + 60, HOUR + 3600, HOUR + 3700),
+        ];
+        for query in queries.clone() {
+            let _ = processor.process_query(query);
+        }
+        let metrics = processor.metrics();
+        let (queries_run, failed, cache_hits, api_calls) = (
+            metrics.queries,
+            metrics.failed,
+            metrics.cache_hits,
+            metrics.api_calls,
+        );
+        // Flushes the log
+        drop(processor);
+
+        let lines = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<Value>>();
+        assert_eq!(lines.len(), queries_run);
+        let errors = lines.iter().filter(|line| line.get("error").is_some());
+        assert_eq!(errors.count(), failed);
+        for (index, (line, query)) in lines.iter().zip(&queries).enumerate() {
+            assert_eq!(line["index"], index);
+            assert_eq!(line["query"], query.as_str());
+        }
+        let hours = lines
+            .iter()
+            .flat_map(|line| line["hours"].as_array().unwrap())
+            .collect::<Vec<&Value>>();
+        let hits = hours.iter().filter(|hour| hour["hit"] == true).count();
+        assert_eq!(hits, cache_hits);
+        assert_eq!(hours.len() - hits, api_calls);
+        assert_eq!((cache_hits, api_calls), (3, 2));
+        assert_eq!(lines[1]["result"], "32");
+        assert_eq!(
+            lines[1]["parsed"]["windows"][0][1],
+            (HOUR + 5400) * 1000 + 999
+        );
+    }
+}
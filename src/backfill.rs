@@ -0,0 +1,254 @@
+use anyhow::anyhow;
+use chrono::Utc;
+use clap::Args;
+use std::io::{self, IsTerminal, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{error, info};
+
+use crate::config::{Config, ConfigArgs};
+use crate::endpoint::{Endpoint, EndpointStats};
+use crate::hour_file::{self, HourFormat};
+use crate::server::ApiResponse;
+use crate::source::FillSource;
+use crate::{build_source, logging, query};
+
+/// Flags of the `backfill` command
+#[derive(Debug, Args)]
+pub struct BackfillArgs {
+    /// Start of the range (exclusive), in any of the query time formats
+    #[arg(long, value_name = "TIME", allow_hyphen_values = true)]
+    from: String,
+    /// End of the range (inclusive), in any of the query time formats
+    #[arg(long, value_name = "TIME", allow_hyphen_values = true)]
+    to: String,
+    /// Directory to write the hour files to, as `<DIR>/<SYMBOL>/<HOUR>.csv`,
+    /// or `<HOUR>.bin` in the binary format
+    #[arg(long, value_name = "DIR")]
+    out: PathBuf,
+    /// Format of the hour files
+    #[arg(long, value_enum, default_value = "csv")]
+    hour_format: HourFormat,
+    /// Symbol to backfill [default: the default symbol]
+    #[arg(long, value_name = "SYMBOL")]
+    symbol: Option<String>,
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+/// Endpoints the hours are fetched through, with their rate limits and retries, as queries
+/// fetch them: hours older than `--archive-after-hours` from the archive, the others live
+struct Endpoints {
+    live: Endpoint,
+    archive: Option<(Endpoint, i64)>,
+}
+
+impl Endpoints {
+    fn new(config: &Config, source: Box<dyn FillSource>) -> Self {
+        Endpoints {
+            live: Endpoint::new("live", source, config.live_policy),
+            archive: config.archive.as_ref().map(|archive| {
+                let source = archive
+                    .source
+                    .build(config.lenient_parse, config.strict_schema);
+                let endpoint = Endpoint::new("archive", source, archive.policy);
+                (endpoint, archive.after.as_secs() as i64)
+            }),
+        }
+    }
+
+    /// Fetches the hour of the symbol from its endpoint, given the current time
+    fn fetch(&mut self, symbol: &str, hour: i64, now: i64) -> anyhow::Result<ApiResponse> {
+        let endpoint = match &mut self.archive {
+            Some((archive, after)) if hour < now - *after => archive,
+            _ => &mut self.live,
+        };
+        endpoint.get_fills(symbol, hour, hour + 3600)
+    }
+
+    /// Returns the name and counters of each endpoint
+    fn stats(&self) -> Vec<(&'static str, Arc<EndpointStats>)> {
+        let mut stats = vec![(self.live.name(), self.live.stats())];
+        if let Some((archive, _)) = &self.archive {
+            stats.push((archive.name(), archive.stats()));
+        }
+        stats
+    }
+}
+
+/// Entry point of the `backfill` command: fetches every hour the range touches from the
+/// configured source, through its rate limit and retries, and writes it to an hour file, the format fixtures and disk sources read.
+/// Hours whose file already exists and reads back are skipped, so an interrupted backfill
+/// resumes where it stopped. A failed hour doesn't stop the run, but fails the command
+/// once every other hour has been tried.
+pub fn main(args: BackfillArgs) -> anyhow::Result<()> {
+    let config = Config::from(args.config);
+    logging::init(config.log_format);
+    let symbol = args.symbol.unwrap_or_else(|| config.default_symbol.clone());
+
+    let now = Utc::now();
+    let from_ms = query::parse_time("--from", &args.from, now)?;
+    let to_ms = query::parse_time("--to", &args.to, now)?;
+    if from_ms > to_ms {
+        return Err(anyhow!("--from {} is after --to {}", args.from, args.to));
+    }
+    let hour_of = |time_ms: i64| {
+        let time = time_ms.div_euclid(1000);
+        time - time.rem_euclid(3600)
+    };
+    let hours = (hour_of(from_ms)..=hour_of(to_ms))
+        .step_by(3600)
+        .collect::<Vec<i64>>();
+
+    let mut endpoints = Endpoints::new(&config, build_source(&config));
+    let progress = io::stderr().is_terminal();
+    let mut fetched = 0;
+    let mut written = 0;
+    let mut skipped = 0;
+    let mut failures = Vec::new();
+
+    for (i, &hour) in hours.iter().enumerate() {
+        if progress {
+            eprint!(
+                "\rBackfilling {}: hour {}/{} ({} failed)",
+                symbol,
+                i + 1,
+                hours.len(),
+                failures.len()
+            );
+        }
+
+        let path = hour_file::hour_path(&args.out, &symbol, hour, args.hour_format);
+        if path.exists() && hour_file::read(&path, false).is_ok() {
+            skipped += 1;
+            continue;
+        }
+
+        let result = if hour + 3600 > now.timestamp() {
+            // A partial hour would be skipped as complete when resuming
+            Err(anyhow!("the hour has not ended yet"))
+        } else if config.max_api_calls.is_some_and(|budget| fetched >= budget) {
+            Err(anyhow!("API call budget exhausted"))
+        } else {
+            fetched += 1;
+            endpoints
+                .fetch(&symbol, hour, now.timestamp())
+                .and_then(|response| hour_file::write(&path, &symbol, hour, &response.fills))
+        };
+        match result {
+            Ok(()) => written += 1,
+            Err(e) => {
+                error!("Failed to backfill {} hour {}: {:#}", symbol, hour, e);
+                failures.push((hour, e));
+            }
+        }
+    }
+    if progress {
+        eprintln!();
+    }
+
+    info!("Backfilled {} into {}", symbol, args.out.display());
+    for (name, stats) in endpoints.stats() {
+        let (requests, failures, retries) = stats.counts();
+        info!(
+            "Requests to the {} endpoint: {} ({} failed, {} retries)",
+            name, requests, failures, retries
+        );
+    }
+    let mut stdout = io::stdout().lock();
+    writeln!(
+        stdout,
+        "{} hours: {} written, {} already present, {} failed",
+        hours.len(),
+        written,
+        skipped,
+        failures.len()
+    )?;
+    for (hour, e) in &failures {
+        writeln!(stdout, "    {} hour {}: {:#}", symbol, hour, e)?;
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} of {} hours failed",
+            failures.len(),
+            hours.len()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::endpoint::{ArchiveConfig, EndpointPolicy};
+    use crate::source::SourceSpec;
+    use crate::testing::{fill, FlakySource, MemorySource, HOUR};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn hours_are_fetched_through_the_rate_limit_and_retries() {
+        let config = Config {
+            live_policy: EndpointPolicy {
+                rate_limit: Some(20.0),
+                retries: 1,
+                retry_delay: Duration::ZERO,
+                page: None,
+            },
+            ..Config::default()
+        };
+        let source = MemorySource::new(vec![fill(HOUR + 60, 1, 1, "10", "1")]);
+        let flaky = FlakySource {
+            failures: 1,
+            source: source.clone(),
+        };
+        let mut endpoints = Endpoints::new(&config, Box::new(flaky));
+
+        let started = Instant::now();
+        let response = endpoints.fetch("BTC-USD", HOUR, HOUR + 7200).unwrap();
+        assert_eq!(response.fills.len(), 1);
+        endpoints
+            .fetch("BTC-USD", HOUR + 3600, HOUR + 7200)
+            .unwrap();
+        // The retry and the second hour each waited their turn at 20 requests per second
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(source.requests(), 2);
+        let stats = endpoints.stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].0, "live");
+        assert_eq!(stats[0].1.counts(), (3, 1, 1));
+    }
+
+    #[test]
+    fn old_hours_are_fetched_from_the_archive() {
+        let hours = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/multi-hour-range/hours"
+        );
+        let config = Config {
+            archive: Some(ArchiveConfig {
+                source: SourceSpec::Disk(hours.into()),
+                after: Duration::from_secs(48 * 3600),
+                policy: EndpointPolicy::default(),
+            }),
+            ..Config::default()
+        };
+        let live = MemorySource::new(Vec::new());
+        let mut endpoints = Endpoints::new(&config, Box::new(live.clone()));
+
+        let hour = 1700820000;
+        let now = hour + 50 * 3600;
+        assert!(!endpoints
+            .fetch("DEFAULT", hour, now)
+            .unwrap()
+            .fills
+            .is_empty());
+        assert_eq!(live.requests(), 0);
+        endpoints.fetch("DEFAULT", now - 3600, now).unwrap();
+        assert_eq!(live.requests(), 1);
+        let stats = endpoints.stats();
+        assert_eq!(stats[1].0, "archive");
+        assert_eq!(stats[1].1.counts(), (1, 0, 0));
+    }
+}
@@ -0,0 +1,233 @@
+use anyhow::anyhow;
+use clap::Args;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::clock::SystemClock;
+use crate::config::{parse_rate, Config, ConfigArgs};
+use crate::source::{DiskSource, FillSource};
+use crate::workload::{self, WorkloadConfig};
+use crate::{hour_file, logging, stats, Processor};
+
+/// First hour of the workload against the mock source, which serves any hour
+#[cfg(feature = "mock-source")]
+const MOCK_FIRST_HOUR: i64 = 1700816400;
+
+/// Flags of the `bench` command
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    /// Number of queries in the workload
+    #[arg(long, value_name = "N", default_value_t = 10_000)]
+    queries: usize,
+    /// Number of distinct hours the queries are spread over
+    #[arg(long, value_name = "K", default_value_t = 168, value_parser = clap::value_parser!(u64).range(1..))]
+    hours: u64,
+    /// Probability, from 0 to 1, that a query returns to an hour an earlier query touched
+    #[arg(long, value_name = "P", default_value_t = 0.9, value_parser = parse_rate)]
+    hit_ratio: f64,
+    /// Number of workers, each with a processor, cache and source of its own, taking
+    /// the queries in turn like instances behind a round-robin balancer
+    #[arg(long, value_name = "W", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    workers: u64,
+    /// Seed of the workload; the same seed and flags always generate the same queries
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    seed: u64,
+    /// Query the hour files under DIR, as written by `backfill` or `fixture record`,
+    /// instead of the mock source; the workload spreads over the first K hours of the symbol
+    #[arg(long, value_name = "DIR")]
+    dataset: Option<PathBuf>,
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+/// What a worker measured of its share of the workload
+#[derive(Debug, Default)]
+struct WorkerReport {
+    latencies: Vec<Duration>,
+    failed: usize,
+    /// Hours the queries found in the cache, and hours they loaded in all
+    hits: usize,
+    hours: usize,
+    peak_cache_bytes: usize,
+}
+
+/// Entry point of the `bench` command: generates a seeded workload (see `workload::generate`),
+/// answers it through the normal query path against the mock source or a recorded dataset,
+/// and reports the throughput, the latency percentiles, the hit rate achieved and the peak
+/// cache bytes. Failed queries are counted, not fatal.
+pub fn main(args: BenchArgs) -> anyhow::Result<()> {
+    let config = Config::from(args.config.clone());
+    logging::init(config.log_format);
+    let symbol = config.default_symbol.clone();
+    let hours = match &args.dataset {
+        Some(dir) => dataset_hours(&dir.join(&symbol), args.hours as usize)?,
+        None => mock_hours(args.hours)?,
+    };
+    let workload = WorkloadConfig {
+        seed: args.seed,
+        queries: args.queries,
+        hours: hours.clone(),
+        hit_ratio: args.hit_ratio,
+    };
+    let queries = workload::generate(&workload);
+    info!(
+        "Running {} queries over {} hours of {} with {} workers",
+        queries.len(),
+        hours.len(),
+        symbol,
+        args.workers
+    );
+
+    let workers = args.workers as usize;
+    let start = Instant::now();
+    let reports = thread::scope(|scope| {
+        let handles = (0..workers)
+            .map(|worker| {
+                let queries = queries
+                    .iter()
+                    .skip(worker)
+                    .step_by(workers)
+                    .cloned()
+                    .collect::<Vec<String>>();
+                let config = Config::from(args.config.clone());
+                let dataset = args.dataset.clone();
+                scope.spawn(move || run_worker(config, dataset, queries))
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .map_err(|_| anyhow!("A bench worker panicked"))
+            })
+            .collect::<anyhow::Result<Vec<WorkerReport>>>()
+    })?;
+    let elapsed = start.elapsed();
+
+    let latencies = reports
+        .iter()
+        .flat_map(|report| report.latencies.iter().copied())
+        .collect::<Vec<Duration>>();
+    let failed = reports.iter().map(|report| report.failed).sum::<usize>();
+    let hits = reports.iter().map(|report| report.hits).sum::<usize>();
+    let loaded = reports.iter().map(|report| report.hours).sum::<usize>();
+    let peak_cache_bytes = reports
+        .iter()
+        .map(|report| report.peak_cache_bytes)
+        .sum::<usize>();
+    let [p50, p90, p99, max] = [50, 90, 99, 100].map(|p| stats::percentile(&latencies, p));
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+
+    let mut stdout = io::stdout().lock();
+    writeln!(
+        stdout,
+        "Workload: {} queries over {} hours, hit ratio {}, seed {}, {} workers, {}",
+        latencies.len(),
+        hours.len(),
+        args.hit_ratio,
+        args.seed,
+        workers,
+        args.dataset
+            .as_ref()
+            .map_or("mock source".to_string(), |dir| format!(
+                "dataset {}",
+                dir.display()
+            ))
+    )?;
+    writeln!(
+        stdout,
+        "Throughput: {:.1} queries/s ({} queries in {:.3}s, {} failed)",
+        latencies.len() as f64 / elapsed.as_secs_f64().max(f64::MIN_POSITIVE),
+        latencies.len(),
+        elapsed.as_secs_f64(),
+        failed
+    )?;
+    writeln!(
+        stdout,
+        "Latency: p50 {:.3}ms, p90 {:.3}ms, p99 {:.3}ms, max {:.3}ms",
+        ms(p50),
+        ms(p90),
+        ms(p99),
+        ms(max)
+    )?;
+    writeln!(
+        stdout,
+        "Hit rate: {:.2}% ({} of {} hours from the cache)",
+        stats::hit_rate(hits, loaded - hits),
+        hits,
+        loaded
+    )?;
+    writeln!(
+        stdout,
+        "Peak cache bytes: {} (summed over workers)",
+        peak_cache_bytes
+    )?;
+    Ok(())
+}
+
+/// Answers a worker's share of the queries with a processor of its own
+fn run_worker(config: Config, dataset: Option<PathBuf>, queries: Vec<String>) -> WorkerReport {
+    let source: Box<dyn FillSource> = match dataset {
+        Some(dir) => Box::new(DiskSource {
+            dir,
+            strict_schema: config.strict_schema,
+        }),
+        None => mock_source(&config),
+    };
+    let mut processor = Processor::with_source(config, source, Box::new(SystemClock));
+    let mut report = WorkerReport::default();
+    processor.process_queries(queries, |outcome| {
+        report.latencies.push(outcome.duration);
+        report.failed += outcome.result.is_err() as usize;
+        report.hits += outcome.hours.iter().filter(|hour| hour.hit).count();
+        report.hours += outcome.hours.len();
+    });
+    report.peak_cache_bytes = processor.peak_cache_bytes();
+    report
+}
+
+/// Returns the first `count` hours of the symbol's hour files under `dir`
+fn dataset_hours(dir: &Path, count: usize) -> anyhow::Result<Vec<i64>> {
+    let mut hours = hour_file::hours(dir)?;
+    hours.truncate(count);
+    if hours.len() < count {
+        info!(
+            "{} holds only {} hours, spreading the workload over them",
+            dir.display(),
+            hours.len()
+        );
+    }
+    Ok(hours)
+}
+
+/// Returns `count` consecutive hours of the mock source
+#[cfg(feature = "mock-source")]
+fn mock_hours(count: u64) -> anyhow::Result<Vec<i64>> {
+    Ok((0..count as i64)
+        .map(|i| MOCK_FIRST_HOUR + i * 3600)
+        .collect())
+}
+
+#[cfg(not(feature = "mock-source"))]
+fn mock_hours(_count: u64) -> anyhow::Result<Vec<i64>> {
+    Err(anyhow!(
+        "bench needs --dataset, or a build with the mock-source feature"
+    ))
+}
+
+#[cfg(feature = "mock-source")]
+fn mock_source(config: &Config) -> Box<dyn FillSource> {
+    Box::new(crate::mock::MockSource::new(
+        config.mock.clone().unwrap_or_default(),
+    ))
+}
+
+// Unreachable: without the feature, `mock_hours` fails before any worker starts
+#[cfg(not(feature = "mock-source"))]
+fn mock_source(_config: &Config) -> Box<dyn FillSource> {
+    unreachable!("the mock source is not built in")
+}
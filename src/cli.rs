@@ -0,0 +1,121 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+use crate::backfill::BackfillArgs;
+use crate::bench::BenchArgs;
+use crate::config::ConfigArgs;
+use crate::fixture::FixtureCommand;
+#[cfg(feature = "net")]
+use crate::serve::ServeArgs;
+use crate::snapshot_tool::{ExportArgs, ImportArgs, StatsArgs, WarmArgs};
+use crate::watch::WatchArgs;
+
+/// Caching proxy answering trade queries over the orderbook trades API
+#[derive(Debug, Parser)]
+#[command(version, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Flags of the `query` command, which runs when no command is given
+    #[command(flatten)]
+    pub query: QueryArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Process queries, one per line, and print one result line per query (the default)
+    Query(QueryArgs),
+    /// Download every hour of a time range into hour files
+    Backfill(BackfillArgs),
+    /// Answer a seeded synthetic workload against the mock source or a recorded dataset
+    /// and report throughput, latency, hit rate and peak cache bytes
+    Bench(BenchArgs),
+    /// Record and replay fixtures of upstream responses
+    Fixture {
+        #[command(subcommand)]
+        command: FixtureCommand,
+    },
+    /// Watch a spool directory and answer every query file renamed into it, keeping the
+    /// cache warm from file to file
+    Watch(WatchArgs),
+    /// Serve queries to any number of clients over HTTP and the line protocol on TCP or
+    /// Unix sockets, with a pool of workers
+    #[cfg(feature = "net")]
+    Serve(ServeArgs),
+    /// Answer queries without printing the answers, to load the hours they touch into the
+    /// snapshot file a later run starts from
+    Warm(WarmArgs),
+    /// Write the hours of a snapshot file to hour files
+    Export(ExportArgs),
+    /// Load the hour files of a directory into a snapshot file
+    Import(ImportArgs),
+    /// Load a snapshot file and print its statistics
+    Stats(StatsArgs),
+}
+
+/// Compression of the query input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum InputCompression {
+    /// Plain text
+    None,
+    /// Gzip, possibly of several concatenated members
+    Gzip,
+}
+
+/// Flags of the `query` command
+#[derive(Debug, Args)]
+pub struct QueryArgs {
+    /// Read the queries from FILE instead of stdin
+    #[arg(long, value_name = "FILE")]
+    pub input: Option<PathBuf>,
+    /// Compression of the query input; by default an --input file ending in .gz is gzip
+    /// and anything else is plain text
+    #[arg(long, value_enum, value_name = "COMPRESSION")]
+    pub input_compression: Option<InputCompression>,
+    /// Only report how many hours the queries need and how many of them would be API calls,
+    /// without fetching anything or printing results
+    #[arg(long)]
+    pub dry_run: bool,
+    /// List the distinct hours of every symbol in the dry-run report
+    #[arg(long, requires = "dry_run")]
+    pub list_hours: bool,
+    /// After processing, write a JSON summary of the run (queries, failures, cache and API
+    /// statistics, latencies, duration and exit code) to PATH, or to stderr when PATH is "-"
+    #[arg(long, value_name = "PATH", conflicts_with = "dry_run")]
+    pub summary_json: Option<PathBuf>,
+    /// Answer the queries with N workers, each with a processor, cache and source of its
+    /// own, writing the answers in input order. The first worker loads and writes the
+    /// snapshot, and commands acting on one cache, like SUB or DQ, need a single worker.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with_all = ["summary_json", "lookahead", "retry_failed"]
+    )]
+    pub workers: u64,
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn flags_are_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn no_command_runs_query() {
+        let cli = Cli::try_parse_from(["interview", "--workers", "2"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.query.workers, 2);
+        assert!(Cli::try_parse_from(["interview", "--workers", "0"]).is_err());
+        assert!(Cli::try_parse_from(["interview", "--workers", "2", "--lookahead", "4"]).is_err());
+        let cli = Cli::try_parse_from(["interview", "stats", "snapshot.json"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Stats(_))));
+    }
+}
@@ -0,0 +1,465 @@
+//! Client of the `serve` command, for programs embedding the proxy. A `ProxyClient` speaks
+//! the binary protocol on a TCP or Unix socket (see `serve::binary`), or HTTP, and answers
+//! with the values the server computes rather than text to parse.
+//!
+//! ```no_run
+//! use interview::client::ProxyClient;
+//!
+//! let mut client = ProxyClient::connect_tcp("127.0.0.1:7000".parse().unwrap())?;
+//! let trades = client.count(1700820000, 1700830000)?;
+//! # Ok::<(), interview::client::ClientError>(())
+//! ```
+
+use rust_decimal::Decimal;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::pool::Priority;
+use crate::processor::TIMEOUT;
+use crate::query::QueryKind;
+use crate::serve::binary::{self, Request, Response, Status, Value};
+use crate::server::{Fill, Side};
+
+/// Why a request of a client failed
+#[derive(Debug)]
+pub enum ClientError {
+    /// The server couldn't be reached, or the connection failed, and kept failing when
+    /// reconnected
+    Connection(io::Error),
+    /// The server answered with something this client doesn't understand
+    Protocol(String),
+    /// The query failed, with the message of the error
+    Query(String),
+    /// The server's upstream was unavailable, with the message of the error
+    Unavailable(String),
+    /// The server's queue was full; the request may be retried later
+    Busy(String),
+    /// The query was still unanswered when its deadline passed
+    Timeout,
+    /// The transport of the client can't make the request
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Connection(e) => write!(f, "Failed to reach the server: {}", e),
+            ClientError::Protocol(message) => write!(f, "Unexpected answer: {}", message),
+            ClientError::Query(message) => write!(f, "The query failed: {}", message),
+            ClientError::Unavailable(message) => {
+                write!(f, "The upstream was unavailable: {}", message)
+            }
+            ClientError::Busy(message) => write!(f, "{}", message),
+            ClientError::Timeout => write!(f, "The query timed out"),
+            ClientError::Unsupported(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// Delays between the attempts to reach a socket server: the first retry waits `initial`,
+/// each later one twice as long as the one before, up to `max`
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    /// Number of attempts after the first before a request fails
+    pub retries: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(50),
+            max: Duration::from_secs(2),
+            retries: 5,
+        }
+    }
+}
+
+/// Where a client sends its requests
+enum Endpoint {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    Http(SocketAddr),
+}
+
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Client of a server started by `serve`. Socket clients keep one connection, reopened
+/// with `Backoff` when it fails, and retry the request on it; HTTP clients open a
+/// connection per request, as the server closes it after answering.
+pub struct ProxyClient {
+    endpoint: Endpoint,
+    /// Connection of a socket client, in the binary protocol; `None` until it is reopened
+    connection: Option<BufReader<Box<dyn Stream>>>,
+    backoff: Backoff,
+    priority: Priority,
+}
+
+impl ProxyClient {
+    /// Connects to the TCP listener of a server at `addr`
+    pub fn connect_tcp(addr: SocketAddr) -> Result<ProxyClient, ClientError> {
+        ProxyClient::connect(Endpoint::Tcp(addr), Backoff::default())
+    }
+
+    /// Connects to the Unix socket listener of a server at `path`
+    pub fn connect_unix(path: impl AsRef<Path>) -> Result<ProxyClient, ClientError> {
+        ProxyClient::connect(
+            Endpoint::Unix(path.as_ref().to_path_buf()),
+            Backoff::default(),
+        )
+    }
+
+    /// Returns a client of the HTTP listener of a server at `addr`
+    pub fn http(addr: SocketAddr) -> ProxyClient {
+        ProxyClient {
+            endpoint: Endpoint::Http(addr),
+            connection: None,
+            backoff: Backoff::default(),
+            priority: Priority::Interactive,
+        }
+    }
+
+    fn connect(endpoint: Endpoint, backoff: Backoff) -> Result<ProxyClient, ClientError> {
+        let mut client = ProxyClient {
+            endpoint,
+            connection: None,
+            backoff,
+            priority: Priority::Interactive,
+        };
+        client.with_retries(|_| Ok(()))?;
+        Ok(client)
+    }
+
+    /// Sets the delays between the attempts to reach a socket server
+    pub fn with_backoff(mut self, backoff: Backoff) -> ProxyClient {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Sets the class the queries of the client are queued in, interactive by default
+    pub fn with_priority(mut self, priority: Priority) -> ProxyClient {
+        self.priority = priority;
+        self
+    }
+
+    /// Returns the number of taker trades of the default symbol in the window (START, END],
+    /// in Unix seconds, like `C START END`
+    pub fn count(&mut self, start: i64, end: i64) -> Result<i64, ClientError> {
+        match self.query(QueryKind::Count, start, end)?.value {
+            Value::Integer(count) => Ok(count),
+            value => Err(unexpected(QueryKind::Count, value)),
+        }
+    }
+
+    /// Returns the USD volume of the default symbol in the window, like `V START END`
+    pub fn volume(&mut self, start: i64, end: i64) -> Result<Decimal, ClientError> {
+        match self.query(QueryKind::Volume, start, end)?.value {
+            Value::Integer(volume) => Ok(Decimal::from(volume)),
+            Value::Decimal(volume) => Ok(volume),
+            value => Err(unexpected(QueryKind::Volume, value)),
+        }
+    }
+
+    /// Returns the volume-weighted average price of the market buys or sells of the default
+    /// symbol in the window, like `WB START END` or `WS START END`, or `None` without any
+    pub fn vwap(
+        &mut self,
+        side: Side,
+        start: i64,
+        end: i64,
+    ) -> Result<Option<Decimal>, ClientError> {
+        let kind = match side {
+            Side::Buy => QueryKind::BuyVwap,
+            Side::Sell => QueryKind::SellVwap,
+            Side::Unknown => {
+                return Err(ClientError::Unsupported(
+                    "A VWAP is of the market buys or the market sells",
+                ))
+            }
+        };
+        match self.query(kind, start, end)?.value {
+            Value::Integer(price) => Ok(Some(Decimal::from(price))),
+            Value::Decimal(price) => Ok(Some(price)),
+            Value::Text(text) if text == "NONE" => Ok(None),
+            value => Err(unexpected(kind, value)),
+        }
+    }
+
+    /// Answers a query of the default symbol, as a response whose status is `Status::Ok`;
+    /// `MA` and `BURST`, which take more parameters, fail over sockets
+    pub fn query(
+        &mut self,
+        kind: QueryKind,
+        start: i64,
+        end: i64,
+    ) -> Result<Response, ClientError> {
+        let request = Request {
+            kind,
+            priority: self.priority,
+            start,
+            end,
+        };
+        let response = match self.endpoint {
+            Endpoint::Http(addr) => {
+                let target = format!(
+                    "/query?q={}&priority={}",
+                    encode_component(&request.query()),
+                    self.priority.name()
+                );
+                let response = http_get(addr, &target)?;
+                let body = String::from_utf8_lossy(&response.body)
+                    .trim_end()
+                    .to_string();
+                match response.status {
+                    200 => Response {
+                        status: Status::Ok,
+                        is_final: response.header("x-final") == Some("true"),
+                        value: if body == TIMEOUT {
+                            Value::Text(body)
+                        } else {
+                            Value::of_answer(&body)
+                        },
+                    },
+                    status => return Err(http_error(status, body)),
+                }
+            }
+            Endpoint::Tcp(_) | Endpoint::Unix(_) => {
+                let payload = request.encode();
+                self.with_retries(|connection| {
+                    binary::write_frame(connection.get_mut(), &payload)?;
+                    connection.get_mut().flush()?;
+                    match binary::read_frame(connection) {
+                        Ok(Some(payload)) => Ok(payload),
+                        Ok(None) => Err(io::ErrorKind::UnexpectedEof.into()),
+                        Err(e) => Err(io::Error::other(e)),
+                    }
+                })
+                .and_then(|payload| {
+                    Response::decode(&payload)
+                        .map_err(|e| ClientError::Protocol(format!("{:#}", e)))
+                })?
+            }
+        };
+        match (response.status, &response.value) {
+            (Status::Ok, Value::Text(text)) if text == TIMEOUT => Err(ClientError::Timeout),
+            (Status::Ok, _) => Ok(response),
+            (Status::Timeout, _) => Err(ClientError::Timeout),
+            (Status::Busy, value) => Err(ClientError::Busy(text_of(value))),
+            (Status::Error, value) => Err(ClientError::Query(text_of(value))),
+        }
+    }
+
+    /// Returns the fills of the default symbol in the window (START, END], in Unix
+    /// seconds, as a query of the window sees them, fetching every page of `GET /fills`.
+    /// Only HTTP clients can, as the socket protocols answer queries only.
+    pub fn fills_in_range(&mut self, start: i64, end: i64) -> Result<Vec<Fill>, ClientError> {
+        let Endpoint::Http(addr) = self.endpoint else {
+            return Err(ClientError::Unsupported(
+                "The fills of a window are served over HTTP only",
+            ));
+        };
+        let mut fills = Vec::new();
+        let mut cursor = None;
+        loop {
+            let mut target = format!(
+                "/fills?start={}&end={}&format=ndjson&priority={}",
+                start,
+                end,
+                self.priority.name()
+            );
+            if let Some(cursor) = &cursor {
+                target += &format!("&cursor={}", cursor);
+            }
+            let response = http_get(addr, &target)?;
+            if response.status != 200 {
+                return Err(http_error(
+                    response.status,
+                    String::from_utf8_lossy(&response.body)
+                        .trim_end()
+                        .to_string(),
+                ));
+            }
+            for line in response
+                .body
+                .split(|byte| *byte == b'\n')
+                .filter(|line| !line.is_empty())
+            {
+                fills.push(
+                    serde_json::from_slice(line)
+                        .map_err(|e| ClientError::Protocol(format!("Invalid fill: {}", e)))?,
+                );
+            }
+            match response.header("x-next-cursor") {
+                Some(next) => cursor = Some(next.to_string()),
+                None => return Ok(fills),
+            }
+        }
+    }
+
+    /// Runs `exchange` on the connection of a socket client, opening it first when it isn't,
+    /// and reopening it and running `exchange` again while either fails, waiting out
+    /// `backoff` between the attempts
+    fn with_retries<T>(
+        &mut self,
+        mut exchange: impl FnMut(&mut BufReader<Box<dyn Stream>>) -> io::Result<T>,
+    ) -> Result<T, ClientError> {
+        let mut delay = self.backoff.initial;
+        let mut attempt = 0;
+        loop {
+            let result = match self.connection.as_mut() {
+                Some(connection) => exchange(connection),
+                None => match self.open() {
+                    Ok(connection) => exchange(self.connection.insert(connection)),
+                    Err(e) => Err(e),
+                },
+            };
+            match result {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    self.connection = None;
+                    if attempt >= self.backoff.retries {
+                        return Err(ClientError::Connection(e));
+                    }
+                }
+            }
+            attempt += 1;
+            thread::sleep(delay);
+            delay = (delay * 2).min(self.backoff.max);
+        }
+    }
+
+    /// Opens a connection to a socket server, in the binary protocol
+    fn open(&self) -> io::Result<BufReader<Box<dyn Stream>>> {
+        let mut stream: Box<dyn Stream> = match &self.endpoint {
+            Endpoint::Tcp(addr) => Box::new(TcpStream::connect(addr)?),
+            Endpoint::Unix(path) => Box::new(UnixStream::connect(path)?),
+            Endpoint::Http(_) => unreachable!("HTTP clients don't keep a connection"),
+        };
+        stream.write_all(&[binary::MAGIC])?;
+        Ok(BufReader::new(stream))
+    }
+}
+
+/// Returns the error of an answer of a query that isn't of the value its kind answers with
+fn unexpected(kind: QueryKind, value: Value) -> ClientError {
+    ClientError::Protocol(format!("{} answered {:?}", kind, value))
+}
+
+fn text_of(value: &Value) -> String {
+    match value {
+        Value::Integer(integer) => integer.to_string(),
+        Value::Decimal(decimal) => decimal.to_string(),
+        Value::Text(text) => text.clone(),
+    }
+}
+
+/// Returns the error of an HTTP answer other than 200, with its body
+fn http_error(status: u16, body: String) -> ClientError {
+    match status {
+        400 => ClientError::Query(body),
+        429 => ClientError::Busy(body),
+        503 => ClientError::Unavailable(body),
+        status => ClientError::Protocol(format!("HTTP status {}: {}", status, body)),
+    }
+}
+
+/// Percent-encodes a query string component, every byte but the unreserved ones
+fn encode_component(component: &str) -> String {
+    component
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// A response to `http_get`
+struct HttpResponse {
+    status: u16,
+    /// Header names in lowercase, and their values
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Returns the value of the first header of the name, given in lowercase
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Sends a GET request to `addr` and returns the response
+fn http_get(addr: SocketAddr, target: &str) -> Result<HttpResponse, ClientError> {
+    let protocol = |message: &str| ClientError::Protocol(message.to_string());
+    let mut stream = TcpStream::connect(addr).map_err(ClientError::Connection)?;
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        target, addr
+    )
+    .map_err(ClientError::Connection)?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .map_err(ClientError::Connection)?;
+    let status = line
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| protocol(&format!("Invalid HTTP status line {:?}", line)))?;
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        reader
+            .read_line(&mut line)
+            .map_err(ClientError::Connection)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| protocol(&format!("Invalid HTTP header {:?}", line)))?;
+        headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+    }
+    let mut response = HttpResponse {
+        status,
+        headers,
+        body: Vec::new(),
+    };
+    match response.header("content-length").map(str::parse::<u64>) {
+        Some(Ok(length)) => reader.take(length).read_to_end(&mut response.body),
+        Some(Err(_)) => return Err(protocol("Invalid Content-Length")),
+        None => reader.read_to_end(&mut response.body),
+    }
+    .map_err(ClientError::Connection)?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_components_are_percent_encoded() {
+        assert_eq!(encode_component("C 1700820000 -1"), "C%201700820000%20-1");
+        assert_eq!(encode_component("(] a&b=c"), "%28%5D%20a%26b%3Dc");
+    }
+}
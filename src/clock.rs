@@ -0,0 +1,120 @@
+use chrono::{DateTime, Utc};
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub use host::Instant;
+/// Monotonic time of durations and deadlines. `std::time::Instant` everywhere it works;
+/// on `wasm32-unknown-unknown`, whose standard library has no clock, one read from the
+/// host's `env.interview_now_ms` import.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub use std::time::Instant;
+
+/// Source of the current time, injectable so time-dependent behavior
+/// (incomplete hours, queries reaching into the future) can be controlled
+pub trait Clock {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system's wall clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        return Utc::now();
+        #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+        return DateTime::from_timestamp_millis(host::now_ms() as i64).unwrap_or_default();
+    }
+}
+
+/// Returns the nanoseconds since the Unix epoch of the wall clock, zero when it is set
+/// before the epoch
+pub fn unix_nanos() -> u64 {
+    SystemClock
+        .now()
+        .timestamp_nanos_opt()
+        .map_or(0, |nanos| nanos.max(0) as u64)
+}
+
+/// Time of the host of a `wasm32-unknown-unknown` build, which imports it
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+mod host {
+    use std::ops::{Add, AddAssign, Sub};
+    use std::time::Duration;
+
+    #[link(wasm_import_module = "env")]
+    extern "C" {
+        /// Milliseconds since the Unix epoch, like JavaScript's `Date.now()` or
+        /// `performance.timeOrigin + performance.now()`
+        fn interview_now_ms() -> f64;
+    }
+
+    pub fn now_ms() -> f64 {
+        // SAFETY: the import takes nothing and returns a number
+        unsafe { interview_now_ms() }
+    }
+
+    /// Time since the Unix epoch, kept from going backward
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct Instant(Duration);
+
+    /// Latest time read, so a host clock set back doesn't move instants backward
+    static LATEST_MS: std::sync::Mutex<f64> = std::sync::Mutex::new(0.0);
+
+    impl Instant {
+        pub fn now() -> Instant {
+            let mut latest = LATEST_MS.lock().unwrap_or_else(|e| e.into_inner());
+            *latest = latest.max(now_ms());
+            Instant(Duration::from_secs_f64(*latest / 1000.0))
+        }
+
+        pub fn duration_since(&self, earlier: Instant) -> Duration {
+            self.saturating_duration_since(earlier)
+        }
+
+        pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+            self.0.saturating_sub(earlier.0)
+        }
+
+        pub fn elapsed(&self) -> Duration {
+            Instant::now().saturating_duration_since(*self)
+        }
+
+        pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+            self.0.checked_add(duration).map(Instant)
+        }
+
+        pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+            self.0.checked_sub(duration).map(Instant)
+        }
+    }
+
+    impl Add<Duration> for Instant {
+        type Output = Instant;
+
+        fn add(self, duration: Duration) -> Instant {
+            Instant(self.0 + duration)
+        }
+    }
+
+    impl AddAssign<Duration> for Instant {
+        fn add_assign(&mut self, duration: Duration) {
+            self.0 += duration;
+        }
+    }
+
+    impl Sub<Duration> for Instant {
+        type Output = Instant;
+
+        fn sub(self, duration: Duration) -> Instant {
+            Instant(self.0 - duration)
+        }
+    }
+
+    impl Sub<Instant> for Instant {
+        type Output = Duration;
+
+        fn sub(self, earlier: Instant) -> Duration {
+            self.saturating_duration_since(earlier)
+        }
+    }
+}
@@ -0,0 +1,668 @@
+use clap::{Args, ValueEnum};
+use rust_decimal::RoundingStrategy;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::endpoint::{ArchiveConfig, EndpointPolicy, MIN_RATE_LIMIT};
+use crate::logging::LogFormat;
+use crate::output::{OutputFormat, Template};
+use crate::query::Bounds;
+use crate::retry::RetryConfig;
+use crate::server;
+use crate::snapshot::{SnapshotConfig, SnapshotFormat};
+use crate::source::{SourceSpec, VenueSpec, DEFAULT_VENUE};
+
+/// What to do with queries whose end time is beyond the current time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FuturePolicy {
+    /// Fail the query with an error
+    Reject,
+    /// Answer the query up to the current time and annotate the result as CLAMPED
+    Clamp,
+}
+
+/// How cached hours fetched before they ended are refreshed between queries,
+/// once their TTL has elapsed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StaleRefresh {
+    /// Fetch the hour again right away
+    Refetch,
+    /// Evict the hour, so the next query touching it fetches it again
+    Invalidate,
+}
+
+/// Rounding of decimal answers to a fixed scale, for values halfway between two digits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Rounding {
+    /// To the even digit (banker's rounding): 0.125 to 0.12, 0.135 to 0.14
+    HalfEven,
+    /// Away from zero: 0.125 to 0.13, -0.125 to -0.13
+    HalfUp,
+}
+
+impl From<Rounding> for RoundingStrategy {
+    fn from(rounding: Rounding) -> Self {
+        match rounding {
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+        }
+    }
+}
+
+/// Fields identifying the taker trade of a fill, so fills of one trade count once
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DedupKey {
+    /// The sequence number
+    SequenceNumber,
+    /// The venue's trade id, or the sequence number for fills without one
+    TradeId,
+    /// The sequence number together with the fill time
+    SequenceNumberTime,
+}
+
+/// A dedup key for a venue, given as `[VENUE=]KEY` with the default venue when none is named
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DedupKeySpec {
+    pub venue: String,
+    pub key: DedupKey,
+}
+
+impl FromStr for DedupKeySpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (venue, key) = s.split_once('=').unwrap_or((DEFAULT_VENUE, s));
+        Ok(DedupKeySpec {
+            venue: venue.to_string(),
+            key: <DedupKey as ValueEnum>::from_str(key, true)?,
+        })
+    }
+}
+
+/// Default of `--max-range-hours`: a month of hours
+pub const DEFAULT_MAX_RANGE_HOURS: u64 = 720;
+
+/// Runtime configuration of the query processor, built from command-line flags
+/// with environment variables as fallback
+pub struct Config {
+    /// Symbol used by queries that don't name one
+    pub default_symbol: String,
+    /// Queries taking longer than this are logged at warn level; `None` disables the log
+    pub slow_query_threshold: Option<Duration>,
+    /// Queries still unanswered this long after they started are answered TIMEOUT;
+    /// `None` waits for every answer
+    pub query_timeout: Option<Duration>,
+    /// Number of input queries read ahead of the one being answered, whose uncached hours
+    /// are fetched in the background meanwhile; `None` fetches every hour when it is needed
+    pub lookahead: Option<usize>,
+    /// Format of log records
+    pub log_format: LogFormat,
+    /// Format of the answers
+    pub output_format: OutputFormat,
+    /// Shape of the text answers to queries; `None` writes the bare results
+    pub output_template: Option<Template>,
+    /// Log the statistics every this many processed queries; `None` disables it
+    pub stats_every_queries: Option<usize>,
+    /// Log the statistics every this long; `None` disables it
+    pub stats_interval: Option<Duration>,
+    /// Handling of queries whose end time is beyond the current time
+    pub future_policy: FuturePolicy,
+    /// How long an hour fetched before it ended is served from the cache before being refetched
+    pub incomplete_ttl: Duration,
+    /// Refresh of stale incomplete hours between queries; `None` refreshes them only
+    /// when a query touches them
+    pub stale_refresh: Option<StaleRefresh>,
+    /// Refresh stale incomplete hours by fetching only the fills after the latest cached one
+    pub differential_refresh: bool,
+    /// Bounds of the windows of queries that don't give their own
+    pub window_bounds: Bounds,
+    /// Maximum number of hours a single query may touch, at least 1
+    pub max_range_hours: u64,
+    /// Number of hours the cache holds, across all symbols
+    pub cache_hours: usize,
+    /// Bytes the cache holds, evicting the least recently used hours past it; `None`
+    /// bounds the cache by hours only. Overrides `cache_mem_fraction`.
+    pub cache_bytes: Option<usize>,
+    /// Fraction of the memory available at startup the cache holds, when `cache_bytes`
+    /// isn't given; `None` bounds the cache by hours only
+    pub cache_mem_fraction: Option<f64>,
+    /// How often the available memory is checked again with `cache_mem_fraction`
+    pub memory_check_interval: Duration,
+    /// Fraction of the system's memory below which the available memory counts as under
+    /// pressure, shrinking the cache budget to `cache_mem_fraction` of what is left
+    pub memory_pressure: f64,
+    /// Before each query, evict the cached hours starting more than this long ago;
+    /// `None` leaves eviction to the LRU
+    pub auto_evict_age: Option<Duration>,
+    /// Maximum number of requests made to the upstream; `None` means unlimited
+    pub max_api_calls: Option<usize>,
+    /// Hours with more fills than this are cached as per-hour aggregates instead of fills;
+    /// `None` caches every hour's fills
+    pub stream_threshold: Option<usize>,
+    /// Hours with more fills than this are kept in a slot of their own instead of the cache,
+    /// so they don't evict other hours; `None` caches hours of any size
+    pub max_cached_hour_fills: Option<usize>,
+    /// Log failed queries and continue with the next one instead of stopping the run
+    pub keep_going: bool,
+    /// Retry queries failed by an unavailable upstream later instead of failing them;
+    /// `None` fails them right away
+    pub retry_failed: Option<RetryConfig>,
+    /// Upstream that hours of the default venue are fetched from
+    pub source: SourceSpec,
+    /// Request rate and retries of `source`
+    pub live_policy: EndpointPolicy,
+    /// Upstream of the default venue's hours too old for `source`; `None` fetches
+    /// every hour from `source`
+    pub archive: Option<ArchiveConfig>,
+    /// Upstreams of the venues queries can name with an "@VENUE" prefix
+    pub venues: BTreeMap<String, SourceSpec>,
+    /// Dedup keys of the venues that don't identify taker trades by sequence number
+    pub dedup_keys: BTreeMap<String, DedupKey>,
+    /// Skip fills that fail to parse instead of failing the fetch
+    pub lenient_parse: bool,
+    /// Fail the fetch of an hour holding a fill with a non-positive price or quantity
+    /// instead of dropping the fill
+    pub strict_data: bool,
+    /// Fail the fetch of an hour holding a fill of unknown direction
+    pub strict_direction: bool,
+    /// Fail the fetch of an hour whose fills have fields unknown to `Fill` instead of ignoring them
+    pub strict_schema: bool,
+    /// Suffix count and volume answers with the fill counts of the hours they touched
+    pub verbose_results: bool,
+    /// Number of fraction digits every decimal answer is rounded and padded to;
+    /// `None` prints decimal answers as computed
+    pub scale: Option<u32>,
+    /// Rounding of decimal answers to the scale
+    pub rounding: Rounding,
+    /// Fraction of queries additionally answered from fresh fetches to cross-check the cache
+    pub verify_sample: f64,
+    /// File that a JSON record of every processed query is appended to
+    pub audit_log: Option<PathBuf>,
+    /// Time the phases of every query and report them in the final statistics
+    pub profile: bool,
+    /// Keep an index of the cached hours by sequence number for CS and VS lines,
+    /// instead of scanning the cache for them
+    pub seq_index: bool,
+    /// Snapshot the complete cached hours to a file while running and reload them at startup;
+    /// `None` starts with an empty cache
+    pub snapshot: Option<SnapshotConfig>,
+    /// Serve fills from the deterministic mock source instead of the trades API
+    #[cfg(feature = "mock-source")]
+    pub mock: Option<crate::mock::MockConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            default_symbol: server::DEFAULT_SYMBOL.to_string(),
+            slow_query_threshold: None,
+            query_timeout: None,
+            lookahead: None,
+            log_format: LogFormat::Text,
+            output_format: OutputFormat::Text,
+            output_template: None,
+            stats_every_queries: None,
+            stats_interval: None,
+            future_policy: FuturePolicy::Reject,
+            incomplete_ttl: Duration::from_secs(60),
+            stale_refresh: None,
+            differential_refresh: false,
+            window_bounds: Bounds::default(),
+            max_range_hours: DEFAULT_MAX_RANGE_HOURS,
+            cache_hours: 168,
+            cache_bytes: None,
+            cache_mem_fraction: None,
+            memory_check_interval: Duration::from_secs(60),
+            memory_pressure: 0.1,
+            auto_evict_age: None,
+            max_api_calls: None,
+            stream_threshold: None,
+            max_cached_hour_fills: None,
+            keep_going: false,
+            retry_failed: None,
+            source: SourceSpec::Api,
+            live_policy: EndpointPolicy::default(),
+            archive: None,
+            venues: BTreeMap::new(),
+            dedup_keys: BTreeMap::new(),
+            lenient_parse: false,
+            strict_data: false,
+            strict_direction: false,
+            strict_schema: false,
+            verbose_results: false,
+            scale: None,
+            rounding: Rounding::HalfEven,
+            verify_sample: 0.0,
+            audit_log: None,
+            profile: false,
+            seq_index: false,
+            snapshot: None,
+            #[cfg(feature = "mock-source")]
+            mock: None,
+        }
+    }
+}
+
+/// Command-line flags of the query processor, with environment variables as fallback
+#[derive(Debug, Clone, Args)]
+pub struct ConfigArgs {
+    /// Symbol used by queries that don't name one
+    #[arg(long, value_name = "SYMBOL", env = "DEFAULT_SYMBOL", default_value = server::DEFAULT_SYMBOL)]
+    default_symbol: String,
+    /// Log a warning for every query taking longer than MS milliseconds; 0 disables it
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    slow_query_ms: u64,
+    /// Answer TIMEOUT to every query still unanswered MS milliseconds after it started,
+    /// fetches and aggregation included, leaving its fetches to complete in the background
+    /// and populate the cache; 0 disables it
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    query_timeout_ms: u64,
+    /// Read up to N queries ahead of the one being answered and fetch the uncached hours
+    /// they need in the background, at most N fetches at a time; 0 disables it
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    lookahead: usize,
+    /// Format of log records
+    #[arg(long, value_enum, default_value = "text")]
+    log_format: LogFormat,
+    /// Format of the answers: text lines, or length-prefixed MessagePack maps, JSON lines or
+    /// CSV rows for programs (logs always go to stderr)
+    #[arg(
+        long = "output",
+        value_enum,
+        value_name = "FORMAT",
+        default_value = "text"
+    )]
+    output_format: OutputFormat,
+    /// Write the text answers to queries in the shape of TEMPLATE, replacing the placeholders
+    /// {query}, {type}, {start}, {end}, {result}, {cache_hits} and {duration_ms};
+    /// "{{" and "}}" write literal braces
+    #[arg(long = "format", value_name = "TEMPLATE")]
+    output_template: Option<Template>,
+    /// Log the cache/API statistics every N processed queries; 0 disables it
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    stats_every: usize,
+    /// Log the cache/API statistics every T seconds; 0 disables it
+    #[arg(long, value_name = "T", default_value_t = 0)]
+    stats_interval_secs: u64,
+    /// Handling of queries whose end time is beyond the current time
+    #[arg(long, value_enum, default_value = "reject")]
+    future_policy: FuturePolicy,
+    /// How many seconds an hour fetched before it ended is served from the cache
+    #[arg(long, value_name = "T", default_value_t = 60)]
+    incomplete_ttl_secs: u64,
+    /// Before each query, refresh every cached hour whose incomplete-hour TTL has elapsed,
+    /// instead of only the hours the query touches
+    #[arg(long, value_enum, value_name = "MODE")]
+    refresh_stale: Option<StaleRefresh>,
+    /// Refresh stale incomplete hours by fetching only the fills from the second of the latest
+    /// cached one on and merging them in, instead of refetching the whole hour
+    #[arg(long)]
+    differential_refresh: bool,
+    /// Whether windows hold the fills at their start and end times, in interval notation:
+    /// "(]" (after the start, up to and including the end), "[]", "()" or "[)";
+    /// a query can give its own after the venue
+    #[arg(long, value_name = "BOUNDS", default_value = "(]")]
+    window_bounds: Bounds,
+    /// Maximum number of hours a single query may touch
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_RANGE_HOURS, value_parser = clap::value_parser!(u64).range(1..))]
+    max_range_hours: u64,
+    /// Number of hours the cache holds, across all symbols
+    #[arg(long, value_name = "N", default_value_t = 168, value_parser = clap::value_parser!(u64).range(1..))]
+    cache_hours: u64,
+    /// Bytes the cache holds (approximately, as in the cache size statistics), evicting
+    /// the least recently used hours past it; 0 disables it. Overrides --cache-mem-fraction.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    cache_bytes: usize,
+    /// Size the cache to this fraction (0 to 1) of the memory available at startup,
+    /// shrinking it when the system's memory comes under pressure; 0 disables it
+    #[arg(long, value_name = "F", default_value_t = 0.0, value_parser = parse_rate)]
+    cache_mem_fraction: f64,
+    /// With --cache-mem-fraction, check the available memory every T seconds
+    #[arg(long, value_name = "T", default_value_t = 60)]
+    memory_check_secs: u64,
+    /// With --cache-mem-fraction, shrink the cache when the available memory falls below
+    /// this fraction (0 to 1) of the system's memory
+    #[arg(long, value_name = "F", default_value_t = 0.1, value_parser = parse_rate)]
+    memory_pressure: f64,
+    /// Before each query, evict the cached hours starting more than T seconds
+    /// before the current time; 0 disables it
+    #[arg(long, value_name = "T", default_value_t = 0)]
+    auto_evict_age_secs: u64,
+    /// Fail queries that need the upstream once N upstream requests have been made
+    #[arg(long, value_name = "N")]
+    max_api_calls: Option<usize>,
+    /// Cache hours holding more than N fills as count and volume aggregates instead of
+    /// their fills; queries those can't answer fetch the hour again
+    #[arg(long, value_name = "N")]
+    stream_threshold_fills: Option<usize>,
+    /// Keep hours holding more than N fills out of the cache, in a single slot of their own
+    /// that the next such hour takes over, so one anomalous hour doesn't evict others
+    #[arg(long, value_name = "N")]
+    max_cached_hour_fills: Option<usize>,
+    /// Log failed queries and continue with the next one instead of stopping the run
+    #[arg(long)]
+    keep_going: bool,
+    /// Retry queries that fail because the upstream is unavailable after a delay,
+    /// writing their answers as "RETRY LINE RESULT" once they succeed
+    #[arg(long)]
+    retry_failed: bool,
+    /// Seconds between the failed attempts of a query retried by --retry-failed
+    #[arg(long, value_name = "T", default_value_t = 5, requires = "retry_failed")]
+    retry_delay_secs: u64,
+    /// Number of retries of a query before it fails permanently
+    #[arg(long, value_name = "N", default_value_t = 3, requires = "retry_failed", value_parser = clap::value_parser!(u32).range(1..))]
+    retry_rounds: u32,
+    /// Number of queries waiting for a retry at once; queries failing while it is full
+    /// fail right away
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1000,
+        requires = "retry_failed"
+    )]
+    retry_queue: usize,
+    /// Upstream to fetch hours from: the trades API (api)
+    /// or hour files written by backfill (disk:<DIR>)
+    #[arg(long, value_name = "SOURCE", default_value = "api")]
+    source: SourceSpec,
+    /// Fetch the hours starting more than --archive-after-hours ago from this upstream,
+    /// in the --source format, instead of --source
+    #[arg(long, value_name = "SOURCE")]
+    archive_source: Option<SourceSpec>,
+    /// Age in hours past which hours are fetched from --archive-source
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 48,
+        requires = "archive_source"
+    )]
+    archive_after_hours: u64,
+    /// Requests per second sent to --source at most; 0 means unlimited
+    #[arg(long, value_name = "RATE", default_value_t = 0.0, value_parser = parse_rate_limit)]
+    live_rate_limit: f64,
+    /// Number of times a request to --source failed by an unavailable upstream is retried
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    live_retries: u32,
+    /// Requests per second sent to --archive-source at most; 0 means unlimited
+    #[arg(long, value_name = "RATE", default_value_t = 0.0, value_parser = parse_rate_limit, requires = "archive_source")]
+    archive_rate_limit: f64,
+    /// Number of times a request to --archive-source failed by an unavailable upstream
+    /// is retried
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        requires = "archive_source"
+    )]
+    archive_retries: u32,
+    /// Request the hours of --archive-source in pages of this many minutes, one request
+    /// per page, for an archive that serves a range a slice of time at a time
+    #[arg(long, value_name = "MINUTES", value_parser = clap::value_parser!(u64).range(1..=60), requires = "archive_source")]
+    archive_page_minutes: Option<u64>,
+    /// Milliseconds between a failed request to --source or --archive-source and its retry
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    endpoint_retry_delay_ms: u64,
+    /// Add a venue that queries can select with an "@NAME" prefix, served by SOURCE
+    /// (api or disk:<DIR>); may be repeated
+    #[arg(long = "venue", value_name = "NAME=SOURCE")]
+    venues: Vec<VenueSpec>,
+    /// Identify taker trades of VENUE (the default venue when omitted) by KEY instead of
+    /// the sequence number: sequence-number, trade-id or sequence-number-time; may be repeated
+    #[arg(long = "dedup-key", value_name = "[VENUE=]KEY")]
+    dedup_keys: Vec<DedupKeySpec>,
+    /// Skip and count fills that fail to parse instead of failing the fetch
+    #[arg(long)]
+    lenient_parse: bool,
+    /// Fail the fetch of an hour holding a fill with a non-positive price or quantity
+    /// instead of dropping and counting the fill
+    #[arg(long)]
+    strict_data: bool,
+    /// Fail the fetch of an hour holding a fill whose direction is neither buy (1)
+    /// nor sell (2 or -1) instead of counting it as a trade of unknown side
+    #[arg(long)]
+    strict_direction: bool,
+    /// Fail the fetch of an hour whose fills have fields unknown to this version instead of
+    /// ignoring them, as when validating fixtures
+    #[arg(long)]
+    strict_schema: bool,
+    /// Suffix count and volume answers with the number of fills in the hours they touched
+    /// and the number of those hours that were empty
+    #[arg(long)]
+    verbose_results: bool,
+    /// Round every decimal answer to N fraction digits and pad it with zeros to exactly N,
+    /// so equal values print the same however they were summed
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(..=28))]
+    scale: Option<u32>,
+    /// How --scale rounds values halfway between two digits: to the even digit (half-even,
+    /// banker's rounding) or away from zero (half-up)
+    #[arg(long, value_enum, default_value = "half-even", requires = "scale")]
+    rounding: Rounding,
+    /// Cross-check this fraction of queries (0 to 1) against fresh fetches
+    #[arg(long, value_name = "RATE", default_value_t = 0.0, value_parser = parse_rate)]
+    verify_sample: f64,
+    /// Append one JSON line per processed query, with its result or error,
+    /// per-hour cache hits and fetch times, to PATH
+    #[arg(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+    /// Time the parse, lookup, fetch, aggregate and output phases of every query and
+    /// report their totals and percentiles in the final statistics (and the audit log)
+    #[arg(long)]
+    profile: bool,
+    /// Maintain an index of the cached hours by the sequence numbers they hold, so CS and VS
+    /// lines only look at the hours holding their range instead of scanning the cache
+    #[arg(long)]
+    seq_index: bool,
+    /// Load the cache from PATH at startup and append the complete hours fetched to it
+    /// while running, so a restarted run doesn't fetch them again
+    #[arg(long, value_name = "PATH")]
+    snapshot_file: Option<PathBuf>,
+    /// Append to the snapshot file every N newly fetched complete hours
+    #[arg(long, value_name = "N", default_value_t = 10, requires = "snapshot_file", value_parser = clap::value_parser!(u64).range(1..))]
+    snapshot_every_hours: u64,
+    /// Also append to the snapshot file every T seconds when hours were fetched;
+    /// 0 disables it
+    #[arg(
+        long,
+        value_name = "T",
+        default_value_t = 0,
+        requires = "snapshot_file"
+    )]
+    snapshot_interval_secs: u64,
+    /// Rewrite the snapshot file with just the cached hours once it grows past N bytes
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 256 * 1024 * 1024,
+        requires = "snapshot_file"
+    )]
+    snapshot_compact_bytes: u64,
+    /// Layout of the snapshot file: JSON lines, or compact checksummed binary blocks that
+    /// load faster. An existing file in the other layout is rewritten in this one.
+    #[arg(long, value_enum, default_value = "json", requires = "snapshot_file")]
+    snapshot_format: SnapshotFormat,
+    #[cfg(feature = "mock-source")]
+    #[command(flatten)]
+    mock: crate::mock::MockArgs,
+}
+
+/// Parses a fraction between 0 and 1
+pub fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!("{} is not a number between 0 and 1", s)),
+    }
+}
+
+/// Parses a rate limit in requests per second, 0 meaning unlimited
+fn parse_rate_limit(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && (rate == 0.0 || rate >= MIN_RATE_LIMIT) => Ok(rate),
+        _ => Err(format!(
+            "{} is not a number of requests per second (0 for unlimited, or from {})",
+            s, MIN_RATE_LIMIT
+        )),
+    }
+}
+
+impl From<ConfigArgs> for Config {
+    fn from(args: ConfigArgs) -> Self {
+        Config {
+            default_symbol: args.default_symbol,
+            slow_query_threshold: (args.slow_query_ms > 0)
+                .then(|| Duration::from_millis(args.slow_query_ms)),
+            query_timeout: (args.query_timeout_ms > 0)
+                .then(|| Duration::from_millis(args.query_timeout_ms)),
+            lookahead: (args.lookahead > 0).then_some(args.lookahead),
+            log_format: args.log_format,
+            output_format: args.output_format,
+            output_template: args.output_template,
+            stats_every_queries: (args.stats_every > 0).then_some(args.stats_every),
+            stats_interval: (args.stats_interval_secs > 0)
+                .then(|| Duration::from_secs(args.stats_interval_secs)),
+            future_policy: args.future_policy,
+            incomplete_ttl: Duration::from_secs(args.incomplete_ttl_secs),
+            stale_refresh: args.refresh_stale,
+            differential_refresh: args.differential_refresh,
+            window_bounds: args.window_bounds,
+            max_range_hours: args.max_range_hours,
+            cache_hours: args.cache_hours as usize,
+            cache_bytes: (args.cache_bytes > 0).then_some(args.cache_bytes),
+            cache_mem_fraction: (args.cache_mem_fraction > 0.0).then_some(args.cache_mem_fraction),
+            memory_check_interval: Duration::from_secs(args.memory_check_secs),
+            memory_pressure: args.memory_pressure,
+            auto_evict_age: (args.auto_evict_age_secs > 0)
+                .then(|| Duration::from_secs(args.auto_evict_age_secs)),
+            max_api_calls: args.max_api_calls,
+            stream_threshold: args.stream_threshold_fills,
+            max_cached_hour_fills: args.max_cached_hour_fills,
+            keep_going: args.keep_going,
+            retry_failed: args.retry_failed.then_some(RetryConfig {
+                delay: Duration::from_secs(args.retry_delay_secs),
+                rounds: args.retry_rounds,
+                capacity: args.retry_queue,
+            }),
+            source: args.source,
+            live_policy: EndpointPolicy {
+                rate_limit: (args.live_rate_limit > 0.0).then_some(args.live_rate_limit),
+                retries: args.live_retries,
+                retry_delay: Duration::from_millis(args.endpoint_retry_delay_ms),
+                page: None,
+            },
+            archive: args.archive_source.map(|source| ArchiveConfig {
+                source,
+                after: Duration::from_secs(args.archive_after_hours * 3600),
+                policy: EndpointPolicy {
+                    rate_limit: (args.archive_rate_limit > 0.0).then_some(args.archive_rate_limit),
+                    retries: args.archive_retries,
+                    retry_delay: Duration::from_millis(args.endpoint_retry_delay_ms),
+                    page: args
+                        .archive_page_minutes
+                        .map(|minutes| Duration::from_secs(minutes * 60)),
+                },
+            }),
+            venues: args
+                .venues
+                .into_iter()
+                .map(|venue| (venue.name, venue.source))
+                .collect(),
+            dedup_keys: args
+                .dedup_keys
+                .into_iter()
+                .map(|spec| (spec.venue, spec.key))
+                .collect(),
+            lenient_parse: args.lenient_parse,
+            strict_data: args.strict_data,
+            strict_direction: args.strict_direction,
+            strict_schema: args.strict_schema,
+            verbose_results: args.verbose_results,
+            scale: args.scale,
+            rounding: args.rounding,
+            verify_sample: args.verify_sample,
+            audit_log: args.audit_log,
+            profile: args.profile,
+            seq_index: args.seq_index,
+            snapshot: args.snapshot_file.map(|path| SnapshotConfig {
+                path,
+                every_hours: args.snapshot_every_hours as usize,
+                interval: (args.snapshot_interval_secs > 0)
+                    .then(|| Duration::from_secs(args.snapshot_interval_secs)),
+                compact_bytes: args.snapshot_compact_bytes,
+                format: args.snapshot_format,
+            }),
+            #[cfg(feature = "mock-source")]
+            mock: args.mock.into_config(),
+        }
+    }
+}
+
+impl Config {
+    /// Returns the configuration of one of several workers each running a processor: the
+    /// first worker loads and writes the snapshot file, which can't take two writers, and
+    /// each worker after the first writes its audit log to `<PATH>.<WORKER>`
+    pub fn for_worker(mut self, worker: usize) -> Self {
+        if worker > 0 {
+            self.snapshot = None;
+            if let Some(path) = &mut self.audit_log {
+                let mut worker_path = path.as_os_str().to_owned();
+                worker_path.push(format!(".{}", worker));
+                *path = PathBuf::from(worker_path);
+            }
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        config: ConfigArgs,
+    }
+
+    fn parse(args: &[&str]) -> Result<Config, clap::Error> {
+        let args = std::iter::once("interview").chain(args.iter().copied());
+        Cli::try_parse_from(args).map(|cli| cli.config.into())
+    }
+
+    #[test]
+    fn max_range_hours_is_positive_with_one_default() {
+        assert_eq!(
+            parse(&[]).unwrap().max_range_hours,
+            Config::default().max_range_hours
+        );
+        assert_eq!(
+            parse(&["--max-range-hours", "1"]).unwrap().max_range_hours,
+            1
+        );
+        assert!(parse(&["--max-range-hours", "0"]).is_err());
+        assert!(parse(&["--max-range-hours", "-24"]).is_err());
+    }
+
+    #[test]
+    fn rate_limits_are_unlimited_or_at_least_the_minimum() {
+        let live_rate = |rate: &str| {
+            parse(&["--live-rate-limit", rate]).map(|config| config.live_policy.rate_limit)
+        };
+        assert_eq!(live_rate("0").unwrap(), None);
+        assert_eq!(live_rate("0.001").unwrap(), Some(0.001));
+        assert_eq!(live_rate("20").unwrap(), Some(20.0));
+        for rate in ["0.0001", "1e-300", "-1", "inf", "NaN"] {
+            assert!(live_rate(rate).is_err(), "{}", rate);
+        }
+    }
+
+    #[test]
+    fn archive_pages_need_an_archive() {
+        let archive = ["--archive-source", "api", "--archive-page-minutes", "15"];
+        let policy = parse(&archive).unwrap().archive.unwrap().policy;
+        assert_eq!(policy.page, Some(Duration::from_secs(900)));
+        assert!(parse(&archive[2..]).is_err());
+        assert!(parse(&["--archive-source", "api", "--archive-page-minutes", "61"]).is_err());
+    }
+}
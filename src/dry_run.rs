@@ -0,0 +1,160 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, Write};
+use tracing::warn;
+
+use crate::{query, query_text, Processor};
+
+/// Plans every query in the input without fetching anything and writes a report of the
+/// hours the queries need: how many are already cached, how many would take an API call,
+/// and how long the requests of those calls would take at the upstreams' rate limits.
+/// With `list_hours`, the report also lists the distinct hours of every venue and symbol.
+pub fn run(
+    processor: &Processor,
+    input: impl BufRead,
+    output: &mut impl Write,
+    list_hours: bool,
+) -> anyhow::Result<()> {
+    let mut queries = 0;
+    let mut invalid = 0;
+    let mut market_hours = BTreeMap::<(String, String), BTreeSet<i64>>::new();
+
+    for line in input.lines() {
+        let line = line?;
+        let Some(query) = query_text(&line) else {
+            continue;
+        };
+        queries += 1;
+        match processor.plan_query(query) {
+            Ok((venue, symbol, hours)) => market_hours
+                .entry((venue, symbol))
+                .or_default()
+                .extend(hours),
+            Err(e) => {
+                warn!("Invalid query: {:#}", e);
+                invalid += 1;
+            }
+        }
+    }
+
+    let hours = market_hours.values().map(BTreeSet::len).sum::<usize>();
+    let mut cached = 0;
+    // Requests to each upstream, with its rate limit
+    let mut upstreams = BTreeMap::<String, (Option<f64>, usize)>::new();
+    for ((venue, symbol), hours) in &market_hours {
+        for &hour in hours {
+            if processor.is_cached(venue, symbol, hour) {
+                cached += 1;
+                continue;
+            }
+            let (name, rate_limit, requests) = processor.hour_upstream(venue, hour)?;
+            upstreams.entry(name).or_insert((rate_limit, 0)).1 += requests;
+        }
+    }
+
+    writeln!(output, "Queries: {} ({} invalid)", queries, invalid)?;
+    writeln!(output, "Distinct hours: {}", hours)?;
+    writeln!(output, "Cached hours: {}", cached)?;
+    writeln!(output, "API calls needed: {}", hours - cached)?;
+    // Upstreams without a rate limit take no time of their own
+    let seconds = upstreams
+        .values()
+        .filter_map(|&(rate_limit, requests)| Some(requests as f64 / rate_limit?))
+        .fold(0.0, |total, seconds| total + seconds);
+    writeln!(output, "Estimated time: {:.1}s", seconds)?;
+    for (name, (rate_limit, requests)) in &upstreams {
+        match rate_limit {
+            Some(rate) => writeln!(
+                output,
+                "  {}: {} requests at {} per second, {:.1}s",
+                name,
+                requests,
+                rate,
+                *requests as f64 / rate
+            )?,
+            None => writeln!(output, "  {}: {} requests, no rate limit", name, requests)?,
+        }
+    }
+    if list_hours {
+        for ((venue, symbol), hours) in &market_hours {
+            writeln!(
+                output,
+                "{}: {}",
+                query::market_name(venue, symbol),
+                hour_ranges(hours).join(", ")
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Compresses runs of consecutive hours into "FIRST..LAST (N hours)"
+fn hour_ranges(hours: &BTreeSet<i64>) -> Vec<String> {
+    let mut runs: Vec<(i64, i64)> = Vec::new();
+    for &hour in hours {
+        match runs.last_mut() {
+            Some((_, last)) if *last + 3600 == hour => *last = hour,
+            _ => runs.push((hour, hour)),
+        }
+    }
+
+    runs.into_iter()
+        .map(|(first, last)| {
+            if first == last {
+                first.to_string()
+            } else {
+                format!("{}..{} ({} hours)", first, last, (last - first) / 3600 + 1)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::endpoint::EndpointPolicy;
+    use crate::testing::{fill, MemorySource, TestClock, HOUR};
+    use std::time::Duration;
+
+    #[test]
+    fn report_estimates_the_time_of_the_uncached_hours_at_the_rate_limit() {
+        let config = Config {
+            live_policy: EndpointPolicy {
+                rate_limit: Some(4.0),
+                page: Some(Duration::from_secs(15 * 60)),
+                ..EndpointPolicy::default()
+            },
+            ..Config::default()
+        };
+        let source = MemorySource::new(vec![fill(HOUR + 60, 1, 1, "10", "1")]);
+        let mut processor = Processor::with_source(
+            config,
+            Box::new(source.clone()),
+            Box::new(TestClock::at(HOUR + 86400)),
+        );
+        processor
+            .process_query(format!("C {} {}", HOUR, HOUR + 1800))
+            .unwrap();
+        let requests = source.requests();
+
+        let input = format!("C {} {}\nC {} +3h\nX\n", HOUR, HOUR + 1800, HOUR);
+        let mut output = Vec::new();
+        run(&processor, input.as_bytes(), &mut output, true).unwrap();
+        // Three uncached hours of four pages each, at four requests per second
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                "Queries: 3 (1 invalid)\n\
+                 Distinct hours: 4\n\
+                 Cached hours: 1\n\
+                 API calls needed: 3\n\
+                 Estimated time: 3.0s\n  \
+                 live: 12 requests at 4 per second, 3.0s\n\
+                 DEFAULT: {}..{} (4 hours)\n",
+                HOUR,
+                HOUR + 3 * 3600
+            )
+        );
+        assert_eq!(source.requests(), requests);
+    }
+}
@@ -0,0 +1,238 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::clock::Instant;
+use crate::server::ApiResponse;
+use crate::source::{is_retryable, FillSource, SourceSpec};
+
+/// Lowest rate limit an endpoint takes, in requests per second: one request every
+/// 1000 seconds, so the time between two requests stays representable
+pub const MIN_RATE_LIMIT: f64 = 0.001;
+
+/// Request rate, retries and pagination of one upstream endpoint
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EndpointPolicy {
+    /// Requests per second the endpoint is sent at most, from `MIN_RATE_LIMIT`;
+    /// `None` means unlimited
+    pub rate_limit: Option<f64>,
+    /// Number of times a request failed by an unavailable upstream is retried
+    pub retries: u32,
+    /// Time between a failed request and its retry
+    pub retry_delay: Duration,
+    /// Length of the pages a range is requested in, for upstreams that serve a range
+    /// a slice of time per request; `None` requests a range at once
+    pub page: Option<Duration>,
+}
+
+impl Default for EndpointPolicy {
+    fn default() -> Self {
+        EndpointPolicy {
+            rate_limit: None,
+            retries: 0,
+            retry_delay: Duration::from_millis(500),
+            page: None,
+        }
+    }
+}
+
+/// Requests an endpoint sent, each counted once it has run, shared with the statistics
+#[derive(Debug, Default)]
+pub struct EndpointStats {
+    /// Requests sent, pages and retries included
+    pub requests: AtomicUsize,
+    /// Requests that failed, whether retried or not
+    pub failures: AtomicUsize,
+    /// Requests sent again after a failure
+    pub retries: AtomicUsize,
+}
+
+impl EndpointStats {
+    /// Returns the requests, failures and retries counted so far
+    pub fn counts(&self) -> (usize, usize, usize) {
+        (
+            self.requests.load(Ordering::Relaxed),
+            self.failures.load(Ordering::Relaxed),
+            self.retries.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Archive upstream serving the hours of the default venue that are too old for `--source`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveConfig {
+    pub source: SourceSpec,
+    /// Hours starting longer ago than this are fetched from the archive
+    pub after: Duration,
+    pub policy: EndpointPolicy,
+}
+
+/// A source behind the policy of its endpoint: ranges are requested page by page when it
+/// pages them, requests are spaced out to its rate limit, and requests failed by an
+/// unavailable upstream (see `is_retryable`) are retried
+pub struct Endpoint {
+    /// Name of the endpoint in logs and statistics, like "live" or "archive"
+    name: &'static str,
+    source: Box<dyn FillSource>,
+    policy: EndpointPolicy,
+    /// Earliest time the next request may be sent under the rate limit
+    next_request: Option<Instant>,
+    stats: Arc<EndpointStats>,
+}
+
+impl Endpoint {
+    pub fn new(name: &'static str, source: Box<dyn FillSource>, policy: EndpointPolicy) -> Self {
+        Endpoint {
+            name,
+            source,
+            policy,
+            next_request: None,
+            stats: Arc::default(),
+        }
+    }
+
+    /// Returns the name of the endpoint
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the counters of the requests the endpoint sent, which keep counting
+    pub fn stats(&self) -> Arc<EndpointStats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Waits until the rate limit lets the next request go
+    fn wait_turn(&mut self) {
+        let Some(rate) = self.policy.rate_limit else {
+            return;
+        };
+        let now = Instant::now();
+        let start = self.next_request.map_or(now, |next| next.max(now));
+        thread::sleep(start - now);
+        self.next_request = Some(start + Duration::from_secs_f64(1.0 / rate));
+    }
+}
+
+impl Endpoint {
+    /// Requests one range, waiting for the rate limit and retrying it as configured
+    fn request(&mut self, symbol: &str, start: i64, end: i64) -> anyhow::Result<ApiResponse> {
+        let mut retries = 0;
+        loop {
+            self.wait_turn();
+            let result = self.source.get_fills(symbol, start, end);
+            self.stats.requests.fetch_add(1, Ordering::Relaxed);
+            match result {
+                Err(e) if retries < self.policy.retries && is_retryable(&e) => {
+                    self.stats.failures.fetch_add(1, Ordering::Relaxed);
+                    self.stats.retries.fetch_add(1, Ordering::Relaxed);
+                    retries += 1;
+                    warn!(
+                        "Request to the {} endpoint for {} ({}, {}] failed, retrying ({} of {}): {:#}",
+                        self.name, symbol, start, end, retries, self.policy.retries, e
+                    );
+                    thread::sleep(self.policy.retry_delay);
+                }
+                Err(e) => {
+                    self.stats.failures.fetch_add(1, Ordering::Relaxed);
+                    return Err(e);
+                }
+                response => return response,
+            }
+        }
+    }
+}
+
+impl FillSource for Endpoint {
+    fn get_fills(
+        &mut self,
+        symbol: &str,
+        start_timestamp_in_seconds: i64,
+        end_timestamp_in_seconds: i64,
+    ) -> anyhow::Result<ApiResponse> {
+        // A source serving whole hours only can't be asked for pages of one
+        let page = self
+            .policy
+            .page
+            .filter(|_| self.source.serves_partial_hours())
+            .map(|page| page.as_secs().max(1) as i64);
+        let Some(page) = page else {
+            return self.request(symbol, start_timestamp_in_seconds, end_timestamp_in_seconds);
+        };
+
+        let mut response = ApiResponse {
+            fills: Vec::new(),
+            malformed: 0,
+            unknown_fields: Vec::new(),
+        };
+        let mut page_start = start_timestamp_in_seconds;
+        while page_start < end_timestamp_in_seconds {
+            let page_end = page_start
+                .saturating_add(page)
+                .min(end_timestamp_in_seconds);
+            let page_response = self.request(symbol, page_start, page_end)?;
+            response.fills.extend(page_response.fills);
+            response.malformed += page_response.malformed;
+            for field in page_response.unknown_fields {
+                if !response.unknown_fields.contains(&field) {
+                    response.unknown_fields.push(field);
+                }
+            }
+            page_start = page_end;
+        }
+        Ok(response)
+    }
+
+    fn serves_partial_hours(&self) -> bool {
+        self.source.serves_partial_hours()
+    }
+
+    fn newest_hour(&self, symbol: &str) -> Option<i64> {
+        self.source.newest_hour(symbol)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{fill, FlakySource, MemorySource, HOUR};
+
+    fn endpoint(failures: usize, policy: EndpointPolicy) -> Endpoint {
+        let source = MemorySource::new(vec![fill(HOUR + 60, 1, 1, "10", "1")]);
+        Endpoint::new(
+            "archive",
+            Box::new(FlakySource { failures, source }),
+            policy,
+        )
+    }
+
+    #[test]
+    fn requests_are_counted_once_they_ran() {
+        let policy = EndpointPolicy {
+            retries: 1,
+            retry_delay: Duration::ZERO,
+            ..EndpointPolicy::default()
+        };
+        let mut retried = endpoint(1, policy);
+        let response = retried.get_fills("BTC-USD", HOUR, HOUR + 3600).unwrap();
+        assert_eq!(response.fills.len(), 1);
+        assert_eq!(retried.stats().counts(), (2, 1, 1));
+
+        let mut failed = endpoint(2, policy);
+        assert!(failed.get_fills("BTC-USD", HOUR, HOUR + 3600).is_err());
+        assert_eq!(failed.stats().counts(), (2, 2, 1));
+    }
+
+    #[test]
+    fn the_slowest_rate_limit_spaces_requests_without_overflow() {
+        let policy = EndpointPolicy {
+            rate_limit: Some(MIN_RATE_LIMIT),
+            ..EndpointPolicy::default()
+        };
+        let mut endpoint = endpoint(0, policy);
+        endpoint.get_fills("BTC-USD", HOUR, HOUR + 3600).unwrap();
+        let wait = endpoint.next_request.unwrap() - Instant::now();
+        assert!(wait > Duration::from_secs(999) && wait <= Duration::from_secs(1000));
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::anyhow;
+use arrow_array::{
+    ArrayRef, Decimal128Array, Int32Array, Int64Array, RecordBatch, StringArray,
+    TimestampNanosecondArray, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use parquet::arrow::ArrowWriter;
+use rust_decimal::Decimal;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::intern::HourKey;
+use crate::server::{Fill, Liquidity};
+
+/// Digits of the decimal columns, the most Decimal128 holds
+const DECIMAL_PRECISION: u8 = 38;
+
+/// A cached hour of fills, by venue, symbol and hour
+pub type Hour<'a> = (&'a HourKey, &'a [Fill]);
+
+/// Writes the hours to a Parquet file, one row group per hour, and returns the number of rows.
+///
+/// Columns: venue, symbol and hour (Unix seconds) of the row's hour, then the fill's time
+/// (nanoseconds, UTC), direction, price and quantity (decimal128 at the largest scale of
+/// any exported price, quantity or fee, so no digit is lost), sequence number, liquidity
+/// ("maker", "taker" or null), trade id, and fee and fee currency (null when the venue
+/// sent none).
+pub fn write_parquet(path: &Path, hours: &[Hour]) -> anyhow::Result<usize> {
+    let scale = hours
+        .iter()
+        .flat_map(|(_, fills)| fills.iter())
+        .flat_map(|fill| {
+            [fill.price.scale(), fill.quantity.scale()]
+                .into_iter()
+                .chain(fill.fee.map(|fee| fee.scale()))
+        })
+        .max()
+        .unwrap_or(0) as i8;
+    let decimal = DataType::Decimal128(DECIMAL_PRECISION, scale);
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("venue", DataType::Utf8, false),
+        Field::new("symbol", DataType::Utf8, false),
+        Field::new("hour", DataType::Int64, false),
+        Field::new(
+            "time",
+            DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into())),
+            false,
+        ),
+        Field::new("direction", DataType::Int32, false),
+        Field::new("price", decimal.clone(), false),
+        Field::new("quantity", decimal.clone(), false),
+        Field::new("sequence_number", DataType::UInt64, false),
+        Field::new("liquidity", DataType::Utf8, true),
+        Field::new("trade_id", DataType::UInt64, true),
+        Field::new("fee", decimal.clone(), true),
+        Field::new("fee_currency", DataType::Utf8, true),
+    ]));
+
+    let file = File::create(path)
+        .map_err(|e| anyhow!("Failed to create Parquet file {}: {}", path.display(), e))?;
+    let mut writer = ArrowWriter::try_new(file, Arc::clone(&schema), None)?;
+    let mut rows = 0;
+    for &((venue, symbol, hour), fills) in hours {
+        if fills.is_empty() {
+            continue;
+        }
+        let scaled = |value: Decimal| {
+            10i128
+                .checked_pow((scale as u32).saturating_sub(value.scale()))
+                .and_then(|factor| value.mantissa().checked_mul(factor))
+                .ok_or_else(|| anyhow!("{} doesn't fit a decimal of scale {}", value, scale))
+        };
+        let times = fills
+            .iter()
+            .map(|fill| {
+                fill.time
+                    .timestamp_nanos_opt()
+                    .ok_or_else(|| anyhow!("Fill time {} is out of range", fill.time))
+            })
+            .collect::<anyhow::Result<Vec<i64>>>()?;
+        let prices = fills
+            .iter()
+            .map(|fill| scaled(fill.price))
+            .collect::<anyhow::Result<Vec<i128>>>()?;
+        let quantities = fills
+            .iter()
+            .map(|fill| scaled(fill.quantity))
+            .collect::<anyhow::Result<Vec<i128>>>()?;
+        let fees = fills
+            .iter()
+            .map(|fill| fill.fee.map(scaled).transpose())
+            .collect::<anyhow::Result<Vec<Option<i128>>>>()?;
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from(vec![&**venue; fills.len()])),
+            Arc::new(StringArray::from(vec![&**symbol; fills.len()])),
+            Arc::new(Int64Array::from(vec![*hour; fills.len()])),
+            Arc::new(TimestampNanosecondArray::from(times).with_timezone("UTC")),
+            Arc::new(Int32Array::from_iter_values(
+                fills.iter().map(|fill| fill.direction),
+            )),
+            Arc::new(
+                Decimal128Array::from(prices).with_precision_and_scale(DECIMAL_PRECISION, scale)?,
+            ),
+            Arc::new(
+                Decimal128Array::from(quantities)
+                    .with_precision_and_scale(DECIMAL_PRECISION, scale)?,
+            ),
+            Arc::new(UInt64Array::from_iter_values(
+                fills.iter().map(|fill| fill.sequence_number),
+            )),
+            Arc::new(StringArray::from_iter(fills.iter().map(|fill| {
+                fill.liquidity.map(|liquidity| match liquidity {
+                    Liquidity::Maker => "maker",
+                    Liquidity::Taker => "taker",
+                })
+            }))),
+            Arc::new(UInt64Array::from_iter(
+                fills.iter().map(|fill| fill.trade_id),
+            )),
+            Arc::new(
+                Decimal128Array::from(fees).with_precision_and_scale(DECIMAL_PRECISION, scale)?,
+            ),
+            Arc::new(StringArray::from_iter(fills.iter().map(|fill| {
+                fill.fee_currency
+                    .as_ref()
+                    .map(|currency| currency.as_str().to_string())
+            }))),
+        ];
+        writer.write(&RecordBatch::try_new(Arc::clone(&schema), columns)?)?;
+        // Closes the row group, so every hour gets its own
+        writer.flush()?;
+        rows += fills.len();
+    }
+    writer.close()?;
+    Ok(rows)
+}
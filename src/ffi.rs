@@ -0,0 +1,349 @@
+//! C interface to a processor, for the Python bindings in `python/` (`--features python`)
+//! and the WebAssembly build in `wasm/` (`--features wasm`, see `wasm.rs`).
+//!
+//! Strings cross the interface as a pointer and a length in bytes, UTF-8 and not
+//! NUL-terminated. A call that fails returns a non-zero status, or a null handle, and
+//! leaves its error where `interview_error_ptr` and `interview_error_len` read it.
+//! Answers stay in the handle until its next call, where `interview_result_ptr` and
+//! `interview_result_len` read them.
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::ffi::c_void;
+use std::{ptr, slice};
+
+use crate::clock::SystemClock;
+use crate::config::Config;
+use crate::server::{ApiResponse, Fill};
+use crate::source::{FillSource, SourceSpec, UpstreamUnavailable};
+use crate::{stats, Processor};
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+mod wasm;
+
+/// Status of a call that succeeded
+pub const OK: i32 = 0;
+/// Status of a call that failed
+pub const FAILED: i32 = 1;
+/// Status a fetch callback returns for an upstream that is unavailable, as opposed to
+/// one whose fills are bad, so `--retry-failed` style retries apply
+pub const UNAVAILABLE: i32 = 2;
+/// Status a fetch callback returns for a fetch it started but can't finish before
+/// returning, like a JavaScript one awaiting a Promise: the call fetching fails, to be
+/// made again once the callback can return the fills
+pub const PENDING: i32 = 3;
+
+/// Fetches the fills of `symbol` within (`start`, `end`] in Unix seconds for a processor
+/// created with it: writes them to `sink` as a JSON array of fills, in the layout of
+/// `fixture record`, with `interview_sink_write` and returns `OK`, or writes an error
+/// message and returns `FAILED`, `UNAVAILABLE` or `PENDING`. `user` is the pointer the
+/// processor was created with.
+pub type FetchCallback = extern "C" fn(
+    user: *mut c_void,
+    symbol: *const u8,
+    symbol_len: usize,
+    start: i64,
+    end: i64,
+    sink: *mut Sink,
+) -> i32;
+
+/// Bytes a fetch callback writes its fills or error to
+#[derive(Default)]
+pub struct Sink(Vec<u8>);
+
+thread_local! {
+    /// Error of the last call of this thread that failed
+    static LAST_ERROR: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Keeps the error for `interview_error_ptr`
+fn set_error(error: &anyhow::Error) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = format!("{:#}", error).into_bytes());
+}
+
+/// Serves fills from a fetch callback
+struct CallbackSource {
+    callback: FetchCallback,
+    /// The `user` pointer, kept as an address so the source is `Send`; the caller
+    /// keeps what it points to alive and usable from the thread fetching
+    user: usize,
+}
+
+impl FillSource for CallbackSource {
+    fn get_fills(&mut self, symbol: &str, start: i64, end: i64) -> anyhow::Result<ApiResponse> {
+        let mut sink = Sink::default();
+        let status = (self.callback)(
+            self.user as *mut c_void,
+            symbol.as_ptr(),
+            symbol.len(),
+            start,
+            end,
+            &mut sink,
+        );
+        let written = String::from_utf8_lossy(&sink.0);
+        match status {
+            OK => {
+                let fills = serde_json::from_slice::<Vec<Fill>>(&sink.0)
+                    .context("The fetch callback returned invalid fills")?;
+                Ok(ApiResponse {
+                    fills,
+                    malformed: 0,
+                    unknown_fields: Vec::new(),
+                })
+            }
+            UNAVAILABLE => Err(UpstreamUnavailable(written.into_owned()).into()),
+            PENDING => Err(anyhow!(
+                "The fetch of {} ({}, {}] is pending",
+                symbol,
+                start,
+                end
+            )),
+            _ => Err(anyhow!("The fetch callback failed: {}", written)),
+        }
+    }
+}
+
+/// Settings of a processor created through the interface, as a JSON object; a field left
+/// out takes the default of the command line flag
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ProcessorConfig {
+    /// See `--cache-hours`
+    cache_hours: Option<usize>,
+    /// See `--source`; ignored with a fetch callback
+    source: Option<String>,
+    /// See `--symbol`
+    symbol: Option<String>,
+}
+
+/// A processor and its last answer
+pub struct Handle {
+    processor: Processor,
+    result: Vec<u8>,
+}
+
+/// Cache size and counters of a processor, as `interview_cache_stats` answers them
+#[derive(Serialize)]
+struct CacheStatsJson {
+    hours: usize,
+    fills: usize,
+    max_hour_fills: usize,
+    bytes: usize,
+    cache_hits: usize,
+    api_calls: usize,
+    hit_rate: f64,
+}
+
+/// Returns the string the pointer and length point to
+///
+/// # Safety
+/// `ptr` points to `len` readable bytes, or `len` is 0
+unsafe fn read_str<'a>(ptr: *const u8, len: usize) -> anyhow::Result<&'a str> {
+    if len == 0 {
+        return Ok("");
+    }
+    std::str::from_utf8(slice::from_raw_parts(ptr, len)).context("Invalid UTF-8 string")
+}
+
+/// Runs the call on the handle, keeping its answer or error, and returns its status
+///
+/// # Safety
+/// `handle` was returned by `interview_new` and not freed
+unsafe fn answer(
+    handle: *mut Handle,
+    call: impl FnOnce(&mut Processor) -> anyhow::Result<Vec<u8>>,
+) -> i32 {
+    let handle = &mut *handle;
+    match call(&mut handle.processor) {
+        Ok(result) => {
+            handle.result = result;
+            OK
+        }
+        Err(e) => {
+            set_error(&e);
+            FAILED
+        }
+    }
+}
+
+/// Creates a processor with the settings of the `config` JSON object (`cache_hours`,
+/// `source` and `symbol`, each optional), fetching from `callback` with `user` when one
+/// is given and from its `source` otherwise, or from the host's `interview_fetch` import
+/// in a WebAssembly build. Returns null when the settings are invalid.
+///
+/// # Safety
+/// `config` points to `config_len` readable bytes, and `user` stays valid for the callback
+/// as long as the processor lives
+#[no_mangle]
+pub unsafe extern "C" fn interview_new(
+    config: *const u8,
+    config_len: usize,
+    callback: Option<FetchCallback>,
+    user: *mut c_void,
+) -> *mut Handle {
+    let created = (|| {
+        let json = read_str(config, config_len)?;
+        let settings = match json.trim() {
+            "" => ProcessorConfig::default(),
+            json => serde_json::from_str::<ProcessorConfig>(json).context("Invalid settings")?,
+        };
+        let mut config = Config::default();
+        if let Some(cache_hours) = settings.cache_hours {
+            config.cache_hours = cache_hours;
+        }
+        if let Some(symbol) = settings.symbol {
+            config.default_symbol = symbol;
+        }
+        if let Some(source) = settings.source {
+            config.source = source.parse::<SourceSpec>()?;
+        }
+        let source: Box<dyn FillSource> = match callback {
+            Some(callback) => Box::new(CallbackSource {
+                callback,
+                user: user as usize,
+            }),
+            #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+            None => Box::new(CallbackSource {
+                callback: wasm::host_fetch,
+                user: user as usize,
+            }),
+            #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+            None => crate::build_source(&config),
+        };
+        Ok(Processor::with_source(
+            config,
+            source,
+            Box::new(SystemClock),
+        ))
+    })();
+    match created {
+        Ok(processor) => Box::into_raw(Box::new(Handle {
+            processor,
+            result: Vec::new(),
+        })),
+        Err(e) => {
+            set_error(&e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Frees a processor
+///
+/// # Safety
+/// `handle` was returned by `interview_new` and is not used after, or is null
+#[no_mangle]
+pub unsafe extern "C" fn interview_free(handle: *mut Handle) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// Answers a query line, like a line of `query`'s input, with its result
+///
+/// # Safety
+/// `handle` was returned by `interview_new` and not freed, and `line` points to
+/// `line_len` readable bytes
+#[no_mangle]
+pub unsafe extern "C" fn interview_query(
+    handle: *mut Handle,
+    line: *const u8,
+    line_len: usize,
+) -> i32 {
+    answer(handle, |processor| {
+        let line = read_str(line, line_len)?;
+        Ok(processor.process_query(line.to_string())?.into_bytes())
+    })
+}
+
+/// Answers the fills of the symbol (the default one when empty) within (`start`, `end`]
+/// in Unix seconds, through the cache, as a JSON array of fills like `GET /fills`
+///
+/// # Safety
+/// `handle` was returned by `interview_new` and not freed, and `symbol` points to
+/// `symbol_len` readable bytes
+#[no_mangle]
+pub unsafe extern "C" fn interview_fills_in_range(
+    handle: *mut Handle,
+    symbol: *const u8,
+    symbol_len: usize,
+    start: i64,
+    end: i64,
+) -> i32 {
+    answer(handle, |processor| {
+        let symbol = read_str(symbol, symbol_len)?;
+        let window = match symbol {
+            "" => format!("(] {} {}", start, end),
+            symbol => format!("(] {} {} {}", symbol, start, end),
+        };
+        let fills = processor.window_fills(&window)?;
+        Ok(serde_json::to_vec(&fills)?)
+    })
+}
+
+/// Answers the size of the cache and the hit and API call counters, as a JSON object
+///
+/// # Safety
+/// `handle` was returned by `interview_new` and not freed
+#[no_mangle]
+pub unsafe extern "C" fn interview_cache_stats(handle: *mut Handle) -> i32 {
+    answer(handle, |processor| {
+        let size = processor.cache_stats();
+        let metrics = processor.metrics();
+        let stats = CacheStatsJson {
+            hours: size.hours,
+            fills: size.fills,
+            max_hour_fills: size.max_hour_fills,
+            bytes: size.bytes,
+            cache_hits: metrics.cache_hits,
+            api_calls: metrics.api_calls,
+            hit_rate: stats::hit_rate(metrics.cache_hits, metrics.api_calls),
+        };
+        Ok(serde_json::to_vec(&stats)?)
+    })
+}
+
+/// Returns the answer of the handle's last call that succeeded
+///
+/// # Safety
+/// `handle` was returned by `interview_new` and not freed
+#[no_mangle]
+pub unsafe extern "C" fn interview_result_ptr(handle: *const Handle) -> *const u8 {
+    (*handle).result.as_ptr()
+}
+
+/// Returns the length of `interview_result_ptr`'s answer
+///
+/// # Safety
+/// `handle` was returned by `interview_new` and not freed
+#[no_mangle]
+pub unsafe extern "C" fn interview_result_len(handle: *const Handle) -> usize {
+    (*handle).result.len()
+}
+
+/// Returns the error of this thread's last call that failed, valid until its next one fails
+#[no_mangle]
+pub extern "C" fn interview_error_ptr() -> *const u8 {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+/// Returns the length of `interview_error_ptr`'s error
+#[no_mangle]
+pub extern "C" fn interview_error_len() -> usize {
+    LAST_ERROR.with(|last| last.borrow().len())
+}
+
+/// Appends bytes to the sink of a fetch callback
+///
+/// # Safety
+/// `sink` is the sink the callback was called with, and `bytes` points to `len` readable
+/// bytes
+#[no_mangle]
+pub unsafe extern "C" fn interview_sink_write(sink: *mut Sink, bytes: *const u8, len: usize) {
+    if len > 0 {
+        (*sink)
+            .0
+            .extend_from_slice(slice::from_raw_parts(bytes, len));
+    }
+}
@@ -0,0 +1,75 @@
+//! What the C interface needs in a `wasm32-unknown-unknown` build, whose host can't hand
+//! the library function pointers: fills are fetched through the host's `interview_fetch`
+//! import, and the host allocates the bytes it passes in through `interview_alloc`. The
+//! wrapper in `wasm/interview.mjs` drives it from JavaScript.
+
+use std::ffi::c_void;
+use std::ptr;
+
+use super::{answer, read_str, Handle, Sink};
+
+#[link(wasm_import_module = "env")]
+extern "C" {
+    /// The `FetchCallback` of every processor created without one, told apart by `user`
+    fn interview_fetch(
+        user: *mut c_void,
+        symbol: *const u8,
+        symbol_len: usize,
+        start: i64,
+        end: i64,
+        sink: *mut c_void,
+    ) -> i32;
+}
+
+/// Fetches through the host's import
+pub(super) extern "C" fn host_fetch(
+    user: *mut c_void,
+    symbol: *const u8,
+    symbol_len: usize,
+    start: i64,
+    end: i64,
+    sink: *mut Sink,
+) -> i32 {
+    // SAFETY: the host reads the symbol and writes the sink while the call lasts
+    unsafe { interview_fetch(user, symbol, symbol_len, start, end, sink.cast()) }
+}
+
+/// Returns `len` bytes for the host to write a string to, freed with `interview_dealloc`
+#[no_mangle]
+pub extern "C" fn interview_alloc(len: usize) -> *mut u8 {
+    Box::into_raw(vec![0u8; len].into_boxed_slice()) as *mut u8
+}
+
+/// Frees bytes of `interview_alloc`
+///
+/// # Safety
+/// `ptr` and `len` are those of an `interview_alloc` not freed yet
+#[no_mangle]
+pub unsafe extern "C" fn interview_dealloc(ptr: *mut u8, len: usize) {
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(ptr, len)));
+}
+
+/// Answers the hours a query line would fetch, as a JSON array of `[SYMBOL, START, END]`
+/// requests the way the processor makes them, so a host fetching asynchronously can
+/// fetch them all at once before running the line
+///
+/// # Safety
+/// `handle` was returned by `interview_new` and not freed, and `line` points to
+/// `line_len` readable bytes
+#[no_mangle]
+pub unsafe extern "C" fn interview_missing_hours(
+    handle: *mut Handle,
+    line: *const u8,
+    line_len: usize,
+) -> i32 {
+    answer(handle, |processor| {
+        let line = read_str(line, line_len)?;
+        let (venue, symbol, hours) = processor.plan_query(line)?;
+        let missing = hours
+            .into_iter()
+            .filter(|&hour| !processor.is_cached(&venue, &symbol, hour))
+            .map(|hour| (symbol.as_str(), hour, hour + 3600))
+            .collect::<Vec<_>>();
+        Ok(serde_json::to_vec(&missing)?)
+    })
+}
@@ -0,0 +1,195 @@
+use anyhow::{anyhow, Context};
+use clap::{Parser, Subcommand};
+use std::fs;
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::clock::SystemClock;
+use crate::config::{Config, ConfigArgs};
+use crate::hour_file::{self, HourFormat};
+use crate::server::ApiResponse;
+use crate::source::{DiskSource, FillSource};
+use crate::{build_source, logging, run_queries, Processor};
+
+/// A fixture is a directory holding:
+/// - `hours/<SYMBOL>/<HOUR>.csv`: the upstream's response for each fetched hour, as-is
+/// - `queries.txt`: the queries to process
+/// - `expected.txt`: the expected output
+/// - `args.txt` (optional): flags to replay the fixture with, replacing those of the run;
+///   `--hours DIR` replays the hour files of another fixture instead of its own
+const QUERIES_FILE: &str = "queries.txt";
+const EXPECTED_FILE: &str = "expected.txt";
+const HOURS_DIR: &str = "hours";
+const ARGS_FILE: &str = "args.txt";
+
+/// Flags of a fixture's args file
+#[derive(Debug, Parser)]
+#[command(no_binary_name = true)]
+struct FixtureArgs {
+    /// Directory of the hour files to replay, relative to the fixture, for fixtures sharing
+    /// the hours of another; the fixture's `hours` directory by default
+    #[arg(long, value_name = "DIR")]
+    hours: Option<PathBuf>,
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+/// Wraps a source and records every response it returns into a fixture
+struct RecordingSource {
+    inner: Box<dyn FillSource>,
+    hours_dir: PathBuf,
+}
+
+impl FillSource for RecordingSource {
+    fn get_fills(
+        &mut self,
+        symbol: &str,
+        start_timestamp_in_seconds: i64,
+        end_timestamp_in_seconds: i64,
+    ) -> anyhow::Result<ApiResponse> {
+        let response =
+            self.inner
+                .get_fills(symbol, start_timestamp_in_seconds, end_timestamp_in_seconds)?;
+        let path = hour_file::hour_path(
+            &self.hours_dir,
+            symbol,
+            start_timestamp_in_seconds,
+            HourFormat::Csv,
+        );
+        hour_file::write(&path, symbol, start_timestamp_in_seconds, &response.fills)?;
+        Ok(response)
+    }
+
+    // Each response is recorded as the file of a whole hour
+    fn serves_partial_hours(&self) -> bool {
+        false
+    }
+
+    fn newest_hour(&self, symbol: &str) -> Option<i64> {
+        self.inner.newest_hour(symbol)
+    }
+}
+
+/// Subcommands of the `fixture` command
+#[derive(Debug, Subcommand)]
+pub enum FixtureCommand {
+    /// Process the queries on stdin like a normal run and capture the queries,
+    /// the upstream responses and the output into DIR
+    Record {
+        /// Fixture directory to create
+        dir: PathBuf,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Replay each fixture's queries against its recorded responses
+    /// and diff the output with the expected output
+    Run {
+        /// Fixture directories to replay
+        #[arg(required = true)]
+        dirs: Vec<PathBuf>,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+}
+
+/// Entry point of the `fixture` command
+pub fn main(command: FixtureCommand) -> anyhow::Result<()> {
+    match command {
+        FixtureCommand::Record { dir, config } => {
+            let config = Config::from(config);
+            logging::init(config.log_format);
+            record(&dir, config)
+        }
+        FixtureCommand::Run { dirs, config } => {
+            logging::init(Config::from(config.clone()).log_format);
+            let mut failed = 0;
+            for dir in &dirs {
+                let (config, hours_dir) = fixture_config(dir, &config)?;
+                if !run(dir, &hours_dir, config)? {
+                    failed += 1;
+                }
+            }
+            if failed > 0 {
+                return Err(anyhow!("{} of {} fixtures failed", failed, dirs.len()));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Records a live run of the queries on stdin into the fixture directory
+fn record(dir: &Path, config: Config) -> anyhow::Result<()> {
+    let mut queries = String::new();
+    io::stdin().read_to_string(&mut queries)?;
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join(QUERIES_FILE), &queries)?;
+
+    let source = Box::new(RecordingSource {
+        inner: build_source(&config),
+        hours_dir: dir.join(HOURS_DIR),
+    });
+    let mut processor = Processor::with_source(config, source, Box::new(SystemClock));
+    let mut output = Vec::new();
+    run_queries(&mut processor, Cursor::new(queries), &mut output)?;
+
+    fs::write(dir.join(EXPECTED_FILE), &output)?;
+    io::stdout().write_all(&output)?;
+    info!("Recorded fixture {}", dir.display());
+    Ok(())
+}
+
+/// Returns the configuration to replay the fixture with, the flags of its args file when
+/// it has one and the flags of the run otherwise, and the directory of its hour files
+fn fixture_config(dir: &Path, config: &ConfigArgs) -> anyhow::Result<(Config, PathBuf)> {
+    let path = dir.join(ARGS_FILE);
+    if !path.exists() {
+        return Ok((Config::from(config.clone()), dir.join(HOURS_DIR)));
+    }
+    let args = fs::read_to_string(&path)?;
+    let args = FixtureArgs::try_parse_from(args.split_whitespace())
+        .with_context(|| format!("Invalid flags in {}", path.display()))?;
+    let hours_dir = dir.join(args.hours.as_deref().unwrap_or(Path::new(HOURS_DIR)));
+    Ok((Config::from(args.config), hours_dir))
+}
+
+/// Replays a fixture from the hour files of `hours_dir` and reports whether its output
+/// matches the expected output
+fn run(dir: &Path, hours_dir: &Path, config: Config) -> anyhow::Result<bool> {
+    let queries = fs::read_to_string(dir.join(QUERIES_FILE))?;
+    let expected = fs::read_to_string(dir.join(EXPECTED_FILE))?;
+
+    let source = Box::new(DiskSource {
+        dir: hours_dir.to_path_buf(),
+        strict_schema: config.strict_schema,
+    });
+    let mut processor = Processor::with_source(config, source, Box::new(SystemClock));
+    let mut output = Vec::new();
+    run_queries(&mut processor, Cursor::new(queries), &mut output)?;
+    let output = String::from_utf8(output)?;
+
+    let expected_lines = expected.lines().collect::<Vec<&str>>();
+    let output_lines = output.lines().collect::<Vec<&str>>();
+    let mut mismatches = 0;
+    for i in 0..expected_lines.len().max(output_lines.len()) {
+        let expected_line = expected_lines.get(i).copied().unwrap_or("<missing>");
+        let output_line = output_lines.get(i).copied().unwrap_or("<missing>");
+        if expected_line != output_line {
+            println!(
+                "{} line {}: expected {}, got {}",
+                dir.display(),
+                i + 1,
+                expected_line,
+                output_line
+            );
+            mismatches += 1;
+        }
+    }
+
+    if mismatches == 0 {
+        println!("{}: ok", dir.display());
+    } else {
+        println!("{}: {} mismatched lines", dir.display(), mismatches);
+    }
+    Ok(mismatches == 0)
+}
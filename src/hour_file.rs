@@ -0,0 +1,230 @@
+use anyhow::{anyhow, Context};
+use clap::ValueEnum;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::server::{self, Fill, FILL_FIELDS};
+use crate::snapshot::{self, HourRecord};
+use crate::source::DEFAULT_VENUE;
+use crate::DuplicateFills;
+
+/// Layout of an hour file, told apart by its extension
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum HourFormat {
+    /// `<HOUR>.csv`: a header row and one row per fill, readable with any tool
+    #[default]
+    Csv,
+    /// `<HOUR>.bin`: the hour as a block of the binary snapshot layout (see
+    /// `snapshot::encode_hour`), checksummed, at full precision and an order of magnitude
+    /// faster to read
+    Binary,
+}
+
+impl HourFormat {
+    /// Every format, in the order `find` looks for them
+    const LOOKUP_ORDER: [HourFormat; 2] = [HourFormat::Binary, HourFormat::Csv];
+
+    /// Returns the extension of the files in the format
+    pub fn extension(self) -> &'static str {
+        match self {
+            HourFormat::Csv => "csv",
+            HourFormat::Binary => "bin",
+        }
+    }
+
+    /// Returns the format of the file by its extension, `None` when it is no hour file
+    pub fn of(path: &Path) -> Option<HourFormat> {
+        let extension = path.extension()?;
+        Self::LOOKUP_ORDER
+            .into_iter()
+            .find(|format| extension == format.extension())
+    }
+}
+
+/// Path of the file holding the fills of one symbol and hour under `dir`, in the format
+pub fn hour_path(dir: &Path, symbol: &str, hour: i64, format: HourFormat) -> PathBuf {
+    dir.join(symbol)
+        .join(format!("{}.{}", hour, format.extension()))
+}
+
+/// Returns the file of the symbol and hour under `dir`, the binary one when there are both,
+/// or `None` when there is neither
+pub fn find(dir: &Path, symbol: &str, hour: i64) -> Option<PathBuf> {
+    HourFormat::LOOKUP_ORDER
+        .into_iter()
+        .map(|format| hour_path(dir, symbol, hour, format))
+        .find(|path| path.exists())
+}
+
+/// Writes the fills of the symbol's hour to the file in the format of its extension,
+/// replacing it atomically
+pub fn write(path: &Path, symbol: &str, hour: i64, fills: &[Fill]) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    if HourFormat::of(path) == Some(HourFormat::Binary) {
+        let record = HourRecord {
+            venue: DEFAULT_VENUE.to_string(),
+            symbol: symbol.to_string(),
+            hour,
+            duplicates: DuplicateFills::default(),
+            fills: fills.to_vec(),
+        };
+        fs::write(&tmp_path, snapshot::encode_hour(&record))?;
+    } else {
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_path(&tmp_path)?;
+        writer.write_record(FILL_FIELDS)?;
+        for fill in fills {
+            writer.serialize(fill)?;
+        }
+        writer.flush()?;
+    }
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+/// Fills of an hour file
+pub struct HourFile {
+    pub fills: Vec<Fill>,
+    /// Columns of the file that aren't fields of a fill, ignored (see `server::unknown_fields`)
+    pub unknown_fields: Vec<String>,
+}
+
+/// Reads the fills of an hour file, in the format of its extension. Columns of a CSV file are
+/// matched to the fields of a fill by name, so files written before fills carried their
+/// optional fields, and files with columns added since, are read too; with `strict_schema`,
+/// unknown columns fail the read. A binary file fails the read unless it is whole and of the
+/// version this build reads.
+pub fn read(path: &Path, strict_schema: bool) -> anyhow::Result<HourFile> {
+    if HourFormat::of(path) == Some(HourFormat::Binary) {
+        let bytes = fs::read(path)?;
+        let record = snapshot::decode_hour(&bytes)
+            .with_context(|| format!("Invalid hour file {}", path.display()))?;
+        return Ok(HourFile {
+            fills: record.fills,
+            unknown_fields: Vec::new(),
+        });
+    }
+
+    let mut reader = csv::Reader::from_path(path)?;
+    let unknown_fields = server::unknown_fields(reader.headers()?, strict_schema)
+        .with_context(|| format!("Invalid columns in {}", path.display()))?;
+    let fills = reader
+        .deserialize()
+        .enumerate()
+        .map(|(i, fill)| fill.with_context(|| format!("Invalid fill #{} in {}", i, path.display())))
+        .collect::<anyhow::Result<Vec<Fill>>>()?;
+    Ok(HourFile {
+        fills,
+        unknown_fields,
+    })
+}
+
+/// Returns the hours of the symbol's files under `dir`, in either format, in ascending order
+pub fn hours(dir: &Path) -> anyhow::Result<Vec<i64>> {
+    let mut hours = fs::read_dir(dir)
+        .with_context(|| format!("Failed to list the hour files in {}", dir.display()))?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            HourFormat::of(&path)?;
+            path.file_stem()?.to_str()?.parse::<i64>().ok()
+        })
+        .collect::<Vec<i64>>();
+    hours.sort_unstable();
+    hours.dedup();
+    if hours.is_empty() {
+        return Err(anyhow!("No hour files in {}", dir.display()));
+    }
+    Ok(hours)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{fill, HOUR};
+    use chrono::DateTime;
+
+    /// Fills with and without each optional field, at times finer than the second
+    fn fills() -> Vec<Fill> {
+        vec![
+            Fill {
+                time: DateTime::from_timestamp_millis(HOUR * 1000 + 60_250).unwrap(),
+                liquidity: Some(server::Liquidity::Taker),
+                trade_id: Some(7),
+                fee: Some("-0.125".parse().unwrap()),
+                fee_currency: Some("USD".parse().unwrap()),
+                ..fill(0, 1, 1, "37000.50", "0.0010")
+            },
+            fill(HOUR + 3600, 2, 2, "37001", "2"),
+        ]
+    }
+
+    #[test]
+    fn hour_files_round_trip_in_both_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        for format in [HourFormat::Csv, HourFormat::Binary] {
+            let path = hour_path(dir.path(), "BTC", HOUR, format);
+            write(&path, "BTC", HOUR, &fills()).unwrap();
+            assert_eq!(HourFormat::of(&path), Some(format));
+            let file = read(&path, true).unwrap();
+            assert_eq!(file.fills, fills(), "{:?}", format);
+            assert!(file.unknown_fields.is_empty());
+        }
+        // The binary file of an hour is read before its CSV file
+        let binary = hour_path(dir.path(), "BTC", HOUR, HourFormat::Binary);
+        assert_eq!(find(dir.path(), "BTC", HOUR), Some(binary));
+        assert_eq!(find(dir.path(), "BTC", HOUR + 3600), None);
+        assert_eq!(hours(&dir.path().join("BTC")).unwrap(), vec![HOUR]);
+    }
+
+    #[test]
+    fn binary_hour_files_keep_every_digit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = hour_path(dir.path(), "BTC", HOUR, HourFormat::Binary);
+        let fills = vec![fill(
+            HOUR + 1,
+            1,
+            1,
+            "0.000000000000000001",
+            "123456789.123456789000",
+        )];
+        write(&path, "BTC", HOUR, &fills).unwrap();
+        let read = read(&path, false).unwrap().fills;
+        assert_eq!(read[0].price.to_string(), "0.000000000000000001");
+        assert_eq!(read[0].quantity.to_string(), "123456789.123456789000");
+    }
+
+    #[test]
+    fn damaged_binary_hour_files_fail_to_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = hour_path(dir.path(), "BTC", HOUR, HourFormat::Binary);
+        write(&path, "BTC", HOUR, &fills()).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let error = |bytes: &[u8]| {
+            fs::write(&path, bytes).unwrap();
+            match read(&path, false) {
+                Ok(_) => panic!("read a damaged hour file"),
+                Err(e) => format!("{:#}", e),
+            }
+        };
+
+        let mut flipped = bytes.clone();
+        flipped[40] ^= 1;
+        assert!(error(&flipped).ends_with("checksum mismatch"));
+        let mut newer = bytes.clone();
+        newer[6] = 2;
+        assert!(error(&newer).ends_with("binary version 2, this build reads version 1"));
+        assert!(error(&bytes[..bytes.len() - 1]).ends_with("block is cut short"));
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(error(&trailing).ends_with("1 bytes after the hour's block"));
+        assert!(error(b"").ends_with("not in the binary layout"));
+    }
+}
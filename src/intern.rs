@@ -0,0 +1,115 @@
+use std::collections::HashSet;
+use std::mem;
+use std::sync::Arc;
+
+/// Venue, symbol and start (Unix seconds) of a cached hour, whose strings are shared by
+/// every hour of the market through an `Interner`
+pub type HourKey = (Arc<str>, Arc<str>, i64);
+
+/// Strings the interner holds at most before it drops those no one else holds
+pub const MAX_INTERNED: usize = 1024;
+
+/// Pool of the venue and symbol strings of the cache keys, so the hours of a market share
+/// one allocation of each instead of a copy per hour. Bounded by `MAX_INTERNED`: once
+/// full, the strings held by nothing but the pool, like those of markets whose hours all
+/// left the cache, are dropped, and a string that still finds no room is returned
+/// unpooled, so the pool can't grow with every market the cache ever held.
+#[derive(Debug, Default)]
+pub struct Interner {
+    strings: HashSet<Arc<str>>,
+}
+
+impl Interner {
+    /// Returns the pooled copy of the string, adding it to the pool when it has room
+    pub fn intern(&mut self, s: &str) -> Arc<str> {
+        if let Some(interned) = self.strings.get(s) {
+            return Arc::clone(interned);
+        }
+        if self.strings.len() >= MAX_INTERNED {
+            self.sweep();
+        }
+        let interned = Arc::<str>::from(s);
+        if self.strings.len() < MAX_INTERNED {
+            self.strings.insert(Arc::clone(&interned));
+        }
+        interned
+    }
+
+    /// Returns the pooled copy of the string if there is one, without adding it: a key
+    /// whose strings aren't pooled can't be in the cache
+    pub fn get(&self, s: &str) -> Option<Arc<str>> {
+        self.strings.get(s).cloned()
+    }
+
+    /// Returns the key of the market's hour, interning its strings
+    pub fn key(&mut self, venue: &str, symbol: &str, hour: i64) -> HourKey {
+        (self.intern(venue), self.intern(symbol), hour)
+    }
+
+    /// Returns the key of the market's hour if its strings are pooled, see `get`
+    pub fn get_key(&self, venue: &str, symbol: &str, hour: i64) -> Option<HourKey> {
+        Some((self.get(venue)?, self.get(symbol)?, hour))
+    }
+
+    /// Drops the strings held by nothing but the pool
+    pub fn sweep(&mut self) {
+        self.strings.retain(|s| Arc::strong_count(s) > 1);
+    }
+
+    /// Returns the number of pooled strings
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    /// Returns whether the pool is empty
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+
+    /// Returns the approximate bytes of one pooled string: its bytes and reference counts
+    pub fn string_bytes(s: &str) -> usize {
+        2 * mem::size_of::<usize>() + s.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_strings_share_one_allocation() {
+        let mut interner = Interner::default();
+        let first = interner.intern("BTC-USD");
+        let second = interner.intern(&String::from("BTC-USD"));
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(Arc::ptr_eq(&interner.get("BTC-USD").unwrap(), &first));
+        assert_eq!(interner.get("ETH-USD"), None);
+        assert_eq!(interner.len(), 1);
+    }
+
+    #[test]
+    fn a_full_pool_drops_the_strings_no_one_holds() {
+        let mut interner = Interner::default();
+        let held = (0..MAX_INTERNED / 2)
+            .map(|i| interner.intern(&format!("HELD-{}", i)))
+            .collect::<Vec<_>>();
+        for i in 0..MAX_INTERNED {
+            interner.intern(&format!("GONE-{}", i));
+        }
+        assert!(interner.len() <= MAX_INTERNED);
+        assert!(held
+            .iter()
+            .all(|s| Arc::ptr_eq(s, &interner.get(s).unwrap())));
+
+        // With every string held, a new one is returned unpooled
+        let all_held = (0..MAX_INTERNED)
+            .map(|i| interner.intern(&format!("FULL-{}", i)))
+            .collect::<Vec<_>>();
+        let unpooled = interner.intern("UNPOOLED");
+        assert_eq!(interner.len(), MAX_INTERNED);
+        assert_eq!(interner.get(&unpooled), None);
+        drop(all_held);
+        interner.sweep();
+        assert_eq!(interner.len(), held.len());
+    }
+}
@@ -0,0 +1,48 @@
+pub mod audit;
+pub mod backfill;
+pub mod bench;
+pub mod cli;
+#[cfg(feature = "net")]
+pub mod client;
+pub mod clock;
+pub mod config;
+pub mod dry_run;
+pub mod endpoint;
+#[cfg(feature = "arrow")]
+pub mod export;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fixture;
+pub mod hour_file;
+pub mod intern;
+pub mod logging;
+pub mod memory;
+#[cfg(feature = "mock-source")]
+pub mod mock;
+pub mod otlp;
+pub mod output;
+pub mod pipeline;
+pub mod pool;
+pub mod processor;
+pub mod profile;
+pub mod protocol;
+pub mod query;
+pub mod retry;
+pub mod rng;
+pub mod seq_index;
+#[cfg(feature = "net")]
+pub mod serve;
+pub mod server;
+pub mod snapshot;
+pub mod snapshot_tool;
+pub mod source;
+pub mod stats;
+pub mod subscription;
+pub mod summary;
+#[cfg(test)]
+mod testing;
+pub mod watch;
+pub mod workload;
+
+pub use pipeline::{build_source, check_output_flags, query_text, run_queries};
+pub use processor::{DeadlineExceeded, DuplicateFills, Processor, QueryOutcome};
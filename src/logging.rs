@@ -0,0 +1,51 @@
+use clap::ValueEnum;
+use std::io::IsTerminal;
+use tracing::Level;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
+
+use crate::otlp::{OtlpEndpoint, OtlpLayer};
+
+/// Output format of log records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Free-text lines, one per event
+    Text,
+    /// One JSON object per event with level, timestamp, target, message,
+    /// the event's structured fields, and the spans it happened in
+    Json,
+}
+
+/// Initializes the global tracing subscriber, writing to stderr and honoring `RUST_LOG`
+/// for filtering like env_logger did (errors only when unset)
+pub fn init(format: LogFormat) {
+    init_with_otlp(format, None)
+}
+
+/// Initializes the global tracing subscriber like `init`, also exporting the spans of this
+/// crate, down to the debug level whatever `RUST_LOG` says, to the OTLP endpoint if any
+pub fn init_with_otlp(format: LogFormat, otlp: Option<OtlpEndpoint>) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("error"));
+    let console = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    let console = match format {
+        LogFormat::Text => console.with_filter(filter).boxed(),
+        LogFormat::Json => console
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_filter(filter)
+            .boxed(),
+    };
+    let otlp = otlp.map(|endpoint| {
+        let spans = Targets::new().with_target(env!("CARGO_CRATE_NAME"), Level::DEBUG);
+        OtlpLayer::new(endpoint).with_filter(spans)
+    });
+    tracing_subscriber::registry()
+        .with(console)
+        .with(otlp)
+        .init();
+}
@@ -0,0 +1,47 @@
+use std::fs;
+
+/// Memory of the system, in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemMemory {
+    pub total: usize,
+    /// Memory that can be given to processes without swapping
+    pub available: usize,
+}
+
+/// Source of the system's memory, injectable so cache sizing from the available memory
+/// (`--cache-mem-fraction`) can be controlled
+pub trait MemoryProbe {
+    /// Returns the system's memory, or `None` when it can't be read
+    fn probe(&self) -> Option<SystemMemory>;
+}
+
+/// Reads the system's memory from `/proc/meminfo`; on systems without it, the memory
+/// can't be read and the cache is bounded by hours only
+pub struct ProcMeminfo;
+
+impl MemoryProbe for ProcMeminfo {
+    fn probe(&self) -> Option<SystemMemory> {
+        parse_meminfo(&fs::read_to_string("/proc/meminfo").ok()?)
+    }
+}
+
+/// Parses the memory from the text of `/proc/meminfo`, whose lines are like
+/// "MemAvailable:   12345678 kB"
+fn parse_meminfo(text: &str) -> Option<SystemMemory> {
+    let field = |name: &str| {
+        text.lines().find_map(|line| {
+            let kib = line.strip_prefix(name)?.strip_prefix(':')?;
+            let kib = kib
+                .trim()
+                .strip_suffix("kB")?
+                .trim()
+                .parse::<usize>()
+                .ok()?;
+            Some(kib.saturating_mul(1024))
+        })
+    };
+    Some(SystemMemory {
+        total: field("MemTotal")?,
+        available: field("MemAvailable")?,
+    })
+}
@@ -0,0 +1,276 @@
+use chrono::DateTime;
+use clap::Args;
+use rust_decimal::Decimal;
+use std::thread;
+use std::time::Duration;
+
+use crate::rng::SplitMix64;
+use crate::server::{ApiResponse, Fill};
+use crate::source::FillSource;
+
+/// Parameters of the mock fill source
+#[derive(Debug, Clone)]
+pub struct MockConfig {
+    /// Seed of the generator; the same seed always produces the same fills
+    pub seed: u64,
+    /// Average number of fills per hour
+    pub fills_per_hour: u64,
+    /// Price at the start of every hour, before the hour's random offset
+    pub base_price: Decimal,
+    /// Largest price change between consecutive fills
+    pub price_step: Decimal,
+    /// Probability that a fill shares the sequence number of the previous fill,
+    /// like the fills of one taker trade walking the book
+    pub duplicate_rate: f64,
+    /// How long every request takes, like a slow upstream
+    pub latency: Duration,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        MockConfig {
+            seed: 0,
+            fills_per_hour: 1500,
+            base_price: Decimal::new(58, 0),
+            price_step: Decimal::new(5, 3),
+            duplicate_rate: 0.3,
+            latency: Duration::ZERO,
+        }
+    }
+}
+
+/// Command-line flags of the mock source; any of them selects it
+#[derive(Debug, Clone, Args)]
+pub struct MockArgs {
+    /// Serve fills from the deterministic mock source instead of the trades API
+    #[arg(long)]
+    mock: bool,
+    /// Seed of the mock source [default: 0]
+    #[arg(long, value_name = "N")]
+    mock_seed: Option<u64>,
+    /// Average number of fills per hour of the mock source [default: 1500]
+    #[arg(long, value_name = "N")]
+    mock_fills_per_hour: Option<u64>,
+    /// Price at the start of every mock hour [default: 58]
+    #[arg(long, value_name = "PRICE")]
+    mock_base_price: Option<Decimal>,
+    /// Largest price change between consecutive mock fills [default: 0.005]
+    #[arg(long, value_name = "PRICE")]
+    mock_price_step: Option<Decimal>,
+    /// Probability that a mock fill continues the previous taker trade [default: 0.3]
+    #[arg(long, value_name = "P")]
+    mock_duplicate_rate: Option<f64>,
+    /// Milliseconds every request to the mock source takes [default: 0]
+    #[arg(long, value_name = "MS")]
+    mock_latency_ms: Option<u64>,
+}
+
+impl MockArgs {
+    /// Returns the mock configuration when any mock flag was given
+    pub fn into_config(self) -> Option<MockConfig> {
+        let selected = self.mock
+            || self.mock_seed.is_some()
+            || self.mock_fills_per_hour.is_some()
+            || self.mock_base_price.is_some()
+            || self.mock_price_step.is_some()
+            || self.mock_duplicate_rate.is_some()
+            || self.mock_latency_ms.is_some();
+        if !selected {
+            return None;
+        }
+
+        let defaults = MockConfig::default();
+        Some(MockConfig {
+            seed: self.mock_seed.unwrap_or(defaults.seed),
+            fills_per_hour: self.mock_fills_per_hour.unwrap_or(defaults.fills_per_hour),
+            base_price: self.mock_base_price.unwrap_or(defaults.base_price),
+            price_step: self.mock_price_step.unwrap_or(defaults.price_step),
+            duplicate_rate: self.mock_duplicate_rate.unwrap_or(defaults.duplicate_rate),
+            latency: self
+                .mock_latency_ms
+                .map_or(defaults.latency, Duration::from_millis),
+        })
+    }
+}
+
+/// Deterministic pseudo-random fill source for offline runs, demos and benchmarks.
+/// Every hour is generated independently from the seed, the symbol and the hour,
+/// so the fills of an hour don't depend on which hours were requested before it.
+pub struct MockSource {
+    config: MockConfig,
+}
+
+impl MockSource {
+    pub fn new(config: MockConfig) -> Self {
+        MockSource { config }
+    }
+
+    fn hour_rng(&self, symbol: &str, hour: i64) -> SplitMix64 {
+        // FNV-1a over the inputs, so the stream doesn't depend on std's randomized hasher
+        let mut hash = 0xCBF2_9CE4_8422_2325u64;
+        let bytes = self
+            .config
+            .seed
+            .to_le_bytes()
+            .into_iter()
+            .chain(symbol.bytes())
+            .chain(hour.to_le_bytes());
+        for byte in bytes {
+            hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3);
+        }
+        SplitMix64(hash)
+    }
+
+    /// Generates the fills of one hour bucket, sorted by time
+    fn generate_hour(&self, symbol: &str, hour: i64) -> Vec<Fill> {
+        let mut rng = self.hour_rng(symbol, hour);
+        let density = self.config.fills_per_hour;
+        let count = density / 2 + rng.below(density + 1);
+
+        let mut times = (0..count)
+            .map(|_| hour + 1 + rng.below(3600) as i64)
+            .collect::<Vec<i64>>();
+        times.sort_unstable();
+
+        // Each hour starts within +-1% of the base price, then walks by at most price_step per fill
+        let step_units = (self.config.price_step * Decimal::new(1000, 0))
+            .trunc()
+            .try_into()
+            .unwrap_or(0i64);
+        let mut price =
+            self.config.base_price * (Decimal::ONE + Decimal::new(rng.below(201) as i64 - 100, 4));
+        let mut sequence_number = (hour as u64).wrapping_mul(1000);
+        let mut fills: Vec<Fill> = Vec::with_capacity(times.len());
+
+        for time in times {
+            let previous = fills.last().copied();
+            let fill = match previous {
+                // Another fill of the previous taker trade, one price level further
+                Some(previous) if rng.unit() < self.config.duplicate_rate => Fill {
+                    price: (previous.price
+                        + Decimal::new(previous.direction as i64 * (1 + rng.below(5) as i64), 3))
+                    .max(Decimal::new(1, 3)),
+                    quantity: Decimal::new(1 + rng.below(10_000) as i64, 3),
+                    ..previous
+                },
+                _ => {
+                    let step = rng.below(2 * step_units as u64 + 1) as i64 - step_units;
+                    price = (price + Decimal::new(step, 3)).max(Decimal::new(1, 3));
+                    sequence_number += 1 + rng.below(20);
+                    Fill {
+                        time: DateTime::from_timestamp(time, 0).unwrap_or_default(),
+                        direction: if rng.below(2) == 0 { 1 } else { -1 },
+                        price,
+                        quantity: Decimal::new(1 + rng.below(10_000) as i64, 3),
+                        sequence_number,
+                        liquidity: None,
+                        trade_id: None,
+                        fee: None,
+                        fee_currency: None,
+                    }
+                }
+            };
+            fills.push(fill);
+        }
+
+        fills
+    }
+}
+
+impl FillSource for MockSource {
+    fn get_fills(
+        &mut self,
+        symbol: &str,
+        start_timestamp_in_seconds: i64,
+        end_timestamp_in_seconds: i64,
+    ) -> anyhow::Result<ApiResponse> {
+        thread::sleep(self.config.latency);
+        let first_hour = start_timestamp_in_seconds - start_timestamp_in_seconds.rem_euclid(3600);
+        let mut fills = Vec::new();
+        for hour in (first_hour..end_timestamp_in_seconds).step_by(3600) {
+            fills.extend(self.generate_hour(symbol, hour).into_iter().filter(|fill| {
+                fill.time.timestamp() > start_timestamp_in_seconds
+                    && fill.time.timestamp() <= end_timestamp_in_seconds
+            }));
+        }
+
+        Ok(ApiResponse {
+            fills,
+            malformed: 0,
+            unknown_fields: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::HOUR;
+
+    /// Returns the fills of the hour as "TIME SEQUENCE DIRECTION PRICE QUANTITY" lines
+    fn lines(source: &mut MockSource, symbol: &str, start: i64, end: i64) -> Vec<String> {
+        let response = source.get_fills(symbol, start, end).unwrap();
+        response
+            .fills
+            .iter()
+            .map(|fill| {
+                format!(
+                    "{} {} {} {} {}",
+                    fill.time.timestamp(),
+                    fill.sequence_number,
+                    fill.direction,
+                    fill.price,
+                    fill.quantity
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn generated_fills_match_the_golden_hour() {
+        let mut source = MockSource::new(MockConfig {
+            fills_per_hour: 4,
+            ..MockConfig::default()
+        });
+        assert_eq!(
+            lines(&mut source, "BTC", HOUR, HOUR + 3600),
+            [
+                "1700816451 1700816400001 1 58.3344 6.768",
+                "1700816451 1700816400001 1 58.3384 7.220",
+                "1700817615 1700816400013 -1 58.3394 8.859",
+                "1700817763 1700816400017 1 58.3444 5.359",
+                "1700819734 1700816400025 1 58.3404 8.179",
+            ]
+        );
+
+        let fills = MockSource::new(MockConfig::default()).generate_hour("BTC", HOUR);
+        let volume = fills
+            .iter()
+            .map(|fill| fill.price * fill.quantity)
+            .sum::<Decimal>();
+        assert_eq!(
+            (fills.len(), volume.to_string()),
+            (1352, "396798.6087630".to_string())
+        );
+    }
+
+    #[test]
+    fn hours_depend_on_the_seed_symbol_and_hour_only() {
+        let mut source = MockSource::new(MockConfig::default());
+        let hour = lines(&mut source, "BTC", HOUR, HOUR + 3600);
+        let by_hour = (-2..2)
+            .rev()
+            .map(|i| lines(&mut source, "BTC", HOUR + i * 3600, HOUR + (i + 1) * 3600))
+            .collect::<Vec<Vec<String>>>();
+        assert_eq!(by_hour[1], hour);
+        let range = by_hour.into_iter().rev().flatten().collect::<Vec<String>>();
+        assert_eq!(lines(&mut source, "BTC", HOUR - 7200, HOUR + 7200), range);
+
+        let mut reseeded = MockSource::new(MockConfig {
+            seed: 1,
+            ..MockConfig::default()
+        });
+        assert_ne!(lines(&mut reseeded, "BTC", HOUR, HOUR + 3600), hour);
+        assert_ne!(lines(&mut source, "ETH", HOUR, HOUR + 3600), hour);
+    }
+}
@@ -0,0 +1,386 @@
+use anyhow::{anyhow, Context};
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{warn, Subscriber};
+use tracing_subscriber::layer::Context as LayerContext;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::rng::SplitMix64;
+
+/// Longest time a closed span waits before it is exported
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most spans exported in one request
+const MAX_BATCH: usize = 512;
+
+/// Closed spans waiting for the exporter at most; spans closed while it is full are dropped
+const QUEUE_SPANS: usize = 8192;
+
+/// Trace collector spans are exported to, over OTLP/HTTP with the JSON encoding:
+/// `http://HOST:PORT[/PATH]`, the path `/v1/traces` by default
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OtlpEndpoint {
+    /// `HOST:PORT` to connect to
+    pub addr: String,
+    pub path: String,
+}
+
+impl FromStr for OtlpEndpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let rest = s.strip_prefix("http://").ok_or_else(|| {
+            anyhow!(
+                "Invalid OTLP endpoint: {} (expected http://HOST:PORT[/PATH])",
+                s
+            )
+        })?;
+        let (addr, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/v1/traces"),
+        };
+        match addr.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                Ok(OtlpEndpoint {
+                    addr: addr.to_string(),
+                    path: path.to_string(),
+                })
+            }
+            _ => Err(anyhow!(
+                "Invalid OTLP endpoint: {} (expected http://HOST:PORT[/PATH])",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OtlpEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}{}", self.addr, self.path)
+    }
+}
+
+/// Trace and span a span continues, from a W3C `traceparent` value like
+/// `00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01`
+fn parse_traceparent(value: &str) -> Option<(u128, u64)> {
+    let [version, trace_id, parent_id, _flags] = value.split('-').collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    if version != "00" || trace_id.len() != 32 || parent_id.len() != 16 {
+        return None;
+    }
+    let trace_id = u128::from_str_radix(trace_id, 16).ok()?;
+    let parent_id = u64::from_str_radix(parent_id, 16).ok()?;
+    (trace_id != 0 && parent_id != 0).then_some((trace_id, parent_id))
+}
+
+/// A span being recorded, kept in the extensions of its span until it closes
+struct SpanData {
+    trace_id: u128,
+    span_id: u64,
+    parent_id: Option<u64>,
+    name: &'static str,
+    /// Whether the span serves a request from outside the process: a span with a
+    /// `traceparent` field, which requests carry their trace context in
+    server: bool,
+    start: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+}
+
+/// Collects the fields of a span as OTLP attributes. A `traceparent` field is
+/// taken as the trace context the span continues instead.
+#[derive(Default)]
+struct Fields {
+    attributes: Vec<(&'static str, Value)>,
+    traceparent: Option<String>,
+}
+
+impl Fields {
+    fn set(&mut self, field: &Field, value: Value) {
+        self.attributes.retain(|(name, _)| *name != field.name());
+        self.attributes.push((field.name(), value));
+    }
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "traceparent" {
+            self.traceparent = Some(value.to_string());
+        } else {
+            self.set(field, json!({ "stringValue": value }));
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field, json!({ "boolValue": value }));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.set(field, json!({ "doubleValue": value }));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+/// Layer exporting every closed span to a trace collector (see `--otlp-endpoint`), on a
+/// thread of its own so spans never wait for the collector. A span is part of the trace
+/// of its parent, or of the trace its `traceparent` field names, or starts a trace.
+pub struct OtlpLayer {
+    spans: SyncSender<Value>,
+    ids: Mutex<SplitMix64>,
+}
+
+impl OtlpLayer {
+    /// Starts the exporter to the endpoint and returns the layer feeding it
+    pub fn new(endpoint: OtlpEndpoint) -> Self {
+        let (spans, received) = mpsc::sync_channel(QUEUE_SPANS);
+        thread::spawn(move || export(&endpoint, &received));
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        OtlpLayer {
+            spans,
+            ids: Mutex::new(SplitMix64(seed)),
+        }
+    }
+
+    /// Returns a random id, never 0, which OTLP reserves for no id
+    fn next_id(&self) -> u64 {
+        let mut ids = self.ids.lock().unwrap();
+        loop {
+            let id = ids.next_u64();
+            if id != 0 {
+                return id;
+            }
+        }
+    }
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        let server = attrs.fields().field("traceparent").is_some();
+        let parent = span.parent().and_then(|parent| {
+            let extensions = parent.extensions();
+            let data = extensions.get::<SpanData>()?;
+            Some((data.trace_id, data.span_id))
+        });
+        let traceparent = fields.traceparent.as_deref().and_then(parse_traceparent);
+        let (trace_id, parent_id) = match parent.or(traceparent) {
+            Some((trace_id, parent_id)) => (trace_id, Some(parent_id)),
+            None => (
+                (self.next_id() as u128) << 64 | self.next_id() as u128,
+                None,
+            ),
+        };
+        span.extensions_mut().insert(SpanData {
+            trace_id,
+            span_id: self.next_id(),
+            parent_id,
+            name: attrs.metadata().name(),
+            server,
+            start: SystemTime::now(),
+            attributes: fields.attributes,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: LayerContext<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        let Some(data) = extensions.get_mut::<SpanData>() else {
+            return;
+        };
+        let mut fields = Fields {
+            attributes: std::mem::take(&mut data.attributes),
+            traceparent: None,
+        };
+        values.record(&mut fields);
+        data.attributes = fields.attributes;
+    }
+
+    fn on_close(&self, id: Id, ctx: LayerContext<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(data) = span.extensions_mut().remove::<SpanData>() else {
+            return;
+        };
+        // A full queue means the collector can't keep up; the span is dropped
+        let _ = self.spans.try_send(encode_span(&data, SystemTime::now()));
+    }
+}
+
+/// Returns the time in nanoseconds since the epoch, as the string OTLP encodes 64-bit
+/// integers in
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos())
+        .to_string()
+}
+
+/// Returns the span in the OTLP JSON encoding, ended at `end`
+fn encode_span(data: &SpanData, end: SystemTime) -> Value {
+    let mut span = json!({
+        "traceId": format!("{:032x}", data.trace_id),
+        "spanId": format!("{:016x}", data.span_id),
+        "name": data.name,
+        // SPAN_KIND_SERVER or SPAN_KIND_INTERNAL
+        "kind": if data.server { 2 } else { 1 },
+        "startTimeUnixNano": unix_nanos(data.start),
+        "endTimeUnixNano": unix_nanos(end),
+        "attributes": data
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": value }))
+            .collect::<Vec<_>>(),
+    });
+    if let Some(parent_id) = data.parent_id {
+        span["parentSpanId"] = json!(format!("{:016x}", parent_id));
+    }
+    span
+}
+
+/// Returns the body of an export request of the spans
+fn export_request(spans: Vec<Value>) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": env!("CARGO_PKG_NAME") } },
+                    { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } },
+                ],
+            },
+            "scopeSpans": [{
+                "scope": { "name": env!("CARGO_PKG_NAME") },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+/// Sends one export request of the spans to the endpoint and checks it was accepted
+fn post(endpoint: &OtlpEndpoint, spans: Vec<Value>) -> anyhow::Result<()> {
+    let body = export_request(spans).to_string();
+    let mut stream = TcpStream::connect(&endpoint.addr)
+        .with_context(|| format!("Failed to connect to {}", endpoint))?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        endpoint.path,
+        endpoint.addr,
+        body.len(),
+        body
+    )?;
+    let mut status_line = String::new();
+    BufReader::new(&stream).read_line(&mut status_line)?;
+    match status_line.split(' ').nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(anyhow!("{} answered {}", endpoint, status_line.trim_end())),
+    }
+}
+
+/// Exports the closed spans in batches, every `EXPORT_INTERVAL` or `MAX_BATCH` spans,
+/// until every layer sending them is gone. A batch the collector doesn't take is dropped,
+/// with a warning once until an export succeeds again.
+fn export(endpoint: &OtlpEndpoint, received: &Receiver<Value>) {
+    let mut batch = Vec::new();
+    let mut failing = false;
+    let mut next_export = Instant::now() + EXPORT_INTERVAL;
+    loop {
+        let open =
+            match received.recv_timeout(next_export.saturating_duration_since(Instant::now())) {
+                Ok(span) => {
+                    batch.push(span);
+                    true
+                }
+                Err(RecvTimeoutError::Timeout) => true,
+                Err(RecvTimeoutError::Disconnected) => false,
+            };
+        if batch.len() >= MAX_BATCH || Instant::now() >= next_export || !open {
+            if !batch.is_empty() {
+                match post(endpoint, std::mem::take(&mut batch)) {
+                    Ok(()) => failing = false,
+                    Err(e) if !failing => {
+                        failing = true;
+                        warn!("Failed to export spans, dropping them: {:#}", e);
+                    }
+                    Err(_) => {}
+                }
+            }
+            next_export = Instant::now() + EXPORT_INTERVAL;
+        }
+        if !open {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoints_parse_with_the_default_path() {
+        let endpoint = "http://127.0.0.1:4318".parse::<OtlpEndpoint>().unwrap();
+        assert_eq!(endpoint.addr, "127.0.0.1:4318");
+        assert_eq!(endpoint.path, "/v1/traces");
+        let endpoint = "http://collector:4318/otlp/v1/traces"
+            .parse::<OtlpEndpoint>()
+            .unwrap();
+        assert_eq!(endpoint.to_string(), "http://collector:4318/otlp/v1/traces");
+        for invalid in [
+            "127.0.0.1:4318",
+            "https://collector:4318",
+            "http://collector",
+            "http://:4318",
+        ] {
+            assert!(invalid.parse::<OtlpEndpoint>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn traceparents_name_the_trace_and_parent() {
+        assert_eq!(
+            parse_traceparent("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"),
+            Some((0x0af7651916cd43dd8448eb211c80319c, 0xb7ad6b7169203331))
+        );
+        for invalid in [
+            "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b71692033-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+        ] {
+            assert_eq!(parse_traceparent(invalid), None, "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,537 @@
+use anyhow::anyhow;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// Format of the answers written to the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// One line per answer
+    Text,
+    /// One MessagePack map per answer, each preceded by its length in bytes
+    /// as a 4-byte big-endian integer
+    Msgpack,
+    /// One JSON object per line, with the fields of the MessagePack maps
+    Json,
+    /// A header row, then one row per answer with the fields of the MessagePack maps as
+    /// columns, empty where an answer has no such field
+    Csv,
+}
+
+/// Columns of the CSV answers, in order
+const CSV_COLUMNS: [&str; 7] = [
+    "type",
+    "line",
+    "id",
+    "eval_time",
+    "result",
+    "final",
+    "latest_end_ms",
+];
+
+/// An answer written to the output
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Answer<'a> {
+    /// Answer to the query or command on an input line, with the id the line gave it
+    /// under protocol version 2
+    Answer {
+        line: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<&'a str>,
+        result: &'a str,
+        /// Whether the answer is final, for queries: computed from hours that had all ended
+        /// when they were fetched, so it won't grow
+        #[serde(rename = "final", skip_serializing_if = "Option::is_none")]
+        is_final: Option<bool>,
+        /// End the query's LATEST windows were resolved to, in Unix milliseconds
+        #[serde(skip_serializing_if = "Option::is_none")]
+        latest_end_ms: Option<i64>,
+        /// The query answered, for `--format` templates; `None` for commands
+        #[serde(skip)]
+        context: Option<&'a AnswerContext>,
+    },
+    /// Evaluation of a standing query, at a time in Unix seconds
+    Sub {
+        id: u64,
+        eval_time: i64,
+        result: &'a str,
+        #[serde(rename = "final")]
+        is_final: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        latest_end_ms: Option<i64>,
+        #[serde(skip)]
+        context: &'a AnswerContext,
+    },
+    /// Error of the query or command on an input line, written by the server, which
+    /// answers every line, where a run of `query` stops or skips it
+    Error {
+        line: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<&'a str>,
+        error: &'a str,
+    },
+    /// Answer to a query that succeeded when retried, by the line it was on
+    Retry {
+        line: usize,
+        result: &'a str,
+        #[serde(rename = "final")]
+        is_final: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        latest_end_ms: Option<i64>,
+        #[serde(skip)]
+        context: &'a AnswerContext,
+    },
+}
+
+/// What a `--format` template shows of an answered query besides its result
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AnswerContext {
+    /// The query as given, without its protocol id
+    pub query: String,
+    /// Code of the query's type, see `QueryKind::code`
+    pub kind: &'static str,
+    /// Start of the query's first window and end of its last, in Unix seconds
+    pub start: i64,
+    pub end: i64,
+    /// Number of hours the query found in the cache
+    pub cache_hits: usize,
+    /// Time the query took, from start to answer
+    pub duration_ms: u64,
+}
+
+/// A field of an answer a template shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    Query,
+    Type,
+    Start,
+    End,
+    Result,
+    CacheHits,
+    DurationMs,
+}
+
+impl Placeholder {
+    /// Each placeholder under its name in templates
+    const NAMES: [(Placeholder, &'static str); 7] = [
+        (Placeholder::Query, "query"),
+        (Placeholder::Type, "type"),
+        (Placeholder::Start, "start"),
+        (Placeholder::End, "end"),
+        (Placeholder::Result, "result"),
+        (Placeholder::CacheHits, "cache_hits"),
+        (Placeholder::DurationMs, "duration_ms"),
+    ];
+}
+
+/// A piece of a template: text written as is, or a placeholder replaced by its field
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Field(Placeholder),
+}
+
+/// Shape of the text answers to queries, given with `--format`, like "{type},{start},{end},{result}".
+/// Placeholders in braces are replaced by the fields of the answer (see `Placeholder::NAMES`),
+/// "{{" and "}}" stand for literal braces, and everything else is written as is.
+/// Parsed once at startup, so an invalid template fails before any query is read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template(Vec<Segment>);
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let (name, rest) = chars
+                        .as_str()
+                        .split_once('}')
+                        .ok_or_else(|| anyhow!("Unclosed {{ (write {{{{ for a literal brace)"))?;
+                    let placeholder = Placeholder::NAMES
+                        .iter()
+                        .find(|(_, known)| *known == name)
+                        .map(|&(placeholder, _)| placeholder)
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "Unknown placeholder {{{}}} (expected one of {})",
+                                name,
+                                Placeholder::NAMES
+                                    .iter()
+                                    .map(|(_, name)| format!("{{{}}}", name))
+                                    .collect::<Vec<String>>()
+                                    .join(", ")
+                            )
+                        })?;
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Field(placeholder));
+                    chars = rest.chars();
+                }
+                '}' => return Err(anyhow!("Unmatched }} (write }}}} for a literal brace)")),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        Ok(Template(segments))
+    }
+}
+
+impl Template {
+    /// Returns the template filled with the result and the context of a query's answer
+    pub fn render(&self, result: &str, context: &AnswerContext) -> String {
+        self.0
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.clone(),
+                Segment::Field(Placeholder::Query) => context.query.clone(),
+                Segment::Field(Placeholder::Type) => context.kind.to_string(),
+                Segment::Field(Placeholder::Start) => context.start.to_string(),
+                Segment::Field(Placeholder::End) => context.end.to_string(),
+                Segment::Field(Placeholder::Result) => result.to_string(),
+                Segment::Field(Placeholder::CacheHits) => context.cache_hits.to_string(),
+                Segment::Field(Placeholder::DurationMs) => context.duration_ms.to_string(),
+            })
+            .collect()
+    }
+}
+
+/// Row of the CSV answers, see `CSV_COLUMNS`
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    kind: &'static str,
+    line: Option<usize>,
+    id: Option<String>,
+    eval_time: Option<i64>,
+    result: &'a str,
+    is_final: Option<bool>,
+    latest_end_ms: Option<i64>,
+}
+
+impl<'a> From<Answer<'a>> for CsvRow<'a> {
+    fn from(answer: Answer<'a>) -> Self {
+        match answer {
+            Answer::Answer {
+                line,
+                id,
+                result,
+                is_final,
+                latest_end_ms,
+                ..
+            } => CsvRow {
+                kind: "answer",
+                line: Some(line),
+                id: id.map(str::to_string),
+                eval_time: None,
+                result,
+                is_final,
+                latest_end_ms,
+            },
+            Answer::Sub {
+                id,
+                eval_time,
+                result,
+                is_final,
+                latest_end_ms,
+                ..
+            } => CsvRow {
+                kind: "sub",
+                line: None,
+                id: Some(id.to_string()),
+                eval_time: Some(eval_time),
+                result,
+                is_final: Some(is_final),
+                latest_end_ms,
+            },
+            Answer::Error { line, id, error } => CsvRow {
+                kind: "error",
+                line: Some(line),
+                id: id.map(str::to_string),
+                eval_time: None,
+                result: error,
+                is_final: None,
+                latest_end_ms: None,
+            },
+            Answer::Retry {
+                line,
+                result,
+                is_final,
+                latest_end_ms,
+                ..
+            } => CsvRow {
+                kind: "retry",
+                line: Some(line),
+                id: None,
+                eval_time: None,
+                result,
+                is_final: Some(is_final),
+                latest_end_ms,
+            },
+        }
+    }
+}
+
+/// Writes answers in the configured format
+pub struct AnswerWriter<'a, W: Write> {
+    writer: &'a mut W,
+    format: OutputFormat,
+    /// Shape of the text answers to queries; `None` writes the bare results
+    template: Option<&'a Template>,
+    /// Whether the CSV header row was written
+    header_written: bool,
+}
+
+impl<'a, W: Write> AnswerWriter<'a, W> {
+    pub fn new(writer: &'a mut W, format: OutputFormat, template: Option<&'a Template>) -> Self {
+        AnswerWriter {
+            writer,
+            format,
+            template,
+            header_written: false,
+        }
+    }
+
+    /// Returns the text of a query's result, shaped by the template when there is one
+    fn text(&self, result: &str, context: Option<&AnswerContext>) -> String {
+        match (self.template, context) {
+            (Some(template), Some(context)) => template.render(result, context),
+            _ => result.to_string(),
+        }
+    }
+
+    pub fn write(&mut self, answer: Answer) -> io::Result<()> {
+        match self.format {
+            OutputFormat::Text => match answer {
+                Answer::Answer {
+                    id: Some(id),
+                    result,
+                    context,
+                    ..
+                } => writeln!(self.writer, "{}: {}", id, self.text(result, context)),
+                Answer::Answer {
+                    result, context, ..
+                } => writeln!(self.writer, "{}", self.text(result, context)),
+                Answer::Sub {
+                    id,
+                    eval_time,
+                    result,
+                    context,
+                    ..
+                } => writeln!(
+                    self.writer,
+                    "SUB {} {} {}",
+                    id,
+                    eval_time,
+                    self.text(result, Some(context))
+                ),
+                Answer::Error {
+                    id: Some(id),
+                    error,
+                    ..
+                } => writeln!(self.writer, "{}: ERROR {}", id, error),
+                Answer::Error { error, .. } => writeln!(self.writer, "ERROR {}", error),
+                Answer::Retry {
+                    line,
+                    result,
+                    context,
+                    ..
+                } => {
+                    writeln!(
+                        self.writer,
+                        "RETRY {} {}",
+                        line,
+                        self.text(result, Some(context))
+                    )
+                }
+            },
+            OutputFormat::Msgpack => {
+                let frame = rmp_serde::to_vec_named(&answer)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                self.writer.write_all(&(frame.len() as u32).to_be_bytes())?;
+                self.writer.write_all(&frame)
+            }
+            OutputFormat::Json => {
+                serde_json::to_writer(&mut *self.writer, &answer)?;
+                writeln!(self.writer)
+            }
+            OutputFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(Vec::new());
+                if !self.header_written {
+                    writer.write_record(CSV_COLUMNS)?;
+                    self.header_written = true;
+                }
+                writer.serialize(CsvRow::from(answer))?;
+                let rows = writer
+                    .into_inner()
+                    .map_err(|e| io::Error::other(e.to_string()))?;
+                self.writer.write_all(&rows)
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> AnswerContext {
+        AnswerContext {
+            query: "C [) 1700816400 1700820000".to_string(),
+            kind: "C",
+            start: 1700816400,
+            end: 1700820000,
+            cache_hits: 1,
+            duration_ms: 3,
+        }
+    }
+
+    fn render(template: &str) -> String {
+        template
+            .parse::<Template>()
+            .unwrap()
+            .render("5", &context())
+    }
+
+    #[test]
+    fn templates_render_each_placeholder() {
+        assert_eq!(
+            render("{type},{start},{end},{result}"),
+            "C,1700816400,1700820000,5"
+        );
+        assert_eq!(render("result={result}"), "result=5");
+        assert_eq!(
+            render("{query}|{cache_hits}|{duration_ms}"),
+            "C [) 1700816400 1700820000|1|3"
+        );
+        assert_eq!(render("{{{type}}} }}{{"), "{C} }{");
+        assert_eq!(render("plain"), "plain");
+        assert_eq!(render(""), "");
+    }
+
+    /// Writes an answer to a query, one to a command, a standing query's and a retried one's
+    fn write_all(format: OutputFormat) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut writer = AnswerWriter::new(&mut output, format, None);
+        let context = context();
+        writer
+            .write(Answer::Answer {
+                line: 1,
+                id: Some("q1"),
+                result: "5",
+                is_final: Some(false),
+                latest_end_ms: Some(1700817000123),
+                context: Some(&context),
+            })
+            .unwrap();
+        writer
+            .write(Answer::Answer {
+                line: 2,
+                id: None,
+                result: "1700816400,3\n1700820000,4",
+                is_final: None,
+                latest_end_ms: None,
+                context: None,
+            })
+            .unwrap();
+        writer
+            .write(Answer::Sub {
+                id: 1,
+                eval_time: 1700820000,
+                result: "6",
+                is_final: true,
+                latest_end_ms: None,
+                context: &context,
+            })
+            .unwrap();
+        writer
+            .write(Answer::Retry {
+                line: 3,
+                result: "7",
+                is_final: true,
+                latest_end_ms: None,
+                context: &context,
+            })
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn json_answers_carry_the_fields_of_msgpack_maps() {
+        assert_eq!(
+            String::from_utf8(write_all(OutputFormat::Json)).unwrap(),
+            r#"{"type":"answer","line":1,"id":"q1","result":"5","final":false,"latest_end_ms":1700817000123}
+{"type":"answer","line":2,"result":"1700816400,3\n1700820000,4"}
+{"type":"sub","id":1,"eval_time":1700820000,"result":"6","final":true}
+{"type":"retry","line":3,"result":"7","final":true}
+"#
+        );
+    }
+
+    #[test]
+    fn msgpack_frames_decode_to_the_json_answers() {
+        let output = write_all(OutputFormat::Msgpack);
+        let mut frames = Vec::new();
+        let mut rest = &output[..];
+        while !rest.is_empty() {
+            let (len, tail) = rest.split_at(4);
+            let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+            let (frame, tail) = tail.split_at(len);
+            frames.push(rmp_serde::from_slice::<serde_json::Value>(frame).unwrap());
+            rest = tail;
+        }
+        let json = String::from_utf8(write_all(OutputFormat::Json)).unwrap();
+        let answers = json
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<serde_json::Value>>();
+        assert_eq!(frames, answers);
+        assert_eq!(frames.len(), 4);
+    }
+
+    #[test]
+    fn csv_answers_have_one_header_and_a_column_per_field() {
+        assert_eq!(
+            String::from_utf8(write_all(OutputFormat::Csv)).unwrap(),
+            "type,line,id,eval_time,result,final,latest_end_ms\n\
+             answer,1,q1,,5,false,1700817000123\n\
+             answer,2,,,\"1700816400,3\n1700820000,4\",,\n\
+             sub,,1,1700820000,6,true,\n\
+             retry,3,,,7,true,\n"
+        );
+    }
+
+    #[test]
+    fn invalid_templates_fail_to_parse() {
+        for (template, error) in [
+            ("{nope}", "Unknown placeholder {nope}"),
+            ("{result", "Unclosed {"),
+            ("result}", "Unmatched }"),
+        ] {
+            let message = template.parse::<Template>().unwrap_err().to_string();
+            assert!(message.starts_with(error), "{}: {}", template, message);
+        }
+    }
+}
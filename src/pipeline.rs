@@ -0,0 +1,826 @@
+use anyhow::Context;
+use clap::ValueEnum;
+use flate2::read::MultiGzDecoder;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::cli::{InputCompression, QueryArgs};
+use crate::clock::{Instant, SystemClock};
+use crate::config::{Config, ConfigArgs};
+use crate::output::{Answer, AnswerWriter, OutputFormat};
+use crate::pool::{Priority, Reply, WorkerPool, DEFAULT_BATCH_AGING};
+use crate::processor::Processor;
+use crate::retry::RetryQueue;
+use crate::source::FillSource;
+use crate::stats::StatsReporter;
+use crate::subscription::Subscriptions;
+use crate::summary::Summary;
+use crate::{dry_run, logging, protocol, source, stats, subscription};
+
+/// Entry point of the `query` command
+pub fn main(args: QueryArgs) -> anyhow::Result<()> {
+    let config = Config::from(args.config.clone());
+    logging::init(config.log_format);
+    check_output_flags(&config)?;
+    let input = open_input(args.input.as_deref(), args.input_compression)?;
+    if args.workers > 1 && !args.dry_run {
+        info!("Starting query processing with {} workers...", args.workers);
+        // The answers are drained as they come, so the queue holds a few per worker at most
+        let pool = start_pool(
+            args.config,
+            args.workers as usize,
+            DEFAULT_BATCH_AGING,
+            usize::MAX,
+        );
+        let result = run_pooled(&pool, &config, input, &mut io::stdout().lock());
+        pool.shutdown();
+        return result;
+    }
+    let source = build_source(&config);
+    let mut processor = Processor::with_source(config, source, Box::new(SystemClock));
+
+    if args.dry_run {
+        return dry_run::run(&processor, input, &mut io::stdout().lock(), args.list_hours);
+    }
+
+    info!("Starting query processing...");
+
+    let run_start = Instant::now();
+    let result = run_queries(&mut processor, input, &mut io::stdout().lock());
+    // Also after a failed run, so a rerun finds the hours it fetched
+    processor.snapshot();
+    if let Some(path) = &args.summary_json {
+        let exit_code = if result.is_ok() { 0 } else { 1 };
+        Summary::new(&processor, run_start.elapsed(), exit_code).write(path)?;
+    }
+    result?;
+
+    stats::log_run_stats(&processor);
+
+    Ok(())
+}
+
+/// Opens the query input: the file, or stdin without one, decompressed as given or, by
+/// default, as gzip when the file ends in .gz
+pub fn open_input(
+    path: Option<&Path>,
+    compression: Option<InputCompression>,
+) -> anyhow::Result<Box<dyn BufRead + Send>> {
+    let reader: Box<dyn Read + Send> = match path {
+        Some(path) => Box::new(
+            File::open(path)
+                .with_context(|| format!("Failed to open query file {}", path.display()))?,
+        ),
+        None => Box::new(io::stdin()),
+    };
+    let compression = compression.unwrap_or_else(|| match path.and_then(Path::extension) {
+        Some(extension) if extension == "gz" => InputCompression::Gzip,
+        _ => InputCompression::None,
+    });
+    Ok(match compression {
+        InputCompression::None => Box::new(BufReader::new(reader)),
+        InputCompression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+    })
+}
+
+/// Starts `workers` workers with processors configured by the flags, see `Config::for_worker`,
+/// and `WorkerPool::start` for `batch_aging` and `max_depth`
+pub fn start_pool(
+    args: ConfigArgs,
+    workers: usize,
+    batch_aging: Duration,
+    max_depth: usize,
+) -> WorkerPool {
+    let factory = move |worker: usize| {
+        let config = Config::from(args.clone()).for_worker(worker);
+        let source = build_source(&config);
+        Processor::with_source(config, source, Box::new(SystemClock))
+    };
+    WorkerPool::start(workers, batch_aging, max_depth, Arc::new(factory))
+}
+
+/// Fails on output flags that don't go together
+pub fn check_output_flags(config: &Config) -> anyhow::Result<()> {
+    if config.output_template.is_some() && config.output_format != OutputFormat::Text {
+        return Err(anyhow::anyhow!(
+            "--format only shapes text answers, not --output {}",
+            config
+                .output_format
+                .to_possible_value()
+                .map_or_else(String::new, |value| value.get_name().to_string())
+        ));
+    }
+    Ok(())
+}
+
+/// Creates the configured fill source
+pub fn build_source(config: &Config) -> Box<dyn FillSource> {
+    #[cfg(feature = "mock-source")]
+    if let Some(mock_config) = config.mock.clone() {
+        return Box::new(crate::mock::MockSource::new(mock_config));
+    }
+    config
+        .source
+        .build(config.lenient_parse, config.strict_schema)
+}
+
+/// Number of input lines read ahead of the query being processed
+const INPUT_BUFFER_LINES: usize = 1024;
+
+/// Processes every query in the input, writing one result line per query to the output.
+/// Standing queries registered with "SUB" are answered again on their schedule until
+/// they are cancelled with "UNSUB" or the input ends; see `subscription::Command`.
+/// With `--retry-failed`, queries failed by an unavailable upstream are attempted again
+/// after the configured delay, even once the input has ended, and their answers are
+/// written as "RETRY LINE RESULT" with the line number of the query in the input.
+/// With `--lookahead`, the hours of the queries read ahead are fetched in the background
+/// while earlier queries are answered; answers are still written in input order.
+pub fn run_queries(
+    processor: &mut Processor,
+    input: impl BufRead + Send + 'static,
+    output: &mut impl Write,
+) -> anyhow::Result<()> {
+    let mut stats_reporter = StatsReporter::new(processor.config());
+    let mut subscriptions = Subscriptions::default();
+    let template = processor.config().output_template.clone();
+    let mut output = AnswerWriter::new(output, processor.config().output_format, template.as_ref());
+    let mut retries = processor.config().retry_failed.map(RetryQueue::new);
+
+    // Lines are read on their own thread, so standing queries come due while input is idle
+    let (sender, receiver) = mpsc::sync_channel(INPUT_BUFFER_LINES);
+    thread::spawn(move || {
+        for line in input.lines() {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut input_open = true;
+    let mut line_number = 0;
+    let mut query_lines = 0;
+    let mut version = protocol::DEFAULT_VERSION;
+    let mut ahead = VecDeque::new();
+    loop {
+        if let Some(lookahead) = processor.config().lookahead {
+            while ahead.len() < lookahead {
+                let Ok(line) = receiver.try_recv() else {
+                    break;
+                };
+                if let Some(query) = line.as_deref().ok().and_then(query_text) {
+                    processor.prefetch(protocol::split_id(query).map_or(query, |(_, rest)| rest));
+                }
+                ahead.push_back(line);
+            }
+        }
+        let retry_due = retries.as_ref().and_then(RetryQueue::next_due);
+        let line = if let Some(line) = ahead.pop_front() {
+            Ok(line)
+        } else if input_open {
+            match subscriptions.next_due().into_iter().chain(retry_due).min() {
+                Some(due) => receiver.recv_timeout(due.saturating_duration_since(Instant::now())),
+                None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            }
+        } else {
+            // Once the input ended, only the retries are left to wait for
+            match retry_due {
+                Some(due) => {
+                    thread::sleep(due.saturating_duration_since(Instant::now()));
+                    Err(RecvTimeoutError::Timeout)
+                }
+                None => break,
+            }
+        };
+        let line = match line {
+            Ok(line) => line
+                .with_context(|| format!("Failed to read line {} of the input", line_number + 1))?,
+            Err(RecvTimeoutError::Timeout) => {
+                if input_open {
+                    for (id, query) in subscriptions.take_due(Instant::now()) {
+                        let result = processor.process_query(query);
+                        emit_subscription(processor, &mut output, id, result)?;
+                        stats_reporter.record_query(processor);
+                    }
+                }
+                if let Some(retries) = &mut retries {
+                    run_retries(processor, retries, &mut output, &mut stats_reporter)?;
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                input_open = false;
+                continue;
+            }
+        };
+        line_number += 1;
+        let Some(mut query) = query_text(&line) else {
+            continue;
+        };
+        query_lines += 1;
+
+        let mut id = None;
+        let mut plain_query = false;
+        let result = match (
+            protocol::parse_version(query),
+            protocol::take_id(query, version),
+        ) {
+            (Some(_), _) if query_lines > 1 => Err(anyhow::anyhow!(
+                "VERSION must be the first query line of the input: {}",
+                query
+            )),
+            (Some(requested), _) => requested.map(|requested| {
+                version = requested;
+                format!("VERSION {}", version)
+            }),
+            (None, Err(e)) => Err(e),
+            (None, Ok((line_id, rest))) => {
+                (id, query) = (line_id, rest);
+                let word = query.split_whitespace().next().unwrap_or_default();
+                match subscription::Command::parse(query) {
+                    None if word.eq_ignore_ascii_case("DQ") => processor.data_quality(query),
+                    None if word.eq_ignore_ascii_case("STATS") => processor.stats(query),
+                    None if word.eq_ignore_ascii_case("CS") || word.eq_ignore_ascii_case("VS") => {
+                        processor.sequence_query(query)
+                    }
+                    None if word.eq_ignore_ascii_case("EVICT_BEFORE") => {
+                        processor.evict_before(query)
+                    }
+                    None if word.eq_ignore_ascii_case("EXPORT_PARQUET") => {
+                        processor.export_parquet(query)
+                    }
+                    None => {
+                        plain_query = true;
+                        processor.process_query(query.to_string())
+                    }
+                    Some(Err(e)) => Err(e),
+                    Some(Ok(subscription::Command::Subscribe { interval, query })) => {
+                        // Answered once right away, so a query that can't be answered isn't registered
+                        match processor.process_query(query.to_string()) {
+                            Ok(result) => {
+                                let id = subscriptions.add(query, interval, Instant::now());
+                                emit_subscription(processor, &mut output, id, Ok(result))?;
+                                stats_reporter.record_query(processor);
+                                continue;
+                            }
+                            Err(e) => Err(e),
+                        }
+                    }
+                    Some(Ok(subscription::Command::Unsubscribe(id))) => {
+                        subscriptions.remove(id).map(|()| format!("UNSUB {}", id))
+                    }
+                }
+            }
+        };
+        match result {
+            Ok(result) => {
+                let output_start =
+                    (plain_query && processor.profile().is_some()).then(Instant::now);
+                output.write(Answer::Answer {
+                    line: line_number,
+                    id,
+                    result: &result,
+                    is_final: plain_query.then(|| processor.last_answer_final()),
+                    latest_end_ms: processor.last_latest_end().filter(|_| plain_query),
+                    context: plain_query.then(|| processor.last_context()),
+                })?;
+                if let Some(output_start) = output_start {
+                    processor.record_output(output_start.elapsed());
+                }
+            }
+            Err(e)
+                if plain_query
+                    && source::is_retryable(&e)
+                    && retries.as_mut().is_some_and(|retries| {
+                        retries.push(line_number, query, Instant::now())
+                    }) =>
+            {
+                warn!("Query on line {} will be retried: {:#}", line_number, e);
+            }
+            Err(e) if processor.config().keep_going => {
+                error!("Skipping failed query on line {}: {:#}", line_number, e);
+                processor.record_failure(line_number, query, format!("{:#}", e));
+                continue;
+            }
+            Err(e) => {
+                processor.record_failure(line_number, query, format!("{:#}", e));
+                return Err(e.context(format!("Query on line {} failed", line_number)));
+            }
+        }
+        stats_reporter.record_query(processor);
+    }
+
+    if let Some(retries) = retries {
+        info!(
+            retried_queries_recovered = retries.recovered,
+            retried_queries_failed = retries.failures().len(),
+            "Retried queries: {} answered, {} failed permanently",
+            retries.recovered,
+            retries.failures().len()
+        );
+        for failure in retries.failures() {
+            error!(
+                "Query on line {} failed permanently after {} attempts: {}: {}",
+                failure.line, failure.attempts, failure.query, failure.error
+            );
+            processor.record_failure(
+                failure.line,
+                &failure.query,
+                format!("{} (after {} attempts)", failure.error, failure.attempts),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Answers the queries of one worker's pending at most, per worker, before the input is
+/// read on
+const PENDING_PER_WORKER: usize = 16;
+
+/// Processes every query in the input like `run_queries`, with the queries answered by the
+/// workers of the pool and the answers written in input order. Commands acting on one
+/// processor's cache or schedule, such as SUB, DQ or EVICT_BEFORE, fail the line, as do
+/// the pool's queries under `--keep-going` rules.
+pub fn run_pooled(
+    pool: &WorkerPool,
+    config: &Config,
+    input: impl BufRead,
+    output: &mut impl Write,
+) -> anyhow::Result<()> {
+    let mut output = AnswerWriter::new(
+        output,
+        config.output_format,
+        config.output_template.as_ref(),
+    );
+    let mut pending: VecDeque<(usize, Option<String>, Receiver<Reply>)> = VecDeque::new();
+    let mut version = protocol::DEFAULT_VERSION;
+    let mut query_lines = 0;
+    let max_pending = pool.workers() * PENDING_PER_WORKER;
+
+    for (index, line) in input.lines().enumerate() {
+        let line_number = index + 1;
+        let line =
+            line.with_context(|| format!("Failed to read line {} of the input", line_number))?;
+        let Some(query) = query_text(&line) else {
+            continue;
+        };
+        query_lines += 1;
+        match pooled_line(query, &mut version, query_lines) {
+            Ok(PooledLine::Query { id, query }) => {
+                pending.push_back((line_number, id, pool.submit(query, Priority::Batch)?));
+            }
+            Ok(PooledLine::Version(version)) => {
+                drain_pending(&mut pending, 0, config, &mut output)?;
+                output.write(Answer::Answer {
+                    line: line_number,
+                    id: None,
+                    result: &format!("VERSION {}", version),
+                    is_final: None,
+                    latest_end_ms: None,
+                    context: None,
+                })?;
+            }
+            Err(e) => {
+                drain_pending(&mut pending, 0, config, &mut output)?;
+                fail_line(config, line_number, e)?;
+            }
+        }
+        drain_pending(&mut pending, max_pending, config, &mut output)?;
+    }
+    drain_pending(&mut pending, 0, config, &mut output)
+}
+
+/// A query line of an input answered by a pool's workers
+pub enum PooledLine {
+    /// A query for the workers, with the id the line gave it under protocol version 2
+    Query { id: Option<String>, query: String },
+    /// A VERSION line, with the version the rest of the input is held to
+    Version(u32),
+}
+
+/// Tells the query on the `query_lines`th query line of an input answered by a pool's
+/// workers, under the protocol version negotiated so far, which a VERSION line updates.
+/// Fails on lines a pool doesn't take, see `SINGLE_WORKER_COMMANDS`.
+pub fn pooled_line(
+    query: &str,
+    version: &mut u32,
+    query_lines: usize,
+) -> anyhow::Result<PooledLine> {
+    match (
+        protocol::parse_version(query),
+        protocol::take_id(query, *version),
+    ) {
+        (Some(_), _) if query_lines > 1 => Err(anyhow::anyhow!(
+            "VERSION must be the first query line of the input: {}",
+            query
+        )),
+        (Some(requested), _) => requested.map(|requested| {
+            *version = requested;
+            PooledLine::Version(requested)
+        }),
+        (None, Err(e)) => Err(e),
+        (None, Ok((id, query))) => {
+            let word = query.split_whitespace().next().unwrap_or_default();
+            if SINGLE_WORKER_COMMANDS
+                .iter()
+                .any(|command| word.eq_ignore_ascii_case(command))
+            {
+                Err(anyhow::anyhow!(
+                    "{} acts on one cache and needs a single worker (--workers 1): {}",
+                    word.to_ascii_uppercase(),
+                    query
+                ))
+            } else {
+                Ok(PooledLine::Query {
+                    id: id.map(str::to_string),
+                    query: query.to_string(),
+                })
+            }
+        }
+    }
+}
+
+/// Commands `run_pooled` doesn't take, as they act on the cache or schedule of one processor
+const SINGLE_WORKER_COMMANDS: [&str; 8] = [
+    "SUB",
+    "UNSUB",
+    "DQ",
+    "STATS",
+    "CS",
+    "VS",
+    "EVICT_BEFORE",
+    "EXPORT_PARQUET",
+];
+
+/// Writes the answers of the pending queries in input order until at most `keep` are left
+/// pending, waiting for those not answered yet, and without waiting for those past `keep`
+/// once the first is unanswered
+fn drain_pending(
+    pending: &mut VecDeque<(usize, Option<String>, Receiver<Reply>)>,
+    keep: usize,
+    config: &Config,
+    output: &mut AnswerWriter<impl Write>,
+) -> anyhow::Result<()> {
+    while let Some((_, _, receiver)) = pending.front() {
+        let reply = if pending.len() > keep {
+            receiver.recv().ok()
+        } else {
+            match receiver.try_recv() {
+                Ok(reply) => Some(reply),
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => None,
+            }
+        };
+        let (line, id, _) = pending.pop_front().unwrap();
+        let reply = reply.ok_or_else(|| {
+            anyhow::anyhow!("The worker answering the query on line {} stopped", line)
+        })?;
+        match reply.result {
+            Ok(result) => output.write(Answer::Answer {
+                line,
+                id: id.as_deref(),
+                result: &result,
+                is_final: Some(reply.is_final),
+                latest_end_ms: reply.latest_end_ms,
+                context: Some(&reply.context),
+            })?,
+            Err(e) => fail_line(config, line, e)?,
+        }
+    }
+    Ok(())
+}
+
+/// Logs a failed line and skips it under `--keep-going`, or fails the run
+fn fail_line(config: &Config, line: usize, e: anyhow::Error) -> anyhow::Result<()> {
+    if config.keep_going {
+        error!("Skipping failed query on line {}: {:#}", line, e);
+        Ok(())
+    } else {
+        Err(e.context(format!("Query on line {} failed", line)))
+    }
+}
+
+/// Attempts the queries due for a retry, writing the answers of those that succeed
+/// as "RETRY LINE RESULT" and scheduling those that fail again
+fn run_retries(
+    processor: &mut Processor,
+    retries: &mut RetryQueue,
+    output: &mut AnswerWriter<impl Write>,
+    stats_reporter: &mut StatsReporter,
+) -> anyhow::Result<()> {
+    for retry in retries.take_due(Instant::now()) {
+        match processor.process_query(retry.query.clone()) {
+            Ok(result) => {
+                output.write(Answer::Retry {
+                    line: retry.line,
+                    result: &result,
+                    is_final: processor.last_answer_final(),
+                    latest_end_ms: processor.last_latest_end(),
+                    context: processor.last_context(),
+                })?;
+                output.flush()?;
+                retries.recovered += 1;
+            }
+            Err(e) => {
+                let retryable = source::is_retryable(&e);
+                retries.fail(retry, retryable, format!("{:#}", e), Instant::now());
+            }
+        }
+        stats_reporter.record_query(processor);
+    }
+    Ok(())
+}
+
+/// Writes an answer of a standing query as "SUB ID EVAL_TIME RESULT", with the evaluation
+/// time in Unix seconds. A failed evaluation is logged and the subscription carries on,
+/// since a later evaluation may succeed.
+fn emit_subscription(
+    processor: &Processor,
+    output: &mut AnswerWriter<impl Write>,
+    id: u64,
+    result: anyhow::Result<String>,
+) -> anyhow::Result<()> {
+    match result {
+        Ok(result) => {
+            output.write(Answer::Sub {
+                id,
+                eval_time: processor.now().timestamp(),
+                result: &result,
+                is_final: processor.last_answer_final(),
+                latest_end_ms: processor.last_latest_end(),
+                context: processor.last_context(),
+            })?;
+            output.flush()?;
+        }
+        Err(e) => error!("Subscription {} failed to evaluate: {:#}", id, e),
+    }
+    Ok(())
+}
+
+/// Returns the query on an input line, without any inline " #" comment and surrounding whitespace.
+/// Blank lines and lines starting with '#' hold no query.
+pub fn query_text(line: &str) -> Option<&str> {
+    let comment_start = line
+        .match_indices('#')
+        .find(|&(i, _)| line[..i].ends_with(char::is_whitespace))
+        .map_or(line.len(), |(i, _)| i);
+    let query = line[..comment_start].trim();
+
+    if query.is_empty() || query.starts_with('#') {
+        None
+    } else {
+        Some(query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::RetryConfig;
+    use crate::testing::{
+        capture_logs, fill, FlakySource, MemorySource, PacedInput, SlowSource, TestClock, HOUR,
+    };
+    use std::io::Cursor;
+
+    /// Runs the queries of the input through a processor fetching from the source, with the
+    /// clock a day past `HOUR`, and returns the processor and what the run wrote
+    fn run(
+        config: Config,
+        source: impl FillSource + 'static,
+        input: impl Read + Send + 'static,
+    ) -> (Processor, String) {
+        let clock = Box::new(TestClock::at(HOUR + 86400));
+        let mut processor = Processor::with_source(config, Box::new(source), clock);
+        let mut output = Vec::new();
+        run_queries(&mut processor, BufReader::new(input), &mut output).unwrap();
+        (processor, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn query_past_its_deadline_times_out_and_the_next_one_hits_its_hours() {
+        let config = Config {
+            query_timeout: Some(Duration::from_millis(100)),
+            ..Config::default()
+        };
+        let fills = MemorySource::new(vec![
+            fill(HOUR + 60, 1, 1, "10", "1"),
+            fill(HOUR + 120, 2, 2, "11", "2"),
+        ]);
+        let source = SlowSource {
+            latency: Duration::from_millis(300),
+            source: fills.clone(),
+        };
+        let query = format!("C {} {}", HOUR, HOUR + 1800);
+        // Asked again once the fetch the first query left behind has finished
+        let input = PacedInput::new(&[(0, &query), (600, &query)]);
+        let (processor, output) = run(config, source, input);
+        assert_eq!(output, "TIMEOUT\n2\n");
+        assert_eq!(fills.requests(), 1);
+        let metrics = processor.metrics();
+        assert_eq!(metrics.timed_out_queries, 1);
+        assert_eq!((metrics.cache_hits, metrics.api_calls), (1, 1));
+    }
+
+    #[test]
+    fn subscriptions_are_answered_again_until_unsubscribed() {
+        let source = MemorySource::new(vec![fill(HOUR + 60, 1, 1, "10", "1")]);
+        let query = format!("C {} {}", HOUR, HOUR + 1800);
+        let subscribe = format!("SUB 1 {}", query);
+        // Answered right away and about a second and two seconds later, then cancelled
+        // half a second before it is due again, and silent for the next two seconds
+        let input = PacedInput::new(&[(0, &subscribe), (2500, "UNSUB 1"), (2000, &query)]);
+        let (_, output) = run(Config::default(), source.clone(), input);
+        let sub = format!("SUB 1 {} 1", HOUR + 86400);
+        assert_eq!(
+            output.lines().collect::<Vec<&str>>(),
+            [&sub, &sub, &sub, "UNSUB 1", "1"]
+        );
+        // The answers after the first came from the cache
+        assert_eq!(source.requests(), 1);
+    }
+
+    #[test]
+    fn queries_failed_by_an_unavailable_upstream_are_answered_once_it_recovers() {
+        let config = Config {
+            retry_failed: Some(RetryConfig {
+                delay: Duration::from_millis(50),
+                rounds: 3,
+                capacity: 10,
+            }),
+            ..Config::default()
+        };
+        let fills = MemorySource::new(vec![
+            fill(HOUR + 60, 1, 1, "10", "1"),
+            fill(HOUR + 120, 2, 2, "11", "2"),
+            fill(HOUR + 3600 + 60, 3, 1, "12", "1"),
+        ]);
+        // Down for the first two requests
+        let source = FlakySource {
+            failures: 2,
+            source: fills.clone(),
+        };
+        let input = Cursor::new(format!(
+            "C {} {}\nV {} {}\nB {} {}\n",
+            HOUR,
+            HOUR + 1800,
+            HOUR + 3600,
+            HOUR + 5400,
+            HOUR,
+            HOUR + 1800
+        ));
+        let (processor, output) = run(config, source, input);
+        // The third query found the upstream back; the first two are retried after it
+        assert_eq!(output, "1\nRETRY 1 2\nRETRY 2 12\n");
+        assert_eq!(fills.requests(), 2);
+        assert_eq!(processor.metrics().failed, 2);
+    }
+
+    #[test]
+    fn fetching_from_a_slow_source_dominates_the_profile() {
+        let config = Config {
+            profile: true,
+            ..Config::default()
+        };
+        let source = SlowSource {
+            latency: Duration::from_millis(30),
+            source: MemorySource::new(vec![
+                fill(HOUR + 60, 1, 1, "10", "1"),
+                fill(HOUR + 3600 + 60, 2, 1, "12", "1"),
+            ]),
+        };
+        let input = Cursor::new(format!(
+            "C {} {}\nC {} {}\n",
+            HOUR,
+            HOUR + 7200,
+            HOUR,
+            HOUR + 7200
+        ));
+        let (processor, output) = run(config, source, input);
+        assert_eq!(output, "2\n2\n");
+        let table = processor.profile().unwrap().table();
+        assert!(table.contains("over 2 queries"), "{}", table);
+        let shares = table
+            .lines()
+            .skip(3)
+            .map(|row| {
+                let cells = row.split_whitespace().collect::<Vec<&str>>();
+                let share = cells[2].trim_end_matches('%').parse::<f64>().unwrap();
+                (cells[0], share)
+            })
+            .collect::<Vec<(&str, f64)>>();
+        assert_eq!(shares.len(), 5, "{}", table);
+        let (phase, share) = shares
+            .iter()
+            .copied()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        // Two 30ms fetches, against microseconds of everything else
+        assert_eq!(phase, "fetch", "{}", table);
+        assert!(share > 90.0, "{}", table);
+    }
+
+    #[test]
+    fn statistics_are_logged_every_n_queries() {
+        let config = Config {
+            stats_every_queries: Some(2),
+            ..Config::default()
+        };
+        let source = MemorySource::new(vec![fill(HOUR + 60, 1, 1, "10", "1")]);
+        let input = Cursor::new(format!("C {} {}\n", HOUR, HOUR + 1800).repeat(5));
+        let ((_, output), logs) = capture_logs(|| run(config, source, input));
+        assert_eq!(output, "1\n".repeat(5));
+        let dumps = logs
+            .lines()
+            .filter(|line| line.contains("Periodic statistics"))
+            .collect::<Vec<&str>>();
+        // After the second and fourth queries; the fifth ends the run before the sixth
+        assert_eq!(dumps.len(), 2, "{}", logs);
+        assert!(
+            dumps[0]
+                .contains("after 2 queries: last 2 queries hit rate 50.00% (1 hits, 1 API calls)"),
+            "{}",
+            dumps[0]
+        );
+        assert!(
+            dumps[1].contains("after 4 queries: last 2 queries hit rate 100.00% (2 hits, 0 API calls); cumulative hit rate 75.00% (3 hits, 1 API calls); 1 hours cached"),
+            "{}",
+            dumps[1]
+        );
+    }
+
+    /// Output that takes its time over every answer line, like the aggregation of a heavy
+    /// query
+    struct SlowOutput {
+        latency: Duration,
+        written: Vec<u8>,
+    }
+
+    impl Write for SlowOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            for _ in buf.iter().filter(|&&byte| byte == b'\n') {
+                thread::sleep(self.latency);
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn lookahead_overlaps_fetching_with_answering() {
+        let hours = 6;
+        let latency = Duration::from_millis(100);
+        let fills = (0..hours)
+            .map(|n| fill(HOUR + n * 3600 + 60, n as u64 + 1, 1, "10", "1"))
+            .collect::<Vec<_>>();
+        let input = (0..hours)
+            .map(|n| format!("C {} {}\n", HOUR + n * 3600, HOUR + n * 3600 + 1800))
+            .collect::<String>();
+        let time = |lookahead| {
+            let config = Config {
+                lookahead,
+                ..Config::default()
+            };
+            let source = SlowSource {
+                latency,
+                source: MemorySource::new(fills.clone()),
+            };
+            let clock = Box::new(TestClock::at(HOUR + 86400));
+            let mut processor = Processor::with_source(config, Box::new(source), clock);
+            let mut output = SlowOutput {
+                latency,
+                written: Vec::new(),
+            };
+            let started = std::time::Instant::now();
+            let input = BufReader::new(Cursor::new(input.clone()));
+            run_queries(&mut processor, input, &mut output).unwrap();
+            let elapsed = started.elapsed();
+            assert_eq!(String::from_utf8(output.written).unwrap(), "1\n".repeat(6));
+            elapsed
+        };
+        // Each hour fetched, then its answer taken time over: the sum of both
+        let sequential = time(None);
+        let fetches = latency * hours as u32;
+        assert!(sequential >= fetches * 2, "{:?}", sequential);
+        // The later hours are fetched while the earlier answers are written, so the run
+        // takes about as long as one of the two, and a step or two of the other
+        let overlapped = time(Some(hours as usize));
+        assert!(overlapped >= fetches, "{:?}", overlapped);
+        assert!(
+            overlapped < fetches + latency * 4,
+            "{:?} against {:?} without the lookahead",
+            overlapped,
+            sequential
+        );
+    }
+}
@@ -0,0 +1,483 @@
+use clap::ValueEnum;
+use std::collections::{BTreeMap, VecDeque};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, Span};
+
+use crate::output::AnswerContext;
+use crate::query::QueryKind;
+use crate::stats::{self, KindCounters};
+use crate::Processor;
+
+/// How long a batch query waits, by default, before it is served like an interactive one
+pub const DEFAULT_BATCH_AGING: Duration = Duration::from_millis(1000);
+
+/// Class of a query, which orders the queries waiting for a worker
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Priority {
+    /// Queries a user waits on, like a dashboard's, served ahead of the batch class
+    Interactive,
+    /// Bulk queries, served when no interactive query waits, or once aged
+    Batch,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 2] = [Priority::Interactive, Priority::Batch];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Priority::Interactive => "interactive",
+            Priority::Batch => "batch",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl FromStr for Priority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <Priority as ValueEnum>::from_str(s, true).map_err(|_| {
+            anyhow::anyhow!("Unknown priority {:?} (expected interactive or batch)", s)
+        })
+    }
+}
+
+/// What a worker answered to a query
+#[derive(Debug)]
+pub struct Reply {
+    pub result: anyhow::Result<String>,
+    /// See `Processor::last_answer_final`
+    pub is_final: bool,
+    /// See `Processor::last_latest_end`
+    pub latest_end_ms: Option<i64>,
+    pub context: AnswerContext,
+}
+
+/// A query waiting for a worker, with where its reply goes
+struct Job {
+    /// What the job answers, like its query line, for the logs
+    label: String,
+    queued_at: Instant,
+    /// Answers the job with the worker's processor and sends the answer where it is awaited
+    work: Work,
+}
+
+type Work = Box<dyn FnOnce(&mut Processor) + Send>;
+
+/// How the queries of a class went through the queue
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueueStats {
+    /// Queries waiting for a worker
+    pub depth: usize,
+    /// Queries taken by a worker so far
+    pub started: u64,
+    /// Time the started queries waited for a worker, in total
+    pub wait: Duration,
+    /// Longest time a started query waited for a worker
+    pub max_wait: Duration,
+    /// Queries refused because the queue was full
+    pub rejected: u64,
+}
+
+/// Error of a query refused because `max_depth` queries wait for a worker already
+#[derive(Debug)]
+pub struct QueueFull {
+    pub max_depth: usize,
+}
+
+impl std::fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Busy: {} queries are waiting for a worker already",
+            self.max_depth
+        )
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+/// Queries waiting for a worker, by class. Interactive queries are served ahead of batch
+/// queries, but a batch query is served as if it had been queued `batch_aging` later than
+/// it was, so one that waited that long goes ahead of the interactive queries queued after
+/// that time and a steady stream of them can't starve the batch class. Within a class,
+/// queries are served in arrival order. No more than `max_depth` queries wait, of both
+/// classes together.
+struct Queue {
+    jobs: Mutex<QueueState>,
+    /// Signalled when a job is queued or the queue is closed
+    available: Condvar,
+    batch_aging: Duration,
+    max_depth: usize,
+}
+
+#[derive(Default)]
+struct QueueState {
+    classes: [VecDeque<Job>; 2],
+    stats: [QueueStats; 2],
+    /// Jobs taken by a worker and not answered yet
+    in_flight: usize,
+    /// Set once no more jobs are submitted, so idle workers exit
+    closed: bool,
+}
+
+impl Queue {
+    fn new(batch_aging: Duration, max_depth: usize) -> Self {
+        Queue {
+            jobs: Mutex::default(),
+            available: Condvar::new(),
+            batch_aging,
+            max_depth,
+        }
+    }
+
+    fn push(&self, priority: Priority, job: Job) -> Result<(), QueueFull> {
+        let mut state = self.jobs.lock().unwrap();
+        if state.classes.iter().map(VecDeque::len).sum::<usize>() >= self.max_depth {
+            state.stats[priority.index()].rejected += 1;
+            return Err(QueueFull {
+                max_depth: self.max_depth,
+            });
+        }
+        state.classes[priority.index()].push_back(job);
+        drop(state);
+        self.available.notify_one();
+        Ok(())
+    }
+
+    /// Waits for the next job, or returns `None` once the queue is closed and empty
+    fn pop(&self) -> Option<Job> {
+        let mut state = self.jobs.lock().unwrap();
+        loop {
+            if let Some(priority) = self.next_class(&state) {
+                let job = state.classes[priority.index()].pop_front().unwrap();
+                let waited = job.queued_at.elapsed();
+                let stats = &mut state.stats[priority.index()];
+                stats.started += 1;
+                stats.wait += waited;
+                stats.max_wait = stats.max_wait.max(waited);
+                state.in_flight += 1;
+                return Some(job);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.available.wait(state).unwrap();
+        }
+    }
+
+    /// Returns the class whose first job is served next, see `Queue`
+    fn next_class(&self, state: &QueueState) -> Option<Priority> {
+        let [interactive, batch] = &state.classes;
+        match (interactive.front(), batch.front()) {
+            (Some(interactive), Some(batch))
+                if batch.queued_at + self.batch_aging < interactive.queued_at =>
+            {
+                Some(Priority::Batch)
+            }
+            (Some(_), _) => Some(Priority::Interactive),
+            (None, Some(_)) => Some(Priority::Batch),
+            (None, None) => None,
+        }
+    }
+
+    /// Records that a job taken by `pop` was answered
+    fn finish(&self) {
+        self.jobs.lock().unwrap().in_flight -= 1;
+    }
+
+    fn in_flight(&self) -> usize {
+        self.jobs.lock().unwrap().in_flight
+    }
+
+    fn stats(&self, priority: Priority) -> QueueStats {
+        let state = self.jobs.lock().unwrap();
+        QueueStats {
+            depth: state.classes[priority.index()].len(),
+            ..state.stats[priority.index()].clone()
+        }
+    }
+
+    fn close(&self) {
+        self.jobs.lock().unwrap().closed = true;
+        self.available.notify_all();
+    }
+}
+
+/// Creates the processor of the worker with the given index, on the worker's thread
+pub type ProcessorFactory = dyn Fn(usize) -> Processor + Send + Sync;
+
+/// Workers answering queries, each with a processor, cache and source of its own, taking
+/// the queries in the order of their class (see `Queue`). A query goes to whichever worker is free,
+/// so an hour may be fetched once per worker; the number of workers bounds the queries
+/// answered at a time, and the depth of the queue those waiting.
+pub struct WorkerPool {
+    queue: Arc<Queue>,
+    workers: Vec<JoinHandle<()>>,
+    /// Set once every worker has created its processor, the first loading the snapshot
+    started: Arc<AtomicBool>,
+    /// Counters of the queries the workers answered per query type, added up
+    kind_counters: Arc<Mutex<BTreeMap<QueryKind, KindCounters>>>,
+}
+
+impl WorkerPool {
+    /// Starts `workers` workers, each creating its processor with `factory`; a batch query
+    /// waits `batch_aging` at most before it goes ahead of the interactive queries, and no
+    /// more than `max_depth` queries wait for a worker
+    pub fn start(
+        workers: usize,
+        batch_aging: Duration,
+        max_depth: usize,
+        factory: Arc<ProcessorFactory>,
+    ) -> Self {
+        let queue = Arc::new(Queue::new(batch_aging, max_depth));
+        let started = Arc::new(AtomicBool::new(false));
+        let (count, created) = (workers.max(1), Arc::new(AtomicUsize::new(0)));
+        let workers = (0..count)
+            .map(|worker| {
+                let queue = Arc::clone(&queue);
+                let factory = Arc::clone(&factory);
+                let (started, created) = (Arc::clone(&started), Arc::clone(&created));
+                thread::spawn(move || {
+                    let processor = factory(worker);
+                    if created.fetch_add(1, Ordering::SeqCst) + 1 == count {
+                        started.store(true, Ordering::SeqCst);
+                    }
+                    run_worker(processor, &queue)
+                })
+            })
+            .collect();
+        WorkerPool {
+            queue,
+            workers,
+            started,
+            kind_counters: Arc::default(),
+        }
+    }
+
+    /// Returns the number of workers
+    pub fn workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Returns the flag set once every worker has created its processor
+    pub fn started(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.started)
+    }
+
+    /// Queues the query in its class for the next free worker and returns where its reply
+    /// arrives, or refuses it when the queue is full
+    pub fn submit(&self, query: String, priority: Priority) -> Result<Receiver<Reply>, QueueFull> {
+        self.run(query.clone(), priority, move |processor| {
+            let result = processor.process_query(query);
+            Reply {
+                result,
+                is_final: processor.last_answer_final(),
+                latest_end_ms: processor.last_latest_end(),
+                context: processor.last_context().clone(),
+            }
+        })
+    }
+
+    /// Queues other work than a query line, like `submit`: the next free worker runs
+    /// `work` with its processor, and `label` names the work in the logs
+    pub fn run<T: Send + 'static>(
+        &self,
+        label: String,
+        priority: Priority,
+        work: impl FnOnce(&mut Processor) -> T + Send + 'static,
+    ) -> Result<Receiver<T>, QueueFull> {
+        let (reply, receiver) = mpsc::channel();
+        let kind_counters = Arc::clone(&self.kind_counters);
+        // The work runs in the span it was queued in, like the span of an HTTP request
+        let span = Span::current();
+        let work = move |processor: &mut Processor| {
+            let _enter = span.enter();
+            let before = processor.metrics().kind_counters();
+            let value = work(processor);
+            // Counted before the answer is sent, so a client sees its queries in the metrics
+            let mut kind_counters = kind_counters.lock().unwrap();
+            for (kind, after) in processor.metrics().kind_counters() {
+                let before = before.get(&kind).copied().unwrap_or_default();
+                kind_counters
+                    .entry(kind)
+                    .or_default()
+                    .add_since(&after, &before);
+            }
+            drop(kind_counters);
+            // A gone receiver no longer waits for the answer, but the work still warmed the cache
+            let _ = reply.send(value);
+        };
+        self.queue.push(
+            priority,
+            Job {
+                label,
+                queued_at: Instant::now(),
+                work: Box::new(work),
+            },
+        )?;
+        Ok(receiver)
+    }
+
+    /// Returns the number of queries the workers are answering
+    pub fn in_flight(&self) -> usize {
+        self.queue.in_flight()
+    }
+
+    /// Returns the counters of the queries answered so far per query type, of every worker
+    pub fn kind_counters(&self) -> BTreeMap<QueryKind, KindCounters> {
+        self.kind_counters.lock().unwrap().clone()
+    }
+
+    /// Returns how the queries of the class went through the queue so far
+    pub fn queue_stats(&self, priority: Priority) -> QueueStats {
+        self.queue.stats(priority)
+    }
+
+    /// Lets the workers answer the queued queries, then stops them: each snapshots its
+    /// cache and logs its run statistics
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.queue.close();
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                error!("A query worker panicked");
+            }
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Answers queued queries with the processor until the queue is closed
+fn run_worker(mut processor: Processor, queue: &Queue) {
+    while let Some(job) = queue.pop() {
+        debug!("Running {}", job.label);
+        (job.work)(&mut processor);
+        queue.finish();
+    }
+    processor.snapshot();
+    stats::log_run_stats(&processor);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(label: &str, queued_at: Instant) -> Job {
+        Job {
+            label: label.to_string(),
+            queued_at,
+            work: Box::new(|_| {}),
+        }
+    }
+
+    fn drain(queue: &Queue) -> Vec<String> {
+        queue.close();
+        std::iter::from_fn(|| queue.pop())
+            .map(|job| job.label)
+            .collect()
+    }
+
+    #[test]
+    fn interactive_queries_jump_the_batch_queue() {
+        let queue = Queue::new(Duration::from_secs(60), usize::MAX);
+        let now = Instant::now();
+        for (query, priority) in [
+            ("batch 1", Priority::Batch),
+            ("batch 2", Priority::Batch),
+            ("interactive 1", Priority::Interactive),
+            ("batch 3", Priority::Batch),
+            ("interactive 2", Priority::Interactive),
+        ] {
+            queue.push(priority, job(query, now)).unwrap();
+        }
+        assert_eq!(
+            drain(&queue),
+            [
+                "interactive 1",
+                "interactive 2",
+                "batch 1",
+                "batch 2",
+                "batch 3"
+            ]
+        );
+        let stats = queue.stats(Priority::Batch);
+        assert_eq!((stats.depth, stats.started), (0, 3));
+        assert!(stats.max_wait >= stats.wait / 3);
+    }
+
+    #[test]
+    fn aged_batch_queries_go_ahead_of_later_interactive_ones() {
+        let queue = Queue::new(Duration::from_secs(10), usize::MAX);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        queue
+            .push(Priority::Batch, job("batch at 0", at(0)))
+            .unwrap();
+        queue
+            .push(Priority::Batch, job("batch at 5", at(5)))
+            .unwrap();
+        for secs in [2, 9, 11, 14, 16] {
+            queue
+                .push(
+                    Priority::Interactive,
+                    job(&format!("interactive at {}", secs), at(secs)),
+                )
+                .unwrap();
+        }
+        // A batch query is served as if queued 10 seconds later
+        assert_eq!(
+            drain(&queue),
+            [
+                "interactive at 2",
+                "interactive at 9",
+                "batch at 0",
+                "interactive at 11",
+                "interactive at 14",
+                "batch at 5",
+                "interactive at 16",
+            ]
+        );
+        assert_eq!(queue.stats(Priority::Interactive).started, 5);
+    }
+
+    #[test]
+    fn full_queues_refuse_queries() {
+        let queue = Queue::new(Duration::ZERO, 2);
+        let now = Instant::now();
+        queue.push(Priority::Batch, job("batch", now)).unwrap();
+        queue
+            .push(Priority::Interactive, job("interactive", now))
+            .unwrap();
+        assert!(queue
+            .push(Priority::Interactive, job("refused", now))
+            .is_err());
+        assert_eq!(queue.stats(Priority::Interactive).rejected, 1);
+
+        let taken = queue.pop().unwrap();
+        assert_eq!(
+            (taken.label.as_str(), queue.in_flight()),
+            ("interactive", 1)
+        );
+        queue.push(Priority::Batch, job("queued", now)).unwrap();
+        queue.finish();
+        assert_eq!(queue.in_flight(), 0);
+        assert_eq!(drain(&queue), ["batch", "queued"]);
+    }
+}
@@ -0,0 +1,4265 @@
+use chrono::{DateTime, Utc};
+use lru::LruCache;
+use rust_decimal::{Decimal, MathematicalOps};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::num::NonZero;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, debug_span, error, field, info, info_span, warn};
+
+use crate::audit::{AuditLog, HourTrace, QueryTrace};
+use crate::clock::{self, Clock, Instant, SystemClock};
+use crate::config::{Config, DedupKey, FuturePolicy, StaleRefresh};
+use crate::endpoint::Endpoint;
+#[cfg(feature = "arrow")]
+use crate::export;
+use crate::intern::{HourKey, Interner};
+use crate::memory::{MemoryProbe, ProcMeminfo};
+use crate::output::AnswerContext;
+use crate::profile::{Phase, PhaseTimer, Profile};
+use crate::query::{Query, QueryKind, Series, Window};
+use crate::rng::SplitMix64;
+use crate::seq_index::SeqIndex;
+use crate::server::{ApiResponse, Currency, Fill, Liquidity, Side};
+use crate::snapshot::{HourRecord, Snapshotter};
+use crate::source::{ApiSource, FillSource, DEFAULT_VENUE};
+use crate::stats::{CacheStats, Metrics};
+use crate::summary::QueryFailure;
+use crate::{query, seq_index, snapshot};
+
+mod stream;
+
+use stream::{DuplicateResolver, HourFold, Repeat, PAGE_SECS};
+
+/// Returns a hash of one fill, which the content hash of its hour sums
+fn fill_hash(fill: &Fill) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    fill.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Returns a hash of the fills of an hour, whatever their order: equal for two fetches of
+/// an hour exactly when they returned the same fills, but for collisions
+fn content_hash(fills: &[Fill]) -> u64 {
+    fills
+        .iter()
+        .fold(0, |hash, fill| hash.wrapping_add(fill_hash(fill)))
+}
+
+/// Returns the hour a fill belongs in: the hour whose range (start, end], as it is
+/// fetched, holds the fill's time, so a fill on the hour belongs in the hour it ends
+fn fill_hour(fill: &Fill) -> i64 {
+    let time = (fill.time.timestamp_millis() - 1).div_euclid(1000);
+    time - time.rem_euclid(3600)
+}
+
+/// Returns whether the fill has a positive price and quantity.
+/// The upstream has sent zero quantities and negative prices, which would skew volumes.
+fn is_valid(fill: &Fill) -> bool {
+    fill.price > Decimal::ZERO && fill.quantity > Decimal::ZERO
+}
+
+/// Identity of the taker trade of a fill under a dedup key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum TradeKey {
+    Sequence(u64),
+    TradeId(u64),
+    SequenceTime(u64, i64),
+}
+
+/// Returns the identity of the fill's taker trade under the dedup key
+fn trade_key(fill: &Fill, dedup_key: DedupKey) -> TradeKey {
+    match (dedup_key, fill.trade_id) {
+        (DedupKey::TradeId, Some(trade_id)) => TradeKey::TradeId(trade_id),
+        (DedupKey::SequenceNumberTime, _) => {
+            TradeKey::SequenceTime(fill.sequence_number, fill.time.timestamp_millis())
+        }
+        _ => TradeKey::Sequence(fill.sequence_number),
+    }
+}
+
+/// Fills of an hour that repeat the taker trade of an earlier fill of the hour (its sequence
+/// number, or the venue's dedup key), beyond the fills of one taker trade at different prices
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DuplicateFills {
+    /// Fills equal to an earlier fill in every field; kept, as a taker trade can fill
+    /// twice at the same price and quantity
+    pub identical: usize,
+    /// Fills whose time or direction differs from the first fill of their taker trade,
+    /// so they can't belong to the same taker trade; dropped in favor of the first fill seen
+    pub conflicting: usize,
+}
+
+/// An hour of fills fetched from the upstream, with what was skipped or dropped on the way
+struct FetchedHour {
+    /// Valid fills sorted by time
+    fills: Vec<Fill>,
+    /// Number of fills that failed to parse and were skipped
+    malformed: usize,
+    /// Fields of the fills unknown to `Fill`, ignored
+    unknown_fields: Vec<String>,
+    /// Number of fills dropped for a non-positive price or quantity
+    invalid: usize,
+    /// Number of fills dropped for a time in another hour
+    out_of_hour: usize,
+    duplicates: DuplicateFills,
+    /// Number of fills of unknown direction, counted before any was left out of `fills`
+    unknown_direction: usize,
+    /// Hash of every fill of the hour, see `content_hash`
+    content_hash: u64,
+    /// Aggregates of the hour when it was streamed past the streaming threshold, in which
+    /// case `fills` holds only the fills the query streaming it needs
+    summary: Option<HourSummary>,
+    /// Aggregates of the fills within each window of the query streaming the hour, in
+    /// order, when it was streamed past the threshold and the query asked for them
+    partials: Vec<HourSummary>,
+}
+
+/// A source shared with the threads fetching from it under a query deadline
+type SharedSource = Arc<Mutex<Box<dyn FillSource>>>;
+
+/// Error of a query still unanswered when its deadline passed, answered as TIMEOUT
+#[derive(Debug)]
+pub struct DeadlineExceeded {
+    pub timeout: Duration,
+    /// How long ago the deadline passed when it was noticed
+    pub overrun: Duration,
+}
+
+impl std::fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Query timeout of {:?} exceeded by {:?}",
+            self.timeout, self.overrun
+        )
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// Answer of a query still unanswered when its deadline passed
+pub const TIMEOUT: &str = "TIMEOUT";
+
+/// Everything known about one processed query, as passed to the callback of
+/// `Processor::process_queries`
+#[derive(Debug)]
+pub struct QueryOutcome {
+    /// The query as given
+    pub query: String,
+    /// The parsed query, once parsing succeeded
+    pub parsed: Option<Query>,
+    /// The answer, or why there is none. A query past its deadline fails with
+    /// `DeadlineExceeded` and one the upstream couldn't serve with `UpstreamUnavailable`
+    /// in its chain, for `downcast_ref`; `process_query` answers the former with TIMEOUT.
+    pub result: anyhow::Result<String>,
+    /// The hours loaded, in the order they were loaded, with their cache status and fetch time
+    pub hours: Vec<HourTrace>,
+    /// Whether the answer is final: every hour it was computed from was fetched after it
+    /// ended, so asking again can't give another answer (short of an upstream correction),
+    /// and no window ended at LATEST
+    pub is_final: bool,
+    /// End the LATEST windows were resolved to, in Unix milliseconds, when there were any
+    pub latest_end_ms: Option<i64>,
+    /// Time the query took, from start to answer
+    pub duration: Duration,
+}
+
+/// Aggregates kept instead of the fills of an hour over the streaming threshold,
+/// enough to answer the count and volume queries whose windows hold all of its fills
+#[derive(Debug, Clone, Copy, Default)]
+struct HourSummary {
+    buy_count: usize,
+    sell_count: usize,
+    unknown_count: usize,
+    volume: Decimal,
+    /// Number of fills the aggregates were computed from
+    fill_count: usize,
+    /// Times of the earliest and the latest of those fills, in Unix milliseconds
+    span: Option<(i64, i64)>,
+}
+
+impl HourSummary {
+    /// Aggregates the fills of an hour the way `compute_result` does, counting every taker
+    /// trade once under the dedup key
+    fn of(fills: &[Fill], dedup_key: DedupKey) -> Self {
+        let mut summary = HourSummary::default();
+        let mut unique_trades = HashSet::with_capacity(fills.len());
+        for fill in fills {
+            summary.add(fill, unique_trades.insert(trade_key(fill, dedup_key)));
+        }
+        summary
+    }
+
+    /// Adds a fill to the aggregates, counting its taker trade when it is the first fill of it
+    fn add(&mut self, fill: &Fill, new_trade: bool) {
+        if new_trade {
+            match fill.side() {
+                Side::Buy => self.buy_count += 1,
+                Side::Sell => self.sell_count += 1,
+                Side::Unknown => self.unknown_count += 1,
+            }
+        }
+        self.volume += fill.quantity * fill.price;
+        self.fill_count += 1;
+        let time_ms = fill.time.timestamp_millis();
+        self.span = Some(match self.span {
+            Some((first_ms, last_ms)) => (first_ms.min(time_ms), last_ms.max(time_ms)),
+            None => (time_ms, time_ms),
+        });
+    }
+
+    /// Returns whether the window, which holds the fills within (start_ms, end_ms], holds
+    /// every fill the aggregates were computed from (`Some(true)`) or none
+    /// (`Some(false)`), or `None` when it holds only some of them
+    fn coverage(&self, window: &Window) -> Option<bool> {
+        let Some((first_ms, last_ms)) = self.span else {
+            return Some(false);
+        };
+        if window.start_ms < first_ms && window.end_ms >= last_ms {
+            Some(true)
+        } else if window.end_ms < first_ms || window.start_ms >= last_ms {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+/// Fills of one cached venue, symbol and hour
+struct CacheEntry {
+    /// Empty when the hour is summarized
+    fills: Vec<Fill>,
+    /// Aggregates of the fills of an hour over the streaming threshold, whose fills
+    /// aren't cached
+    summary: Option<HourSummary>,
+    /// Duplicate taker trades seen when the hour was fetched
+    duplicates: DuplicateFills,
+    /// When the hour was fetched
+    fetched_at: DateTime<Utc>,
+    /// Whether the hour had ended when it was fetched.
+    /// Incomplete hours are refetched once the incomplete-hour TTL has elapsed.
+    complete: bool,
+    /// Hash of the fills the hour was cached with, to tell whether a refetch changed them
+    /// (see `content_hash`)
+    content_hash: u64,
+}
+
+impl CacheEntry {
+    /// Returns the number of fills the hour was cached with, summarized or not
+    fn fill_count(&self) -> usize {
+        self.summary
+            .map_or(self.fills.len(), |summary| summary.fill_count)
+    }
+
+    /// Returns whether the entry is written to snapshots: complete, with its fills
+    fn is_snapshottable(&self) -> bool {
+        self.complete && self.summary.is_none()
+    }
+
+    /// Returns the snapshot record of the entry's hour
+    fn record(&self, (venue, symbol, hour): &HourKey) -> HourRecord {
+        HourRecord {
+            venue: venue.to_string(),
+            symbol: symbol.to_string(),
+            hour: *hour,
+            duplicates: self.duplicates,
+            fills: self.fills.clone(),
+        }
+    }
+
+    /// Returns how long ago the entry was fetched
+    fn age(&self, now: DateTime<Utc>) -> Duration {
+        (now - self.fetched_at).to_std().unwrap_or_default()
+    }
+
+    /// Returns whether the entry can still be served from the cache
+    fn is_fresh(&self, now: DateTime<Utc>, incomplete_ttl: Duration) -> bool {
+        self.complete || self.age(now) < incomplete_ttl
+    }
+
+    /// Replaces the fills of the entry by their aggregates and returns them
+    fn summarize(&mut self, dedup_key: DedupKey) -> HourSummary {
+        let summary = HourSummary::of(&self.fills, dedup_key);
+        self.fills = Vec::new();
+        self.summary = Some(summary);
+        summary
+    }
+}
+
+/// A proxy server implementation for orderbook trades that caches hourly trade data
+/// to minimize expensive API calls.
+///
+/// Caching Strategy:
+/// - Uses LRU cache with a configurable capacity, 168 hours (one week of data) by default
+/// - Caches full hourly data to handle arbitrary queries within each hour
+/// - Trades within an hour are cached together to optimize for temporal locality
+/// - Hours of different venues and symbols share one cache, so eviction is global across them
+pub struct Processor {
+    /// LRU cache stores hourly trade data
+    /// Key: (Venue, Symbol, Hour timestamp (rounded down))
+    /// Value: Vector of fills for that venue, symbol and hour, and whether the hour was complete
+    cache: LruCache<HourKey, CacheEntry>,
+    /// Venue and symbol strings of the cache keys, shared by the hours of a market
+    interner: Interner,
+    /// Memory used by the entries of `cache`, as estimated by `entry_bytes`
+    cached_bytes: usize,
+    /// Most memory the entries of `cache` used at once
+    peak_cached_bytes: usize,
+    /// Bytes the cache holds past which it evicts its least recently used hours, from
+    /// `--cache-bytes` or the available memory; `None` bounds the cache by hours only
+    cache_budget: Option<usize>,
+    /// Source of the available memory, the cache budget is sized from
+    memory: Box<dyn MemoryProbe>,
+    /// When the available memory was last checked
+    last_memory_check: Instant,
+    /// Temporary storage for current query processing
+    current_fills: Vec<Fill>,
+    /// Summarized hours of the current query, answered from their aggregates
+    /// instead of fills in `current_fills`
+    current_summaries: Vec<HourSummary>,
+    /// Aggregates of the streamed hours of the current query within each of its windows,
+    /// by window index, answered instead of fills in `current_fills` (see `stream_hour`)
+    current_partials: Vec<(usize, HourSummary)>,
+    /// When each hour with fills in `current_fills` was fetched, to settle which hour keeps
+    /// a taker trade found in several
+    current_hours: BTreeMap<i64, DateTime<Utc>>,
+    /// Runtime configuration (default symbol, slow-query threshold, ...)
+    config: Config,
+    /// Upstream of the default venue, fetched on cache misses
+    source: SharedSource,
+    /// Upstream of the default venue's hours too old for `source`, when configured
+    archive: Option<SharedSource>,
+    /// Upstreams of the other configured venues
+    venues: BTreeMap<String, SharedSource>,
+    /// Fetches running in the background: prefetches of upcoming queries' hours, and fetches
+    /// still running after the deadline of the query that started them passed
+    pending_fetches: BTreeMap<HourKey, Receiver<anyhow::Result<ApiResponse>>>,
+    /// When the current query times out, under a query timeout
+    deadline: Option<Instant>,
+    /// Source of the current time
+    clock: Box<dyn Clock>,
+    /// Whether the answer of the latest query is final (see `QueryOutcome::is_final`)
+    last_answer_final: bool,
+    /// Resolved end of the LATEST windows of the latest query, in Unix milliseconds
+    last_latest_end: Option<i64>,
+    /// Newest hour a fetch returned fills for, by venue and symbol, for LATEST windows
+    /// of sources that can't tell the newest hour they have
+    newest_fetched: BTreeMap<(String, String), i64>,
+    /// What `--format` templates show of the latest query
+    last_context: AnswerContext,
+    /// Latest fetched hour with more fills than the cache admits, outside the LRU
+    jumbo: Option<(HourKey, CacheEntry)>,
+    /// Hours of the cache by the sequence numbers they hold, when enabled
+    seq_index: Option<SeqIndex>,
+    /// Whether the exhaustion of the API call budget has been logged
+    budget_exhausted_logged: bool,
+    /// Picks the queries to verify
+    rng: SplitMix64,
+    /// Record of every processed query, when configured
+    audit: Option<AuditLog>,
+    /// Query, cache and upstream counters, in total and per query type
+    metrics: Metrics,
+    /// Phase times of the answered queries, when profiling
+    profile: Option<Profile>,
+    /// Queries that failed, by input line, for the end-of-run summary
+    failures: Vec<QueryFailure>,
+    /// Writer of the cache snapshots, when configured
+    snapshotter: Option<Snapshotter>,
+    /// Complete hours fetched since the last snapshot, in the order they were fetched
+    unsnapshotted: Vec<HourKey>,
+    /// When the last snapshot was taken
+    last_snapshot: Instant,
+}
+
+impl Default for Processor {
+    fn default() -> Self {
+        Self::new(Config::default())
+    }
+}
+
+impl Processor {
+    /// Returns the approximate memory usage of one cache entry in bytes, but for the
+    /// strings of its key, which the hours of its market share (see `get_cache_size`)
+    fn entry_bytes(_key: &HourKey, entry: &CacheEntry) -> usize {
+        std::mem::size_of::<HourKey>() // key size
+            + std::mem::size_of::<CacheEntry>() // entry and vector overhead
+            + entry.fills.len() * std::mem::size_of::<Fill>() // actual fills
+    }
+
+    /// Returns the size of the cache entries accepted by `include`, with the interned
+    /// strings of their keys counted once however many hours share them
+    fn get_cache_size(&self, include: impl Fn(&str, &str) -> bool) -> CacheStats {
+        let mut size = CacheStats::default();
+        // Length of each distinct string of the keys, by its address
+        let mut strings = HashMap::new();
+
+        // Add size of each cache entry, the jumbo slot's included
+        let jumbo = self.jumbo.as_ref().map(|(key, entry)| (key, entry));
+        for (key, entry) in self.cache.iter().chain(jumbo) {
+            if !include(&key.0, &key.1) {
+                continue;
+            }
+            size.hours += 1;
+            size.fills += entry.fills.len();
+            size.bytes += Self::entry_bytes(key, entry);
+            size.max_hour_fills = size.max_hour_fills.max(entry.fills.len());
+            for s in [&key.0, &key.1] {
+                strings.insert(Arc::as_ptr(s) as *const u8, Interner::string_bytes(s));
+            }
+        }
+        size.bytes += strings.values().sum::<usize>();
+
+        size
+    }
+
+    /// Returns the runtime configuration
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Returns the current time of the processor's clock
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    /// Returns the query, cache and upstream counters
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Returns the phase times of the answered queries, when profiling
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Adds the time spent writing the answer of the latest query to its phase times
+    pub fn record_output(&mut self, duration: Duration) {
+        if let Some(profile) = &mut self.profile {
+            profile.record_output(duration);
+        }
+    }
+
+    /// Returns whether the answer of the latest query is final: every hour it was computed
+    /// from was fetched after it ended
+    pub fn last_answer_final(&self) -> bool {
+        self.last_answer_final
+    }
+
+    /// Returns the end the LATEST windows of the latest query were resolved to, in Unix
+    /// milliseconds, when it had any
+    pub fn last_latest_end(&self) -> Option<i64> {
+        self.last_latest_end
+    }
+
+    /// Returns what `--format` templates show of the latest query
+    pub fn last_context(&self) -> &AnswerContext {
+        &self.last_context
+    }
+
+    /// Returns the most memory the cached hours used at once, as estimated by `entry_bytes`
+    pub fn peak_cache_bytes(&self) -> usize {
+        self.peak_cached_bytes
+    }
+
+    /// Returns the cache's byte budget, from `--cache-bytes` or the available memory
+    pub fn cache_budget(&self) -> Option<usize> {
+        self.cache_budget
+    }
+
+    /// Returns the size of the whole cache
+    pub fn cache_stats(&self) -> CacheStats {
+        self.get_cache_size(|_, _| true)
+    }
+
+    /// Returns the failed queries, in the order they failed
+    pub fn failures(&self) -> &[QueryFailure] {
+        &self.failures
+    }
+
+    /// Records a failed query and the line of the input it was on
+    pub fn record_failure(&mut self, line: usize, query: &str, error: String) {
+        self.failures.push(QueryFailure {
+            line,
+            query: query.to_string(),
+            error,
+        });
+    }
+
+    /// Prints the cache statistics in a formatted string, in aggregate and per venue and symbol
+    pub fn print_cache_stats(&self) -> String {
+        let CacheStats {
+            hours,
+            fills: total_fills,
+            bytes: mut total_bytes,
+            max_hour_fills: max_fills,
+        } = self.cache_stats();
+        total_bytes += std::mem::size_of::<LruCache<HourKey, CacheEntry>>();
+        let mut cache_stats = format!(
+            r#"
+Cache Statistics:
+    Number of hours cached: {}
+    Total fills stored: {}
+    Maximum fills in a single hour: {}
+    Approximate memory usage: {} bytes ({:.2} MB)"#,
+            hours,
+            total_fills,
+            max_fills,
+            total_bytes,
+            total_bytes as f64 / 1_000_000.0
+        );
+
+        let markets = self
+            .cache
+            .iter()
+            .map(|((venue, symbol, _), _)| (&**venue, &**symbol))
+            .collect::<HashSet<(&str, &str)>>()
+            .into_iter()
+            .map(|(venue, symbol)| {
+                let size = self.get_cache_size(|v, s| v == venue && s == symbol);
+                ((venue, symbol), size)
+            })
+            .collect::<BTreeMap<_, _>>();
+        for ((venue, symbol), size) in markets {
+            cache_stats += &format!(
+                r#"
+    {}:
+        Number of hours cached: {}
+        Total fills stored: {}
+        Maximum fills in a single hour: {}
+        Approximate memory usage: {} bytes ({:.2} MB)"#,
+                query::market_name(venue, symbol),
+                size.hours,
+                size.fills,
+                size.max_hour_fills,
+                size.bytes,
+                size.bytes as f64 / 1_000_000.0
+            );
+        }
+
+        cache_stats
+    }
+
+    /// Creates a new Processor with:
+    /// - LRU cache sized for the configured number of hours (one week of data by default),
+    ///   shared by all symbols
+    /// - Temporary vector to store fills for the current query
+    /// - The given runtime configuration
+    /// - The trades API as the upstream
+    /// - The system clock
+    pub fn new(config: Config) -> Self {
+        Self::with_clock(config, Box::new(SystemClock))
+    }
+
+    /// Creates a new Processor like `new`, reading the current time from the given clock
+    pub fn with_clock(config: Config, clock: Box<dyn Clock>) -> Self {
+        let source = Box::new(ApiSource {
+            lenient: config.lenient_parse,
+            strict_schema: config.strict_schema,
+        });
+        Self::with_source(config, source, clock)
+    }
+
+    /// Creates a new Processor like `new`, fetching the default venue from the given source,
+    /// the other configured venues from theirs, and reading the current time from the given clock
+    pub fn with_source(config: Config, source: Box<dyn FillSource>, clock: Box<dyn Clock>) -> Self {
+        let audit = config.audit_log.as_deref().map(AuditLog::open);
+        let profile = config.profile.then(Profile::default);
+        let seq_index = config.seq_index.then(SeqIndex::default);
+        for venue in config.dedup_keys.keys() {
+            if venue != DEFAULT_VENUE && !config.venues.contains_key(venue) {
+                warn!("Ignoring the dedup key of unknown venue {}", venue);
+            }
+        }
+        let venues = config
+            .venues
+            .iter()
+            .map(|(name, spec)| {
+                let source = spec.build(config.lenient_parse, config.strict_schema);
+                // Each venue is its own upstream, limited and retried like --source
+                let endpoint = Endpoint::new("venue", source, config.live_policy);
+                let endpoint: Box<dyn FillSource> = Box::new(endpoint);
+                (name.clone(), Arc::new(Mutex::new(endpoint)))
+            })
+            .collect();
+        let live = Endpoint::new("live", source, config.live_policy);
+        let mut metrics = Metrics::default();
+        metrics.endpoints.push((live.name(), live.stats()));
+        let source: Box<dyn FillSource> = Box::new(live);
+        let archive = config.archive.as_ref().map(|archive| {
+            let source = archive
+                .source
+                .build(config.lenient_parse, config.strict_schema);
+            let endpoint = Endpoint::new("archive", source, archive.policy);
+            metrics.endpoints.push((endpoint.name(), endpoint.stats()));
+            let endpoint: Box<dyn FillSource> = Box::new(endpoint);
+            Arc::new(Mutex::new(endpoint))
+        });
+        let mut processor = Processor {
+            cache: LruCache::new(NonZero::new(config.cache_hours.max(1)).unwrap()),
+            interner: Interner::default(),
+            cached_bytes: 0,
+            peak_cached_bytes: 0,
+            cache_budget: None,
+            memory: Box::new(ProcMeminfo),
+            last_memory_check: Instant::now(),
+            current_fills: Vec::new(),
+            current_summaries: Vec::new(),
+            current_partials: Vec::new(),
+            current_hours: BTreeMap::new(),
+            config,
+            source: Arc::new(Mutex::new(source)),
+            archive,
+            venues,
+            pending_fetches: BTreeMap::new(),
+            deadline: None,
+            clock,
+            last_answer_final: false,
+            last_latest_end: None,
+            newest_fetched: BTreeMap::new(),
+            last_context: AnswerContext::default(),
+            jumbo: None,
+            seq_index,
+            budget_exhausted_logged: false,
+            rng: SplitMix64(clock::unix_nanos()),
+            audit,
+            metrics,
+            profile,
+            failures: Vec::new(),
+            snapshotter: None,
+            unsnapshotted: Vec::new(),
+            last_snapshot: Instant::now(),
+        };
+        processor.size_cache();
+        processor.load_snapshot();
+        processor
+    }
+
+    /// Sizes the cache budget from the given memory probe instead of the system's memory
+    pub fn with_memory_probe(mut self, memory: Box<dyn MemoryProbe>) -> Self {
+        self.memory = memory;
+        self.size_cache();
+        self.enforce_cache_budget();
+        self
+    }
+
+    /// Sets the cache budget from `--cache-bytes`, or else from `--cache-mem-fraction` of the
+    /// available memory. Memory that can't be read leaves the cache bounded by hours only.
+    fn size_cache(&mut self) {
+        if let Some(bytes) = self.config.cache_bytes {
+            if self.config.cache_mem_fraction.is_some() {
+                info!("--cache-bytes overrides --cache-mem-fraction");
+            }
+            info!(cache_budget = bytes, "Cache budget: {} bytes", bytes);
+            self.cache_budget = Some(bytes);
+            return;
+        }
+        let Some(fraction) = self.config.cache_mem_fraction else {
+            return;
+        };
+        self.last_memory_check = Instant::now();
+        let Some(memory) = self.memory.probe() else {
+            warn!("Can't read the available memory; the cache is bounded by --cache-hours only");
+            self.cache_budget = None;
+            return;
+        };
+        let budget = (memory.available as f64 * fraction) as usize;
+        info!(
+            cache_budget = budget,
+            "Cache budget: {} bytes, {} of the {} bytes available",
+            budget,
+            fraction,
+            memory.available
+        );
+        self.cache_budget = Some(budget);
+    }
+
+    /// Checks the available memory again once the check interval elapsed, under
+    /// `--cache-mem-fraction`, and shrinks the cache budget to the fraction of what is left
+    /// when the available memory fell below the pressure threshold. The budget never grows
+    /// back, so hours evicted under pressure aren't refetched when it eases.
+    fn check_memory(&mut self) {
+        let (None, Some(fraction)) = (self.config.cache_bytes, self.config.cache_mem_fraction)
+        else {
+            return;
+        };
+        if self.last_memory_check.elapsed() < self.config.memory_check_interval {
+            return;
+        }
+        self.last_memory_check = Instant::now();
+        let Some(memory) = self.memory.probe() else {
+            return;
+        };
+        if memory.available as f64 >= memory.total as f64 * self.config.memory_pressure {
+            return;
+        }
+        let budget = (memory.available as f64 * fraction) as usize;
+        if self.cache_budget.is_some_and(|current| current <= budget) {
+            return;
+        }
+        warn!(
+            cache_budget = budget,
+            "Memory under pressure, {} of {} bytes available: shrinking the cache budget to {} bytes",
+            memory.available,
+            memory.total,
+            budget
+        );
+        self.cache_budget = Some(budget);
+        self.enforce_cache_budget();
+    }
+
+    /// Evicts the least recently used hours while the cache holds more bytes than its
+    /// budget. The most recently used hour is kept even when it alone is over the budget,
+    /// as the query that cached it is still reading it.
+    fn enforce_cache_budget(&mut self) {
+        let Some(budget) = self.cache_budget else {
+            return;
+        };
+        while self.cached_bytes > budget && self.cache.len() > 1 {
+            let Some((key, entry)) = self.cache.pop_lru() else {
+                break;
+            };
+            self.cached_bytes -= Self::entry_bytes(&key, &entry);
+            if let Some(index) = &mut self.seq_index {
+                index.remove(&key);
+            }
+            self.metrics.budget_evictions += 1;
+            debug!(
+                "Evicted {} hour {} to keep the cache within {} bytes",
+                query::market_name(&key.0, &key.1),
+                key.2,
+                budget
+            );
+        }
+    }
+
+    /// Fills the cache from the snapshot file, when configured, and starts the snapshot writer.
+    /// A torn tail is dropped, and the next snapshot is written over it (see `snapshot::load`).
+    /// A snapshot that can't be read at all, such as a binary one of another version,
+    /// disables snapshots for the run with a warning and is left untouched, as it may hold
+    /// hours a newer build can read.
+    fn load_snapshot(&mut self) {
+        let Some(config) = &self.config.snapshot else {
+            return;
+        };
+        let (path, format) = (config.path.clone(), config.format);
+        let snapshot::Loaded {
+            records,
+            valid_bytes,
+            format: file_format,
+        } = match snapshot::load(&path) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                warn!("Snapshots disabled: {:#}", e);
+                return;
+            }
+        };
+        let now = self.clock.now();
+        let hours = records.len();
+        for mut record in records {
+            // Snapshots taken before hours were clipped may hold fills of the neighbouring hours
+            let loaded = record.fills.len();
+            record
+                .fills
+                .retain(|fill| self.hour_of(fill) == record.hour);
+            self.metrics.out_of_hour_fills += loaded - record.fills.len();
+            let key = self
+                .interner
+                .key(&record.venue, &record.symbol, record.hour);
+            if record.duplicates != DuplicateFills::default() {
+                self.metrics
+                    .duplicate_fills
+                    .insert(key.clone(), record.duplicates);
+            }
+            let entry = CacheEntry {
+                content_hash: content_hash(&record.fills),
+                fills: record.fills,
+                summary: None,
+                duplicates: record.duplicates,
+                fetched_at: now,
+                complete: true,
+            };
+            self.cache_put(key, entry);
+        }
+        if hours > 0 {
+            info!(
+                "Loaded {} hours from snapshot {} ({} cached)",
+                hours,
+                path.display(),
+                self.cache.len()
+            );
+        }
+        let snapshotter =
+            Snapshotter::start(path, valid_bytes, file_format.unwrap_or(format), format);
+        if file_format.is_some_and(|file_format| file_format != format) {
+            info!("Rewriting the snapshot as {:?}", format);
+            snapshotter.compact(self.snapshot_records());
+        }
+        self.snapshotter = Some(snapshotter);
+        self.last_snapshot = Instant::now();
+    }
+
+    /// Returns the complete cached hours with their fills, oldest first, so the most recently
+    /// used hours survive a reload into a smaller cache
+    fn snapshot_records(&self) -> Vec<HourRecord> {
+        self.cache
+            .iter()
+            .rev()
+            .filter(|(_, entry)| entry.is_snapshottable())
+            .map(|(key, entry)| entry.record(key))
+            .collect()
+    }
+
+    /// Snapshots the hours fetched since the last snapshot once enough were fetched
+    /// or enough time has passed
+    fn maybe_snapshot(&mut self) {
+        let Some(config) = &self.config.snapshot else {
+            return;
+        };
+        let due = self.unsnapshotted.len() >= config.every_hours
+            || config.interval.is_some_and(|interval| {
+                !self.unsnapshotted.is_empty() && self.last_snapshot.elapsed() >= interval
+            });
+        if due {
+            self.snapshot();
+        }
+    }
+
+    /// Hands the complete hours fetched since the last snapshot to the snapshot writer,
+    /// or every complete cached hour when the file is due for compaction.
+    /// The fills are copied here and written on the writer's thread.
+    pub fn snapshot(&mut self) {
+        let (Some(config), Some(snapshotter)) = (&self.config.snapshot, &self.snapshotter) else {
+            return;
+        };
+        if snapshotter.needs_compaction(config.compact_bytes) {
+            snapshotter.compact(self.snapshot_records());
+        } else if !self.unsnapshotted.is_empty() {
+            let hours = self
+                .unsnapshotted
+                .iter()
+                .filter_map(|key| Some((key, self.cache.peek(key)?)))
+                .filter(|(_, entry)| entry.is_snapshottable())
+                .map(|(key, entry)| entry.record(key))
+                .collect();
+            snapshotter.append(hours);
+        }
+        self.unsnapshotted.clear();
+        self.last_snapshot = Instant::now();
+    }
+
+    /// Rounds a timestamp in milliseconds down to the start of its hour, in seconds
+    fn get_start_hour(&self, time_ms: i64) -> i64 {
+        let time = time_ms.div_euclid(1000);
+        time - time.rem_euclid(3600)
+    }
+
+    /// Returns the hour a fill belongs in, see `fill_hour`
+    fn hour_of(&self, fill: &Fill) -> i64 {
+        fill_hour(fill)
+    }
+
+    /// Checks, in debug builds, that every fill of an entry belongs in the entry's hour
+    fn debug_assert_in_hour(&self, key: &HourKey, entry: &CacheEntry) {
+        debug_assert!(
+            entry.fills.iter().all(|fill| self.hour_of(fill) == key.2),
+            "Fill outside {} hour {}: {:?}",
+            query::market_name(&key.0, &key.1),
+            key.2,
+            entry.fills.iter().find(|fill| self.hour_of(fill) != key.2)
+        );
+    }
+
+    /// Returns the hours touched by any of the query's windows, including the hours its
+    /// answer looks back into, in ascending order,
+    /// or an error naming the query when they are more than the configured limit
+    fn window_hours(&self, parsed: &Query, query: &str) -> anyhow::Result<BTreeSet<i64>> {
+        let too_many = |hours: u64| {
+            anyhow::anyhow!(
+                "Query touches {} hours, more than the limit of {} hours: {}",
+                hours,
+                self.config.max_range_hours,
+                query
+            )
+        };
+
+        let mut hours = BTreeSet::new();
+        for window in &parsed.windows {
+            let start_hour = self.get_start_hour(window.start_ms - parsed.lookback_ms());
+            let end_hour = self.get_start_hour(window.end_ms);
+            // Refuse a runaway window before enumerating its hours
+            let range_hours = ((end_hour - start_hour) / 3600 + 1).max(0) as u64;
+            if range_hours > self.config.max_range_hours {
+                return Err(too_many(range_hours));
+            }
+            hours.extend((start_hour..=end_hour).step_by(3600));
+        }
+        if hours.len() as u64 > self.config.max_range_hours {
+            return Err(too_many(hours.len() as u64));
+        }
+        Ok(hours)
+    }
+
+    /// Appends the fills of the given venue, symbol and hour to the current fills,
+    /// fetching them from the source on a cache miss or when the cached hour was
+    /// incomplete and its TTL has elapsed.
+    /// Returns whether the hour was served from the cache and how many fills it holds.
+    fn load_hour(
+        &mut self,
+        parsed: &Query,
+        hour: i64,
+        fetch_time: &mut Duration,
+    ) -> anyhow::Result<(bool, usize)> {
+        let (venue, symbol) = (parsed.venue.as_str(), parsed.symbol.as_str());
+        let span = debug_span!("hour", venue, symbol, hour, cache_hit = field::Empty);
+        let _enter = span.enter();
+
+        let market = query::market_name(venue, symbol);
+        let now = self.clock.now();
+        let key = self.interner.key(venue, symbol, hour);
+        let jumbo = self
+            .jumbo
+            .as_ref()
+            .filter(|(jumbo_key, _)| *jumbo_key == key);
+        if let Some(entry) = self.cache.get(&key).or(jumbo.map(|(_, entry)| entry)) {
+            if !entry.is_fresh(now, self.config.incomplete_ttl) {
+                debug!(
+                    "Cached {} hour {} was incomplete and is {:?} old, refetching",
+                    market,
+                    hour,
+                    entry.age(now)
+                );
+            } else if let Some(summary) = entry.summary {
+                if Self::is_summarizable(parsed, &summary) {
+                    span.record("cache_hit", true);
+                    debug!("Cache hit for summarized {} hour: {}", market, hour);
+                    self.current_summaries.push(summary);
+                    self.metrics.record_hit(parsed);
+                    return Ok((true, summary.fill_count));
+                }
+                debug!(
+                    "Cached {} hour {} is summarized and the query needs its fills, refetching",
+                    market, hour
+                );
+            } else {
+                span.record("cache_hit", true);
+                debug!("Cache hit for {} hour: {}", market, hour);
+                self.current_fills.extend(&entry.fills);
+                self.current_hours.insert(hour, entry.fetched_at);
+                self.metrics.record_hit(parsed);
+                return Ok((true, entry.fills.len()));
+            }
+        }
+
+        span.record("cache_hit", false);
+        debug!("Cache miss for {} hour: {}", market, hour);
+        self.metrics.record_miss(parsed);
+        let fetch_start = Instant::now();
+        // A fetch already in the background was counted by the query that started it
+        let awaited = self.pending_fetches.contains_key(&key);
+        let fetched = self.refresh_hour(&key, Some(parsed));
+        // A fetch left to the background was still a call, made by this query
+        if fetched.is_err() && !awaited && self.pending_fetches.contains_key(&key) {
+            self.metrics.record_api_call(parsed);
+        }
+        let mut fetched = fetched?;
+        *fetch_time += fetch_start.elapsed();
+        let partials = std::mem::take(&mut fetched.partials);
+        let mut fills = match fetched.summary {
+            Some(_) => std::mem::take(&mut fetched.fills),
+            None => Vec::new(),
+        };
+        let mut entry = self.cache_entry(&key, fetched, now);
+        let fill_count = entry.fill_count();
+        // The fills of an oversized hour only live as long as the query needs them
+        if entry.summary.is_some() {
+            self.metrics.streamed_hours += 1;
+            debug!(
+                "Streamed {} hour {} of {} fills, caching their aggregates",
+                market, hour, fill_count
+            );
+            if Self::is_foldable(parsed) {
+                self.current_partials
+                    .extend(partials.into_iter().enumerate());
+            } else {
+                self.current_fills.extend(fills);
+                self.current_hours.insert(hour, entry.fetched_at);
+            }
+        } else if self.is_oversized(&entry) {
+            self.metrics.streamed_hours += 1;
+            debug!(
+                "Summarizing {} hour {} of {} fills instead of caching them",
+                market, hour, fill_count
+            );
+            fills = std::mem::take(&mut entry.fills);
+            let summary = HourSummary::of(&fills, self.dedup_key(venue));
+            entry.summary = Some(summary);
+            if Self::is_summarizable(parsed, &summary) {
+                self.current_summaries.push(summary);
+            } else {
+                self.current_fills.extend(fills);
+                self.current_hours.insert(hour, entry.fetched_at);
+            }
+        } else {
+            self.current_fills.extend(&entry.fills);
+            self.current_hours.insert(hour, entry.fetched_at);
+        }
+        if !awaited {
+            self.metrics.record_api_call(parsed);
+        }
+        if fill_count > 0 {
+            let newest = self
+                .newest_fetched
+                .entry((venue.to_string(), symbol.to_string()))
+                .or_insert(hour);
+            *newest = (*newest).max(hour);
+        }
+        self.admit(key, entry);
+        Ok((false, fill_count))
+    }
+
+    /// Caches a fetched entry, unless its hour holds more fills than the cache admits:
+    /// such an hour takes the jumbo slot instead, replacing the hour there,
+    /// so it doesn't evict any cached hour
+    fn admit(&mut self, key: HourKey, entry: CacheEntry) {
+        if self
+            .jumbo
+            .as_ref()
+            .is_some_and(|(jumbo_key, _)| *jumbo_key == key)
+        {
+            self.jumbo = None;
+        }
+        let too_large = self
+            .config
+            .max_cached_hour_fills
+            .is_some_and(|max| entry.fills.len() > max);
+        if too_large {
+            self.metrics.jumbo_hours += 1;
+            debug!(
+                "Keeping {} hour {} of {} fills in the jumbo slot instead of the cache",
+                query::market_name(&key.0, &key.1),
+                key.2,
+                entry.fills.len()
+            );
+            self.cache_pop(&key);
+            self.debug_assert_in_hour(&key, &entry);
+            self.jumbo = Some((key, entry));
+            return;
+        }
+        if self.snapshotter.is_some() && entry.complete && entry.summary.is_none() {
+            self.unsnapshotted.push(key.clone());
+        }
+        self.cache_put(key, entry);
+    }
+
+    /// Puts an entry in the cache, keeping the sequence index in step with the entry
+    /// and with the hour the LRU evicts for it, if any
+    fn cache_put(&mut self, key: HourKey, entry: CacheEntry) {
+        self.debug_assert_in_hour(&key, &entry);
+        if let Some(index) = &mut self.seq_index {
+            index.insert(&key, &entry.fills);
+        }
+        self.cached_bytes += Self::entry_bytes(&key, &entry);
+        if let Some((evicted, evicted_entry)) = self.cache.push(key.clone(), entry) {
+            self.cached_bytes -= Self::entry_bytes(&evicted, &evicted_entry);
+            if let Some(index) = self.seq_index.as_mut().filter(|_| evicted != key) {
+                index.remove(&evicted);
+            }
+        }
+        self.enforce_cache_budget();
+        self.peak_cached_bytes = self.peak_cached_bytes.max(self.cached_bytes);
+    }
+
+    /// Removes an entry from the cache and the sequence index
+    fn cache_pop(&mut self, key: &HourKey) -> Option<CacheEntry> {
+        if let Some(index) = &mut self.seq_index {
+            index.remove(key);
+        }
+        let entry = self.cache.pop(key)?;
+        self.cached_bytes -= Self::entry_bytes(key, &entry);
+        Some(entry)
+    }
+
+    /// Returns the entry of the hour, in the cache or the jumbo slot, without promoting it
+    fn peek_entry(&self, key: &HourKey) -> Option<&CacheEntry> {
+        self.cache.peek(key).or(self
+            .jumbo
+            .as_ref()
+            .filter(|(jumbo_key, _)| jumbo_key == key)
+            .map(|(_, entry)| entry))
+    }
+
+    /// Returns whether the fetched entry holds more fills than the streaming threshold
+    fn is_oversized(&self, entry: &CacheEntry) -> bool {
+        self.config
+            .stream_threshold
+            .is_some_and(|threshold| entry.fills.len() > threshold)
+    }
+
+    /// Returns whether the query is answered from aggregates of the fills within its windows:
+    /// it counts trades or sums volume, without looking back before its windows
+    fn is_foldable(parsed: &Query) -> bool {
+        matches!(
+            parsed.kind,
+            QueryKind::Count
+                | QueryKind::RawCount
+                | QueryKind::Buys
+                | QueryKind::Sells
+                | QueryKind::Volume
+                | QueryKind::FillRate
+        ) && parsed.lookback_ms() == 0
+    }
+
+    /// Returns whether the query can be answered from the aggregates of a summarized hour
+    /// instead of its fills: it is foldable, and each of its windows holds every fill of
+    /// the hour or none of them. Decided from the times of the hour's first and last fills,
+    /// so `C H H+3600`, whose window holds the fills within (H+0.999, H+3600.999], covers
+    /// an hour whose first fill comes after its first second.
+    fn is_summarizable(parsed: &Query, summary: &HourSummary) -> bool {
+        Self::is_foldable(parsed)
+            && parsed
+                .windows
+                .iter()
+                .all(|window| summary.coverage(window).is_some())
+    }
+
+    /// Counts what was skipped or dropped in a fetched hour and turns it into a cache entry
+    fn cache_entry(
+        &mut self,
+        key: &HourKey,
+        fetched: FetchedHour,
+        now: DateTime<Utc>,
+    ) -> CacheEntry {
+        let (venue, symbol, hour) = (&key.0, &key.1, key.2);
+        let market = query::market_name(venue, symbol);
+        let FetchedHour {
+            fills,
+            malformed,
+            unknown_fields,
+            invalid,
+            out_of_hour,
+            duplicates,
+            unknown_direction,
+            content_hash,
+            summary,
+            partials: _,
+        } = fetched;
+        for field in unknown_fields {
+            let hours = self
+                .metrics
+                .unknown_fields
+                .entry(field.clone())
+                .or_default();
+            if *hours == 0 {
+                info!(
+                    "Ignoring fill field {} unknown to this version, first sent in {} hour {}",
+                    field, market, hour
+                );
+            }
+            *hours += 1;
+        }
+        if malformed > 0 {
+            warn!(
+                "Skipped {} malformed fills in {} hour {}",
+                malformed, market, hour
+            );
+            self.metrics.malformed_fills += malformed;
+        }
+        if unknown_direction > 0 {
+            debug!(
+                "{} fills of unknown direction in {} hour {}",
+                unknown_direction, market, hour
+            );
+            self.metrics.unknown_direction_fills += unknown_direction;
+        }
+        if out_of_hour > 0 {
+            debug!(
+                "Dropped {} fills outside {} hour {}",
+                out_of_hour, market, hour
+            );
+            self.metrics.out_of_hour_fills += out_of_hour;
+        }
+        if invalid > 0 {
+            warn!(
+                "Dropped {} fills with a non-positive price or quantity in {} hour {}",
+                invalid, market, hour
+            );
+            self.metrics.invalid_fills.insert(key.clone(), invalid);
+        } else {
+            self.metrics.invalid_fills.remove(key);
+        }
+        if duplicates != DuplicateFills::default() {
+            debug!(
+                "{} identical and {} conflicting duplicate fills in {} hour {}",
+                duplicates.identical, duplicates.conflicting, market, hour
+            );
+            self.metrics.duplicate_fills.insert(key.clone(), duplicates);
+        } else {
+            self.metrics.duplicate_fills.remove(key);
+        }
+        let fill_count = summary.map_or(fills.len(), |summary| summary.fill_count);
+        CacheEntry {
+            content_hash: self.check_content(key, content_hash, fill_count),
+            // The fills kept of a streamed hour are the query's, not the hour's
+            fills: if summary.is_some() { Vec::new() } else { fills },
+            summary,
+            duplicates,
+            fetched_at: now,
+            // The hour containing "now" may still receive fills, so it is never cached as complete
+            complete: hour + 3600 <= now.timestamp(),
+        }
+    }
+
+    /// Returns the content hash of the fills fetched for an hour, counting and logging the
+    /// hour as changed when it is cached with other fills, as after an upstream correction
+    fn check_content(&mut self, key: &HourKey, hash: u64, fill_count: usize) -> u64 {
+        let Some(cached) = self.peek_entry(key) else {
+            return hash;
+        };
+        if cached.content_hash != hash {
+            warn!(
+                old_fills = cached.fill_count(),
+                new_fills = fill_count,
+                "Hour content changed after initial fetch: {} hour {} was cached with {} fills \
+                 fetched {:?} ago and now has {}",
+                query::market_name(&key.0, &key.1),
+                key.2,
+                cached.fill_count(),
+                cached.age(self.clock.now()),
+                fill_count
+            );
+            self.metrics.changed_hours += 1;
+        }
+        hash
+    }
+
+    /// Refreshes the cached hours that were fetched before they ended and whose TTL has
+    /// elapsed, as configured: refetched in place, without promoting them in the LRU,
+    /// or evicted so that the next query touching them refetches them. The hour in the
+    /// jumbo slot is refreshed the same way.
+    fn refresh_stale(&mut self, refresh: StaleRefresh) {
+        let now = self.clock.now();
+        let jumbo = self.jumbo.as_ref().map(|(key, entry)| (key, entry));
+        let stale = self
+            .cache
+            .iter()
+            .chain(jumbo)
+            .filter(|(_, entry)| !entry.is_fresh(now, self.config.incomplete_ttl))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        for key in stale {
+            let market = query::market_name(&key.0, &key.1);
+            let in_jumbo = self
+                .jumbo
+                .as_ref()
+                .is_some_and(|(jumbo_key, _)| *jumbo_key == key);
+            if refresh == StaleRefresh::Invalidate {
+                debug!("Evicting stale {} hour {}", market, key.2);
+                if in_jumbo {
+                    self.jumbo = None;
+                } else {
+                    self.cache_pop(&key);
+                }
+                continue;
+            }
+            match self.refresh_hour(&key, None) {
+                Ok(fetched) => {
+                    debug!("Refetched stale {} hour {}", market, key.2);
+                    let mut entry = self.cache_entry(&key, fetched, now);
+                    if self.is_oversized(&entry) {
+                        entry.summarize(self.dedup_key(&key.0));
+                    }
+                    if entry.summary.is_some() {
+                        self.metrics.streamed_hours += 1;
+                    }
+                    self.debug_assert_in_hour(&key, &entry);
+                    let too_large = self
+                        .config
+                        .max_cached_hour_fills
+                        .is_some_and(|max| entry.fills.len() > max);
+                    if too_large || in_jumbo {
+                        self.admit(key, entry);
+                    } else if let Some(cached) = self.cache.peek_mut(&key) {
+                        if let Some(index) = &mut self.seq_index {
+                            index.insert(&key, &entry.fills);
+                        }
+                        self.cached_bytes += Self::entry_bytes(&key, &entry);
+                        self.cached_bytes -= Self::entry_bytes(&key, cached);
+                        *cached = entry;
+                        self.enforce_cache_budget();
+                        self.peak_cached_bytes = self.peak_cached_bytes.max(self.cached_bytes);
+                    }
+                }
+                // Left in place, so the next query touching the hour retries
+                Err(e) => warn!("Failed to refetch stale {} hour {}: {:#}", market, key.2, e),
+            }
+        }
+    }
+
+    /// Fetches one hour of the venue from its source, bypassing the cache, and checks its fills
+    /// (see `checked_hour`). Fails when the query's deadline passes first.
+    fn fetch_hour(&mut self, venue: &str, symbol: &str, hour: i64) -> anyhow::Result<FetchedHour> {
+        let key = self.interner.key(venue, symbol, hour);
+        // A fetch the deadline of an earlier query left behind is awaited rather than repeated
+        let response = match self.pending_fetches.remove(&key) {
+            Some(pending) => self.await_fetch(key, pending)?,
+            None => self.request_hour(key)?,
+        };
+        self.checked_hour(venue, symbol, hour, response)
+    }
+
+    /// Refetches the hour, by a differential refresh when configured and possible (see
+    /// `fetch_newer_fills`), and else whole, streamed for the query when possible (see
+    /// `stream_hour`)
+    fn refresh_hour(
+        &mut self,
+        key: &HourKey,
+        parsed: Option<&Query>,
+    ) -> anyhow::Result<FetchedHour> {
+        let (venue, symbol, hour) = (&*key.0, &*key.1, key.2);
+        if self.config.differential_refresh {
+            if let Some(fetched) = self.fetch_newer_fills(key)? {
+                return Ok(fetched);
+            }
+        }
+        if self.is_streamable(key)? {
+            return self.stream_hour(key, parsed);
+        }
+        self.fetch_hour(venue, symbol, hour)
+    }
+
+    /// Returns whether the hour is streamed when fetched (see `stream_hour`): under a
+    /// streaming threshold, from a source serving ranges within an hour, without a query
+    /// deadline or a fetch of the hour already running
+    fn is_streamable(&self, key: &HourKey) -> anyhow::Result<bool> {
+        if self.config.stream_threshold.is_none()
+            || self.deadline.is_some()
+            || self.pending_fetches.contains_key(key)
+        {
+            return Ok(false);
+        }
+        Ok(self
+            .source_of(&key.0, key.2)?
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .serves_partial_hours())
+    }
+
+    /// Fetches the hour in pages of `PAGE_SECS`, checking and folding each page as it
+    /// arrives (see `HourFold`), so an hour past the streaming threshold never holds more
+    /// fills at once than one page and the fills the query needs. Its pages count as one
+    /// request against the API call budget. The query's windows decide what is kept past
+    /// the threshold: the aggregates within each window for a foldable query, and else the
+    /// fills within the windows and their lookback; without a query, nothing.
+    fn stream_hour(
+        &mut self,
+        key: &HourKey,
+        parsed: Option<&Query>,
+    ) -> anyhow::Result<FetchedHour> {
+        let (venue, symbol, hour) = (&*key.0, &*key.1, key.2);
+        let (aggregated, needed) = match parsed {
+            Some(parsed) if Self::is_foldable(parsed) => (parsed.windows.clone(), Vec::new()),
+            Some(parsed) => {
+                let lookback_ms = parsed.lookback_ms();
+                let needed = parsed
+                    .windows
+                    .iter()
+                    .map(|window| (window.start_ms - lookback_ms, window.end_ms))
+                    .collect();
+                (Vec::new(), needed)
+            }
+            None => (Vec::new(), Vec::new()),
+        };
+        let mut fold = HourFold::new(
+            query::market_name(venue, symbol),
+            hour,
+            self.config.stream_threshold.unwrap_or(usize::MAX),
+            (self.config.strict_data, self.config.strict_direction),
+            self.dedup_key(venue),
+            &aggregated,
+            needed,
+        );
+
+        let source = self.reserve_request(key)?;
+        let mut source = source.lock().unwrap_or_else(PoisonError::into_inner);
+        for page_start in (hour..hour + 3600).step_by(PAGE_SECS as usize) {
+            fold.fold(source.get_fills(symbol, page_start, page_start + PAGE_SECS)?)?;
+        }
+        drop(source);
+
+        let oversized = fold.is_oversized();
+        let duplicates = fold.duplicates();
+        let partials = if oversized {
+            fold.partials()
+        } else {
+            Vec::new()
+        };
+        let mut fills = fold.fills;
+        // As in `checked_hour`, a stable sort keeps the fills of one taker trade as sent
+        fills.sort_by_key(|fill| (fill.time, fill.sequence_number));
+        Ok(FetchedHour {
+            fills,
+            malformed: fold.malformed,
+            unknown_fields: fold.unknown_fields,
+            invalid: fold.invalid,
+            out_of_hour: fold.out_of_hour,
+            duplicates,
+            unknown_direction: fold.unknown_direction,
+            content_hash: fold.content_hash,
+            summary: oversized.then_some(fold.summary),
+            partials,
+        })
+    }
+
+    /// Refreshes a stale incomplete hour by requesting only its fills from the second of
+    /// the latest cached fill on, and merging the fills after it into the cached ones,
+    /// checked like a full fetch. The request overlaps the cached fills by that second, and
+    /// the cached fills of it must all come back: a missing one means the upstream dropped
+    /// or rewrote fills since, and the hour is left to a full refetch.
+    /// Returns `None` when the hour can't be refreshed that way: it isn't cached with its
+    /// fills, or has none, its source only serves whole hours, the query has a deadline,
+    /// a fetch of the hour is already running, or there was a gap.
+    fn fetch_newer_fills(&mut self, key: &HourKey) -> anyhow::Result<Option<FetchedHour>> {
+        let (venue, symbol, hour) = (&*key.0, &*key.1, key.2);
+        if self.deadline.is_some() || self.pending_fetches.contains_key(key) {
+            return Ok(None);
+        }
+        let Some(entry) = self
+            .peek_entry(key)
+            .filter(|entry| !entry.complete && entry.summary.is_none())
+        else {
+            return Ok(None);
+        };
+        let Some(latest) = entry.fills.last().map(|fill| fill.time) else {
+            return Ok(None);
+        };
+        let serves_partial_hours = self
+            .source_of(venue, hour)?
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .serves_partial_hours();
+        if !serves_partial_hours {
+            return Ok(None);
+        }
+
+        // Sources serve the fills after the start time, some by the whole second, so the
+        // request starts a second before that of the latest cached fill
+        let overlap_start = latest.timestamp();
+        let source = self.reserve_request(key)?;
+        let response = source
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_fills(symbol, overlap_start - 1, hour + 3600)?;
+
+        let Some(entry) = self.peek_entry(key) else {
+            return Ok(None);
+        };
+        let mut unreturned = HashMap::<Fill, usize>::new();
+        for fill in entry
+            .fills
+            .iter()
+            .filter(|fill| fill.time.timestamp() >= overlap_start)
+        {
+            *unreturned.entry(*fill).or_default() += 1;
+        }
+        let overlapping = unreturned.values().sum::<usize>();
+        let mut newer = Vec::new();
+        for fill in response.fills {
+            if fill.time > latest {
+                newer.push(fill);
+            } else if let Some(count) = unreturned.get_mut(&fill) {
+                *count -= 1;
+                if *count == 0 {
+                    unreturned.remove(&fill);
+                }
+            }
+        }
+        let missing = unreturned.values().sum::<usize>();
+        if missing > 0 {
+            self.metrics.refresh_gaps += 1;
+            warn!(
+                "Differential refresh of {} hour {} is missing {} cached fills, refetching the hour",
+                query::market_name(venue, symbol),
+                hour,
+                missing
+            );
+            return Ok(None);
+        }
+
+        // Cached fills were checked already: only the newer ones add what they drop, and
+        // identical duplicates, kept in the cached fills, are counted again with them
+        let cached = entry.fills.len();
+        let conflicting = entry.duplicates.conflicting;
+        let mut fills = entry.fills.clone();
+        fills.extend(newer);
+        let mut fetched = self.checked_hour(
+            venue,
+            symbol,
+            hour,
+            ApiResponse {
+                fills,
+                malformed: response.malformed,
+                unknown_fields: response.unknown_fields,
+            },
+        )?;
+        fetched.invalid += self
+            .metrics
+            .invalid_fills
+            .get(key)
+            .copied()
+            .unwrap_or_default();
+        fetched.duplicates.conflicting += conflicting;
+        self.metrics.differential_refreshes += 1;
+        self.metrics.fills_not_refetched += cached - overlapping;
+        debug!(
+            "Refreshed {} hour {} with {} newer fills",
+            query::market_name(venue, symbol),
+            hour,
+            fetched.fills.len().saturating_sub(cached)
+        );
+        Ok(Some(fetched))
+    }
+
+    /// Requests one hour from the venue's source. Under a query deadline, the request runs on
+    /// a thread of its own and is awaited until the deadline (see `await_fetch`).
+    fn request_hour(&mut self, key: HourKey) -> anyhow::Result<ApiResponse> {
+        if let Some(deadline) = self.deadline.filter(|deadline| *deadline <= Instant::now()) {
+            return Err(self.deadline_exceeded(deadline));
+        }
+        if self.deadline.is_none() {
+            let source = self.reserve_request(&key)?;
+            let mut source = source.lock().unwrap_or_else(PoisonError::into_inner);
+            return source.get_fills(&key.1, key.2, key.2 + 3600);
+        }
+        let pending = self.spawn_fetch(&key)?;
+        self.await_fetch(key, pending)
+    }
+
+    /// Returns whether the hour is fetched from the archive upstream: whether it starts longer
+    /// ago than `--archive-after-hours`, so the live upstream no longer holds all of it
+    fn is_archived(&self, hour: i64) -> bool {
+        self.config.archive.as_ref().is_some_and(|archive| {
+            hour < self.clock.now().timestamp() - archive.after.as_secs() as i64
+        })
+    }
+
+    /// Returns whether the API call budget is used up
+    fn budget_exhausted(&self) -> bool {
+        self.config
+            .max_api_calls
+            .is_some_and(|budget| self.metrics.upstream_requests >= budget)
+    }
+
+    /// Counts a request for the hour against the API call budget and returns the source of
+    /// its venue, or fails when the budget is used up or the venue is unknown
+    fn reserve_request(&mut self, key: &HourKey) -> anyhow::Result<SharedSource> {
+        let (venue, symbol, hour) = (&*key.0, &*key.1, key.2);
+        if let Some(budget) = self.config.max_api_calls {
+            if self.metrics.upstream_requests >= budget {
+                if !self.budget_exhausted_logged {
+                    self.budget_exhausted_logged = true;
+                    error!(
+                        "API call budget of {} calls exhausted; queries needing the upstream will fail",
+                        budget
+                    );
+                }
+                return Err(anyhow::anyhow!(
+                    "API call budget exhausted ({} of {} calls used), cannot fetch {} hour {}",
+                    self.metrics.upstream_requests,
+                    budget,
+                    query::market_name(venue, symbol),
+                    hour
+                ));
+            }
+        }
+
+        let source = Arc::clone(self.source_of(venue, hour)?);
+        self.metrics.upstream_requests += 1;
+        Ok(source)
+    }
+
+    /// Returns the source the hour of the venue is fetched from
+    fn source_of(&self, venue: &str, hour: i64) -> anyhow::Result<&SharedSource> {
+        if venue != DEFAULT_VENUE {
+            return self
+                .venues
+                .get(venue)
+                .ok_or_else(|| anyhow::anyhow!("Unknown venue @{}", venue));
+        }
+        Ok(match &self.archive {
+            Some(archive) if self.is_archived(hour) => archive,
+            _ => &self.source,
+        })
+    }
+
+    /// Returns the upstream an hour of the venue is fetched from, named like its flags
+    /// (`live`, `archive`, or the venue), its rate limit in requests per second, and the
+    /// requests a fetch of the hour takes: one per page when the upstream is paged and its
+    /// source serves ranges within an hour
+    pub fn hour_upstream(
+        &self,
+        venue: &str,
+        hour: i64,
+    ) -> anyhow::Result<(String, Option<f64>, usize)> {
+        let (name, policy) = if venue != DEFAULT_VENUE {
+            (venue.to_string(), self.config.live_policy)
+        } else {
+            match &self.config.archive {
+                Some(archive) if self.is_archived(hour) => ("archive".to_string(), archive.policy),
+                _ => ("live".to_string(), self.config.live_policy),
+            }
+        };
+        let source = self.source_of(venue, hour)?;
+        let pages = policy
+            .page
+            .filter(|_| {
+                source
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .serves_partial_hours()
+            })
+            .map_or(1, |page| 3600usize.div_ceil(page.as_secs().max(1) as usize));
+        Ok((name, policy.rate_limit, pages))
+    }
+
+    /// Starts a request for the hour on a thread of its own and returns where its response
+    /// will arrive
+    fn spawn_fetch(
+        &mut self,
+        key: &HourKey,
+    ) -> anyhow::Result<Receiver<anyhow::Result<ApiResponse>>> {
+        let source = self.reserve_request(key)?;
+        let (sender, receiver) = mpsc::channel();
+        let (symbol, hour) = (key.1.clone(), key.2);
+        thread::spawn(move || {
+            let mut source = source.lock().unwrap_or_else(PoisonError::into_inner);
+            // The receiver is gone only when the processor is
+            let _ = sender.send(source.get_fills(&symbol, hour, hour + 3600));
+        });
+        Ok(receiver)
+    }
+
+    /// Starts fetching, in the background, the hours an upcoming query will need that are
+    /// neither cached nor being fetched already, so they are cached or on their way by the
+    /// time it is answered (see `collect_fetches` and `fetch_hour`). The fetches count
+    /// against the API call budget and as the query's API calls. Queries that don't plan,
+    /// and hours past the lookahead's limit on fetches in flight or the API call budget,
+    /// are left to be fetched when the query is answered.
+    pub fn prefetch(&mut self, query: &str) {
+        let Some(limit) = self.config.lookahead else {
+            return;
+        };
+        let Ok((parsed, _)) = self.resolve_query(query) else {
+            return;
+        };
+        let Ok(hours) = self.window_hours(&parsed, query) else {
+            return;
+        };
+        for hour in hours {
+            let key = self.interner.key(&parsed.venue, &parsed.symbol, hour);
+            if self.pending_fetches.contains_key(&key) || self.is_cached(&key.0, &key.1, hour) {
+                continue;
+            }
+            if self.pending_fetches.len() >= limit || self.budget_exhausted() {
+                break;
+            }
+            match self.spawn_fetch(&key) {
+                Ok(pending) => {
+                    debug!(
+                        "Prefetching {} hour {}",
+                        query::market_name(&key.0, &key.1),
+                        hour
+                    );
+                    self.metrics.prefetched_hours += 1;
+                    self.metrics.record_api_call(&parsed);
+                    self.pending_fetches.insert(key, pending);
+                }
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Waits for the response of a fetch running on its own thread until the query's deadline.
+    /// A fetch still running then is left to complete in the background: its hour is cached
+    /// by `collect_fetches` before a later query, or awaited by the next query needing it.
+    fn await_fetch(
+        &mut self,
+        key: HourKey,
+        pending: Receiver<anyhow::Result<ApiResponse>>,
+    ) -> anyhow::Result<ApiResponse> {
+        let received = match self.deadline {
+            Some(deadline) => {
+                pending.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => pending.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(response) => response,
+            Err(RecvTimeoutError::Timeout) => {
+                let deadline = self.deadline.unwrap_or_else(Instant::now);
+                debug!(
+                    "Leaving the fetch of {} hour {} to complete in the background",
+                    query::market_name(&key.0, &key.1),
+                    key.2
+                );
+                self.pending_fetches.insert(key, pending);
+                Err(self.deadline_exceeded(deadline))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(anyhow::anyhow!(
+                "The fetch of {} hour {} ended without a response",
+                query::market_name(&key.0, &key.1),
+                key.2
+            )),
+        }
+    }
+
+    /// Caches the hours whose fetches completed in the background since they were prefetched
+    /// or their queries timed out, unless they were cached since. Failed fetches are logged
+    /// and dropped, leaving their hours to be fetched again when needed.
+    fn collect_fetches(&mut self) {
+        let now = self.clock.now();
+        let keys = self.pending_fetches.keys().cloned().collect::<Vec<_>>();
+        for key in keys {
+            let market = query::market_name(&key.0, &key.1);
+            let response = match self.pending_fetches[&key].try_recv() {
+                Ok(response) => response,
+                Err(TryRecvError::Empty) => continue,
+                Err(TryRecvError::Disconnected) => {
+                    Err(anyhow::anyhow!("the fetch ended without a response"))
+                }
+            };
+            self.pending_fetches.remove(&key);
+            let fetched =
+                response.and_then(|response| self.checked_hour(&key.0, &key.1, key.2, response));
+            match fetched {
+                Ok(_) if self.is_cached(&key.0, &key.1, key.2) => {
+                    debug!(
+                        "Fetched {} hour {} in the background, already cached",
+                        market, key.2
+                    );
+                }
+                Ok(fetched) => {
+                    debug!("Fetched {} hour {} in the background", market, key.2);
+                    let mut entry = self.cache_entry(&key, fetched, now);
+                    if self.is_oversized(&entry) {
+                        self.metrics.streamed_hours += 1;
+                        entry.summarize(self.dedup_key(&key.0));
+                    }
+                    self.admit(key, entry);
+                }
+                Err(e) => warn!(
+                    "Background fetch of {} hour {} failed: {:#}",
+                    market, key.2, e
+                ),
+            }
+        }
+    }
+
+    /// Returns the error of a query past its deadline
+    fn deadline_exceeded(&self, deadline: Instant) -> anyhow::Error {
+        DeadlineExceeded {
+            timeout: self.config.query_timeout.unwrap_or_default(),
+            overrun: deadline.elapsed(),
+        }
+        .into()
+    }
+
+    /// Fails the query once its deadline has passed
+    fn check_deadline(&self) -> anyhow::Result<()> {
+        match self.deadline {
+            Some(deadline) if deadline <= Instant::now() => Err(self.deadline_exceeded(deadline)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Drops what can't be cached from the source's response for an hour, as configured,
+    /// and sorts the rest by time.
+    /// Fails when the hour holds an invalid fill or a fill of unknown direction and the data
+    /// or directions are strict.
+    fn checked_hour(
+        &self,
+        venue: &str,
+        symbol: &str,
+        hour: i64,
+        response: ApiResponse,
+    ) -> anyhow::Result<FetchedHour> {
+        let market = query::market_name(venue, symbol);
+        let mut fills = response.fills;
+        // Dropped before caching, so every query over the hour sees the same fills
+        let received = fills.len();
+        if self.config.strict_data {
+            if let Some((index, fill)) = fills.iter().enumerate().find(|(_, fill)| !is_valid(fill))
+            {
+                return Err(anyhow::anyhow!(
+                    "Invalid fill #{} in the {} response for hour {}: non-positive price or quantity: {:?}",
+                    index,
+                    market,
+                    hour,
+                    fill
+                ));
+            }
+        }
+        fills.retain(is_valid);
+        let invalid = received - fills.len();
+        // The upstream sometimes sends fills of the neighbouring hours along, which would be
+        // counted twice once the hours of a window meet; each hour keeps only its own
+        let valid = fills.len();
+        fills.retain(|fill| self.hour_of(fill) == hour);
+        let out_of_hour = valid - fills.len();
+        // Resolved in the order sent, before sorting, so the same fill wins on every fetch
+        let duplicates = Self::resolve_duplicates(&mut fills, self.dedup_key(venue), &market, hour);
+
+        if self.config.strict_direction {
+            if let Some(fill) = fills.iter().find(|fill| fill.side() == Side::Unknown) {
+                return Err(anyhow::anyhow!(
+                    "Fill of unknown direction {} in the {} response for hour {}: {:?}",
+                    fill.direction,
+                    market,
+                    hour,
+                    fill
+                ));
+            }
+        }
+
+        // The upstream doesn't guarantee order; a stable sort keeps the fills of one taker trade as sent
+        fills.sort_by_key(|fill| (fill.time, fill.sequence_number));
+        Ok(FetchedHour {
+            unknown_direction: fills
+                .iter()
+                .filter(|fill| fill.side() == Side::Unknown)
+                .count(),
+            content_hash: content_hash(&fills),
+            fills,
+            malformed: response.malformed,
+            unknown_fields: response.unknown_fields,
+            invalid,
+            out_of_hour,
+            duplicates,
+            summary: None,
+            partials: Vec::new(),
+        })
+    }
+
+    /// Returns how the venue identifies the taker trade of a fill
+    fn dedup_key(&self, venue: &str) -> DedupKey {
+        self.config
+            .dedup_keys
+            .get(venue)
+            .copied()
+            .unwrap_or(DedupKey::SequenceNumber)
+    }
+
+    /// Counts the fills repeating the taker trade of an earlier fill under the dedup key,
+    /// in the order sent, and drops the conflicting ones, keeping the first fill seen of each
+    /// taker trade (see `DuplicateResolver`). Each conflict is logged.
+    fn resolve_duplicates(
+        fills: &mut Vec<Fill>,
+        dedup_key: DedupKey,
+        market: &str,
+        hour: i64,
+    ) -> DuplicateFills {
+        let mut resolver = DuplicateResolver::new(dedup_key, fills.len());
+        fills.retain(|fill| resolver.check(fill, market, hour) != Repeat::Conflicting);
+        resolver.duplicates
+    }
+
+    /// Answers the query again from freshly fetched hours and logs any difference from the
+    /// cached answer. Neither the cache nor the hit/miss counters are touched, and fetches
+    /// left running by an earlier deadline are left to the queries awaiting them, as their
+    /// responses may predate the cached hours.
+    fn verify(&mut self, query: &str, parsed: &Query, hours: &BTreeSet<i64>, cached_result: &str) {
+        let cached_fills = std::mem::take(&mut self.current_fills);
+        let cached_summaries = std::mem::take(&mut self.current_summaries);
+        let cached_partials = std::mem::take(&mut self.current_partials);
+
+        let mut fresh = Ok(());
+        for &hour in hours {
+            let key = self.interner.key(&parsed.venue, &parsed.symbol, hour);
+            let fetched = self.request_hour(key.clone()).and_then(|response| {
+                self.checked_hour(&parsed.venue, &parsed.symbol, hour, response)
+            });
+            match fetched {
+                Ok(fetched) => {
+                    self.check_content(&key, fetched.content_hash, fetched.fills.len());
+                    self.current_fills.extend(&fetched.fills);
+                }
+                Err(e) => {
+                    fresh = Err(e);
+                    break;
+                }
+            }
+        }
+        // Settled by the fetch times of the cached hours, so both answers keep the same hour
+        self.dedup_across_hours(&parsed.venue);
+        let fresh_result = fresh.and_then(|()| self.compute_results(parsed));
+        let fresh_fills = self.current_fills.len();
+        self.current_fills = cached_fills;
+        self.current_summaries = cached_summaries;
+        self.current_partials = cached_partials;
+
+        match fresh_result {
+            Ok(fresh_result) if fresh_result != cached_result => {
+                self.metrics.verify_mismatches += 1;
+                warn!(
+                    cached_result,
+                    fresh_result = fresh_result.as_str(),
+                    "Verification mismatch for \"{}\": cached answer {} ({} fills), fresh answer {} ({} fills)",
+                    query,
+                    cached_result,
+                    self.current_fills.len(),
+                    fresh_result,
+                    fresh_fills
+                );
+            }
+            Ok(_) => debug!("Verified \"{}\" against fresh fetches", query),
+            Err(e) => warn!("Could not verify \"{}\": {:#}", query, e),
+        }
+    }
+
+    /// Drops the fills of a taker trade found in more than one hour of the current fills,
+    /// as when the upstream republishes a boundary fill after a correction, so every query
+    /// type sees each taker trade once. The hour fetched first keeps the trade, the earlier
+    /// hour when both were fetched at once; a republished trade whose fills differ beyond
+    /// their time is logged with both versions.
+    fn dedup_across_hours(&mut self, venue: &str) {
+        if self.current_hours.len() < 2 {
+            return;
+        }
+        let dedup_key = self.dedup_key(venue);
+        let fetched = |hour: i64| {
+            let fetched_at = self.current_hours.get(&hour).copied();
+            (fetched_at.unwrap_or(DateTime::<Utc>::MAX_UTC), hour)
+        };
+
+        // Hour keeping each taker trade, with the trade's first fill in that hour
+        let mut keepers = HashMap::<TradeKey, (i64, Fill)>::with_capacity(self.current_fills.len());
+        for fill in &self.current_fills {
+            let hour = self.hour_of(fill);
+            keepers
+                .entry(trade_key(fill, dedup_key))
+                .and_modify(|(keeper, first)| {
+                    if fetched(hour) < fetched(*keeper) {
+                        (*keeper, *first) = (hour, *fill);
+                    }
+                })
+                .or_insert((hour, *fill));
+        }
+
+        let mut dropped = 0;
+        let mut dropped_trades = HashSet::new();
+        let fills = std::mem::take(&mut self.current_fills);
+        self.current_fills = fills
+            .into_iter()
+            .filter(|fill| {
+                let key = trade_key(fill, dedup_key);
+                let (keeper, first) = keepers[&key];
+                if self.hour_of(fill) == keeper {
+                    return true;
+                }
+                dropped += 1;
+                let retimed = Fill {
+                    time: first.time,
+                    ..*fill
+                };
+                if dropped_trades.insert(key) && retimed != first {
+                    warn!(
+                        "Taker trade with sequence number {} republished differently in hours {} and {}, keeping the hour fetched first: {:?}, dropping: {:?}",
+                        fill.sequence_number,
+                        keeper,
+                        self.hour_of(fill),
+                        first,
+                        fill
+                    );
+                }
+                false
+            })
+            .collect();
+        if dropped > 0 {
+            debug!(
+                "Skipping {} fills of {} taker trades republished in another hour",
+                dropped,
+                dropped_trades.len()
+            );
+        }
+    }
+
+    /// Returns the fills of the current query that fall within (start_ms, end_ms],
+    /// comparing fill times to the millisecond. Like every other reader of the current fills,
+    /// it sees a taker trade of several hours in one of them only, see `dedup_across_hours`.
+    fn fills_in_range(&self, start_ms: i64, end_ms: i64) -> impl Iterator<Item = &Fill> {
+        self.current_fills.iter().filter(move |fill| {
+            fill.time.timestamp_millis() > start_ms && fill.time.timestamp_millis() <= end_ms
+        })
+    }
+
+    /// Formats a decimal answer at the configured scale when there is one, otherwise
+    /// rounded to `places` fraction digits without trailing zeros, or as computed
+    /// when `places` is `None`
+    fn format_decimal(&self, value: Decimal, places: Option<u32>) -> String {
+        match (self.config.scale, places) {
+            (Some(scale), _) => {
+                let mut rounded = value.round_dp_with_strategy(scale, self.config.rounding.into());
+                rounded.rescale(scale);
+                // Rounding a small negative value to zero leaves its sign behind
+                if rounded.is_zero() {
+                    rounded.set_sign_positive(true);
+                }
+                rounded.to_string()
+            }
+            (None, Some(places)) => value.round_dp(places).normalize().to_string(),
+            (None, None) => value.to_string(),
+        }
+    }
+
+    /// Sample standard deviation of the given values using Welford's single-pass algorithm.
+    /// Windows with fewer than two values have no spread and return zero.
+    fn standard_deviation(values: impl Iterator<Item = Decimal>) -> Decimal {
+        let mut count = Decimal::ZERO;
+        let mut mean = Decimal::ZERO;
+        let mut m2 = Decimal::ZERO;
+
+        for value in values {
+            count += Decimal::ONE;
+            let delta = value - mean;
+            mean += delta / count;
+            m2 += delta * (value - mean);
+        }
+
+        if count < Decimal::TWO {
+            return Decimal::ZERO;
+        }
+        (m2 / (count - Decimal::ONE))
+            .sqrt()
+            .unwrap_or(Decimal::ZERO)
+    }
+
+    /// Returns the trailing average price at every step after the window start,
+    /// one "TIMESTAMP AVERAGE" line per point. A point whose trailing window holds no fill
+    /// prints NONE. Two pointers slide over the time-sorted fills, so every fill is
+    /// added and removed at most once.
+    fn moving_average(&self, window: Window, series: Series) -> String {
+        let fills = &self.current_fills;
+        let mut points = Vec::new();
+        let mut first = 0;
+        let mut end = 0;
+        let mut sum = Decimal::ZERO;
+
+        let mut point = window.start_ms.saturating_add(series.step_ms);
+        while point <= window.end_ms {
+            while end < fills.len() && fills[end].time.timestamp_millis() <= point {
+                sum += fills[end].price;
+                end += 1;
+            }
+            while first < end && fills[first].time.timestamp_millis() <= point - series.window_ms {
+                sum -= fills[first].price;
+                first += 1;
+            }
+
+            let average = match end - first {
+                0 => "NONE".to_string(),
+                count => self.format_decimal(sum / Decimal::from(count), Some(8)),
+            };
+            points.push(format!("{} {}", query::format_time(point), average));
+            point = point.saturating_add(series.step_ms);
+        }
+
+        points.join("\n")
+    }
+
+    /// Returns the largest decline from a running peak price to a later price in the window,
+    /// absolute and as an unrounded percentage of that peak. Fills are visited in time order with the
+    /// sequence number breaking ties, as the hours are sorted. Windows with fewer than two
+    /// fills have no decline and return zeros.
+    fn max_drawdown(&self, start_ms: i64, end_ms: i64) -> (Decimal, Decimal) {
+        let mut peak: Option<Decimal> = None;
+        let mut drawdown = Decimal::ZERO;
+        let mut drawdown_percent = Decimal::ZERO;
+
+        for fill in self.fills_in_range(start_ms, end_ms) {
+            let peak = peak.get_or_insert(fill.price);
+            if fill.price > *peak {
+                *peak = fill.price;
+                continue;
+            }
+            drawdown = drawdown.max(*peak - fill.price);
+            if *peak > Decimal::ZERO {
+                drawdown_percent =
+                    drawdown_percent.max((*peak - fill.price) / *peak * Decimal::ONE_HUNDRED);
+            }
+        }
+
+        (drawdown.normalize(), drawdown_percent)
+    }
+
+    /// Returns the number of bursts in the window, the maximal runs of consecutive fills at most
+    /// `gap_ms` apart, and the number of fills of the largest. Fills are visited in time order
+    /// and compared to the millisecond, so fills at the same time are always in one burst, and
+    /// a fill far from any other is a burst of its own. A window without fills has no bursts.
+    fn bursts(&self, start_ms: i64, end_ms: i64, gap_ms: i64) -> (usize, usize) {
+        let mut bursts = 0;
+        let mut largest = 0;
+        let mut size = 0;
+        let mut previous_ms: Option<i64> = None;
+        for fill in self.fills_in_range(start_ms, end_ms) {
+            let time_ms = fill.time.timestamp_millis();
+            match previous_ms {
+                Some(previous_ms) if time_ms - previous_ms <= gap_ms => size += 1,
+                _ => {
+                    bursts += 1;
+                    size = 1;
+                }
+            }
+            largest = largest.max(size);
+            previous_ms = Some(time_ms);
+        }
+        (bursts, largest)
+    }
+
+    /// Formats the average price of fills of the given volume and quantity,
+    /// or NONE when there were no fills
+    fn average_price(&self, volume: Decimal, quantity: Decimal) -> String {
+        if quantity.is_zero() {
+            return "NONE".to_string();
+        }
+        self.format_decimal(volume / quantity, Some(8))
+    }
+
+    /// Computes the answer to the query over the current fills within one of its windows,
+    /// the window at `index` of the query's windows
+    fn compute_result(
+        &self,
+        parsed: &Query,
+        index: usize,
+        window: Window,
+    ) -> anyhow::Result<String> {
+        let kind = parsed.kind;
+        let Window {
+            start_ms, end_ms, ..
+        } = window;
+        // Process fills within time range
+        let mut buy_count = 0;
+        let mut sell_count = 0;
+        let mut unknown_count = 0;
+        let mut fill_count = 0;
+        let mut total_volume = Decimal::ZERO;
+        // Volume and quantity per side, for the average prices
+        let (mut buy_volume, mut buy_quantity) = (Decimal::ZERO, Decimal::ZERO);
+        let (mut sell_volume, mut sell_quantity) = (Decimal::ZERO, Decimal::ZERO);
+        let dedup_key = self.dedup_key(&parsed.venue);
+        let mut unique_trades = HashSet::with_capacity(self.current_fills.len());
+
+        for fill in self.fills_in_range(start_ms, end_ms) {
+            fill_count += 1;
+            if unique_trades.insert(trade_key(fill, dedup_key)) {
+                match fill.side() {
+                    Side::Buy => buy_count += 1,
+                    Side::Sell => sell_count += 1,
+                    Side::Unknown => unknown_count += 1,
+                }
+            }
+            total_volume += fill.quantity * fill.price;
+            match fill.side() {
+                Side::Buy => {
+                    buy_volume += fill.quantity * fill.price;
+                    buy_quantity += fill.quantity;
+                }
+                Side::Sell => {
+                    sell_volume += fill.quantity * fill.price;
+                    sell_quantity += fill.quantity;
+                }
+                Side::Unknown => {}
+            }
+        }
+        // The window holds all fills of a summarized hour or none (see `is_summarizable`),
+        // and the aggregates of a streamed hour within the window were folded for it
+        let summaries = self
+            .current_summaries
+            .iter()
+            .filter(|summary| summary.coverage(&window) == Some(true));
+        let partials = self
+            .current_partials
+            .iter()
+            .filter(|(partial_index, _)| *partial_index == index)
+            .map(|(_, partial)| partial);
+        for summary in summaries.chain(partials) {
+            buy_count += summary.buy_count;
+            sell_count += summary.sell_count;
+            unknown_count += summary.unknown_count;
+            fill_count += summary.fill_count;
+            total_volume += summary.volume;
+        }
+        // Trades of unknown side are neither buys nor sells, but still trades
+        let trade_count = buy_count + sell_count + unknown_count;
+
+        Ok(match kind {
+            QueryKind::Sells => sell_count.to_string(),
+            QueryKind::Buys => buy_count.to_string(),
+            QueryKind::Count => trade_count.to_string(),
+            QueryKind::RawCount => fill_count.to_string(),
+            QueryKind::FillRate => {
+                // Per minute of the query bounds, not of the span between the first and last fill.
+                // Clamping may leave a window that starts after its end, which has no fills.
+                let minutes = Decimal::from(end_ms - start_ms) / Decimal::from(60_000);
+                if minutes <= Decimal::ZERO {
+                    self.format_decimal(Decimal::ZERO, None)
+                } else {
+                    self.format_decimal(Decimal::from(trade_count) / minutes, Some(8))
+                }
+            }
+            QueryKind::Volume => self.format_decimal(total_volume, None),
+            QueryKind::BuyVwap => self.average_price(buy_volume, buy_quantity),
+            QueryKind::SellVwap => self.average_price(sell_volume, sell_quantity),
+            QueryKind::PriceStdDev => self.format_decimal(
+                Self::standard_deviation(
+                    self.fills_in_range(start_ms, end_ms).map(|fill| fill.price),
+                ),
+                Some(8),
+            ),
+            QueryKind::ReturnStdDev => {
+                let prices = self
+                    .fills_in_range(start_ms, end_ms)
+                    .map(|fill| fill.price)
+                    .collect::<Vec<Decimal>>();
+                let log_returns = prices
+                    .windows(2)
+                    .filter_map(|pair| (pair[1] / pair[0]).checked_ln());
+                self.format_decimal(Self::standard_deviation(log_returns), Some(8))
+            }
+            QueryKind::Coverage => {
+                let mut coverage: Option<(i64, i64, usize)> = None;
+                for fill in self.fills_in_range(start_ms, end_ms) {
+                    let time = fill.time.timestamp();
+                    coverage = Some(match coverage {
+                        Some((earliest, latest, count)) => {
+                            (earliest.min(time), latest.max(time), count + 1)
+                        }
+                        None => (time, time, 1),
+                    });
+                }
+                match coverage {
+                    Some((earliest, latest, count)) => {
+                        format!("{} {} {}", earliest, latest, count)
+                    }
+                    None => "NONE".to_string(),
+                }
+            }
+            QueryKind::MakerCount | QueryKind::TakerCount | QueryKind::TakerVolumeShare => {
+                let mut flagged = false;
+                let mut maker_count = 0;
+                let mut taker_count = 0;
+                let mut maker_volume = Decimal::ZERO;
+                let mut taker_volume = Decimal::ZERO;
+                for fill in self.fills_in_range(start_ms, end_ms) {
+                    match fill.liquidity {
+                        Some(Liquidity::Maker) => {
+                            maker_count += 1;
+                            maker_volume += fill.quantity * fill.price;
+                        }
+                        Some(Liquidity::Taker) => {
+                            taker_count += 1;
+                            taker_volume += fill.quantity * fill.price;
+                        }
+                        None => continue,
+                    }
+                    flagged = true;
+                }
+
+                // Zeros would look legitimate, so say so when the data can't answer
+                if !flagged {
+                    "UNSUPPORTED".to_string()
+                } else if kind == QueryKind::MakerCount {
+                    maker_count.to_string()
+                } else if kind == QueryKind::TakerCount {
+                    taker_count.to_string()
+                } else if maker_volume + taker_volume == Decimal::ZERO {
+                    self.format_decimal(Decimal::ZERO, None)
+                } else {
+                    self.format_decimal(taker_volume / (maker_volume + taker_volume), Some(8))
+                }
+            }
+            QueryKind::Fees => self.fees(start_ms, end_ms),
+            QueryKind::Burst => match parsed.burst_gap_ms {
+                Some(gap_ms) => {
+                    let (bursts, largest) = self.bursts(start_ms, end_ms, gap_ms);
+                    format!("{} {}", bursts, largest)
+                }
+                None => return Err(anyhow::anyhow!("BURST query without a gap")),
+            },
+            QueryKind::MaxDrawdown => {
+                self.format_decimal(self.max_drawdown(start_ms, end_ms).0, None)
+            }
+            QueryKind::MaxDrawdownPercent => {
+                self.format_decimal(self.max_drawdown(start_ms, end_ms).1, Some(8))
+            }
+            QueryKind::MovingAverage => match parsed.series {
+                Some(series) => self.moving_average(window, series),
+                None => return Err(anyhow::anyhow!("MA query without series parameters")),
+            },
+        })
+    }
+
+    /// Sums the fees of the fills within (start_ms, end_ms] per currency: the total alone when
+    /// they are charged in one currency, "CURRENCY=TOTAL" per currency in currency order
+    /// otherwise, followed by "PARTIAL=N" when N fills had no fee or no fee currency.
+    /// Without fee data on any fill of a non-empty window the answer is "UNSUPPORTED",
+    /// like the liquidity queries'.
+    fn fees(&self, start_ms: i64, end_ms: i64) -> String {
+        let mut totals = BTreeMap::<Currency, Decimal>::new();
+        let mut fills = 0;
+        let mut missing = 0;
+        for fill in self.fills_in_range(start_ms, end_ms) {
+            fills += 1;
+            match (fill.fee, fill.fee_currency) {
+                (Some(fee), Some(currency)) => *totals.entry(currency).or_default() += fee,
+                _ => missing += 1,
+            }
+        }
+
+        let mut tokens = match totals.len() {
+            0 if fills > 0 => return "UNSUPPORTED".to_string(),
+            0 => vec![self.format_decimal(Decimal::ZERO, None)],
+            1 => totals
+                .values()
+                .map(|&total| self.format_decimal(total, None))
+                .collect(),
+            _ => totals
+                .iter()
+                .map(|(currency, &total)| {
+                    format!("{}={}", currency, self.format_decimal(total, None))
+                })
+                .collect(),
+        };
+        if missing > 0 {
+            tokens.push(format!("PARTIAL={}", missing));
+        }
+        tokens.join(" ")
+    }
+
+    /// Computes the answer for every window over the current fills, separated by spaces
+    fn compute_results(&self, parsed: &Query) -> anyhow::Result<String> {
+        let results = parsed
+            .windows
+            .iter()
+            .enumerate()
+            .map(|(index, &window)| self.compute_result(parsed, index, window))
+            .collect::<anyhow::Result<Vec<String>>>()?;
+        Ok(results.join(" "))
+    }
+
+    /// Parses the query and applies the future policy to its windows.
+    /// Returns the query and whether any of its windows was clamped to the current time.
+    fn resolve_query(&self, query: &str) -> anyhow::Result<(Query, bool)> {
+        let now = self.clock.now();
+        let mut parsed = Query::parse(
+            query,
+            &self.config.default_symbol,
+            self.config.window_bounds,
+            now,
+        )?;
+        if parsed.venue != DEFAULT_VENUE && !self.venues.contains_key(&parsed.venue) {
+            let configured = std::iter::once(DEFAULT_VENUE)
+                .chain(self.venues.keys().map(String::as_str))
+                .collect::<Vec<&str>>();
+            return Err(anyhow::anyhow!(
+                "Unknown venue @{} (configured venues: {}): {}",
+                parsed.venue,
+                configured.join(", "),
+                query
+            ));
+        }
+        for window in &parsed.windows {
+            debug!(
+                "Query window resolved to ({}ms, {}ms]",
+                window.start_ms, window.end_ms
+            );
+        }
+
+        // Queries reaching into the future would fetch hours that have no data yet. An end
+        // within the current second, as the current time given in seconds covers, is not.
+        let now_ms = now.timestamp_millis();
+        let mut clamped = false;
+        for window in parsed
+            .windows
+            .iter_mut()
+            .filter(|window| window.end_ms.div_euclid(1000) > now_ms.div_euclid(1000))
+        {
+            match self.config.future_policy {
+                FuturePolicy::Reject => {
+                    return Err(anyhow::anyhow!(
+                        "Query end time {}ms is beyond the current time {}ms: {}",
+                        window.end_ms,
+                        now_ms,
+                        query
+                    ))
+                }
+                FuturePolicy::Clamp => {
+                    debug!(
+                        "Clamping end time {}ms to the current time {}ms",
+                        window.end_ms, now_ms
+                    );
+                    window.end_ms = now_ms;
+                    clamped = true;
+                }
+            }
+        }
+
+        self.end_latest_at_newest_hour(&mut parsed, now_ms);
+        Ok((parsed, clamped))
+    }
+
+    /// Returns the newest hour of the market the upstream has, in Unix seconds: the one its
+    /// source reports, or else the hour after the newest one a fetch returned fills for,
+    /// so that asking again follows an upstream that moved on
+    fn newest_hour(&self, venue: &str, symbol: &str, now: i64) -> Option<i64> {
+        let source = self.source_of(venue, now - now.rem_euclid(3600)).ok()?;
+        // A source busy with a fetch in the background isn't waited for
+        let reported = source
+            .try_lock()
+            .ok()
+            .and_then(|source| source.newest_hour(symbol));
+        reported.or_else(|| {
+            self.newest_fetched
+                .get(&(venue.to_string(), symbol.to_string()))
+                .map(|hour| hour + 3600)
+        })
+    }
+
+    /// Ends the query's LATEST windows at the end of the newest hour the upstream has, when
+    /// that is before now: a clock past the upstream's data doesn't stretch them over hours
+    /// it has nothing for
+    fn end_latest_at_newest_hour(&self, parsed: &mut Query, now_ms: i64) {
+        if parsed.latest_end_ms().is_none() {
+            return;
+        }
+        let Some(hour) = self.newest_hour(&parsed.venue, &parsed.symbol, now_ms.div_euclid(1000))
+        else {
+            return;
+        };
+        let newest_ms = (hour + 3600) * 1000 - 1;
+        for window in parsed
+            .windows
+            .iter_mut()
+            .filter(|window| window.open_ended && window.end_ms > newest_ms)
+        {
+            debug!(
+                "Ending LATEST window at {}ms, the end of the newest hour {} of the upstream",
+                newest_ms, hour
+            );
+            window.end_ms = newest_ms.max(window.start_ms);
+        }
+    }
+
+    /// Returns the venue, the symbol and the hours the query would load, without fetching anything
+    pub fn plan_query(&self, query: &str) -> anyhow::Result<(String, String, BTreeSet<i64>)> {
+        let (parsed, _) = self.resolve_query(query)?;
+        let hours = self.window_hours(&parsed, query)?;
+        Ok((parsed.venue, parsed.symbol, hours))
+    }
+
+    /// Drops every cached hour, of any venue and symbol, that starts before the cutoff
+    /// in Unix seconds. Returns the number of hours and approximate bytes freed.
+    pub fn evict_older_than(&mut self, cutoff: i64) -> (usize, usize) {
+        let keys = self
+            .cache
+            .iter()
+            .filter(|((_, _, hour), _)| *hour < cutoff)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        let mut freed_bytes = 0;
+        for key in &keys {
+            if let Some(entry) = self.cache_pop(key) {
+                freed_bytes += Self::entry_bytes(key, &entry);
+            }
+        }
+        if let Some((key, entry)) = self.jumbo.take_if(|((_, _, hour), _)| *hour < cutoff) {
+            freed_bytes += Self::entry_bytes(&key, &entry);
+        }
+        if !keys.is_empty() {
+            info!(
+                evicted_hours = keys.len(),
+                freed_bytes,
+                "Evicted {} cached hours starting before {} ({} bytes)",
+                keys.len(),
+                cutoff,
+                freed_bytes
+            );
+        }
+        (keys.len(), freed_bytes)
+    }
+
+    /// Answers an "EVICT_BEFORE TIME" line (any query time format) by evicting the cached
+    /// hours starting before TIME, as "EVICTED HOURS BYTES"
+    pub fn evict_before(&mut self, line: &str) -> anyhow::Result<String> {
+        let [_, time] = query::tokenize(line)[..] else {
+            return Err(anyhow::anyhow!(
+                "Invalid EVICT_BEFORE command: {} (expected EVICT_BEFORE TIME)",
+                line
+            ));
+        };
+        let cutoff = query::parse_time("TIME", time, self.clock.now())?.div_euclid(1000);
+        let (hours, bytes) = self.evict_older_than(cutoff);
+        Ok(format!("EVICTED {} {}", hours, bytes))
+    }
+
+    /// Answers an "EXPORT_PARQUET PATH" line by writing the fills of every cached hour to a
+    /// Parquet file at PATH, in venue, symbol and hour order, as "EXPORTED HOURS ROWS".
+    /// Summarized hours have no fills to export and are left out.
+    pub fn export_parquet(&self, line: &str) -> anyhow::Result<String> {
+        let path = line
+            .split_once(char::is_whitespace)
+            .map(|(_, path)| path.trim())
+            .filter(|path| !path.is_empty())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Invalid EXPORT_PARQUET command: {} (expected EXPORT_PARQUET PATH)",
+                    line
+                )
+            })?;
+        #[cfg(feature = "arrow")]
+        {
+            let mut hours = self
+                .cache
+                .iter()
+                .filter(|(_, entry)| entry.summary.is_none())
+                .map(|(key, entry)| (key, entry.fills.as_slice()))
+                .collect::<Vec<export::Hour>>();
+            hours.sort_unstable_by_key(|&(key, _)| key);
+            let rows = export::write_parquet(std::path::Path::new(path), &hours)?;
+            Ok(format!("EXPORTED {} {}", hours.len(), rows))
+        }
+        #[cfg(not(feature = "arrow"))]
+        Err(anyhow::anyhow!(
+            "Cannot export to {}: EXPORT_PARQUET needs a build with --features arrow",
+            path
+        ))
+    }
+
+    /// Answers a "STATS" line with the counters of the queries processed so far, in total
+    /// and per query type, on one line (see `Metrics::stats_line`)
+    pub fn stats(&self, line: &str) -> anyhow::Result<String> {
+        if query::tokenize(line).len() != 1 {
+            return Err(anyhow::anyhow!(
+                "Invalid STATS command: {} (expected STATS)",
+                line
+            ));
+        }
+        Ok(self.metrics.stats_line())
+    }
+
+    /// Answers a "DQ [@VENUE] [SYMBOL] HOUR" line with the duplicate taker trades seen when
+    /// the hour containing HOUR (any query time format) was last fetched, as
+    /// "identical=N conflicting=M", or NOT_CACHED when the hour isn't in the cache
+    pub fn data_quality(&self, line: &str) -> anyhow::Result<String> {
+        let invalid = || {
+            anyhow::anyhow!(
+                "Invalid DQ command: {} (expected DQ [@VENUE] [SYMBOL] HOUR)",
+                line
+            )
+        };
+        let tokens = query::tokenize(line);
+        let parts = tokens.get(1..).unwrap_or_default();
+        let (venue, rest) = match parts.split_first() {
+            Some((venue, rest)) if venue.starts_with('@') => (&venue[1..], rest),
+            _ => (DEFAULT_VENUE, parts),
+        };
+        let (symbol, time) = match rest {
+            [time] => (self.config.default_symbol.as_str(), *time),
+            [symbol, time] => (*symbol, *time),
+            _ => return Err(invalid()),
+        };
+        let hour = self.get_start_hour(query::parse_time("HOUR", time, self.clock.now())?);
+
+        let key = self.interner.get_key(venue, symbol, hour);
+        Ok(match key.as_ref().and_then(|key| self.peek_entry(key)) {
+            Some(entry) => format!(
+                "identical={} conflicting={}",
+                entry.duplicates.identical, entry.duplicates.conflicting
+            ),
+            None => "NOT_CACHED".to_string(),
+        })
+    }
+
+    /// Answers a "CS [@VENUE] [SYMBOL] FROM TO" line with the number of taker trades, or a
+    /// "VS ..." line with the USD volume, of the cached fills whose sequence number is
+    /// between FROM and TO (inclusive). Never fetches: when the cached hours don't
+    /// provably hold the whole range, the answer is COVERAGE_UNKNOWN (see `sequence_hours`).
+    pub fn sequence_query(&self, line: &str) -> anyhow::Result<String> {
+        let invalid = || {
+            anyhow::anyhow!(
+                "Invalid sequence query: {} (expected CS or VS [@VENUE] [SYMBOL] FROM TO)",
+                line
+            )
+        };
+        let parts = query::tokenize(line);
+        let Some((word, parts)) = parts.split_first() else {
+            return Err(invalid());
+        };
+        let (venue, rest) = match parts.split_first() {
+            Some((venue, rest)) if venue.starts_with('@') => (&venue[1..], rest),
+            _ => (DEFAULT_VENUE, parts),
+        };
+        let (symbol, from, to) = match rest {
+            [from, to] => (self.config.default_symbol.as_str(), *from, *to),
+            [symbol, from, to] => (*symbol, *from, *to),
+            _ => return Err(invalid()),
+        };
+        let parse = |field: &str, token: &str| {
+            token.parse::<u64>().map_err(|_| {
+                anyhow::anyhow!(
+                    "Invalid {} \"{}\": expected a sequence number: {}",
+                    field,
+                    token,
+                    line
+                )
+            })
+        };
+        let (from, to) = (parse("FROM", from)?, parse("TO", to)?);
+        if from > to {
+            return Err(anyhow::anyhow!(
+                "Sequence range ends before it starts ({} > {}): {}",
+                from,
+                to,
+                line
+            ));
+        }
+
+        let Some(hours) = self.sequence_hours(venue, symbol, from, to) else {
+            return Ok("COVERAGE_UNKNOWN".to_string());
+        };
+        let dedup_key = self.dedup_key(venue);
+        let mut trades = HashSet::new();
+        let mut volume = Decimal::ZERO;
+        for entry in hours {
+            for fill in entry
+                .fills
+                .iter()
+                .filter(|fill| (from..=to).contains(&fill.sequence_number))
+            {
+                trades.insert(trade_key(fill, dedup_key));
+                volume += fill.quantity * fill.price;
+            }
+        }
+        Ok(if word.eq_ignore_ascii_case("CS") {
+            trades.len().to_string()
+        } else {
+            self.format_decimal(volume, None)
+        })
+    }
+
+    /// Returns the cached hours of the venue's symbol holding the fills with sequence numbers
+    /// from `from` to `to`, or `None` when the cache doesn't provably hold them all.
+    /// The hours are the ones bounding the range (see `seq_index::hour_bounds`), found through
+    /// the sequence index when enabled, and the hours between them, which must all be cached.
+    /// Summarized hours, and hours in the jumbo slot, count as not cached.
+    fn sequence_hours(
+        &self,
+        venue: &str,
+        symbol: &str,
+        from: u64,
+        to: u64,
+    ) -> Option<Vec<&CacheEntry>> {
+        let (first, last) = match &self.seq_index {
+            Some(index) => index.bounds(venue, symbol, from, to)?,
+            None => {
+                let spans = self
+                    .cache
+                    .iter()
+                    .filter(|((entry_venue, entry_symbol, _), _)| {
+                        **entry_venue == *venue && **entry_symbol == *symbol
+                    })
+                    .filter_map(|((_, _, hour), entry)| {
+                        let sequence_numbers = entry.fills.iter().map(|fill| fill.sequence_number);
+                        let lowest = sequence_numbers.clone().min()?;
+                        Some(((lowest, *hour), sequence_numbers.max()?))
+                    })
+                    .collect::<seq_index::Spans>();
+                seq_index::hour_bounds(&spans, from, to)?
+            }
+        };
+        (first..=last)
+            .step_by(3600)
+            .map(|hour| {
+                self.cache
+                    .peek(&self.interner.get_key(venue, symbol, hour)?)
+                    .filter(|entry| entry.summary.is_none())
+            })
+            .collect()
+    }
+
+    /// Returns whether the hour of the venue's symbol would be served from the cache right now
+    pub fn is_cached(&self, venue: &str, symbol: &str, hour: i64) -> bool {
+        self.interner
+            .get_key(venue, symbol, hour)
+            .and_then(|key| self.peek_entry(&key))
+            .is_some_and(|entry| entry.is_fresh(self.clock.now(), self.config.incomplete_ttl))
+    }
+
+    /// Processes a single query and returns the result
+    /// Query format:
+    /// "TYPE [@VENUE] [BOUNDS] [SYMBOL] START_TIME END_TIME [START_TIME END_TIME ...]"
+    /// where TYPE is one of: buy (B), sell (S), total count (C), raw fill count (R), volume (V),
+    /// average price of the buys (WB) and of the sells (WS),
+    /// price standard deviation (SD), log-return standard deviation (SDR),
+    /// coverage (E: earliest and latest fill timestamp plus fill count),
+    /// maker fill count (MC), taker fill count (TC), taker share of volume (TVS),
+    /// moving average price series (MA, see `moving_average`),
+    /// maximum drawdown, absolute (DD) or as a percentage of the peak (DDP),
+    /// taker trades per minute (FR), fees per currency (FEE, see `fees`),
+    /// or bursts of fills with a trailing GAP_MS (BURST, see `bursts`),
+    /// in any case or as one of their aliases (see `QueryKind`),
+    /// VENUE defaults to the default venue, BOUNDS to the configured window bounds
+    /// (see `Bounds`), and SYMBOL to the processor's default symbol
+    pub fn process_query(&mut self, query: String) -> anyhow::Result<String> {
+        match self.run_query(query).result {
+            Err(e) if e.chain().any(|cause| cause.is::<DeadlineExceeded>()) => {
+                Ok(TIMEOUT.to_string())
+            }
+            result => result,
+        }
+    }
+
+    /// Returns the fills of a window as a query of the window sees them, in time order: loaded
+    /// through the cache, without what the cache drops, and with each taker trade in one hour
+    /// only (see `dedup_across_hours`). The window is given like that of a query line,
+    /// `[@VENUE] [BOUNDS] [SYMBOL] START_TIME END_TIME`, and limited to `--max-range-hours` alike.
+    pub fn window_fills(&mut self, window: &str) -> anyhow::Result<Vec<Fill>> {
+        // A type never answered from summaries, so the fills of every hour are loaded
+        let query = format!("{} {}", QueryKind::PriceStdDev, window);
+        let (parsed, _) = self.resolve_query(&query)?;
+        let [window] = parsed.windows[..] else {
+            return Err(anyhow::anyhow!("Expected a single window: {}", window));
+        };
+        let hours = self.window_hours(&parsed, &query)?;
+
+        self.current_fills.clear();
+        self.current_summaries.clear();
+        self.current_partials.clear();
+        self.current_hours.clear();
+        let mut fetch_time = Duration::ZERO;
+        for &hour in &hours {
+            self.load_hour(&parsed, hour, &mut fetch_time)?;
+        }
+        self.dedup_across_hours(&parsed.venue);
+        self.maybe_snapshot();
+        Ok(self
+            .fills_in_range(window.start_ms, window.end_ms)
+            .copied()
+            .collect())
+    }
+
+    /// Processes each query in turn, as `process_query` does, and passes what came of it to
+    /// `on_outcome` instead of writing anything, for driving the processor from code.
+    /// A failed query doesn't stop the others.
+    ///
+    /// ```
+    /// use interview::clock::SystemClock;
+    /// use interview::config::Config;
+    /// use interview::query::QueryKind;
+    /// use interview::source::DiskSource;
+    /// use interview::Processor;
+    ///
+    /// let source = DiskSource {
+    ///     dir: concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/multi-hour-range/hours").into(),
+    ///     strict_schema: false,
+    /// };
+    /// let mut processor =
+    ///     Processor::with_source(Config::default(), Box::new(source), Box::new(SystemClock));
+    /// let mut outcomes = Vec::new();
+    /// processor.process_queries(
+    ///     ["C 1700820000 1700823599", "V 1700820000 1700823599"].map(String::from),
+    ///     |outcome| outcomes.push(outcome),
+    /// );
+    /// assert_eq!(outcomes.len(), 2);
+    /// assert!(outcomes.iter().all(|outcome| outcome.result.is_ok()));
+    /// assert_eq!(outcomes[0].parsed.as_ref().unwrap().kind, QueryKind::Count);
+    /// // The first query fetched its hour, and the second found it in the cache
+    /// assert_eq!(outcomes[0].hours.len(), 1);
+    /// assert!(!outcomes[0].hours[0].hit);
+    /// assert!(outcomes[1].hours[0].hit);
+    /// ```
+    pub fn process_queries(
+        &mut self,
+        queries: impl IntoIterator<Item = String>,
+        mut on_outcome: impl FnMut(QueryOutcome),
+    ) {
+        for query in queries {
+            on_outcome(self.run_query(query));
+        }
+    }
+
+    /// Processes a single query and returns what came of it: the single execution path of
+    /// `process_query` and `process_queries`
+    fn run_query(&mut self, query: String) -> QueryOutcome {
+        let span = info_span!("query", query = query.as_str());
+        let _enter = span.enter();
+        debug!("Processing query: {}", query);
+        let query_start = Instant::now();
+        self.deadline = self
+            .config
+            .query_timeout
+            .map(|timeout| query_start + timeout);
+        self.collect_fetches();
+        self.check_memory();
+
+        if let Some(age) = self.config.auto_evict_age {
+            let cutoff = self.clock.now().timestamp() - age.as_secs() as i64;
+            self.evict_older_than(cutoff);
+        }
+        if let Some(refresh) = self.config.stale_refresh {
+            self.refresh_stale(refresh);
+        }
+
+        let mut trace = QueryTrace {
+            timer: PhaseTimer::new(self.profile.is_some()),
+            ..QueryTrace::default()
+        };
+        let result = self.answer_query(&query, &mut trace);
+        if let (Some(profile), Ok(_)) = (&mut self.profile, &result) {
+            profile.record(trace.timer.times);
+        }
+        let duration = query_start.elapsed();
+        self.metrics
+            .record_query(trace.parsed.as_ref(), duration, result.is_err());
+        if let Some(audit) = &mut self.audit {
+            audit.record(&query, &trace, &result, duration);
+        }
+        self.maybe_snapshot();
+        self.deadline = None;
+        match &result {
+            Err(e) if e.chain().any(|cause| cause.is::<DeadlineExceeded>()) => {
+                self.metrics.timed_out_queries += 1;
+                warn!("Answering \"{}\" with {}: {:#}", query, TIMEOUT, e);
+            }
+            _ => {}
+        }
+
+        if let Some(threshold) = self.config.slow_query_threshold.filter(|_| result.is_ok()) {
+            if duration > threshold {
+                let fetch_time = trace
+                    .hours
+                    .iter()
+                    .map(|hour| hour.fetch_time)
+                    .sum::<Duration>();
+                let hour_statuses = trace
+                    .hours
+                    .iter()
+                    .map(|hour| format!("{}:{}", hour.hour, if hour.hit { "hit" } else { "miss" }))
+                    .collect::<Vec<String>>();
+                warn!(
+                    query_type = trace.parsed.as_ref().map_or("?", |parsed| parsed.kind.code()),
+                    duration_ms = duration.as_millis() as u64,
+                    fetch_ms = fetch_time.as_millis() as u64,
+                    fills_scanned = self.current_fills.len(),
+                    "Slow query: \"{}\" took {:?} (hours: [{}], upstream fetch: {:?}, fills scanned: {})",
+                    query,
+                    duration,
+                    hour_statuses.join(", "),
+                    fetch_time,
+                    self.current_fills.len()
+                );
+            }
+        }
+
+        self.last_answer_final = result.is_ok() && Self::is_final(&trace);
+        self.last_latest_end = trace.parsed.as_ref().and_then(Query::latest_end_ms);
+        self.last_context = AnswerContext {
+            query: query.clone(),
+            kind: trace
+                .parsed
+                .as_ref()
+                .map_or("?", |parsed| parsed.kind.code()),
+            start: trace
+                .parsed
+                .as_ref()
+                .and_then(|parsed| parsed.windows.first())
+                .map_or(0, |window| window.given_ms.0.div_euclid(1000)),
+            end: trace
+                .parsed
+                .as_ref()
+                .and_then(|parsed| parsed.windows.last())
+                // A LATEST end shows what it was resolved to when answered
+                .map_or(0, |window| {
+                    let end_ms = if window.open_ended {
+                        window.end_ms
+                    } else {
+                        window.given_ms.1
+                    };
+                    end_ms.div_euclid(1000)
+                }),
+            cache_hits: trace.hours.iter().filter(|hour| hour.hit).count(),
+            duration_ms: duration.as_millis() as u64,
+        };
+        QueryOutcome {
+            query,
+            latest_end_ms: self.last_latest_end,
+            is_final: self.last_answer_final,
+            parsed: trace.parsed,
+            result,
+            hours: trace.hours,
+            duration,
+        }
+    }
+
+    /// Returns whether the answer of the traced query is final: every hour it loaded was
+    /// fetched after it ended, and it has no window ending at LATEST, which moves on
+    fn is_final(trace: &QueryTrace) -> bool {
+        trace.hours.iter().all(|hour| hour.complete)
+            && trace
+                .parsed
+                .as_ref()
+                .is_none_or(|parsed| parsed.latest_end_ms().is_none())
+    }
+
+    /// Ends the query's LATEST windows at the newest data loaded for the hour holding their
+    /// end: the time that hour was fetched, when it was fetched before it ended. A cache hit
+    /// on an incomplete hour thus answers up to when it was cached, not up to now.
+    fn clamp_latest(&self, parsed: &mut Query) {
+        for window in parsed.windows.iter_mut().filter(|window| window.open_ended) {
+            let hour = self.get_start_hour(window.end_ms - 1);
+            let key = self.interner.get_key(&parsed.venue, &parsed.symbol, hour);
+            let Some(entry) = key
+                .and_then(|key| self.peek_entry(&key))
+                .filter(|entry| !entry.complete)
+            else {
+                continue;
+            };
+            let fetched_ms = entry.fetched_at.timestamp_millis();
+            if fetched_ms < window.end_ms {
+                debug!(
+                    "Ending LATEST window at {}ms, when its hour was fetched",
+                    fetched_ms
+                );
+                window.end_ms = fetched_ms.max(window.start_ms);
+            }
+        }
+    }
+
+    /// Answers a single query, recording what it did into the trace
+    fn answer_query(&mut self, query: &str, trace: &mut QueryTrace) -> anyhow::Result<String> {
+        let (mut parsed, clamped) = self.resolve_query(query)?;
+        trace.parsed = Some(parsed.clone());
+
+        // Refuse runaway ranges before fetching anything
+        let hours = self.window_hours(&parsed, query)?;
+        trace.timer.lap(Phase::Parse);
+
+        self.current_fills.clear();
+        self.current_summaries.clear();
+        self.current_partials.clear();
+        self.current_hours.clear();
+
+        // Retrieve fills for every hour touched by a window, once even when windows overlap
+        let mut hour_fills = 0;
+        let mut empty_hours = 0;
+        for &hour in &hours {
+            let mut fetch_time = Duration::ZERO;
+            let (hit, fill_count) = self.load_hour(&parsed, hour, &mut fetch_time)?;
+            let key = self.interner.key(&parsed.venue, &parsed.symbol, hour);
+            trace.hours.push(HourTrace {
+                hour,
+                hit,
+                fetch_time,
+                complete: self.peek_entry(&key).is_some_and(|entry| entry.complete),
+            });
+            hour_fills += fill_count;
+            if fill_count == 0 {
+                empty_hours += 1;
+            }
+        }
+        self.check_deadline()?;
+        self.dedup_across_hours(&parsed.venue);
+        if parsed.latest_end_ms().is_some() {
+            self.clamp_latest(&mut parsed);
+            trace.parsed = Some(parsed.clone());
+        }
+        trace.timer.lap(Phase::Lookup);
+        let fetch_time = trace.hours.iter().map(|hour| hour.fetch_time).sum();
+        trace
+            .timer
+            .times
+            .transfer(Phase::Lookup, Phase::Fetch, fetch_time);
+
+        let result = self.compute_results(&parsed)?;
+        // An answer computed past the deadline is as late as one not computed
+        self.check_deadline()?;
+        trace.timer.lap(Phase::Aggregate);
+        if self.config.verify_sample > 0.0 && self.rng.unit() < self.config.verify_sample {
+            self.verify(query, &parsed, &hours, &result);
+        }
+
+        let mut result = if clamped {
+            format!("{} CLAMPED", result)
+        } else {
+            result
+        };
+        // Tells a quiet market from an hour that came back empty
+        if self.config.verbose_results && parsed.kind.is_count_or_volume() {
+            result = format!(
+                "{} [hour_fills={} empty_hours={}]",
+                result, hour_fills, empty_hours
+            );
+        }
+        if let Some(end_ms) = parsed
+            .latest_end_ms()
+            .filter(|_| self.config.verbose_results)
+        {
+            result = format!("{} [end={}ms]", result, end_ms);
+        }
+        if parsed.report_final {
+            result = format!("{} [final={}]", result, Self::is_final(trace));
+        }
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::endpoint::{ArchiveConfig, EndpointPolicy};
+    use crate::hour_file::{self, HourFormat};
+    use crate::source::{DiskSource, SourceSpec};
+    use crate::testing::{
+        capture_logs, fill, MemorySource, SlowSource, StubMemory, TestClock, HOUR,
+    };
+
+    /// Fills of two consecutive hours
+    fn two_hours() -> Vec<Fill> {
+        vec![
+            fill(HOUR + 60, 1, 1, "10", "1"),
+            fill(HOUR + 120, 2, 2, "11", "2"),
+            fill(HOUR + 3600 + 60, 3, 1, "12", "1"),
+        ]
+    }
+
+    /// Returns a processor fetching from the source, with the clock a day past `HOUR`
+    fn processor(config: Config, source: &MemorySource) -> Processor {
+        let clock = Box::new(TestClock::at(HOUR + 86400));
+        Processor::with_source(config, Box::new(source.clone()), clock)
+    }
+
+    #[test]
+    fn window_fills_come_from_the_cache_with_each_taker_trade_once() {
+        let mut fills = two_hours();
+        // Trade 1 republished in the second hour
+        fills.push(fill(HOUR + 3600 + 90, 1, 1, "10", "1"));
+        let source = MemorySource::new(fills);
+        let mut processor = processor(Config::default(), &source);
+        let window = format!("(] {} {}", HOUR, HOUR + 7199);
+        let sequence_numbers = |fills: Vec<Fill>| {
+            fills
+                .iter()
+                .map(|fill| fill.sequence_number)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            sequence_numbers(processor.window_fills(&window).unwrap()),
+            [1, 2, 3]
+        );
+        assert_eq!(source.requests(), 2);
+        let window = format!("(] {} {}", HOUR + 60, HOUR + 3660);
+        assert_eq!(
+            sequence_numbers(processor.window_fills(&window).unwrap()),
+            [2, 3]
+        );
+        assert_eq!(source.requests(), 2);
+        let windows = format!("{} {} {} {}", HOUR, HOUR + 60, HOUR + 120, HOUR + 180);
+        assert!(processor.window_fills(&windows).is_err());
+    }
+
+    /// Returns a fill at the time in Unix milliseconds
+    fn fill_ms(time_ms: i64, sequence_number: u64) -> Fill {
+        Fill {
+            time: DateTime::from_timestamp_millis(time_ms).unwrap(),
+            ..fill(0, sequence_number, 1, "10", "1")
+        }
+    }
+
+    #[test]
+    fn millisecond_ends_split_the_fills_of_a_second() {
+        let second = HOUR + 60;
+        let source = MemorySource::new(vec![
+            fill_ms(second * 1000 - 200, 1),
+            fill_ms(second * 1000, 2),
+            fill_ms(second * 1000 + 200, 3),
+            // The last millisecond of the hour is still in it
+            fill_ms((HOUR + 3600) * 1000 - 1, 4),
+            fill_ms((HOUR + 3600) * 1000, 5),
+        ]);
+        let mut processor = processor(Config::default(), &source);
+        let sequence_numbers = |fills: Vec<Fill>| {
+            fills
+                .iter()
+                .map(|fill| fill.sequence_number)
+                .collect::<Vec<_>>()
+        };
+        let start = format!("{}ms", HOUR * 1000);
+        let cases = [
+            (format!("{}ms", second * 1000 - 1), vec![1]),
+            (format!("{}ms", second * 1000), vec![1, 2]),
+            (format!("{}ms", second * 1000 + 199), vec![1, 2]),
+            // To the second, the whole second
+            (second.to_string(), vec![1, 2, 3]),
+        ];
+        for (end, expected) in cases {
+            let window = format!("{} {}", start, end);
+            let fills = processor.window_fills(&window).unwrap();
+            assert_eq!(sequence_numbers(fills), expected, "{}", window);
+            let count = processor.process_query(format!("C {}", window)).unwrap();
+            assert_eq!(count, expected.len().to_string(), "{}", window);
+        }
+        assert_eq!(source.requests(), 1);
+
+        // ...999 ends in its hour, ...000 in the next
+        let window = format!("{} {}ms", start, (HOUR + 3600) * 1000 - 1);
+        assert_eq!(
+            sequence_numbers(processor.window_fills(&window).unwrap()),
+            [1, 2, 3, 4]
+        );
+        assert_eq!(source.requests(), 1);
+        let window = format!("{} {}ms", start, (HOUR + 3600) * 1000);
+        assert_eq!(
+            sequence_numbers(processor.window_fills(&window).unwrap()),
+            [1, 2, 3, 4, 5]
+        );
+        assert_eq!(source.requests(), 2);
+    }
+
+    #[test]
+    fn metrics_count_a_scripted_hit_and_miss_sequence() {
+        let source = MemorySource::new(two_hours());
+        let mut processor = processor(Config::default(), &source);
+        let script = [
+            (format!("C {} {}", HOUR, HOUR + 1800), "2"),
+            (format!("V {} {}", HOUR + 100, HOUR + 200), "22"),
+            (format!("B {} {}", HOUR + 3600, HOUR + 3700), "1"),
+            (format!("C {} {}", HOUR + 3500, HOUR + 3700), "1"),
+        ];
+        for (query, answer) in script {
+            assert_eq!(processor.process_query(query).unwrap(), answer);
+        }
+        assert!(processor.process_query("X 1 2".to_string()).is_err());
+
+        let metrics = processor.metrics();
+        assert_eq!((metrics.queries, metrics.failed), (5, 1));
+        // Misses on the first query of each hour, hits on the two hours of the last query
+        assert_eq!((metrics.cache_hits, metrics.api_calls), (3, 2));
+        assert_eq!(metrics.upstream_requests, 2);
+        assert_eq!(source.requests(), 2);
+        let count = &metrics.by_kind[&QueryKind::Count];
+        assert_eq!(
+            (
+                count.queries,
+                count.cache_hits,
+                count.misses,
+                count.api_calls
+            ),
+            (2, 2, 1, 1)
+        );
+        let volume = &metrics.by_kind[&QueryKind::Volume];
+        assert_eq!(
+            (volume.queries, volume.cache_hits, volume.misses),
+            (1, 1, 0)
+        );
+        let buys = &metrics.by_kind[&QueryKind::Buys];
+        assert_eq!((buys.cache_hits, buys.misses, buys.api_calls), (0, 1, 1));
+        // The query that failed to parse has no type and no venue
+        assert_eq!(
+            metrics
+                .by_kind
+                .values()
+                .map(|kind| kind.queries)
+                .sum::<usize>(),
+            4
+        );
+        assert_eq!(metrics.by_venue[DEFAULT_VENUE].queries, 4);
+        assert_eq!(
+            crate::stats::hit_rate(metrics.cache_hits, metrics.api_calls),
+            60.0
+        );
+
+        let stats = processor.stats("stats").unwrap();
+        assert!(
+            stats.starts_with("queries=5 failed=1 hits=3 api_calls=2 hit_rate=60.00 "),
+            "{}",
+            stats
+        );
+        assert!(
+            stats.contains(" C:queries=2,failed=0,hits=2,misses=1,api_calls=1,hit_rate=66.67,"),
+            "{}",
+            stats
+        );
+        assert_eq!(stats.matches(":queries=").count(), 3);
+        assert!(processor.stats("STATS C").is_err());
+    }
+
+    #[test]
+    fn the_hours_of_a_market_share_its_key_strings() {
+        let hours = std::path::PathBuf::from(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/multi-hour-range/hours"
+        ));
+        let config = Config {
+            venues: BTreeMap::from([("alt".to_string(), SourceSpec::Disk(hours.clone()))]),
+            ..Config::default()
+        };
+        let source = crate::source::DiskSource {
+            dir: hours,
+            strict_schema: false,
+        };
+        let clock = Box::new(TestClock::at(1700830000 + 86400));
+        let mut processor = Processor::with_source(config, Box::new(source), clock);
+        for query in ["C 1700820000 1700830000", "C @alt 1700820000 1700830000"] {
+            assert_eq!(processor.process_query(query.to_string()).unwrap(), "4509");
+        }
+
+        // Six hours of two venues and one symbol, of three strings in all
+        let keys = processor
+            .cache
+            .iter()
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        assert_eq!(keys.len(), 6);
+        let mut strings = keys
+            .iter()
+            .flat_map(|(venue, symbol, _)| [Arc::as_ptr(venue), Arc::as_ptr(symbol)])
+            .collect::<Vec<_>>();
+        strings.sort();
+        strings.dedup();
+        assert_eq!(strings.len(), 3);
+        assert_eq!(processor.interner.len(), 3);
+        let stats = processor.cache_stats();
+        let entries = processor
+            .cache
+            .iter()
+            .map(|(key, entry)| Processor::entry_bytes(key, entry))
+            .sum::<usize>();
+        let strings = ["default", "alt", "DEFAULT"]
+            .map(Interner::string_bytes)
+            .iter()
+            .sum::<usize>();
+        assert_eq!(stats.bytes, entries + strings);
+
+        // Strings of markets gone from the cache are dropped once nothing else holds them,
+        // like the duplicate counts of their hours kept for the final statistics
+        processor.evict_older_than(i64::MAX);
+        processor.interner.sweep();
+        assert_eq!(processor.interner.len(), 3);
+        processor.metrics.duplicate_fills.clear();
+        processor.interner.sweep();
+        assert!(processor.interner.is_empty());
+    }
+
+    #[test]
+    fn max_range_hours_admits_exactly_that_many_hours() {
+        let source = MemorySource::new(two_hours());
+        let config = Config {
+            max_range_hours: 2,
+            ..Config::default()
+        };
+        let mut processor = processor(config, &source);
+        // An end time of a second holds its fractions, so ending on an hour start reaches into it
+        let two_hours = format!("C {} {}", HOUR, HOUR + 7199);
+        assert_eq!(processor.process_query(two_hours).unwrap(), "3");
+        let three_hours = format!("C {} {}", HOUR, HOUR + 7200);
+        let error = processor.process_query(three_hours).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("Query touches 3 hours, more than the limit of 2 hours"),
+            "{}",
+            error
+        );
+        assert_eq!(source.requests(), 2);
+    }
+
+    #[test]
+    fn planners_apply_max_range_hours_before_building_their_fetch_set() {
+        let source = MemorySource::new(two_hours());
+        let config = Config {
+            max_range_hours: 2,
+            lookahead: Some(8),
+            ..Config::default()
+        };
+        let mut processor = processor(config, &source);
+        let three_hours = format!("C {} {}", HOUR, HOUR + 7200);
+        let error = processor.plan_query(&three_hours).unwrap_err();
+        assert!(
+            error.to_string().starts_with("Query touches 3 hours"),
+            "{}",
+            error
+        );
+        processor.prefetch(&three_hours);
+        assert!(processor.pending_fetches.is_empty());
+
+        let two_hours = format!("C {} {}", HOUR, HOUR + 7199);
+        assert_eq!(processor.plan_query(&two_hours).unwrap().2.len(), 2);
+        processor.prefetch(&two_hours);
+        assert_eq!(processor.pending_fetches.len(), 2);
+        assert_eq!(processor.process_query(two_hours).unwrap(), "3");
+        assert_eq!(source.requests(), 2);
+    }
+
+    #[test]
+    fn unreadable_snapshot_is_left_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.snapshot");
+        let later_version = b"OBSNAP\x63\x00hours of a later build";
+        std::fs::write(&path, later_version).unwrap();
+        let config = Config {
+            snapshot: Some(snapshot::SnapshotConfig {
+                path: path.clone(),
+                every_hours: 1,
+                interval: None,
+                compact_bytes: 0,
+                format: snapshot::SnapshotFormat::Binary,
+            }),
+            ..Config::default()
+        };
+        let source = MemorySource::new(two_hours());
+        let mut processor = processor(config, &source);
+        let query = format!("C {} {}", HOUR, HOUR + 1800);
+        assert_eq!(processor.process_query(query).unwrap(), "2");
+        processor.snapshot();
+        drop(processor);
+        assert_eq!(std::fs::read(&path).unwrap(), later_version);
+    }
+
+    #[test]
+    fn answer_context_shows_the_bounds_as_given() {
+        let source = MemorySource::new(two_hours());
+        let mut processor = processor(Config::default(), &source);
+        let bounds = |processor: &Processor| {
+            let context = processor.last_context();
+            (context.start, context.end)
+        };
+        // The brackets move the window's ends by a millisecond each
+        let query = format!("C [) {} {}", HOUR + 1, HOUR + 3600);
+        assert_eq!(processor.process_query(query).unwrap(), "2");
+        assert_eq!(bounds(&processor), (HOUR + 1, HOUR + 3600));
+        let query = format!("C {} +1800", HOUR);
+        assert_eq!(processor.process_query(query).unwrap(), "2");
+        assert_eq!(bounds(&processor), (HOUR, HOUR + 1800));
+        let query = format!(
+            "C {} {} {} {}",
+            HOUR + 60,
+            HOUR + 61,
+            HOUR + 3600,
+            HOUR + 3700
+        );
+        processor.process_query(query).unwrap();
+        assert_eq!(bounds(&processor), (HOUR + 60, HOUR + 3700));
+    }
+
+    #[test]
+    fn verification_detects_a_changed_hour_from_a_fresh_fetch() {
+        let source = MemorySource::new(two_hours());
+        let config = Config {
+            verify_sample: 1.0,
+            ..Config::default()
+        };
+        let mut processor = processor(config, &source);
+        let query = format!("C {} {}", HOUR, HOUR + 1800);
+        assert_eq!(processor.process_query(query.clone()).unwrap(), "2");
+        assert_eq!(processor.metrics().verify_mismatches, 0);
+
+        // The upstream corrects the hour, and a fetch an earlier deadline left behind still
+        // holds the hour as it was
+        source
+            .fills
+            .lock()
+            .unwrap()
+            .push(fill(HOUR + 180, 4, 1, "10", "1"));
+        let (sender, pending) = std::sync::mpsc::channel();
+        let stale = ApiResponse {
+            fills: two_hours(),
+            malformed: 0,
+            unknown_fields: Vec::new(),
+        };
+        // Still running when the query starts, so it isn't collected into the cache before
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            let _ = sender.send(Ok(stale));
+        });
+        let key = processor
+            .interner
+            .key(DEFAULT_VENUE, crate::server::DEFAULT_SYMBOL, HOUR);
+        processor.pending_fetches.insert(key.clone(), pending);
+
+        // Answered from the cache, then from a fresh request rather than the stale fetch
+        assert_eq!(processor.process_query(query).unwrap(), "2");
+        let metrics = processor.metrics();
+        assert_eq!(metrics.verify_mismatches, 1);
+        assert_eq!((metrics.cache_hits, metrics.api_calls), (1, 1));
+        assert_eq!(source.requests(), 3);
+        assert!(processor.pending_fetches.contains_key(&key));
+        assert_eq!(processor.peek_entry(&key).unwrap().fill_count(), 2);
+    }
+
+    /// Returns a processor keeping hours of more than one fill in the jumbo slot and
+    /// refreshing them as given, with the clock halfway through `HOUR`
+    fn jumbo_processor(refresh: StaleRefresh, source: &MemorySource) -> (Processor, TestClock) {
+        let config = Config {
+            max_cached_hour_fills: Some(1),
+            stale_refresh: Some(refresh),
+            incomplete_ttl: Duration::from_secs(60),
+            ..Config::default()
+        };
+        let clock = TestClock::at(HOUR + 1800);
+        let processor =
+            Processor::with_source(config, Box::new(source.clone()), Box::new(clock.clone()));
+        (processor, clock)
+    }
+
+    #[test]
+    fn stale_jumbo_hour_is_invalidated_without_a_query_on_it() {
+        let source = MemorySource::new(two_hours());
+        let (mut processor, clock) = jumbo_processor(StaleRefresh::Invalidate, &source);
+        let key = processor
+            .interner
+            .key(DEFAULT_VENUE, crate::server::DEFAULT_SYMBOL, HOUR);
+        let query = format!("C {} {}", HOUR, HOUR + 1700);
+        assert_eq!(processor.process_query(query).unwrap(), "2");
+        assert!(processor.jumbo.is_some());
+
+        // Within the TTL the hour stays; past it, a query on another hour drops it
+        let earlier_hour = format!("C {} {}", HOUR - 3600, HOUR - 1800);
+        clock.set(HOUR + 1830);
+        assert_eq!(processor.process_query(earlier_hour.clone()).unwrap(), "0");
+        assert!(processor.peek_entry(&key).is_some());
+        clock.set(HOUR + 1900);
+        assert_eq!(processor.process_query(earlier_hour).unwrap(), "0");
+        assert!(processor.jumbo.is_none());
+        assert!(processor.peek_entry(&key).is_none());
+        // Evicted, not refetched: the requests are those of the two hours
+        assert_eq!(source.requests(), 2);
+    }
+
+    #[test]
+    fn stale_jumbo_hour_is_refetched_without_a_query_on_it() {
+        let source = MemorySource::new(two_hours());
+        let (mut processor, clock) = jumbo_processor(StaleRefresh::Refetch, &source);
+        let key = processor
+            .interner
+            .key(DEFAULT_VENUE, crate::server::DEFAULT_SYMBOL, HOUR);
+        let query = format!("C {} {}", HOUR, HOUR + 1700);
+        assert_eq!(processor.process_query(query.clone()).unwrap(), "2");
+
+        source
+            .fills
+            .lock()
+            .unwrap()
+            .push(fill(HOUR + 1850, 4, 1, "10", "1"));
+        clock.set(HOUR + 1900);
+        let earlier_hour = format!("C {} {}", HOUR - 3600, HOUR - 1800);
+        assert_eq!(processor.process_query(earlier_hour).unwrap(), "0");
+        assert_eq!(source.requests(), 3);
+        let (jumbo_key, entry) = processor.jumbo.as_ref().unwrap();
+        assert_eq!((jumbo_key, entry.fill_count()), (&key, 3));
+        // The refetch brought a fill the hour was cached without
+        assert_eq!(processor.metrics().changed_hours, 1);
+        // Fresh again, so the next query on the hour finds it in the slot
+        let query = format!("C {} {}", HOUR, HOUR + 1880);
+        assert_eq!(processor.process_query(query).unwrap(), "3");
+        assert_eq!(source.requests(), 3);
+    }
+
+    #[test]
+    fn stale_hour_refetched_with_the_same_fills_is_not_changed() {
+        let source = MemorySource::new(two_hours());
+        let (mut processor, clock) = jumbo_processor(StaleRefresh::Refetch, &source);
+        let query = format!("C {} {}", HOUR, HOUR + 1700);
+        assert_eq!(processor.process_query(query).unwrap(), "2");
+
+        clock.set(HOUR + 1900);
+        let earlier_hour = format!("C {} {}", HOUR - 3600, HOUR - 1800);
+        assert_eq!(processor.process_query(earlier_hour).unwrap(), "0");
+        assert_eq!(source.requests(), 3);
+        assert_eq!(processor.metrics().changed_hours, 0);
+    }
+
+    #[test]
+    fn window_bounds_count_the_fills_on_their_start_and_end_as_given() {
+        let (start, end) = (HOUR + 60, HOUR + 120);
+        let source = MemorySource::new(vec![
+            fill(start, 1, 1, "1", "1"),
+            fill(HOUR + 90, 2, 1, "10", "1"),
+            fill(end, 3, 1, "100", "1"),
+        ]);
+        let cases = [
+            ("(]", "2", "110"),
+            ("[]", "3", "111"),
+            ("()", "1", "10"),
+            ("[)", "2", "11"),
+        ];
+        for (bounds, count, volume) in cases {
+            // As a modifier of the query, in seconds and in milliseconds
+            let mut by_modifier = processor(Config::default(), &source);
+            for times in [
+                format!("{} {}", start, end),
+                format!("{}ms {}ms", start * 1000, end * 1000),
+            ] {
+                let query = format!("C {} {}", bounds, times);
+                assert_eq!(
+                    by_modifier.process_query(query).unwrap(),
+                    count,
+                    "{}",
+                    bounds
+                );
+                let query = format!("V {} {}", bounds, times);
+                assert_eq!(
+                    by_modifier.process_query(query).unwrap(),
+                    volume,
+                    "{}",
+                    bounds
+                );
+                let fills = by_modifier.window_fills(&format!("{} {}", bounds, times));
+                assert_eq!(fills.unwrap().len().to_string(), count, "{}", bounds);
+            }
+
+            // As the default of every query
+            let config = Config {
+                window_bounds: bounds.parse().unwrap(),
+                ..Config::default()
+            };
+            let mut by_default = processor(config, &source);
+            let query = format!("C {} {}", start, end);
+            assert_eq!(
+                by_default.process_query(query).unwrap(),
+                count,
+                "{}",
+                bounds
+            );
+        }
+    }
+
+    #[test]
+    fn now_windows_follow_the_clock_and_pick_up_new_fills() {
+        let source = MemorySource::new(two_hours());
+        let clock = TestClock::at(HOUR + 1800);
+        let mut processor = Processor::with_source(
+            Config::default(),
+            Box::new(source.clone()),
+            Box::new(clock.clone()),
+        );
+        let query = "C NOW-1800 NOW".to_string();
+        assert_eq!(processor.process_query(query.clone()).unwrap(), "2");
+
+        source
+            .fills
+            .lock()
+            .unwrap()
+            .push(fill(HOUR + 1900, 4, 1, "10", "1"));
+        // Within the TTL of the hour in progress the cached fills answer
+        clock.set(HOUR + 1850);
+        assert_eq!(processor.process_query(query.clone()).unwrap(), "2");
+        assert_eq!(source.requests(), 1);
+        let context = processor.last_context();
+        assert_eq!((context.start, context.end), (HOUR + 50, HOUR + 1850));
+        // Past it, the hour is refetched with the new fill, and the window has moved past
+        // the cached ones
+        clock.set(HOUR + 1961);
+        assert_eq!(processor.process_query(query).unwrap(), "1");
+        assert_eq!(source.requests(), 2);
+        let context = processor.last_context();
+        assert_eq!((context.start, context.end), (HOUR + 161, HOUR + 1961));
+    }
+
+    #[test]
+    fn overlapping_windows_answer_like_separate_queries_from_one_fetch_per_hour() {
+        let source = MemorySource::new(two_hours());
+        let windows = [
+            (HOUR, HOUR + 90),
+            (HOUR + 60, HOUR + 3700),
+            (HOUR, HOUR + 3700),
+            (HOUR + 100, HOUR + 3600),
+        ];
+        for kind in ["C", "V", "WB", "SD", "E"] {
+            let mut separate = processor(Config::default(), &source);
+            let answers = windows
+                .iter()
+                .map(|(start, end)| {
+                    let query = format!("{} {} {}", kind, start, end);
+                    separate.process_query(query).unwrap()
+                })
+                .collect::<Vec<String>>();
+
+            let requests = source.requests();
+            let mut together = processor(Config::default(), &source);
+            let line = windows
+                .iter()
+                .map(|(start, end)| format!(" {} {}", start, end))
+                .collect::<String>();
+            let answer = together.process_query(format!("{}{}", kind, line)).unwrap();
+            assert_eq!(answer, answers.join(" "), "{}", kind);
+            assert_eq!(source.requests() - requests, 2, "{}", kind);
+        }
+    }
+
+    /// Returns a fill every second from `HOUR` on, of prices going up and down
+    fn every_second(seconds: i64) -> Vec<Fill> {
+        (1..=seconds)
+            .map(|second| {
+                let price = format!("{}.{:02}", 100 + second % 7, second * 37 % 100);
+                fill(HOUR + second, second as u64, 1, &price, "1")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn moving_average_matches_a_naive_average_of_each_window() {
+        let fills = every_second(600);
+        let source = MemorySource::new(fills.clone());
+        let mut processor = processor(Config::default(), &source);
+        let (start, end, window, step) = (HOUR + 50, HOUR + 650, 37, 11);
+        let query = format!("MA {} {} {} {}", start, end, window, step);
+        let answer = processor.process_query(query).unwrap();
+
+        // Every point averages the fills within (point - window, point], from scratch
+        let mut expected = Vec::new();
+        let mut point = start + step;
+        while point <= end {
+            let prices = fills
+                .iter()
+                .filter(|fill| {
+                    let time = fill.time.timestamp();
+                    time > point - window && time <= point
+                })
+                .map(|fill| fill.price)
+                .collect::<Vec<Decimal>>();
+            let average = match prices.len() {
+                0 => "NONE".to_string(),
+                count => (prices.iter().sum::<Decimal>() / Decimal::from(count))
+                    .round_dp(8)
+                    .normalize()
+                    .to_string(),
+            };
+            expected.push(format!("{} {}", point, average));
+            point += step;
+        }
+        assert_eq!(answer, expected.join("\n"));
+    }
+
+    #[test]
+    fn moving_average_takes_linear_time_in_its_fills_and_points() {
+        // 36000 points over windows of 10800 fills: a quadratic average would add up
+        // 400 million prices, the sliding one adds and removes each fill once
+        let source = MemorySource::new(every_second(10 * 3600));
+        let config = Config {
+            cache_hours: 16,
+            ..Config::default()
+        };
+        let mut processor = processor(config, &source);
+        let query = format!("MA {} {} 10800 1", HOUR, HOUR + 10 * 3600 - 1);
+        let started = Instant::now();
+        let answer = processor.process_query(query).unwrap();
+        assert_eq!(answer.lines().count(), 10 * 3600 - 1);
+        assert!(
+            started.elapsed() < Duration::from_secs(30),
+            "{:?}",
+            started.elapsed()
+        );
+    }
+
+    #[test]
+    fn exhausted_call_budget_leaves_the_cached_hours_answering() {
+        let config = Config {
+            max_api_calls: Some(2),
+            ..Config::default()
+        };
+        let source = MemorySource::new(two_hours());
+        let mut processor = processor(config, &source);
+        let first = format!("C {} {}", HOUR, HOUR + 1800);
+        let second = format!("C {} {}", HOUR + 3600, HOUR + 5400);
+        assert_eq!(processor.process_query(first.clone()).unwrap(), "2");
+        assert_eq!(processor.process_query(second.clone()).unwrap(), "1");
+
+        // The third hour would take a call past the budget
+        let third = format!("C {} {}", HOUR + 7200, HOUR + 9000);
+        let error = processor.process_query(third).unwrap_err().to_string();
+        assert!(
+            error.starts_with("API call budget exhausted (2 of 2 calls used)"),
+            "{}",
+            error
+        );
+        let reaching_it = format!("C {} {}", HOUR + 3500, HOUR + 7300);
+        assert!(processor.process_query(reaching_it).is_err());
+
+        // Queries of the cached hours alone are answered from the cache
+        assert_eq!(processor.process_query(first).unwrap(), "2");
+        assert_eq!(processor.process_query(second).unwrap(), "1");
+        let both = format!("C {} {}", HOUR, HOUR + 7199);
+        assert_eq!(processor.process_query(both).unwrap(), "3");
+        assert_eq!(source.requests(), 2);
+        assert_eq!(processor.metrics().upstream_requests, 2);
+    }
+
+    #[test]
+    fn hours_evicted_before_a_time_are_misses_afterwards() {
+        let mut fills = two_hours();
+        fills.push(fill(HOUR + 7200 + 60, 4, 1, "13", "1"));
+        let source = MemorySource::new(fills);
+        let mut processor = processor(Config::default(), &source);
+        let all = format!("C {} {}", HOUR, HOUR + 9000);
+        assert_eq!(processor.process_query(all.clone()).unwrap(), "4");
+        assert_eq!(source.requests(), 3);
+        let bytes = processor.cache_stats().bytes;
+
+        let evicted = processor
+            .evict_before(&format!("EVICT_BEFORE {}", HOUR + 7200))
+            .unwrap();
+        let remaining = processor.cache_stats().bytes;
+        assert_eq!(evicted, format!("EVICTED 2 {}", bytes - remaining));
+        let venue = DEFAULT_VENUE;
+        let symbol = crate::server::DEFAULT_SYMBOL;
+        assert!(!processor.is_cached(venue, symbol, HOUR));
+        assert!(!processor.is_cached(venue, symbol, HOUR + 3600));
+        assert!(processor.is_cached(venue, symbol, HOUR + 7200));
+
+        // The evicted hours are fetched again, the one after the cutoff is a hit
+        let hits = processor.metrics().cache_hits;
+        assert_eq!(processor.process_query(all).unwrap(), "4");
+        assert_eq!(source.requests(), 5);
+        assert_eq!(processor.metrics().cache_hits, hits + 1);
+        // Nothing left before the cutoff
+        let evicted = processor.evict_before(&format!("EVICT_BEFORE {}", HOUR));
+        assert_eq!(evicted.unwrap(), "EVICTED 0 0");
+    }
+
+    #[test]
+    fn future_ends_are_rejected_or_clamped_to_now() {
+        let now = HOUR + 86400;
+        let mut fills = two_hours();
+        fills.push(fill(now - 10, 4, 1, "10", "1"));
+        let source = MemorySource::new(fills);
+        let ends = [(now, "1"), (now + 1, "1"), (now + 365 * 86400, "1")];
+        for policy in [FuturePolicy::Reject, FuturePolicy::Clamp] {
+            let config = Config {
+                future_policy: policy,
+                ..Config::default()
+            };
+            let mut processor = processor(config, &source);
+            for (end, count) in ends {
+                let result = processor.process_query(format!("C {} {}", now - 60, end));
+                match (policy, end > now) {
+                    (_, false) => assert_eq!(result.unwrap(), count, "{:?} {}", policy, end),
+                    (FuturePolicy::Reject, true) => {
+                        let error = result.unwrap_err().to_string();
+                        assert!(error.contains("beyond the current time"), "{}", error);
+                    }
+                    (FuturePolicy::Clamp, true) => {
+                        assert_eq!(result.unwrap(), format!("{} CLAMPED", count))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Serves the fills of its memory source, leaving out those of the given sequence
+    /// number from the ranges starting within an hour, as a differential refresh requests
+    struct SeamGap {
+        source: MemorySource,
+        drop: Option<u64>,
+    }
+
+    impl FillSource for SeamGap {
+        fn get_fills(&mut self, symbol: &str, start: i64, end: i64) -> anyhow::Result<ApiResponse> {
+            let mut response = self.source.get_fills(symbol, start, end)?;
+            if start % 3600 != 0 {
+                response
+                    .fills
+                    .retain(|fill| Some(fill.sequence_number) != self.drop);
+            }
+            Ok(response)
+        }
+    }
+
+    /// Returns a processor refreshing the incomplete hours it caches differentially, from
+    /// the source with `drop` left out of differential responses, with the clock in the
+    /// middle of `HOUR`
+    fn differential_processor(source: &MemorySource, drop: Option<u64>) -> (Processor, TestClock) {
+        let config = Config {
+            differential_refresh: true,
+            ..Config::default()
+        };
+        let clock = TestClock::at(HOUR + 1800);
+        let source = SeamGap {
+            source: source.clone(),
+            drop,
+        };
+        let processor = Processor::with_source(config, Box::new(source), Box::new(clock.clone()));
+        (processor, clock)
+    }
+
+    /// Adds a fill in the second of the latest cached one, `HOUR + 120`, and one after it
+    fn add_newer_fills(source: &MemorySource) {
+        let mut seam = fill(HOUR + 120, 4, 1, "12", "1");
+        seam.time += chrono::Duration::milliseconds(500);
+        let mut fills = source.fills.lock().unwrap();
+        fills.push(seam);
+        fills.push(fill(HOUR + 1850, 5, 2, "13", "1"));
+    }
+
+    #[test]
+    fn differential_refresh_merges_an_overlapping_response_once() {
+        let source = MemorySource::new(two_hours());
+        let (mut processor, clock) = differential_processor(&source, None);
+        let query = format!("C {} {}", HOUR, HOUR + 1799);
+        assert_eq!(processor.process_query(query).unwrap(), "2");
+
+        add_newer_fills(&source);
+        clock.set(HOUR + 1900);
+        // The response repeats the cached fill of HOUR + 120, which is kept once
+        let query = format!("C {} {}", HOUR, HOUR + 1899);
+        assert_eq!(processor.process_query(query).unwrap(), "4");
+        assert_eq!(source.requests(), 2);
+        let metrics = processor.metrics();
+        assert_eq!(
+            (metrics.differential_refreshes, metrics.refresh_gaps),
+            (1, 0)
+        );
+        let key = processor
+            .interner
+            .key(DEFAULT_VENUE, crate::server::DEFAULT_SYMBOL, HOUR);
+        let sequence_numbers = processor
+            .peek_entry(&key)
+            .unwrap()
+            .fills
+            .iter()
+            .map(|fill| fill.sequence_number)
+            .collect::<Vec<u64>>();
+        assert_eq!(sequence_numbers, [1, 2, 4, 5]);
+    }
+
+    #[test]
+    fn differential_refresh_missing_a_cached_fill_refetches_the_hour() {
+        let source = MemorySource::new(two_hours());
+        let (mut processor, clock) = differential_processor(&source, Some(2));
+        let query = format!("C {} {}", HOUR, HOUR + 1799);
+        assert_eq!(processor.process_query(query).unwrap(), "2");
+
+        add_newer_fills(&source);
+        clock.set(HOUR + 1900);
+        // The gap at the seam is refetched whole rather than losing fill 2
+        let query = format!("C {} {}", HOUR, HOUR + 1899);
+        assert_eq!(processor.process_query(query).unwrap(), "4");
+        assert_eq!(source.requests(), 3);
+        let metrics = processor.metrics();
+        assert_eq!(
+            (metrics.differential_refreshes, metrics.refresh_gaps),
+            (0, 1)
+        );
+        let volume = format!("V {} {}", HOUR, HOUR + 1899);
+        assert_eq!(processor.process_query(volume).unwrap(), "57");
+        assert_eq!(source.requests(), 3);
+    }
+
+    /// Returns a processor fetching the hours starting more than 48 hours before its clock
+    /// from `archive` and the others from `live`, with the clock 48 hours past the middle
+    /// of `HOUR`, so `HOUR` is archived and the hour after it live
+    fn archive_processor(
+        page: Option<Duration>,
+        live: &MemorySource,
+        archive: &MemorySource,
+    ) -> Processor {
+        let policy = EndpointPolicy {
+            page,
+            ..EndpointPolicy::default()
+        };
+        let config = Config {
+            archive: Some(ArchiveConfig {
+                source: SourceSpec::Api,
+                after: Duration::from_secs(48 * 3600),
+                policy,
+            }),
+            ..Config::default()
+        };
+        let clock = Box::new(TestClock::at(HOUR + 48 * 3600 + 1800));
+        let mut processor = Processor::with_source(config, Box::new(live.clone()), clock);
+        let endpoint = Endpoint::new("archive", Box::new(archive.clone()), policy);
+        processor
+            .metrics
+            .endpoints
+            .retain(|(name, _)| *name != "archive");
+        processor
+            .metrics
+            .endpoints
+            .push((endpoint.name(), endpoint.stats()));
+        let endpoint: Box<dyn FillSource> = Box::new(endpoint);
+        processor.archive = Some(Arc::new(Mutex::new(endpoint)));
+        processor
+    }
+
+    #[test]
+    fn hours_either_side_of_the_archive_cutoff_go_to_their_endpoints() {
+        // Each endpoint only holds the fills of the hours routed to it
+        let (live, archive) = (
+            MemorySource::new(two_hours()),
+            MemorySource::new(two_hours()),
+        );
+        live.fills
+            .lock()
+            .unwrap()
+            .retain(|fill| fill.time.timestamp() > HOUR + 3600);
+        archive
+            .fills
+            .lock()
+            .unwrap()
+            .retain(|fill| fill.time.timestamp() <= HOUR + 3600);
+        let mut processor = archive_processor(None, &live, &archive);
+
+        let query = format!("C {} {}", HOUR, HOUR + 7199);
+        assert_eq!(processor.process_query(query).unwrap(), "3");
+        assert_eq!((live.requests(), archive.requests()), (1, 1));
+        let metrics = processor.metrics();
+        assert_eq!(metrics.upstream_requests, 2);
+        assert_eq!(metrics.endpoint_requests("live"), 1);
+        assert_eq!(metrics.endpoint_requests("archive"), 1);
+
+        // The next hour back is archived too
+        let query = format!("C {} {}", HOUR - 3600, HOUR);
+        assert_eq!(processor.process_query(query).unwrap(), "0");
+        assert_eq!((live.requests(), archive.requests()), (1, 2));
+    }
+
+    #[test]
+    fn archive_hours_are_requested_page_by_page() {
+        let (live, archive) = (MemorySource::default(), MemorySource::new(two_hours()));
+        let page = Some(Duration::from_secs(15 * 60));
+        let mut processor = archive_processor(page, &live, &archive);
+
+        let query = format!("C {} {}", HOUR, HOUR + 3599);
+        assert_eq!(processor.process_query(query).unwrap(), "2");
+        assert_eq!((live.requests(), archive.requests()), (0, 4));
+        let metrics = processor.metrics();
+        // One hour against the budget, four requests sent by the endpoint
+        assert_eq!(metrics.upstream_requests, 1);
+        assert_eq!(metrics.endpoint_requests("archive"), 4);
+        assert_eq!(metrics.endpoint_requests("live"), 0);
+    }
+
+    /// Serves the fills of a memory source by whole hours only
+    struct WholeHours(MemorySource);
+
+    impl FillSource for WholeHours {
+        fn get_fills(&mut self, symbol: &str, start: i64, end: i64) -> anyhow::Result<ApiResponse> {
+            self.0.get_fills(symbol, start, end)
+        }
+
+        fn serves_partial_hours(&self) -> bool {
+            false
+        }
+    }
+
+    /// Three hours of fills with what summaries have to get right: a fill in the first
+    /// second of an hour and one on its end, the several fills of one taker trade, an
+    /// identical and a conflicting duplicate, and a fill of unknown direction
+    fn awkward_hours() -> Vec<Fill> {
+        let at_ms = |time_ms: i64, fill: Fill| Fill {
+            time: DateTime::from_timestamp_millis(time_ms).unwrap(),
+            ..fill
+        };
+        vec![
+            at_ms(HOUR * 1000 + 500, fill(0, 1, 1, "10", "1")),
+            fill(HOUR + 60, 2, 2, "11", "2"),
+            fill(HOUR + 60, 2, 2, "11.5", "1"),
+            fill(HOUR + 900, 3, 1, "12", "1"),
+            fill(HOUR + 900, 3, 1, "12", "1"),
+            fill(HOUR + 1200, 3, 2, "12", "1"),
+            fill(HOUR + 1800, 4, 0, "13", "3"),
+            fill(HOUR + 3600, 5, 1, "14", "1"),
+            fill(HOUR + 3600 + 300, 6, 2, "15", "2"),
+            fill(HOUR + 3600 + 2400, 7, 1, "16", "1"),
+            fill(HOUR + 7200 + 1, 8, 1, "17", "1"),
+            fill(HOUR + 7200 + 100, 9, 2, "18", "1"),
+            fill(HOUR + 7200 + 200, 10, 1, "19", "1"),
+            fill(HOUR + 7200 + 3000, 11, 2, "20", "4"),
+        ]
+    }
+
+    #[test]
+    fn summarized_and_streamed_hours_answer_like_their_fills() {
+        let queries = [
+            format!("C {} {}", HOUR, HOUR + 3600),
+            format!("C [) {} {}", HOUR, HOUR + 3600),
+            format!("R {} {}", HOUR, HOUR + 3600),
+            format!("C {} {}", HOUR - 1, HOUR + 10800),
+            format!("V {} {}", HOUR + 1, HOUR + 10800),
+            format!("B {} {} {} {}", HOUR, HOUR + 3600, HOUR + 3600, HOUR + 7200),
+            format!("S {} {}", HOUR + 60, HOUR + 7500),
+            format!("FR {} {}", HOUR, HOUR + 10800),
+            format!("C {} {}", HOUR + 100, HOUR + 1200),
+            format!("V {} {}", HOUR + 3000, HOUR + 4000),
+            format!("C [] {} {}", HOUR + 7201, HOUR + 7300),
+            format!("WB {} {}", HOUR, HOUR + 10800),
+            format!("SD {} {}", HOUR + 60, HOUR + 5000),
+            format!("E {} {}", HOUR + 7200, HOUR + 10800),
+            format!("MA {} {} 1800 900", HOUR + 1800, HOUR + 9000),
+            format!("C {} {}", HOUR, HOUR + 3600),
+            format!("V {} {}", HOUR, HOUR + 10800),
+        ];
+        let answers = |processor: &mut Processor| {
+            // Twice, the second time from the cache
+            (0..2)
+                .flat_map(|_| queries.clone())
+                .map(|query| processor.process_query(query).unwrap())
+                .collect::<Vec<String>>()
+        };
+        let streamed = || Config {
+            stream_threshold: Some(3),
+            ..Config::default()
+        };
+
+        let expected = answers(&mut processor(
+            Config::default(),
+            &MemorySource::new(awkward_hours()),
+        ));
+        let source = MemorySource::new(awkward_hours());
+        let mut streaming = processor(streamed(), &source);
+        assert_eq!(answers(&mut streaming), expected);
+        assert!(streaming.metrics().streamed_hours > 0);
+        let source = WholeHours(MemorySource::new(awkward_hours()));
+        let clock = Box::new(TestClock::at(HOUR + 86400));
+        let mut summarizing = Processor::with_source(streamed(), Box::new(source), clock);
+        assert_eq!(answers(&mut summarizing), expected);
+        assert!(summarizing.metrics().streamed_hours > 0);
+    }
+
+    #[test]
+    fn streamed_hours_keep_only_what_the_query_needs() {
+        let fills = (0..3600)
+            .map(|second| fill(HOUR + second + 1, second as u64, 1, "10", "1"))
+            .collect::<Vec<Fill>>();
+        let source = MemorySource::new(fills.clone());
+        let config = Config {
+            stream_threshold: Some(100),
+            ..Config::default()
+        };
+        let mut streaming = processor(config, &source);
+
+        // Folded into aggregates of the window, a page at a time, one call against the budget
+        let query = format!("C {} {}", HOUR + 60, HOUR + 120);
+        assert_eq!(streaming.process_query(query).unwrap(), "60");
+        assert!(streaming.current_fills.is_empty());
+        assert_eq!(source.requests(), (3600 / PAGE_SECS) as usize);
+        assert_eq!(streaming.metrics().upstream_requests, 1);
+        // Only the fills of the window are kept for a query that can't be folded
+        let query = format!("SD {} {}", HOUR + 60, HOUR + 120);
+        assert_eq!(streaming.process_query(query).unwrap(), "0");
+        assert_eq!(streaming.current_fills.len(), 60);
+        // The whole hour is answered from its cached aggregates
+        let query = format!("C {} {}", HOUR, HOUR + 3600);
+        assert_eq!(streaming.process_query(query).unwrap(), "3600");
+        assert_eq!(streaming.metrics().streamed_hours, 2);
+        assert_eq!(streaming.metrics().cache_hits, 1);
+
+        let mut unbounded = processor(Config::default(), &MemorySource::new(fills));
+        unbounded
+            .process_query(format!("C {} {}", HOUR, HOUR + 3600))
+            .unwrap();
+        assert!(streaming.peak_cache_bytes() * 20 < unbounded.peak_cache_bytes());
+    }
+
+    #[test]
+    fn latest_ends_at_the_newest_hour_the_source_reports() {
+        let dir = tempfile::tempdir().unwrap();
+        let fills = two_hours();
+        for (hour, fills) in [(HOUR, &fills[..2]), (HOUR + 3600, &fills[2..])] {
+            let path = hour_file::hour_path(
+                dir.path(),
+                crate::server::DEFAULT_SYMBOL,
+                hour,
+                HourFormat::Csv,
+            );
+            hour_file::write(&path, crate::server::DEFAULT_SYMBOL, hour, fills).unwrap();
+        }
+        let source = DiskSource {
+            dir: dir.path().to_path_buf(),
+            strict_schema: false,
+        };
+        // A hundred days past the data, far more than the range limit of a window to now
+        let clock = TestClock::at(HOUR + 100 * 86400);
+        let mut processor =
+            Processor::with_source(Config::default(), Box::new(source), Box::new(clock));
+        let query = format!("C {} LATEST FINAL", HOUR);
+        assert_eq!(processor.process_query(query).unwrap(), "3 [final=false]");
+        assert_eq!(processor.last_latest_end(), Some((HOUR + 7200) * 1000 - 1));
+        let (_, _, hours) = processor.plan_query(&format!("C {} LATEST", HOUR)).unwrap();
+        assert_eq!(hours, BTreeSet::from([HOUR, HOUR + 3600]));
+    }
+
+    #[test]
+    fn latest_follows_the_newest_hour_fetched_from_a_source_not_reporting_one() {
+        let source = MemorySource::new(two_hours());
+        let clock = TestClock::at(HOUR + 100 * 86400);
+        let mut processor = Processor::with_source(
+            Config::default(),
+            Box::new(source.clone()),
+            Box::new(clock.clone()),
+        );
+        let latest = format!("C {} LATEST", HOUR + 3600);
+        // Nothing fetched yet: the window runs to now
+        assert!(processor.process_query(latest.clone()).is_err());
+
+        assert_eq!(
+            processor
+                .process_query(format!("C {} {}", HOUR, HOUR + 3600))
+                .unwrap(),
+            "2"
+        );
+        // Up to the hour after the newest one that had fills, which may have some by now
+        source
+            .fills
+            .lock()
+            .unwrap()
+            .push(fill(HOUR + 7200 + 60, 4, 1, "13", "1"));
+        assert_eq!(processor.process_query(latest.clone()).unwrap(), "2");
+        assert_eq!(processor.last_latest_end(), Some((HOUR + 10800) * 1000 - 1));
+        assert!(!processor.last_answer_final());
+        // It had, so the next one asks for the hour after that
+        assert_eq!(processor.process_query(latest.clone()).unwrap(), "2");
+        assert_eq!(processor.last_latest_end(), Some((HOUR + 14400) * 1000 - 1));
+
+        // Within the data, LATEST is now
+        clock.set(HOUR + 3600 + 1800);
+        assert_eq!(processor.process_query(latest).unwrap(), "1");
+        assert_eq!(
+            processor.last_latest_end(),
+            Some((HOUR + 3600 + 1800) * 1000)
+        );
+    }
+
+    #[test]
+    fn slow_queries_are_logged_with_their_hours_and_fetch_time() {
+        let config = Config {
+            slow_query_threshold: Some(Duration::from_millis(50)),
+            ..Config::default()
+        };
+        let source = SlowSource {
+            latency: Duration::from_millis(100),
+            source: MemorySource::new(two_hours()),
+        };
+        let clock = Box::new(TestClock::at(HOUR + 86400));
+        let mut processor = Processor::with_source(config, Box::new(source), clock);
+        let query = format!("C {} {}", HOUR, HOUR + 7199);
+        let (answer, logs) = capture_logs(|| processor.process_query(query.clone()));
+        assert_eq!(answer.unwrap(), "3");
+        let slow = logs
+            .lines()
+            .filter(|line| line.contains("Slow query"))
+            .collect::<Vec<&str>>();
+        assert_eq!(slow.len(), 1, "{}", logs);
+        assert!(
+            slow[0].contains(&format!("Slow query: \"{}\" took", query)),
+            "{}",
+            slow[0]
+        );
+        assert!(
+            slow[0].contains(&format!(
+                "(hours: [{}:miss, {}:miss], upstream fetch: ",
+                HOUR,
+                HOUR + 3600
+            )),
+            "{}",
+            slow[0]
+        );
+        assert!(slow[0].contains("fills scanned: 3)"), "{}", slow[0]);
+        assert!(slow[0].contains("query_type=\"C\""), "{}", slow[0]);
+        let field = |name: &str| {
+            slow[0]
+                .split_whitespace()
+                .find_map(|token| token.strip_prefix(name))
+                .and_then(|value| value.parse::<u64>().ok())
+                .unwrap()
+        };
+        // Two fetches of 100ms, whether or not they overlapped
+        assert!(field("fetch_ms=") >= 100, "{}", slow[0]);
+        assert!(field("duration_ms=") >= 100, "{}", slow[0]);
+
+        // Answered from the cache, it is fast again
+        let (answer, logs) = capture_logs(|| processor.process_query(query.clone()));
+        assert_eq!(answer.unwrap(), "3");
+        assert!(!logs.contains("Slow query"), "{}", logs);
+    }
+
+    #[test]
+    fn cache_budget_is_the_fraction_of_the_available_memory() {
+        let source = MemorySource::new(two_hours());
+        let config = |cache_bytes| Config {
+            cache_bytes,
+            cache_mem_fraction: Some(0.25),
+            ..Config::default()
+        };
+        let sized = |config, memory: StubMemory| {
+            processor(config, &source)
+                .with_memory_probe(Box::new(memory))
+                .cache_budget
+        };
+        let memory = StubMemory::new(8_000_000, 2_000_000);
+        assert_eq!(sized(config(None), memory.clone()), Some(500_000));
+
+        // Given bytes win over memory, and memory that can't be read bounds nothing
+        assert_eq!(sized(config(Some(1234)), memory), Some(1234));
+        let unreadable = StubMemory(Arc::new(Mutex::new(None)));
+        assert_eq!(sized(config(None), unreadable), None);
+    }
+
+    #[test]
+    fn memory_pressure_shrinks_the_cache_budget_and_evicts() {
+        let source = MemorySource::new(
+            (0..3)
+                .map(|n| fill(HOUR + n * 3600 + 60, n as u64 + 1, 1, "10", "1"))
+                .collect(),
+        );
+        let config = Config {
+            cache_mem_fraction: Some(0.5),
+            memory_check_interval: Duration::ZERO,
+            memory_pressure: 0.5,
+            ..Config::default()
+        };
+        let total = 1_000_000_000;
+        let memory = StubMemory::new(total, total);
+        let mut processor = processor(config, &source).with_memory_probe(Box::new(memory.clone()));
+        let hour_query = |n: i64| format!("C {} {}", HOUR + n * 3600, HOUR + n * 3600 + 1800);
+        assert_eq!(processor.process_query(hour_query(0)).unwrap(), "1");
+        let hour_bytes = processor.cache_stats().bytes;
+        for n in 1..3 {
+            assert_eq!(processor.process_query(hour_query(n)).unwrap(), "1");
+        }
+        assert_eq!(processor.cache_stats().hours, 3);
+
+        // Less available than the threshold, but not under pressure: nothing changes
+        memory.set_available(total / 2);
+        assert_eq!(processor.process_query(hour_query(2)).unwrap(), "1");
+        assert_eq!(processor.metrics().budget_evictions, 0);
+
+        // Under pressure, the budget is half of what's left: about one hour
+        memory.set_available(2 * hour_bytes);
+        assert_eq!(processor.process_query(hour_query(2)).unwrap(), "1");
+        assert_eq!(processor.cache_budget, Some(hour_bytes));
+        assert_eq!(processor.metrics().budget_evictions, 2);
+        let cached = (0..3)
+            .map(|n| {
+                processor.is_cached(
+                    DEFAULT_VENUE,
+                    crate::server::DEFAULT_SYMBOL,
+                    HOUR + n * 3600,
+                )
+            })
+            .collect::<Vec<bool>>();
+        assert_eq!(cached, [false, false, true]);
+        assert_eq!(source.requests(), 3);
+
+        // It doesn't grow back once the pressure eases
+        memory.set_available(total);
+        assert_eq!(processor.process_query(hour_query(0)).unwrap(), "1");
+        assert_eq!(processor.cache_budget, Some(hour_bytes));
+        assert_eq!(processor.cache_stats().bytes, hour_bytes);
+        assert_eq!(source.requests(), 4);
+    }
+}
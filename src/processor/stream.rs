@@ -0,0 +1,247 @@
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use tracing::warn;
+
+use super::{fill_hash, fill_hour, is_valid, trade_key, DuplicateFills, HourSummary, TradeKey};
+use crate::config::DedupKey;
+use crate::query::Window;
+use crate::server::{ApiResponse, Fill, Side};
+
+/// Length of the pages an hour is requested in when it is streamed, in seconds
+pub(super) const PAGE_SECS: i64 = 300;
+
+/// How a fill relates to the fills of its hour checked before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Repeat {
+    /// The first fill of its taker trade
+    NewTrade,
+    /// Another fill of a taker trade seen before, at the same time and direction
+    SameTrade,
+    /// A fill whose time or direction differs from the first fill of its taker trade,
+    /// dropped
+    Conflicting,
+}
+
+/// Tells the fills repeating the taker trade of an earlier fill of the hour under the
+/// dedup key, in the order sent. It holds the time and direction of the first fill of each
+/// taker trade and a hash of each fill kept rather than the fills, so that an hour can be
+/// checked a page at a time without keeping its fills.
+pub(super) struct DuplicateResolver {
+    dedup_key: DedupKey,
+    first: HashMap<TradeKey, (DateTime<Utc>, i32)>,
+    seen: HashSet<u64>,
+    pub duplicates: DuplicateFills,
+}
+
+impl DuplicateResolver {
+    pub fn new(dedup_key: DedupKey, capacity: usize) -> Self {
+        DuplicateResolver {
+            dedup_key,
+            first: HashMap::with_capacity(capacity),
+            seen: HashSet::with_capacity(capacity),
+            duplicates: DuplicateFills::default(),
+        }
+    }
+
+    /// Checks the next fill sent for the hour, counting it when it is a duplicate and
+    /// logging it when it conflicts with the first fill of its taker trade
+    pub fn check(&mut self, fill: &Fill, market: &str, hour: i64) -> Repeat {
+        let mut repeat = Repeat::SameTrade;
+        let first = *self
+            .first
+            .entry(trade_key(fill, self.dedup_key))
+            .or_insert_with(|| {
+                repeat = Repeat::NewTrade;
+                (fill.time, fill.direction)
+            });
+        if first != (fill.time, fill.direction) {
+            self.duplicates.conflicting += 1;
+            warn!(
+                "Conflicting fills with sequence number {} in {} hour {}, keeping the first at {} with direction {}, dropping: {:?}",
+                fill.sequence_number,
+                market,
+                hour,
+                first.0,
+                first.1,
+                fill
+            );
+            return Repeat::Conflicting;
+        }
+        if !self.seen.insert(fill_hash(fill)) {
+            self.duplicates.identical += 1;
+        }
+        repeat
+    }
+}
+
+/// Aggregates of the fills within one window of the query streaming the hour
+struct Partial {
+    window: Window,
+    summary: HourSummary,
+    /// Taker trades with a fill in the window, each counted once
+    trades: HashSet<TradeKey>,
+}
+
+/// An hour fetched a page at a time, each page checked like a whole fetched hour (see
+/// `Processor::checked_hour`) and folded into the hour's aggregates as it arrives.
+/// The fills are kept until there are more than the streaming threshold; past it, only
+/// the fills the query streaming the hour needs are, and those only when the query can't
+/// be answered from aggregates per window instead.
+pub(super) struct HourFold {
+    market: String,
+    hour: i64,
+    threshold: usize,
+    strict_data: bool,
+    strict_direction: bool,
+    dedup_key: DedupKey,
+    resolver: DuplicateResolver,
+    /// Aggregates of every fill of the hour
+    pub summary: HourSummary,
+    /// Hash of the fills of the hour, see `content_hash`
+    pub content_hash: u64,
+    /// Aggregates per window of the query, when it asks for them
+    partials: Vec<Partial>,
+    /// Ranges of the fills the query needs, in Unix milliseconds, when it can't be
+    /// answered from aggregates
+    needed: Vec<(i64, i64)>,
+    /// Fills kept so far
+    pub fills: Vec<Fill>,
+    pub malformed: usize,
+    pub unknown_fields: Vec<String>,
+    pub invalid: usize,
+    pub out_of_hour: usize,
+    pub unknown_direction: usize,
+}
+
+impl HourFold {
+    /// Starts folding the hour for a query: with the windows to aggregate when the query is
+    /// answered from aggregates, and else with the ranges of the fills it needs
+    pub fn new(
+        market: String,
+        hour: i64,
+        threshold: usize,
+        strict: (bool, bool),
+        dedup_key: DedupKey,
+        aggregated: &[Window],
+        needed: Vec<(i64, i64)>,
+    ) -> Self {
+        HourFold {
+            market,
+            hour,
+            threshold,
+            strict_data: strict.0,
+            strict_direction: strict.1,
+            dedup_key,
+            resolver: DuplicateResolver::new(dedup_key, threshold),
+            summary: HourSummary::default(),
+            content_hash: 0,
+            partials: aggregated
+                .iter()
+                .map(|&window| Partial {
+                    window,
+                    summary: HourSummary::default(),
+                    trades: HashSet::new(),
+                })
+                .collect(),
+            needed,
+            fills: Vec::new(),
+            malformed: 0,
+            unknown_fields: Vec::new(),
+            invalid: 0,
+            out_of_hour: 0,
+            unknown_direction: 0,
+        }
+    }
+
+    /// Returns whether the hour holds more fills than the streaming threshold
+    pub fn is_oversized(&self) -> bool {
+        self.summary.fill_count > self.threshold
+    }
+
+    /// Folds the next page of the hour. Fails like a fetched hour on an invalid fill or
+    /// one of unknown direction when the data or directions are strict.
+    pub fn fold(&mut self, page: ApiResponse) -> anyhow::Result<()> {
+        self.malformed += page.malformed;
+        for field in page.unknown_fields {
+            if !self.unknown_fields.contains(&field) {
+                self.unknown_fields.push(field);
+            }
+        }
+        for fill in page.fills {
+            if !is_valid(&fill) {
+                if self.strict_data {
+                    return Err(anyhow::anyhow!(
+                        "Invalid fill in the {} response for hour {}: non-positive price or quantity: {:?}",
+                        self.market,
+                        self.hour,
+                        fill
+                    ));
+                }
+                self.invalid += 1;
+                continue;
+            }
+            if fill_hour(&fill) != self.hour {
+                self.out_of_hour += 1;
+                continue;
+            }
+            let repeat = self.resolver.check(&fill, &self.market, self.hour);
+            if repeat == Repeat::Conflicting {
+                continue;
+            }
+            if fill.side() == Side::Unknown {
+                if self.strict_direction {
+                    return Err(anyhow::anyhow!(
+                        "Fill of unknown direction {} in the {} response for hour {}: {:?}",
+                        fill.direction,
+                        self.market,
+                        self.hour,
+                        fill
+                    ));
+                }
+                self.unknown_direction += 1;
+            }
+            self.add(fill, repeat == Repeat::NewTrade);
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, fill: Fill, new_trade: bool) {
+        self.summary.add(&fill, new_trade);
+        self.content_hash = self.content_hash.wrapping_add(fill_hash(&fill));
+        let time_ms = fill.time.timestamp_millis();
+        for partial in &mut self.partials {
+            if time_ms > partial.window.start_ms && time_ms <= partial.window.end_ms {
+                let new_trade = partial.trades.insert(trade_key(&fill, self.dedup_key));
+                partial.summary.add(&fill, new_trade);
+            }
+        }
+
+        let needed = |fill: &Fill| {
+            let time_ms = fill.time.timestamp_millis();
+            self.needed
+                .iter()
+                .any(|&(start_ms, end_ms)| time_ms > start_ms && time_ms <= end_ms)
+        };
+        if !self.is_oversized() || needed(&fill) {
+            self.fills.push(fill);
+        }
+        // Crossing the threshold, the fills kept until then go too
+        if self.summary.fill_count == self.threshold + 1 {
+            let fills = std::mem::take(&mut self.fills);
+            self.fills = fills.into_iter().filter(needed).collect();
+        }
+    }
+
+    /// Returns the duplicates seen in the hour
+    pub fn duplicates(&self) -> DuplicateFills {
+        self.resolver.duplicates
+    }
+
+    /// Returns the aggregates of each window the query asked for, in order
+    pub fn partials(&self) -> Vec<HourSummary> {
+        self.partials
+            .iter()
+            .map(|partial| partial.summary)
+            .collect()
+    }
+}
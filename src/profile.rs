@@ -0,0 +1,159 @@
+use std::time::Duration;
+
+use crate::clock::Instant;
+use crate::stats;
+
+/// A phase of answering a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Parsing the query and planning its hours
+    Parse,
+    /// Looking the hours up in the cache and merging their fills
+    Lookup,
+    /// Fetching missed hours from the upstream
+    Fetch,
+    /// Computing the answer over the fills
+    Aggregate,
+    /// Writing the answer
+    Output,
+}
+
+impl Phase {
+    const ALL: [Phase; 5] = [
+        Phase::Parse,
+        Phase::Lookup,
+        Phase::Fetch,
+        Phase::Aggregate,
+        Phase::Output,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Parse => "parse",
+            Phase::Lookup => "lookup",
+            Phase::Fetch => "fetch",
+            Phase::Aggregate => "aggregate",
+            Phase::Output => "output",
+        }
+    }
+}
+
+/// Time spent in each phase of one query
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PhaseTimes([Duration; 5]);
+
+impl PhaseTimes {
+    pub fn get(&self, phase: Phase) -> Duration {
+        self.0[phase as usize]
+    }
+
+    pub fn add(&mut self, phase: Phase, duration: Duration) {
+        self.0[phase as usize] += duration;
+    }
+
+    /// Moves time from one phase to another, for a phase timed inside another one
+    pub fn transfer(&mut self, from: Phase, to: Phase, duration: Duration) {
+        self.0[from as usize] = self.0[from as usize].saturating_sub(duration);
+        self.0[to as usize] += duration;
+    }
+
+    /// Returns the name and time of every phase
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, Duration)> + '_ {
+        Phase::ALL
+            .iter()
+            .map(|&phase| (phase.name(), self.get(phase)))
+    }
+}
+
+/// Times the consecutive phases of one query. A disabled timer never reads the clock.
+#[derive(Debug, Default)]
+pub struct PhaseTimer {
+    last: Option<Instant>,
+    pub times: PhaseTimes,
+}
+
+impl PhaseTimer {
+    pub fn new(enabled: bool) -> Self {
+        PhaseTimer {
+            last: enabled.then(Instant::now),
+            times: PhaseTimes::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.last.is_some()
+    }
+
+    /// Ends the phase: the time since the previous lap is added to it
+    pub fn lap(&mut self, phase: Phase) {
+        if let Some(last) = &mut self.last {
+            let now = Instant::now();
+            self.times.add(phase, now - *last);
+            *last = now;
+        }
+    }
+}
+
+/// Phase times of every profiled query
+#[derive(Debug, Default)]
+pub struct Profile {
+    queries: Vec<PhaseTimes>,
+}
+
+impl Profile {
+    /// Records the phase times of a processed query
+    pub fn record(&mut self, times: PhaseTimes) {
+        self.queries.push(times);
+    }
+
+    /// Adds the time spent writing the answer of the latest query
+    pub fn record_output(&mut self, duration: Duration) {
+        if let Some(times) = self.queries.last_mut() {
+            times.add(Phase::Output, duration);
+        }
+    }
+
+    /// Formats the total and percentiles of every phase as a table
+    pub fn table(&self) -> String {
+        let mut table = format!(
+            "\nPhase profile over {} queries:\n    {:<9} {:>10} {:>7} {:>9} {:>9} {:>9}",
+            self.queries.len(),
+            "PHASE",
+            "TOTAL_MS",
+            "SHARE",
+            "P50_MS",
+            "P90_MS",
+            "P99_MS"
+        );
+        let totals = Phase::ALL.map(|phase| {
+            self.queries
+                .iter()
+                .map(|times| times.get(phase))
+                .sum::<Duration>()
+        });
+        let overall = totals.iter().sum::<Duration>();
+        for (phase, total) in Phase::ALL.into_iter().zip(totals) {
+            let durations = self
+                .queries
+                .iter()
+                .map(|times| times.get(phase))
+                .collect::<Vec<Duration>>();
+            let [p50, p90, p99] = [50, 90, 99].map(|p| stats::percentile(&durations, p));
+            let share = if overall.is_zero() {
+                0.0
+            } else {
+                total.as_secs_f64() / overall.as_secs_f64() * 100.0
+            };
+            table += &format!(
+                "\n    {:<9} {:>10.3} {:>6.2}% {:>9.3} {:>9.3} {:>9.3}",
+                phase.name(),
+                total.as_secs_f64() * 1000.0,
+                share,
+                p50.as_secs_f64() * 1000.0,
+                p90.as_secs_f64() * 1000.0,
+                p99.as_secs_f64() * 1000.0
+            );
+        }
+        table
+    }
+}
@@ -0,0 +1,79 @@
+use anyhow::anyhow;
+
+/// Version of the line protocol spoken when the input doesn't negotiate one
+pub const DEFAULT_VERSION: u32 = 1;
+
+/// Highest version of the line protocol this build speaks.
+/// Version 2 adds query ids: an "ID:" token in front of a line, echoed in front of its answer.
+pub const MAX_VERSION: u32 = 2;
+
+/// Parses a "VERSION N" line, or returns `None` when the line is something else.
+/// Answers with the version the rest of the input is held to, the requested one,
+/// or fails when this build doesn't speak it, so a client expecting a newer grammar
+/// isn't silently held to an older one.
+pub fn parse_version(line: &str) -> Option<anyhow::Result<u32>> {
+    let (word, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    if !word.eq_ignore_ascii_case("VERSION") {
+        return None;
+    }
+    Some(match rest.trim().parse::<u32>() {
+        Ok(version) if (1..=MAX_VERSION).contains(&version) => Ok(version),
+        Ok(version) if version > MAX_VERSION => Err(anyhow!(
+            "Unsupported protocol version {} (this build speaks 1 to {}): {}",
+            version,
+            MAX_VERSION,
+            line
+        )),
+        _ => Err(anyhow!(
+            "Invalid protocol version: {} (expected VERSION N, N from 1)",
+            line
+        )),
+    })
+}
+
+/// Splits the id off a line starting with an "ID:" token, made of letters, digits,
+/// '_' and '-', and returns the id and the rest of the line
+pub fn split_id(line: &str) -> Option<(&str, &str)> {
+    let (word, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let id = word.strip_suffix(':')?;
+    let valid = !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    valid.then(|| (id, rest.trim_start()))
+}
+
+/// Splits the id off a line when the negotiated version supports ids, failing with a
+/// pointer to VERSION when the line uses one under an older version
+pub fn take_id(line: &str, version: u32) -> anyhow::Result<(Option<&str>, &str)> {
+    match split_id(line) {
+        Some((id, rest)) if version >= 2 => Ok((Some(id), rest)),
+        Some(_) => Err(anyhow!(
+            "Query ids need protocol version 2, but version {} is in use (send VERSION 2 as the first line): {}",
+            version,
+            line
+        )),
+        None => Ok((None, line)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_this_build_does_not_speak_fail() {
+        assert!(parse_version("C 1 2").is_none());
+        assert_eq!(parse_version("VERSION 1").unwrap().unwrap(), 1);
+        assert_eq!(parse_version("version 2").unwrap().unwrap(), 2);
+        let error = parse_version("VERSION 3").unwrap().unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "Unsupported protocol version 3 (this build speaks 1 to 2): VERSION 3"
+        );
+        for line in ["VERSION 0", "VERSION", "VERSION two"] {
+            let error = parse_version(line).unwrap().unwrap_err().to_string();
+            assert!(error.starts_with("Invalid protocol version"), "{}", error);
+        }
+    }
+}
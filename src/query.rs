@@ -0,0 +1,961 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::source::{self, DEFAULT_VENUE};
+
+/// The statistic a query asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum QueryKind {
+    /// Number of taker trades (C)
+    Count,
+    /// Number of fills, each fill of a taker trade and each repeat of a fill included (R)
+    RawCount,
+    /// Number of market buys (B)
+    Buys,
+    /// Number of market sells (S)
+    Sells,
+    /// USD volume (V)
+    Volume,
+    /// Volume-weighted average price of the market buys (WB)
+    BuyVwap,
+    /// Volume-weighted average price of the market sells (WS)
+    SellVwap,
+    /// Sample standard deviation of fill prices (SD)
+    PriceStdDev,
+    /// Sample standard deviation of log-returns (SDR)
+    ReturnStdDev,
+    /// Earliest and latest fill timestamp plus fill count (E)
+    Coverage,
+    /// Number of maker fills (MC)
+    MakerCount,
+    /// Number of taker fills (TC)
+    TakerCount,
+    /// Taker share of the USD volume (TVS)
+    TakerVolumeShare,
+    /// Series of trailing average prices (MA)
+    MovingAverage,
+    /// Largest peak-to-trough price decline (DD)
+    MaxDrawdown,
+    /// Largest peak-to-trough price decline as a percentage of the peak (DDP)
+    MaxDrawdownPercent,
+    /// Taker trades per minute of the window (FR)
+    FillRate,
+    /// Fees per currency (FEE)
+    Fees,
+    /// Runs of fills in quick succession and the largest of them (BURST)
+    Burst,
+}
+
+impl QueryKind {
+    /// Every kind with its canonical code followed by its aliases.
+    /// Codes and aliases are matched case-insensitively, so none may equal another ignoring case.
+    const FORMS: &'static [(QueryKind, &'static [&'static str])] = &[
+        (QueryKind::Count, &["C", "count", "trades"]),
+        (QueryKind::RawCount, &["R", "raw", "raw-count"]),
+        (QueryKind::Buys, &["B", "buys", "buy"]),
+        (QueryKind::Sells, &["S", "sells", "sell"]),
+        (QueryKind::Volume, &["V", "volume"]),
+        (QueryKind::BuyVwap, &["WB", "buy-vwap"]),
+        (QueryKind::SellVwap, &["WS", "sell-vwap"]),
+        (QueryKind::PriceStdDev, &["SD", "stddev"]),
+        (QueryKind::ReturnStdDev, &["SDR", "volatility"]),
+        (QueryKind::Coverage, &["E", "coverage"]),
+        (QueryKind::MakerCount, &["MC", "makers"]),
+        (QueryKind::TakerCount, &["TC", "takers"]),
+        (QueryKind::TakerVolumeShare, &["TVS", "taker-share"]),
+        (QueryKind::MovingAverage, &["MA", "moving-average"]),
+        (QueryKind::MaxDrawdown, &["DD", "drawdown"]),
+        (QueryKind::MaxDrawdownPercent, &["DDP", "drawdown-percent"]),
+        (QueryKind::FillRate, &["FR", "fill-rate"]),
+        (QueryKind::Fees, &["FEE", "fees"]),
+        (QueryKind::Burst, &["BURST", "bursts"]),
+    ];
+
+    /// Returns whether the kind answers with a fill count or a volume
+    pub fn is_count_or_volume(self) -> bool {
+        matches!(
+            self,
+            QueryKind::Count
+                | QueryKind::RawCount
+                | QueryKind::Buys
+                | QueryKind::Sells
+                | QueryKind::Volume
+                | QueryKind::MakerCount
+                | QueryKind::TakerCount
+                | QueryKind::FillRate
+        )
+    }
+
+    /// Returns the canonical code of the kind, e.g. "C" for Count
+    pub fn code(self) -> &'static str {
+        Self::FORMS
+            .iter()
+            .find(|(kind, _)| *kind == self)
+            .map_or("?", |(_, forms)| forms[0])
+    }
+
+    /// Returns the number of the kind, its position in the list of kinds (0 for Count,
+    /// 1 for RawCount, ...), as it is coded in the binary protocol
+    pub fn number(self) -> u8 {
+        Self::FORMS
+            .iter()
+            .position(|(kind, _)| *kind == self)
+            .map_or(u8::MAX, |number| number as u8)
+    }
+
+    /// Returns the kind of the number, see `number`
+    pub fn from_number(number: u8) -> Option<QueryKind> {
+        Self::FORMS.get(number as usize).map(|(kind, _)| *kind)
+    }
+
+    /// Returns the form of a query line of the kind, for error messages
+    pub fn usage(self) -> String {
+        match self {
+            QueryKind::MovingAverage => format!(
+                "{} [@VENUE] [BOUNDS] [SYMBOL] START_TIME END_TIME WINDOW_SECONDS STEP_SECONDS",
+                self
+            ),
+            QueryKind::Burst => format!(
+                "{} [@VENUE] [BOUNDS] [SYMBOL] START_TIME END_TIME [START_TIME END_TIME ...] GAP_MS",
+                self
+            ),
+            _ => format!(
+                "{} [@VENUE] [BOUNDS] [SYMBOL] START_TIME END_TIME [START_TIME END_TIME ...]",
+                self
+            ),
+        }
+    }
+}
+
+impl fmt::Display for QueryKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl FromStr for QueryKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Self::FORMS
+            .iter()
+            .find(|(_, forms)| forms.iter().any(|form| form.eq_ignore_ascii_case(s)))
+            .map(|(kind, _)| *kind)
+            .ok_or_else(|| {
+                let accepted = Self::FORMS
+                    .iter()
+                    .map(|(_, forms)| forms.join("/"))
+                    .collect::<Vec<String>>();
+                anyhow!(
+                    "Invalid query type: {} (expected one of {}, in any case)",
+                    s,
+                    accepted.join(", ")
+                )
+            })
+    }
+}
+
+/// Whether a window holds the fills exactly at its start and at its end time, written
+/// in interval notation: "(]" (the default), "[]", "()" or "[)" (the upstream API's)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bounds {
+    pub start_inclusive: bool,
+    pub end_inclusive: bool,
+}
+
+impl Default for Bounds {
+    fn default() -> Self {
+        Bounds {
+            start_inclusive: false,
+            end_inclusive: true,
+        }
+    }
+}
+
+impl fmt::Display for Bounds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let start = if self.start_inclusive { '[' } else { '(' };
+        let end = if self.end_inclusive { ']' } else { ')' };
+        write!(f, "{}{}", start, end)
+    }
+}
+
+impl FromStr for Bounds {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut chars = s.chars();
+        let start_inclusive = match chars.next() {
+            Some('[') => true,
+            Some('(') => false,
+            _ => return Err(invalid_bounds(s)),
+        };
+        let end_inclusive = match (chars.next(), chars.next()) {
+            (Some(']'), None) => true,
+            (Some(')'), None) => false,
+            _ => return Err(invalid_bounds(s)),
+        };
+        Ok(Bounds {
+            start_inclusive,
+            end_inclusive,
+        })
+    }
+}
+
+fn invalid_bounds(s: &str) -> anyhow::Error {
+    anyhow!("Invalid window bounds: {} (expected (], [], () or [))", s)
+}
+
+/// A time window of a query, (start_ms, end_ms] in Unix milliseconds.
+/// Windows given with other bounds are shifted by a millisecond to these when parsed,
+/// as fill times are compared at millisecond precision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    /// Start of the window (exclusive)
+    pub start_ms: i64,
+    /// End of the window (inclusive)
+    pub end_ms: i64,
+    /// Start and end of the window as given, before the bounds shifted them: the first
+    /// millisecond of its start and the last of its end, in Unix milliseconds
+    pub given_ms: (i64, i64),
+    /// Whether the window ends at "LATEST": the newest data available, resolved to the
+    /// current time when parsed and clamped to the data fetched when answered
+    pub open_ended: bool,
+}
+
+/// Parameters of a series query: "MA [@VENUE] [SYMBOL] START_TIME END_TIME WINDOW_SECONDS STEP_SECONDS"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Series {
+    /// Length of the trailing window each point is computed over
+    pub window_ms: i64,
+    /// Distance between consecutive points
+    pub step_ms: i64,
+}
+
+/// A parsed query line:
+/// "TYPE [@VENUE] [BOUNDS] [SYMBOL] START_TIME END_TIME [START_TIME END_TIME ...]"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    pub kind: QueryKind,
+    /// Venue the fills are fetched from, `DEFAULT_VENUE` when the query doesn't name one
+    pub venue: String,
+    pub symbol: String,
+    /// The windows to answer, in the order given; a line holds at least one
+    pub windows: Vec<Window>,
+    /// Series parameters, given for series kinds only
+    pub series: Option<Series>,
+    /// Longest gap between consecutive fills of a burst, given for burst queries only:
+    /// "BURST [@VENUE] [SYMBOL] START_TIME END_TIME [START_TIME END_TIME ...] GAP_MS"
+    pub burst_gap_ms: Option<i64>,
+    /// Whether the answer is suffixed with whether it is final, asked by a trailing "FINAL"
+    /// token: " [final=true]" when every hour the query touched had ended when it was
+    /// fetched, " [final=false]" when one may still receive fills
+    pub report_final: bool,
+}
+
+/// Splits a query line into its tokens. Any run of whitespace, tabs included, separates
+/// two tokens, and a token starting with '#' starts an inline comment, dropped with the rest
+/// of the line.
+pub fn tokenize(text: &str) -> Vec<&str> {
+    text.split_whitespace()
+        .take_while(|token| !token.starts_with('#'))
+        .collect()
+}
+
+impl Query {
+    /// Parses a query (see `tokenize`), using `default_symbol` when the query doesn't name one, `bounds` when
+    /// it doesn't give its own, and resolving relative times against `now`.
+    /// A token starting with '@' right after the type names the venue, and a token in
+    /// interval notation after that (see `Bounds`) the bounds of all of the query's windows.
+    /// The times come in START_TIME END_TIME pairs, so an odd number of tokens
+    /// after those means the first one is the symbol. Either time of a pair, but not both,
+    /// can be a duration from the other (see `strip_duration`): "+N" seconds after the
+    /// start, or "-Nh" hours before the end.
+    pub fn parse(
+        text: &str,
+        default_symbol: &str,
+        bounds: Bounds,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<Self> {
+        let tokens = tokenize(text);
+        let Some((query_type, rest)) = tokens.split_first() else {
+            return Err(anyhow!(
+                "Empty query: expected TYPE [@VENUE] [BOUNDS] [SYMBOL] START_TIME END_TIME \
+                 [START_TIME END_TIME ...]"
+            ));
+        };
+        let kind = query_type.parse::<QueryKind>()?;
+        // Names the problem, the form the kind takes and the tokens it got
+        let invalid = |problem: String| {
+            anyhow!(
+                "Invalid {} query: {} (expected {}), got {:?}",
+                kind,
+                problem,
+                kind.usage(),
+                tokens
+            )
+        };
+
+        let (venue, rest) = match rest {
+            [venue, rest @ ..] if venue.starts_with('@') => {
+                let venue = &venue[1..];
+                if !source::is_venue_name(venue) {
+                    return Err(anyhow!(
+                        "Invalid venue \"@{}\": expected letters, digits, '-' or '_': {}",
+                        venue,
+                        text
+                    ));
+                }
+                (venue, rest)
+            }
+            _ => (DEFAULT_VENUE, rest),
+        };
+
+        let (bounds, rest) = match rest {
+            [token, rest @ ..] if token.starts_with(['(', '[']) => (token.parse::<Bounds>()?, rest),
+            _ => (bounds, rest),
+        };
+        let (rest, report_final) = match rest {
+            [rest @ .., token] if token.eq_ignore_ascii_case("FINAL") => (rest, true),
+            _ => (rest, false),
+        };
+
+        // Series parameters trail the window
+        let (rest, series) = match (kind, rest) {
+            (QueryKind::MovingAverage, [rest @ .., window, step]) => {
+                let series = Series {
+                    window_ms: parse_seconds("WINDOW_SECONDS", window)?,
+                    step_ms: parse_seconds("STEP_SECONDS", step)?,
+                };
+                (rest, Some(series))
+            }
+            (QueryKind::MovingAverage, _) => {
+                return Err(invalid(
+                    "too few tokens for a window, WINDOW_SECONDS and STEP_SECONDS".to_string(),
+                ))
+            }
+            _ => (rest, None),
+        };
+        let (rest, burst_gap_ms) = match (kind, rest) {
+            (QueryKind::Burst, [rest @ .., gap]) if rest.len() >= 2 => {
+                (rest, Some(parse_milliseconds("GAP_MS", gap)?))
+            }
+            (QueryKind::Burst, _) => {
+                return Err(invalid(
+                    "too few tokens for a window and GAP_MS".to_string(),
+                ))
+            }
+            _ => (rest, None),
+        };
+
+        let (symbol, times) = match rest {
+            // A time in the place of the symbol is more likely a time without its pair
+            [symbol, ..] if rest.len() % 2 == 1 && parse_bound("SYMBOL", symbol, now).is_ok() => {
+                return Err(invalid(format!(
+                    "an odd number of times ({}), while they come in START_TIME END_TIME pairs",
+                    rest.len()
+                )))
+            }
+            [symbol, times @ ..] if rest.len() % 2 == 1 => (*symbol, times),
+            _ => (default_symbol, rest),
+        };
+        if times.is_empty() {
+            return Err(invalid("no START_TIME END_TIME pair".to_string()));
+        }
+        if series.is_some() && times.len() != 2 {
+            return Err(invalid(format!(
+                "{} times, where a series takes a single window",
+                times.len()
+            )));
+        }
+
+        let mut windows = Vec::with_capacity(times.len() / 2);
+        for pair in times.chunks(2) {
+            let ((start_first_ms, start_last_ms), (end_first_ms, end_last_ms)) =
+                match (strip_duration(pair[0], '-'), strip_duration(pair[1], '+')) {
+                    (Some(_), Some(_)) => {
+                        return Err(anyhow!(
+                            "Window {} gives two durations ({} {}), so it has no time to \
+                             count from: {}",
+                            windows.len() + 1,
+                            pair[0],
+                            pair[1],
+                            text
+                        ))
+                    }
+                    (Some(duration), None) => {
+                        let duration_ms = parse_duration("START_TIME", pair[0], duration)?;
+                        let end = parse_end(pair[1], now)?;
+                        (shift(end, duration_ms.saturating_neg()), end)
+                    }
+                    (None, Some(duration)) => {
+                        let duration_ms = parse_duration("END_TIME", pair[1], duration)?;
+                        let start = parse_bound("START_TIME", pair[0], now)?;
+                        (start, shift(start, duration_ms))
+                    }
+                    (None, None) => (
+                        parse_bound("START_TIME", pair[0], now)?,
+                        parse_end(pair[1], now)?,
+                    ),
+                };
+            if start_last_ms > end_last_ms {
+                return Err(anyhow!(
+                    "Window {} ends before it starts ({} > {}): {}",
+                    windows.len() + 1,
+                    pair[0],
+                    pair[1],
+                    text
+                ));
+            }
+            // Bounds count whole seconds when given to the second, like the default ones do
+            let end_ms = if bounds.end_inclusive {
+                end_last_ms
+            } else {
+                end_first_ms.saturating_sub(1)
+            };
+            let start_ms = if bounds.start_inclusive {
+                start_first_ms.saturating_sub(1)
+            } else {
+                start_last_ms
+            };
+            // An open window between equal times holds nothing
+            let window = Window {
+                start_ms: start_ms.min(end_ms),
+                end_ms,
+                given_ms: (start_first_ms, end_last_ms),
+                open_ended: is_latest(pair[1]),
+            };
+            // A rate needs time to divide by
+            if kind == QueryKind::FillRate && window.start_ms == window.end_ms {
+                return Err(anyhow!(
+                    "Window {} is empty ({} = {}), so it has no fill rate: {}",
+                    windows.len() + 1,
+                    pair[0],
+                    pair[1],
+                    text
+                ));
+            }
+            windows.push(window);
+        }
+        if let Some(series) = series {
+            if windows[0].end_ms - windows[0].start_ms < series.step_ms {
+                return Err(anyhow!(
+                    "Series step is longer than the query window, so it has no points: {}",
+                    text
+                ));
+            }
+        }
+
+        Ok(Query {
+            kind,
+            venue: venue.to_string(),
+            symbol: symbol.to_string(),
+            windows,
+            series,
+            burst_gap_ms,
+            report_final,
+        })
+    }
+}
+
+/// Parses a positive number of seconds into milliseconds
+fn parse_seconds(field: &str, token: &str) -> anyhow::Result<i64> {
+    match token.parse::<i64>() {
+        Ok(seconds) if seconds > 0 => Ok(seconds.saturating_mul(1000)),
+        _ => Err(anyhow!(
+            "Invalid {} \"{}\": expected a positive number of seconds",
+            field,
+            token
+        )),
+    }
+}
+
+/// Parses a non-negative number of milliseconds
+fn parse_milliseconds(field: &str, token: &str) -> anyhow::Result<i64> {
+    match token.parse::<i64>() {
+        Ok(milliseconds) if milliseconds >= 0 => Ok(milliseconds),
+        _ => Err(anyhow!(
+            "Invalid {} \"{}\": expected a non-negative number of milliseconds",
+            field,
+            token
+        )),
+    }
+}
+
+impl Query {
+    /// Returns the end of the query's windows ending at LATEST, when it has any: the
+    /// current time once parsed, and the newest data loaded once answered
+    pub fn latest_end_ms(&self) -> Option<i64> {
+        self.windows
+            .iter()
+            .find(|window| window.open_ended)
+            .map(|window| window.end_ms)
+    }
+
+    /// Returns how far before a window's start its answer looks back
+    pub fn lookback_ms(&self) -> i64 {
+        self.series.map_or(0, |series| series.window_ms)
+    }
+}
+
+/// Names a symbol of a venue in messages: just the symbol on the default venue,
+/// "@VENUE SYMBOL" on any other
+pub fn market_name(venue: &str, symbol: &str) -> String {
+    if venue == DEFAULT_VENUE {
+        symbol.to_string()
+    } else {
+        format!("@{} {}", venue, symbol)
+    }
+}
+
+/// Formats a time in Unix milliseconds the way it would be given in a query:
+/// as Unix seconds when it covers a whole second, in milliseconds otherwise
+pub fn format_time(time_ms: i64) -> String {
+    if time_ms.rem_euclid(1000) == 999 {
+        time_ms.div_euclid(1000).to_string()
+    } else {
+        format!("{}ms", time_ms)
+    }
+}
+
+/// Returns the first and last millisecond of the given second.
+/// A bound given to the second covers the whole second, so comparing fill times in
+/// milliseconds against its ends gives the same answers as comparing whole seconds.
+fn whole_second(seconds: i64) -> (i64, i64) {
+    let first = seconds.saturating_mul(1000);
+    (first, first.saturating_add(999))
+}
+
+/// Parses a time field into Unix milliseconds, the last millisecond of the second when
+/// given to the second (see `parse_bound`)
+pub fn parse_time(field: &str, token: &str, now: DateTime<Utc>) -> anyhow::Result<i64> {
+    parse_bound(field, token, now).map(|(_, last_ms)| last_ms)
+}
+
+/// Returns whether the token is "LATEST", which ends a window at the newest data available
+fn is_latest(token: &str) -> bool {
+    token.eq_ignore_ascii_case("LATEST")
+}
+
+/// Parses an end time like `parse_bound`, also accepting "LATEST" for the current time
+fn parse_end(token: &str, now: DateTime<Utc>) -> anyhow::Result<(i64, i64)> {
+    if is_latest(token) {
+        return Ok((now.timestamp_millis(), now.timestamp_millis()));
+    }
+    parse_bound("END_TIME", token, now)
+}
+
+/// Parses a time field into the first and last Unix millisecond it covers,
+/// which are the same unless it is given to the second. The field is given either
+/// - as Unix seconds,
+/// - as Unix milliseconds with an "ms" suffix,
+/// - as an RFC3339 datetime, whose offset is honored and whose fractional seconds,
+///   when given, are kept to the millisecond, or
+/// - relative to `now` as "NOW", "NOW-N" or "NOW+N" with N in seconds
+fn parse_bound(field: &str, token: &str, now: DateTime<Utc>) -> anyhow::Result<(i64, i64)> {
+    if is_latest(token) {
+        return Err(anyhow!(
+            "Invalid {} \"{}\": LATEST can only be an END_TIME",
+            field,
+            token
+        ));
+    }
+    if let Some(offset) = strip_now(token) {
+        let offset_seconds = match offset {
+            "" => Some(0),
+            _ if offset.starts_with(['+', '-']) => offset.parse::<i64>().ok(),
+            _ => None,
+        }
+        .ok_or_else(|| {
+            anyhow!(
+                "Invalid {} \"{}\": expected NOW, NOW-N or NOW+N with N in seconds",
+                field,
+                token
+            )
+        })?;
+        let time_ms = now
+            .timestamp_millis()
+            .saturating_add(offset_seconds.saturating_mul(1000));
+        return Ok((time_ms, time_ms));
+    }
+    if let Ok(seconds) = token.parse::<i64>() {
+        return Ok(whole_second(seconds));
+    }
+    if let Some(millis) = token.strip_suffix("ms") {
+        if let Ok(millis) = millis.parse::<i64>() {
+            return Ok((millis, millis));
+        }
+    }
+    DateTime::parse_from_rfc3339(token)
+        .map(|time| {
+            if token.contains('.') {
+                (time.timestamp_millis(), time.timestamp_millis())
+            } else {
+                whole_second(time.timestamp())
+            }
+        })
+        .map_err(|e| {
+            anyhow!(
+                "Invalid {} \"{}\" ({}): expected Unix seconds (1700000000), Unix milliseconds \
+                 (1700000000250ms), an RFC3339 datetime (2023-11-14T22:13:20Z, \
+                 2023-11-14T23:13:20.250+01:00) or NOW-N",
+                field,
+                token,
+                e
+            )
+        })
+}
+
+/// Units a duration can be given in, with their length in seconds
+const DURATION_UNITS: [(char, i64); 4] = [('s', 1), ('m', 60), ('h', 3600), ('d', 86400)];
+
+/// Returns the length of a duration token after its sign: a number followed by a unit of
+/// `DURATION_UNITS`, which an end time starting with '+' may leave out for seconds.
+/// A start time starting with '-' needs the unit, so that a bare negative integer stays
+/// a Unix time and a negative time in milliseconds ("-1000ms") stays a time too.
+fn strip_duration(token: &str, sign: char) -> Option<&str> {
+    token
+        .strip_prefix(sign)
+        .filter(|length| length.starts_with(|c: char| c.is_ascii_digit()))
+        .filter(|length| {
+            let has_unit = length
+                .ends_with(|c: char| DURATION_UNITS.iter().any(|(unit, _)| *unit == c))
+                && !length.ends_with("ms");
+            sign == '+' || has_unit
+        })
+}
+
+/// Parses the length of a duration token into milliseconds
+fn parse_duration(field: &str, token: &str, length: &str) -> anyhow::Result<i64> {
+    let (number, unit_seconds) = match DURATION_UNITS
+        .iter()
+        .find(|(unit, _)| length.ends_with(*unit))
+    {
+        Some(&(_, seconds)) => (&length[..length.len() - 1], seconds),
+        None => (length, 1),
+    };
+    number
+        .parse::<i64>()
+        .map(|number| number.saturating_mul(unit_seconds).saturating_mul(1000))
+        .map_err(|_| {
+            anyhow!(
+                "Invalid {} \"{}\": expected a duration such as {}3600s, {}90m or {}1h",
+                field,
+                token,
+                &token[..1],
+                &token[..1],
+                &token[..1]
+            )
+        })
+}
+
+/// Moves the first and last millisecond of a time by the same offset
+fn shift((first_ms, last_ms): (i64, i64), offset_ms: i64) -> (i64, i64) {
+    (
+        first_ms.saturating_add(offset_ms),
+        last_ms.saturating_add(offset_ms),
+    )
+}
+
+/// Returns the rest of a token starting with "NOW", in any case
+fn strip_now(token: &str) -> Option<&str> {
+    token
+        .get(..3)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("NOW"))
+        .map(|_| &token[3..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const HOUR: i64 = 1700816400;
+
+    fn window(text: &str) -> anyhow::Result<(i64, i64)> {
+        let now = DateTime::from_timestamp(HOUR + 7200, 0).unwrap();
+        let query = Query::parse(text, "BTC-USD", Bounds::default(), now)?;
+        Ok((query.windows[0].start_ms, query.windows[0].end_ms))
+    }
+
+    #[test]
+    fn durations_resolve_against_the_other_time() {
+        let hour = (HOUR * 1000 + 999, HOUR * 1000 + 3_600_999);
+        assert_eq!(window(&format!("C {} +3600", HOUR)).unwrap(), hour);
+        assert_eq!(window(&format!("C {} +60m", HOUR)).unwrap(), hour);
+        assert_eq!(window(&format!("C -1h {}", HOUR + 3600)).unwrap(), hour);
+        assert_eq!(window(&format!("C -3600s {}", HOUR + 3600)).unwrap(), hour);
+        assert_eq!(window(&format!("C -60m {}", HOUR + 3600)).unwrap(), hour);
+        let now_ms = (HOUR + 7200) * 1000;
+        assert_eq!(window("C -1d NOW").unwrap(), (now_ms - 86_400_000, now_ms));
+    }
+
+    #[test]
+    fn negative_start_without_a_unit_is_a_time() {
+        assert_eq!(window("C -3600 0").unwrap(), (-3_599_001, 999));
+        assert_eq!(window("C -3600000ms 0").unwrap(), (-3_600_000, 999));
+        let error = window("C -1x 0").unwrap_err().to_string();
+        assert!(error.starts_with("Invalid START_TIME \"-1x\""), "{}", error);
+        let error = window("C -1h +1h").unwrap_err().to_string();
+        assert!(error.contains("gives two durations"), "{}", error);
+    }
+
+    #[test]
+    fn rfc3339_times_honour_their_offset() {
+        let hour = (HOUR * 1000 + 999, HOUR * 1000 + 3_600_999);
+        for text in [
+            "C 2023-11-24T09:00:00Z 2023-11-24T10:00:00Z",
+            "C 2023-11-24T10:00:00+01:00 2023-11-24T11:00:00+01:00",
+            "C 2023-11-24T03:30:00-05:30 2023-11-24T10:00:00+00:00",
+            "C 2023-11-24T09:00:00Z 1700820000",
+            "C 1700816400 2023-11-24T10:00:00Z",
+        ] {
+            assert_eq!(window(text).unwrap(), hour, "{}", text);
+        }
+        // Fractional seconds are kept to the millisecond
+        assert_eq!(
+            window("C 2023-11-24T10:00:00.250+01:00 2023-11-24T09:00:01.5Z").unwrap(),
+            (HOUR * 1000 + 250, HOUR * 1000 + 1500)
+        );
+    }
+
+    #[test]
+    fn invalid_dates_name_their_field_and_the_accepted_formats() {
+        for (text, field, token) in [
+            (
+                "C 2023-02-30T00:00:00Z 1700820000",
+                "START_TIME",
+                "2023-02-30T00:00:00Z",
+            ),
+            (
+                "C 2023-11-24T25:00:00Z 1700820000",
+                "START_TIME",
+                "2023-11-24T25:00:00Z",
+            ),
+            ("C 1700816400 2023-11-24", "END_TIME", "2023-11-24"),
+            (
+                "C 1700816400 2023-11-24T10:00:00",
+                "END_TIME",
+                "2023-11-24T10:00:00",
+            ),
+            (
+                "C 1700816400 2023-11-24T10:00:00+25:00",
+                "END_TIME",
+                "2023-11-24T10:00:00+25:00",
+            ),
+        ] {
+            let error = window(text).unwrap_err().to_string();
+            assert!(
+                error.starts_with(&format!("Invalid {} \"{}\"", field, token)),
+                "{}",
+                error
+            );
+            assert!(error.contains("an RFC3339 datetime"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn now_resolves_against_the_clock_of_the_query() {
+        let parse = |text: &str, now: i64| {
+            let now = DateTime::from_timestamp_millis(now).unwrap();
+            let query = Query::parse(text, "BTC-USD", Bounds::default(), now)?;
+            anyhow::Ok((query.windows[0].start_ms, query.windows[0].end_ms))
+        };
+        let now_ms = HOUR * 1000 + 250;
+        assert_eq!(
+            parse("C NOW-3600 NOW", now_ms).unwrap(),
+            (now_ms - 3_600_000, now_ms)
+        );
+        // Resolved anew against every clock reading
+        assert_eq!(
+            parse("C NOW-3600 NOW", now_ms + 60_000).unwrap(),
+            (now_ms - 3_540_000, now_ms + 60_000)
+        );
+        assert_eq!(
+            parse("C now-60 Now+0", now_ms).unwrap(),
+            (now_ms - 60_000, now_ms)
+        );
+        assert_eq!(
+            parse(&format!("C {} NOW", HOUR - 1), now_ms).unwrap(),
+            (HOUR * 1000 - 1, now_ms)
+        );
+        for text in ["C NOW-1h NOW", "C NOW60 NOW", "C NOW- NOW"] {
+            let error = parse(text, now_ms).unwrap_err().to_string();
+            assert!(error.contains("expected NOW, NOW-N or NOW+N"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn every_alias_parses_to_its_canonical_kind() {
+        let table = [
+            ("count", QueryKind::Count),
+            ("trades", QueryKind::Count),
+            ("raw", QueryKind::RawCount),
+            ("raw-count", QueryKind::RawCount),
+            ("buys", QueryKind::Buys),
+            ("buy", QueryKind::Buys),
+            ("sells", QueryKind::Sells),
+            ("sell", QueryKind::Sells),
+            ("volume", QueryKind::Volume),
+            ("buy-vwap", QueryKind::BuyVwap),
+            ("sell-vwap", QueryKind::SellVwap),
+            ("stddev", QueryKind::PriceStdDev),
+            ("volatility", QueryKind::ReturnStdDev),
+            ("coverage", QueryKind::Coverage),
+            ("makers", QueryKind::MakerCount),
+            ("takers", QueryKind::TakerCount),
+            ("taker-share", QueryKind::TakerVolumeShare),
+            ("moving-average", QueryKind::MovingAverage),
+            ("drawdown", QueryKind::MaxDrawdown),
+            ("drawdown-percent", QueryKind::MaxDrawdownPercent),
+            ("fill-rate", QueryKind::FillRate),
+            ("fees", QueryKind::Fees),
+            ("bursts", QueryKind::Burst),
+        ];
+        // Every alias of the parser is in the table, with the codes
+        let aliases = QueryKind::FORMS
+            .iter()
+            .map(|(_, forms)| forms.len() - 1)
+            .sum::<usize>();
+        assert_eq!(table.len(), aliases);
+        let codes = QueryKind::FORMS
+            .iter()
+            .map(|&(kind, _)| (kind.code(), kind));
+        for (form, kind) in table.into_iter().chain(codes) {
+            for form in [
+                form.to_string(),
+                form.to_lowercase(),
+                form.to_uppercase(),
+                form[..1].to_uppercase() + &form[1..],
+            ] {
+                assert_eq!(form.parse::<QueryKind>().unwrap(), kind, "{}", form);
+            }
+            // Downstream of the parser, only the canonical kind is left
+            let line = format!("{} {} {}", form, HOUR, HOUR + 60);
+            let line = match kind {
+                QueryKind::MovingAverage => format!("{} 60 30", line),
+                QueryKind::Burst => format!("{} 100", line),
+                _ => line,
+            };
+            let now = DateTime::from_timestamp(HOUR + 7200, 0).unwrap();
+            let query = Query::parse(&line, "BTC-USD", Bounds::default(), now).unwrap();
+            assert_eq!(query.kind, kind, "{}", line);
+        }
+
+        // Neither commands like CS and VS nor prefixes of the multi-letter codes are kinds
+        for form in ["CS", "VS", "VB", "W", "SDRX", "counts", ""] {
+            let error = form.parse::<QueryKind>().unwrap_err().to_string();
+            assert!(
+                error.contains("C/count/trades, R/raw/raw-count"),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn overlapping_windows_parse_in_the_order_given() {
+        let now = DateTime::from_timestamp(HOUR + 7200, 0).unwrap();
+        let line = format!(
+            "C {} {} {} {} {} {}",
+            HOUR,
+            HOUR + 1800,
+            HOUR + 900,
+            HOUR + 2700,
+            HOUR,
+            HOUR + 1800
+        );
+        let query = Query::parse(&line, "BTC-USD", Bounds::default(), now).unwrap();
+        let windows = query
+            .windows
+            .iter()
+            .map(|window| (window.start_ms / 1000, window.end_ms / 1000))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            windows,
+            [
+                (HOUR, HOUR + 1800),
+                (HOUR + 900, HOUR + 2700),
+                (HOUR, HOUR + 1800)
+            ]
+        );
+        assert_eq!(query.symbol, "BTC-USD");
+
+        // An odd number of tokens starts with the symbol
+        let line = format!("C ETH {} {} {} {}", HOUR, HOUR + 60, HOUR + 30, HOUR + 90);
+        let query = Query::parse(&line, "BTC-USD", Bounds::default(), now).unwrap();
+        assert_eq!((query.symbol.as_str(), query.windows.len()), ("ETH", 2));
+
+        // Each pair must be ordered, wherever it is on the line
+        let line = format!("C {} {} {} {}", HOUR, HOUR + 60, HOUR + 90, HOUR + 30);
+        let error = Query::parse(&line, "BTC-USD", Bounds::default(), now).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("Window 2 ends before it starts"),
+            "{}",
+            error
+        );
+    }
+
+    /// Tokens of near-valid query lines: query types, venues, bounds, symbols, times of every
+    /// format, durations, comments and junk
+    fn token() -> impl Strategy<Value = String> {
+        prop_oneof![
+            prop::sample::select(vec![
+                "C",
+                "v",
+                "WB",
+                "ma",
+                "BURST",
+                "FEE",
+                "@",
+                "@venue",
+                "()",
+                "[]",
+                "(]",
+                "[)",
+                "BTC",
+                "#",
+                "# comment",
+                "NOW",
+                "LATEST",
+                "+",
+                "-",
+                "+1h",
+                "-1d",
+                "+0s",
+                "ms",
+                "FINAL",
+            ])
+            .prop_map(str::to_string),
+            any::<i64>().prop_map(|time| time.to_string()),
+            (
+                any::<i64>(),
+                prop::sample::select(vec!["", "s", "ms", "m", "h", "d", "w", "x"])
+            )
+                .prop_map(|(amount, unit)| format!("{:+}{}", amount, unit)),
+            "[0-9T:.Z+-]{1,24}",
+            any::<String>(),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn parsing_never_panics(
+            tokens in prop::collection::vec(token(), 0..8),
+            separators in prop::collection::vec("[ \t]{1,3}", 8),
+            now in any::<i64>(),
+        ) {
+            let line = tokens
+                .iter()
+                .zip(&separators)
+                .map(|(token, separator)| format!("{}{}", token, separator))
+                .collect::<String>();
+            let now = DateTime::from_timestamp(now.rem_euclid(1 << 40), 0).unwrap();
+            let _ = Query::parse(&line, "BTC-USD", Bounds::default(), now);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::clock::Instant;
+
+/// Bounds of the retries of queries failed by an unavailable upstream
+#[derive(Debug, Clone, Copy)]
+pub struct RetryConfig {
+    /// Time between a failed attempt and the next one
+    pub delay: Duration,
+    /// Number of retries of a query before it fails permanently
+    pub rounds: u32,
+    /// Number of queries waiting for a retry at once
+    pub capacity: usize,
+}
+
+/// A failed query waiting for its next attempt
+pub struct Retry {
+    /// Input line number of the query, counting from 1
+    pub line: usize,
+    pub query: String,
+    /// Number of retries made so far
+    attempts: u32,
+    due: Instant,
+}
+
+/// A query that was retried until it failed for good
+pub struct PermanentFailure {
+    pub line: usize,
+    pub query: String,
+    /// Number of attempts made, the first one included
+    pub attempts: u32,
+    pub error: String,
+}
+
+/// Queue of the queries failed by an unavailable upstream, retried in the order they failed
+pub struct RetryQueue {
+    config: RetryConfig,
+    waiting: VecDeque<Retry>,
+    failures: Vec<PermanentFailure>,
+    /// Number of queries answered by a retry
+    pub recovered: usize,
+}
+
+impl RetryQueue {
+    pub fn new(config: RetryConfig) -> Self {
+        RetryQueue {
+            config,
+            waiting: VecDeque::new(),
+            failures: Vec::new(),
+            recovered: 0,
+        }
+    }
+
+    /// Schedules the first retry of a query that just failed, returning false
+    /// when the queue is full and the query wasn't added
+    pub fn push(&mut self, line: usize, query: &str, now: Instant) -> bool {
+        if self.waiting.len() >= self.config.capacity {
+            return false;
+        }
+        self.waiting.push_back(Retry {
+            line,
+            query: query.to_string(),
+            attempts: 0,
+            due: now + self.config.delay,
+        });
+        true
+    }
+
+    /// Returns when the next retry is due, if there is any
+    pub fn next_due(&self) -> Option<Instant> {
+        self.waiting.front().map(|retry| retry.due)
+    }
+
+    /// Removes and returns the retries due at `now`, in the order they failed
+    pub fn take_due(&mut self, now: Instant) -> Vec<Retry> {
+        let due = self
+            .waiting
+            .iter()
+            .take_while(|retry| retry.due <= now)
+            .count();
+        self.waiting.drain(..due).collect()
+    }
+
+    /// Records a failed retry: it is scheduled again while it has rounds left and `retryable`
+    /// is set, and fails permanently otherwise
+    pub fn fail(&mut self, mut retry: Retry, retryable: bool, error: String, now: Instant) {
+        retry.attempts += 1;
+        if retryable && retry.attempts < self.config.rounds {
+            retry.due = now + self.config.delay;
+            self.waiting.push_back(retry);
+        } else {
+            self.failures.push(PermanentFailure {
+                line: retry.line,
+                query: retry.query,
+                attempts: retry.attempts + 1,
+                error,
+            });
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+
+    /// Returns the queries that failed for good, in the order they did
+    pub fn failures(&self) -> &[PermanentFailure] {
+        &self.failures
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::intern::HourKey;
+use crate::server::Fill;
+
+/// Sequence number spans of the cached hours, so the hours holding a range of sequence
+/// numbers are found without scanning every cached hour. Kept in step with the cache:
+/// hours are indexed when cached and removed from the index when they leave it.
+#[derive(Debug, Default)]
+pub struct SeqIndex {
+    /// Highest sequence number of each indexed hour, per venue and symbol,
+    /// by the hour's lowest sequence number and the hour
+    spans: HashMap<(String, String), BTreeMap<(u64, i64), u64>>,
+    /// Lowest sequence number of each indexed hour, to find its span when it is removed
+    lowest: HashMap<HourKey, u64>,
+}
+
+impl SeqIndex {
+    /// Indexes the fills of a cached hour, replacing what was indexed for it.
+    /// Hours without fills hold no sequence numbers and aren't indexed.
+    pub fn insert(&mut self, key: &HourKey, fills: &[Fill]) {
+        self.remove(key);
+        let sequence_numbers = fills.iter().map(|fill| fill.sequence_number);
+        let (Some(lowest), Some(highest)) =
+            (sequence_numbers.clone().min(), sequence_numbers.max())
+        else {
+            return;
+        };
+        let (venue, symbol, hour) = key;
+        self.spans
+            .entry((venue.to_string(), symbol.to_string()))
+            .or_default()
+            .insert((lowest, *hour), highest);
+        self.lowest.insert(key.clone(), lowest);
+    }
+
+    /// Removes an hour that left the cache
+    pub fn remove(&mut self, key: &HourKey) {
+        let Some(lowest) = self.lowest.remove(key) else {
+            return;
+        };
+        let (venue, symbol, hour) = key;
+        let market = (venue.to_string(), symbol.to_string());
+        if let Some(spans) = self.spans.get_mut(&market) {
+            spans.remove(&(lowest, *hour));
+            if spans.is_empty() {
+                self.spans.remove(&market);
+            }
+        }
+    }
+
+    /// Returns the first and last hour of the venue's symbol holding the range of
+    /// sequence numbers from `from` to `to` (see `hour_bounds`)
+    pub fn bounds(&self, venue: &str, symbol: &str, from: u64, to: u64) -> Option<(i64, i64)> {
+        hour_bounds(
+            self.spans.get(&(venue.to_string(), symbol.to_string()))?,
+            from,
+            to,
+        )
+    }
+}
+
+/// Highest sequence number of hours by their lowest sequence number and the hour
+pub type Spans = BTreeMap<(u64, i64), u64>;
+
+/// Returns the first and last of the hours whose sequence numbers bound the range from `from`
+/// to `to`: the hour with the highest lowest sequence number at most `from`, and the first
+/// hour whose highest sequence number is at least `to`. As sequence numbers grow with time,
+/// every fill of the range is in those hours or the hours between them.
+/// Returns `None` when no hour bounds the range on either side.
+pub fn hour_bounds(spans: &Spans, from: u64, to: u64) -> Option<(i64, i64)> {
+    let (_, first) = *spans.range(..=(from, i64::MAX)).next_back()?.0;
+    let last = match spans.range(..=(to, i64::MAX)).next_back() {
+        Some((&(_, hour), &highest)) if highest >= to => hour,
+        _ => spans.range((to.saturating_add(1), i64::MIN)..).next()?.0 .1,
+    };
+    (first <= last).then_some((first, last))
+}
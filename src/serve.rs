@@ -0,0 +1,232 @@
+use anyhow::{anyhow, Context};
+use chrono::Utc;
+use clap::Args;
+use std::fs;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::config::{Config, ConfigArgs};
+use crate::otlp::OtlpEndpoint;
+use crate::pool::{Priority, WorkerPool, DEFAULT_BATCH_AGING};
+use crate::{check_output_flags, logging, pipeline};
+use health::Readiness;
+
+pub mod binary;
+pub mod health;
+pub mod http;
+pub mod metrics;
+pub mod socket;
+
+/// Flags of the `serve` command
+#[derive(Debug, Args)]
+#[command(group(clap::ArgGroup::new("listen").required(true).multiple(true).args(["http", "tcp", "unix"])))]
+pub struct ServeArgs {
+    /// Serve queries over HTTP on ADDR, like 127.0.0.1:8080 (port 0 picks a free port)
+    #[arg(long, value_name = "ADDR")]
+    http: Option<SocketAddr>,
+    /// Serve the line protocol over TCP on ADDR
+    #[arg(long, value_name = "ADDR")]
+    tcp: Option<SocketAddr>,
+    /// Serve the line protocol on a Unix socket at PATH, replacing a socket left there
+    #[arg(long, value_name = "PATH")]
+    unix: Option<PathBuf>,
+    /// Number of workers answering queries, each with a processor, cache and source of
+    /// its own; the first loads and writes the snapshot
+    #[arg(long, value_name = "N", default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+    workers: u64,
+    /// Milliseconds a batch query waits at most before it is served ahead of the interactive
+    /// queries queued after it, so a steady stream of them can't starve the batch class
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_BATCH_AGING.as_millis() as u64)]
+    batch_aging_ms: u64,
+    /// Hour, in Unix seconds, of the default symbol fetched from the live source to probe it
+    /// before the server is ready; by default the hour before the one the server starts in
+    #[arg(long, value_name = "HOUR")]
+    probe_hour: Option<i64>,
+    /// Number of queries waiting for a worker at most, of both classes; the queries beyond
+    /// it are refused, over HTTP with 429 and on sockets with BUSY
+    #[arg(long, value_name = "N", default_value_t = 1024)]
+    queue_depth: usize,
+    /// Longest window, in hours, whose fills `GET /fills` returns
+    #[arg(long, value_name = "HOURS", default_value_t = 24, value_parser = clap::value_parser!(i64).range(1..))]
+    fills_max_span_hours: i64,
+    /// Milliseconds between the probes of a live source that failed its last one
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    probe_interval_ms: u64,
+    /// Export the spans of the queries to the trace collector at URL, over OTLP/HTTP as
+    /// JSON: http://HOST:PORT[/PATH], the path /v1/traces by default
+    #[arg(long, value_name = "URL")]
+    otlp_endpoint: Option<OtlpEndpoint>,
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+/// What the listeners of a server share
+pub struct Server {
+    pub pool: WorkerPool,
+    /// Configuration of the workers; its output flags shape the answers to query lines
+    pub config: Config,
+    /// What `/readyz` waits for: the workers to have created their processors, loading the
+    /// snapshot, and the live source to have answered a probe
+    pub readiness: Readiness,
+    /// See `--fills-max-span-hours`
+    pub fills_max_span_hours: i64,
+}
+
+/// A server started by `start`, serving until the process ends
+pub struct RunningServer {
+    /// Addresses the listeners are bound to, with any port 0 resolved
+    pub http: Option<SocketAddr>,
+    pub tcp: Option<SocketAddr>,
+    pub unix: Option<PathBuf>,
+    listeners: Vec<JoinHandle<()>>,
+}
+
+impl RunningServer {
+    /// Blocks for as long as the listeners run, which is until the process ends
+    pub fn wait(self) -> anyhow::Result<()> {
+        for listener in self.listeners {
+            listener
+                .join()
+                .map_err(|_| anyhow!("A listener panicked"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Entry point of the `serve` command: answers queries from any number of clients, over
+/// HTTP (see `http`) and the line protocol on TCP or Unix sockets (see `socket`), with a
+/// pool of workers (see `WorkerPool`). Each listener prints the address it listens on to
+/// stdout once bound, so a port picked by the system can be found. Runs until killed.
+pub fn main(args: ServeArgs) -> anyhow::Result<()> {
+    let config = Config::from(args.config.clone());
+    logging::init_with_otlp(config.log_format, args.otlp_endpoint.clone());
+    let server = start(args)?;
+    for (protocol, addr) in [("http", server.http), ("tcp", server.tcp)] {
+        if let Some(addr) = addr {
+            announce(protocol, addr)?;
+        }
+    }
+    if let Some(path) = &server.unix {
+        announce("unix", path.display())?;
+    }
+    server.wait()
+}
+
+/// Starts the workers and listeners of a server and returns once the listeners are bound
+pub fn start(args: ServeArgs) -> anyhow::Result<RunningServer> {
+    let config = Config::from(args.config.clone());
+    check_output_flags(&config)?;
+    let pool = pipeline::start_pool(
+        args.config,
+        args.workers as usize,
+        Duration::from_millis(args.batch_aging_ms),
+        args.queue_depth,
+    );
+    let readiness = Readiness::default();
+    readiness.add("workers", pool.started());
+    let probe_hour = args.probe_hour.unwrap_or_else(|| {
+        let now = Utc::now().timestamp();
+        now - now.rem_euclid(3600) - 3600
+    });
+    health::probe_upstream(
+        &config,
+        probe_hour,
+        Duration::from_millis(args.probe_interval_ms),
+        readiness.register("upstream"),
+    );
+    let server = Arc::new(Server {
+        pool,
+        config,
+        readiness,
+        fills_max_span_hours: args.fills_max_span_hours,
+    });
+    let mut running = RunningServer {
+        http: None,
+        tcp: None,
+        unix: None,
+        listeners: Vec::new(),
+    };
+
+    if let Some(addr) = args.tcp {
+        let listener =
+            TcpListener::bind(addr).with_context(|| format!("Failed to listen on {}", addr))?;
+        running.tcp = Some(listener.local_addr()?);
+        let server = Arc::clone(&server);
+        running.listeners.push(serve_forever(
+            "TCP",
+            move || listener.accept().map(|(stream, _)| stream),
+            move |stream: TcpStream| match stream.try_clone() {
+                Ok(reader) => {
+                    socket::serve_connection(&server, reader, stream, Priority::Interactive)
+                }
+                Err(e) => warn!("Failed to read a TCP connection: {}", e),
+            },
+        ));
+    }
+    if let Some(path) = &args.unix {
+        if path.exists() {
+            fs::remove_file(path)
+                .with_context(|| format!("Failed to replace {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Failed to listen on {}", path.display()))?;
+        running.unix = Some(path.clone());
+        let server = Arc::clone(&server);
+        running.listeners.push(serve_forever(
+            "Unix socket",
+            move || listener.accept().map(|(stream, _)| stream),
+            move |stream: UnixStream| match stream.try_clone() {
+                Ok(reader) => {
+                    socket::serve_connection(&server, reader, stream, Priority::Interactive)
+                }
+                Err(e) => warn!("Failed to read a Unix socket connection: {}", e),
+            },
+        ));
+    }
+    if let Some(addr) = args.http {
+        let listener =
+            TcpListener::bind(addr).with_context(|| format!("Failed to listen on {}", addr))?;
+        running.http = Some(listener.local_addr()?);
+        let server = Arc::clone(&server);
+        running.listeners.push(serve_forever(
+            "HTTP",
+            move || listener.accept().map(|(stream, _)| stream),
+            move |stream: TcpStream| http::serve_connection(&server, stream),
+        ));
+    }
+
+    Ok(running)
+}
+
+/// Accepts connections on a thread of its own, with `accept`, and handles each on a
+/// thread of its own, with `handle`, for as long as the process runs
+fn serve_forever<S: Send + 'static>(
+    protocol: &'static str,
+    mut accept: impl FnMut() -> io::Result<S> + Send + 'static,
+    handle: impl Fn(S) + Send + Sync + 'static,
+) -> JoinHandle<()> {
+    let handle = Arc::new(handle);
+    thread::spawn(move || loop {
+        match accept() {
+            Ok(stream) => {
+                let handle = Arc::clone(&handle);
+                thread::spawn(move || handle(stream));
+            }
+            Err(e) => warn!("Failed to accept a {} connection: {}", protocol, e),
+        }
+    })
+}
+
+/// Prints the address a listener is bound to as "Listening on PROTOCOL ADDR"
+fn announce(protocol: &str, addr: impl std::fmt::Display) -> io::Result<()> {
+    info!("Listening on {} {}", protocol, addr);
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "Listening on {} {}", protocol, addr)?;
+    stdout.flush()
+}
@@ -0,0 +1,424 @@
+//! Binary protocol of the socket listeners, for clients that want to skip formatting and
+//! parsing text lines. A connection speaks it when its first byte is `MAGIC`; after that
+//! byte, requests and responses are frames, each a `u32` payload length followed by the
+//! payload. Every integer is little-endian.
+//!
+//! A request payload is `REQUEST_BYTES` long:
+//! - `u8` version, `VERSION`
+//! - `u8` query type, see `QueryKind::number` (0 for `C`, 1 for `R`, ...)
+//! - `u8` flags: bit 0 queues the query in the batch class, see `Priority`
+//! - `i64` start and `i64` end of the window, in Unix seconds, of the default symbol
+//!
+//! A response payload is:
+//! - `u8` version, `VERSION`
+//! - `u8` status, see `Status`
+//! - `u8` flags: bit 0 is set when the answer is final
+//! - `u8` encoding of the value, then the value: 0 for an `i64`, 1 for a decimal as an
+//!   `i128` mantissa and a `u8` scale, and 2 for UTF-8 text, the rest of the payload
+//!
+//! An error answers with its message as text. Responses come in the order of the requests.
+//! A frame that can't be decoded is answered with an error and the connection is closed.
+
+use anyhow::anyhow;
+use rust_decimal::Decimal;
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use tracing::debug;
+
+use super::Server;
+use crate::pool::{Priority, QueueFull, Reply};
+use crate::processor::TIMEOUT;
+use crate::query::QueryKind;
+
+/// First byte of a connection speaking the binary protocol, which no text line starts with
+pub const MAGIC: u8 = 0xB1;
+/// Version of the frames, the first byte of every payload
+pub const VERSION: u8 = 1;
+/// Length of a request payload
+pub const REQUEST_BYTES: usize = 19;
+/// Largest payload read
+const MAX_FRAME_BYTES: u32 = 64 * 1024;
+/// Requests of a connection read ahead of the response being written
+const PENDING_REQUESTS: usize = 256;
+
+/// Flag of a request queued in the batch class
+const FLAG_BATCH: u8 = 1;
+/// Flag of a final answer
+const FLAG_FINAL: u8 = 1;
+
+/// A query of the binary protocol
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub kind: QueryKind,
+    pub priority: Priority,
+    pub start: i64,
+    pub end: i64,
+}
+
+impl Request {
+    /// Decodes a request payload
+    pub fn decode(payload: &[u8]) -> anyhow::Result<Request> {
+        if payload.len() != REQUEST_BYTES {
+            return Err(anyhow!(
+                "Request of {} bytes, expected {}",
+                payload.len(),
+                REQUEST_BYTES
+            ));
+        }
+        if payload[0] != VERSION {
+            return Err(anyhow!(
+                "Unsupported binary protocol version {} (this build speaks {})",
+                payload[0],
+                VERSION
+            ));
+        }
+        let kind = QueryKind::from_number(payload[1])
+            .ok_or_else(|| anyhow!("Unknown query type {}", payload[1]))?;
+        let flags = payload[2];
+        if flags & !FLAG_BATCH != 0 {
+            return Err(anyhow!("Unknown request flags {:#04x}", flags));
+        }
+        Ok(Request {
+            kind,
+            priority: if flags & FLAG_BATCH != 0 {
+                Priority::Batch
+            } else {
+                Priority::Interactive
+            },
+            start: i64::from_le_bytes(payload[3..11].try_into().unwrap()),
+            end: i64::from_le_bytes(payload[11..19].try_into().unwrap()),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(REQUEST_BYTES);
+        payload.push(VERSION);
+        payload.push(self.kind.number());
+        payload.push(match self.priority {
+            Priority::Interactive => 0,
+            Priority::Batch => FLAG_BATCH,
+        });
+        payload.extend_from_slice(&self.start.to_le_bytes());
+        payload.extend_from_slice(&self.end.to_le_bytes());
+        payload
+    }
+
+    /// Returns the query line of the request
+    pub fn query(&self) -> String {
+        format!("{} {} {}", self.kind, self.start, self.end)
+    }
+}
+
+/// Status of a response
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0,
+    /// The query failed; the value is the error
+    Error = 1,
+    /// The queue of the workers was full; the value is a message
+    Busy = 2,
+    /// The query was still unanswered when its deadline passed
+    Timeout = 3,
+}
+
+/// Value of a response
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Integer(i64),
+    Decimal(Decimal),
+    Text(String),
+}
+
+impl Value {
+    /// Returns the value of an answer, a number when it is one
+    pub fn of_answer(answer: &str) -> Value {
+        if let Ok(integer) = answer.parse() {
+            Value::Integer(integer)
+        } else if let Ok(decimal) = answer.parse() {
+            Value::Decimal(decimal)
+        } else {
+            Value::Text(answer.to_string())
+        }
+    }
+}
+
+/// A response of the binary protocol
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: Status,
+    pub is_final: bool,
+    pub value: Value,
+}
+
+impl Response {
+    pub fn error(error: &anyhow::Error) -> Response {
+        Response {
+            status: Status::Error,
+            is_final: false,
+            value: Value::Text(format!("{:#}", error)),
+        }
+    }
+
+    /// Returns the response to the reply of a worker
+    pub fn of_reply(reply: Reply) -> Response {
+        match reply.result {
+            Ok(result) if result == TIMEOUT => Response {
+                status: Status::Timeout,
+                is_final: false,
+                value: Value::Text(result),
+            },
+            Ok(result) => Response {
+                status: Status::Ok,
+                is_final: reply.is_final,
+                value: Value::of_answer(&result),
+            },
+            Err(e) => Response::error(&e),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = vec![
+            VERSION,
+            self.status as u8,
+            if self.is_final { FLAG_FINAL } else { 0 },
+        ];
+        match &self.value {
+            Value::Integer(integer) => {
+                payload.push(0);
+                payload.extend_from_slice(&integer.to_le_bytes());
+            }
+            Value::Decimal(decimal) => {
+                payload.push(1);
+                payload.extend_from_slice(&decimal.mantissa().to_le_bytes());
+                payload.push(decimal.scale() as u8);
+            }
+            Value::Text(text) => {
+                payload.push(2);
+                payload.extend_from_slice(text.as_bytes());
+            }
+        }
+        payload
+    }
+
+    /// Decodes a response payload
+    pub fn decode(payload: &[u8]) -> anyhow::Result<Response> {
+        let [version, status, flags, encoding, value @ ..] = payload else {
+            return Err(anyhow!("Response of {} bytes, too short", payload.len()));
+        };
+        if *version != VERSION {
+            return Err(anyhow!("Unsupported binary protocol version {}", version));
+        }
+        let status = match status {
+            0 => Status::Ok,
+            1 => Status::Error,
+            2 => Status::Busy,
+            3 => Status::Timeout,
+            _ => return Err(anyhow!("Unknown response status {}", status)),
+        };
+        if flags & !FLAG_FINAL != 0 {
+            return Err(anyhow!("Unknown response flags {:#04x}", flags));
+        }
+        let value = match (encoding, value.len()) {
+            (0, 8) => Value::Integer(i64::from_le_bytes(value.try_into().unwrap())),
+            (1, 17) => Value::Decimal(
+                Decimal::try_from_i128_with_scale(
+                    i128::from_le_bytes(value[..16].try_into().unwrap()),
+                    value[16] as u32,
+                )
+                .map_err(|e| anyhow!("Invalid decimal: {}", e))?,
+            ),
+            (2, _) => Value::Text(
+                String::from_utf8(value.to_vec()).map_err(|_| anyhow!("Invalid UTF-8 text"))?,
+            ),
+            _ => {
+                return Err(anyhow!(
+                    "Invalid value of encoding {} and {} bytes",
+                    encoding,
+                    value.len()
+                ))
+            }
+        };
+        Ok(Response {
+            status,
+            is_final: flags & FLAG_FINAL != 0,
+            value,
+        })
+    }
+}
+
+/// Reads a frame and returns its payload, or `None` at the end of the connection
+pub fn read_frame(reader: &mut impl Read) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut length = [0; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let length = u32::from_le_bytes(length);
+    if length > MAX_FRAME_BYTES {
+        return Err(anyhow!(
+            "Frame of {} bytes, over the limit of {}",
+            length,
+            MAX_FRAME_BYTES
+        ));
+    }
+    let mut payload = vec![0; length as usize];
+    reader
+        .read_exact(&mut payload)
+        .map_err(|e| anyhow!("Frame cut short: {}", e))?;
+    Ok(Some(payload))
+}
+
+pub fn write_frame(writer: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(payload)
+}
+
+/// What a request of a connection is answered with, in the order of the requests
+enum Pending {
+    Reply(Receiver<Reply>),
+    Busy(QueueFull),
+    Failed(anyhow::Error),
+}
+
+/// Serves the binary protocol on one connection, after its `MAGIC` byte, like the line
+/// protocol (see `socket::serve_connection`). The queries are queued in the class of
+/// `priority` unless their flags say batch.
+pub fn serve_connection(
+    server: &Server,
+    mut reader: impl Read + Send,
+    writer: impl Write,
+    priority: Priority,
+) {
+    let (sender, receiver) = mpsc::sync_channel::<Pending>(PENDING_REQUESTS);
+    thread::scope(|scope| {
+        scope.spawn(move || loop {
+            let pending = match read_frame(&mut reader).and_then(|payload| match payload {
+                Some(payload) => Request::decode(&payload).map(Some),
+                None => Ok(None),
+            }) {
+                Ok(Some(request)) => {
+                    let priority = match request.priority {
+                        Priority::Batch => Priority::Batch,
+                        Priority::Interactive => priority,
+                    };
+                    match server.pool.submit(request.query(), priority) {
+                        Ok(receiver) => Pending::Reply(receiver),
+                        Err(e) => Pending::Busy(e),
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    // The framing is lost, so the connection closes after the error
+                    let _ = sender.send(Pending::Failed(e));
+                    break;
+                }
+            };
+            if sender.send(pending).is_err() {
+                break;
+            }
+        });
+
+        let mut writer = BufWriter::new(writer);
+        let mut next = receiver.recv().map_err(|_| TryRecvError::Disconnected);
+        while let Ok(pending) = next {
+            let response = match pending {
+                Pending::Reply(receiver) => match receiver.recv() {
+                    Ok(reply) => Response::of_reply(reply),
+                    Err(_) => Response::error(&anyhow!("The worker answering the query stopped")),
+                },
+                Pending::Busy(e) => Response {
+                    status: Status::Busy,
+                    is_final: false,
+                    value: Value::Text(e.to_string()),
+                },
+                Pending::Failed(e) => Response::error(&e),
+            };
+            if let Err(e) = write_frame(&mut writer, &response.encode()) {
+                debug!("Closing a connection that can't be written: {}", e);
+                return;
+            }
+            next = match receiver.try_recv() {
+                Err(TryRecvError::Empty) => {
+                    if writer.flush().is_err() {
+                        return;
+                    }
+                    receiver.recv().map_err(|_| TryRecvError::Disconnected)
+                }
+                next => next,
+            };
+        }
+        let _ = writer.flush();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let request = Request {
+            kind: QueryKind::SellVwap,
+            priority: Priority::Batch,
+            start: 1700816400,
+            end: -1,
+        };
+        assert_eq!(Request::decode(&request.encode()).unwrap(), request);
+        assert_eq!(request.query(), "WS 1700816400 -1");
+        for (answer, value) in [
+            ("4509", Value::Integer(4509)),
+            (
+                "1813528.229735",
+                Value::Decimal("1813528.229735".parse().unwrap()),
+            ),
+            ("-", Value::Text("-".to_string())),
+        ] {
+            let response = Response {
+                status: Status::Ok,
+                is_final: true,
+                value: Value::of_answer(answer),
+            };
+            assert_eq!(response.value, value);
+            assert_eq!(Response::decode(&response.encode()).unwrap(), response);
+        }
+
+        let mut frames = Vec::new();
+        write_frame(&mut frames, b"one").unwrap();
+        write_frame(&mut frames, b"").unwrap();
+        let mut reader = frames.as_slice();
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), b"one");
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), b"");
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+
+    proptest! {
+        #[test]
+        fn malformed_frames_fail_cleanly(bytes in proptest::collection::vec(any::<u8>(), 0..64)) {
+            let _ = Request::decode(&bytes);
+            let _ = Response::decode(&bytes);
+            let mut reader = bytes.as_slice();
+            while let Ok(Some(payload)) = read_frame(&mut reader) {
+                let _ = Request::decode(&payload);
+            }
+        }
+
+        #[test]
+        fn requests_decode_as_encoded(
+            kind in 0..19u8,
+            batch in any::<bool>(),
+            start in any::<i64>(),
+            end in any::<i64>(),
+        ) {
+            let request = Request {
+                kind: QueryKind::from_number(kind).unwrap(),
+                priority: if batch { Priority::Batch } else { Priority::Interactive },
+                start,
+                end,
+            };
+            prop_assert_eq!(Request::decode(&request.encode()).unwrap(), request);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::pipeline;
+
+/// Conditions a server must meet before it is ready to serve, each a flag published by the
+/// subsystem it is about, like the workers or the upstream probe. `/readyz` answers 503
+/// while any is unset.
+#[derive(Default)]
+pub struct Readiness {
+    conditions: Mutex<Vec<(&'static str, Arc<AtomicBool>)>>,
+}
+
+impl Readiness {
+    /// Adds a condition, met while the flag is set
+    pub fn add(&self, name: &'static str, flag: Arc<AtomicBool>) {
+        self.conditions.lock().unwrap().push((name, flag));
+    }
+
+    /// Adds a condition, unmet until the returned flag is set
+    pub fn register(&self, name: &'static str) -> Arc<AtomicBool> {
+        let flag = Arc::new(AtomicBool::new(false));
+        self.add(name, Arc::clone(&flag));
+        flag
+    }
+
+    /// Returns the names of the conditions not met, in the order they were added
+    pub fn unmet(&self) -> Vec<&'static str> {
+        self.conditions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, flag)| !flag.load(Ordering::SeqCst))
+            .map(|(name, _)| *name)
+            .collect()
+    }
+}
+
+/// Probes the live source on a thread of its own, fetching the hour of the default symbol
+/// every `interval` until a fetch succeeds, then sets `probed`
+pub fn probe_upstream(config: &Config, hour: i64, interval: Duration, probed: Arc<AtomicBool>) {
+    let mut source = pipeline::build_source(config);
+    let symbol = config.default_symbol.clone();
+    thread::spawn(move || loop {
+        match source.get_fills(&symbol, hour, hour + 3600) {
+            Ok(_) => {
+                info!("Probed the upstream with hour {} of {}", hour, symbol);
+                probed.store(true, Ordering::SeqCst);
+                return;
+            }
+            Err(e) => {
+                warn!("The upstream failed its probe: {:#}", e);
+                thread::sleep(interval);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readiness_lists_the_unmet_conditions() {
+        let readiness = Readiness::default();
+        assert!(readiness.unmet().is_empty());
+        let workers = readiness.register("workers");
+        readiness.add("upstream", Arc::new(AtomicBool::new(true)));
+        let snapshot = readiness.register("snapshot");
+        assert_eq!(readiness.unmet(), ["workers", "snapshot"]);
+        snapshot.store(true, Ordering::SeqCst);
+        assert_eq!(readiness.unmet(), ["workers"]);
+        workers.store(true, Ordering::SeqCst);
+        assert!(readiness.unmet().is_empty());
+    }
+}
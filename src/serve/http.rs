@@ -0,0 +1,493 @@
+use anyhow::anyhow;
+use chrono::Utc;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::net::TcpStream;
+use tracing::{debug, info_span};
+
+use super::{metrics, socket, Server};
+use crate::output::OutputFormat;
+use crate::pipeline::{self, PooledLine};
+use crate::pool::{Priority, Reply};
+use crate::{protocol, source};
+
+/// Largest request line and headers read, in bytes
+const MAX_HEAD_BYTES: u64 = 16 * 1024;
+/// Largest request body read, in bytes
+const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+/// Seconds a client refused for a full queue is told to wait before it retries
+const RETRY_AFTER_SECS: u64 = 1;
+/// Fills of a `GET /fills` page at most, and by default
+const MAX_FILLS_PAGE: usize = 10_000;
+/// Cache-Control of the fills of a window that ended before the current hour, which don't change
+const HISTORICAL_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// An HTTP/1.1 request, read whole
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    /// Path of the target, without its query string
+    pub path: String,
+    /// Parameters of the query string, decoded, in order
+    pub params: Vec<(String, String)>,
+    /// Header names in lowercase, and their values
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Returns the value of the first parameter of the name
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the value of the first header of the name, given in lowercase
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// An HTTP response
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// A plain text response
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Response {
+            status,
+            headers: vec![("Content-Type", "text/plain; charset=utf-8".to_string())],
+            body: body.into().into_bytes(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+}
+
+/// Serves one request on the connection, which is then closed
+pub fn serve_connection(server: &Server, stream: TcpStream) {
+    let mut reader = BufReader::new(&stream);
+    let response = match read_request(&mut reader) {
+        Ok(request) => route(server, &request),
+        Err(e) => Response::text(400, format!("{:#}\n", e)),
+    };
+    if let Err(e) = write_response(&mut &stream, &response) {
+        debug!("Failed to write an HTTP response: {}", e);
+    }
+}
+
+/// Answers a request by its path:
+/// - `GET /query?q=QUERY` answers one query with its result as plain text, and whether it
+///   is final in the `X-Final` header; a query that fails is answered 400 with its error,
+///   or 503 when the upstream was unavailable, and one refused for a full queue 429
+/// - `POST /query` answers the query lines of the body like the line protocol of a
+///   connection (see `socket::serve_connection`), in the configured output format
+/// - `GET /fills?start=START&end=END` answers the fills of the window, see `answer_fills`
+/// - `GET /metrics` answers the metrics of the server, see `metrics::render`
+/// - `GET /healthz` answers 200 while the process runs, and `GET /readyz` 200 once the
+///   server is ready to serve, or 503 with the conditions it waits for (see `Readiness`)
+///
+/// The queries are queued in the class of the `priority` parameter, interactive by default.
+fn route(server: &Server, request: &Request) -> Response {
+    // The span of the request, continuing the trace of its `traceparent` header, that the
+    // work it queues runs in
+    let span = info_span!(
+        "http",
+        method = request.method.as_str(),
+        path = request.path.as_str(),
+        traceparent = request.header("traceparent")
+    );
+    let _enter = span.enter();
+    let priority = match request.param("priority").map(str::parse).transpose() {
+        Ok(priority) => priority.unwrap_or(Priority::Interactive),
+        Err(e) => return Response::text(400, format!("{:#}\n", e)),
+    };
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/query") => match request.param("q") {
+            Some(query) => answer_query(server, query, priority),
+            None => Response::text(400, "Missing the q parameter: GET /query?q=QUERY\n"),
+        },
+        ("POST", "/query") => answer_lines(server, &request.body, priority),
+        (_, "/query") => {
+            Response::text(405, "Use GET or POST for /query\n").with_header("Allow", "GET, POST")
+        }
+        ("GET", "/fills") => match answer_fills(server, request, priority) {
+            Ok(response) => response,
+            Err(e) => Response::text(400, format!("{:#}\n", e)),
+        },
+        ("GET", "/metrics") => Response {
+            status: 200,
+            headers: vec![("Content-Type", metrics::CONTENT_TYPE.to_string())],
+            body: metrics::render(server).into_bytes(),
+        },
+        ("GET", "/healthz") => Response::text(200, "ok\n"),
+        ("GET", "/readyz") => match server.readiness.unmet().as_slice() {
+            [] => Response::text(200, "ready\n"),
+            unmet => Response::text(503, format!("waiting for {}\n", unmet.join(", "))),
+        },
+        (_, path) => Response::text(404, format!("Not found: {}\n", path)),
+    }
+}
+
+/// Answers one query, see `route`
+fn answer_query(server: &Server, query: &str, priority: Priority) -> Response {
+    let reply = match pipeline::pooled_line(query, &mut protocol::DEFAULT_VERSION.clone(), 1) {
+        Ok(PooledLine::Query { id: None, query }) => match server.pool.submit(query, priority) {
+            Ok(receiver) => receiver
+                .recv()
+                .map_err(|_| anyhow!("The worker answering the query stopped")),
+            Err(e) => {
+                return Response::text(429, format!("{}\n", e))
+                    .with_header("Retry-After", RETRY_AFTER_SECS)
+            }
+        },
+        Ok(_) => Err(anyhow!(
+            "GET /query answers a single query, without VERSION or an id: {}",
+            query
+        )),
+        Err(e) => Err(e),
+    };
+    match reply {
+        Ok(Reply {
+            result: Ok(result),
+            is_final,
+            latest_end_ms,
+            ..
+        }) => {
+            let response = Response::text(200, result + "\n").with_header("X-Final", is_final);
+            match latest_end_ms {
+                Some(end_ms) => response.with_header("X-Latest-End-Ms", end_ms),
+                None => response,
+            }
+        }
+        Ok(Reply { result: Err(e), .. }) | Err(e) => {
+            let status = if source::is_retryable(&e) { 503 } else { 400 };
+            Response::text(status, format!("{:#}\n", e))
+        }
+    }
+}
+
+/// Answers `GET /fills` with a page of the fills of the window (START, END], in Unix seconds,
+/// as a query of the window sees them (see `Processor::window_fills`), of the `symbol` and
+/// `venue` parameters, by default the default ones. The page is a JSON object of the fills,
+/// `{"fills": [...], "next_cursor": ...}`, or with `format=ndjson` a fill per line. It holds
+/// `limit` fills at most, `MAX_FILLS_PAGE` by default, from the `cursor` of the page before,
+/// which is also in the `X-Next-Cursor` header until the last page. A window ended before
+/// the current hour is cached for good, and one touching it not at all.
+fn answer_fills(
+    server: &Server,
+    request: &Request,
+    priority: Priority,
+) -> anyhow::Result<Response> {
+    let number = |name: &str| -> anyhow::Result<Option<i64>> {
+        request
+            .param(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid {} parameter: {}", name, value))
+            })
+            .transpose()
+    };
+    let (Some(start), Some(end)) = (number("start")?, number("end")?) else {
+        return Err(anyhow!(
+            "Missing the window: GET /fills?start=START&end=END, in Unix seconds"
+        ));
+    };
+    if end <= start {
+        return Err(anyhow!(
+            "The window ends at {}, before its start {}",
+            end,
+            start
+        ));
+    }
+    if end - start > server.fills_max_span_hours * 3600 {
+        return Err(anyhow!(
+            "The window spans more than {} hours (see --fills-max-span-hours): {} {}",
+            server.fills_max_span_hours,
+            start,
+            end
+        ));
+    }
+    let cursor = number("cursor")?.unwrap_or(0).max(0) as usize;
+    let limit = match number("limit")? {
+        Some(limit) if limit < 1 => return Err(anyhow!("Invalid limit parameter: {}", limit)),
+        limit => limit.map_or(MAX_FILLS_PAGE, |limit| (limit as usize).min(MAX_FILLS_PAGE)),
+    };
+    let ndjson = match request.param("format") {
+        None | Some("json") => false,
+        Some("ndjson") => true,
+        Some(format) => {
+            return Err(anyhow!(
+                "Unknown format {} (expected json or ndjson)",
+                format
+            ))
+        }
+    };
+
+    let mut window = String::new();
+    if let Some(venue) = request.param("venue") {
+        window += &format!("@{} ", venue);
+    }
+    window += "(] ";
+    if let Some(symbol) = request.param("symbol") {
+        window += &format!("{} ", symbol);
+    }
+    window += &format!("{} {}", start, end);
+    let label = format!("GET /fills {}", window);
+    let fills = match server.pool.run(label, priority, move |processor| {
+        processor.window_fills(&window)
+    }) {
+        Ok(receiver) => receiver
+            .recv()
+            .map_err(|_| anyhow!("The worker answering the request stopped"))?,
+        Err(e) => {
+            return Ok(Response::text(429, format!("{}\n", e))
+                .with_header("Retry-After", RETRY_AFTER_SECS))
+        }
+    };
+    let fills = match fills {
+        Ok(fills) => fills,
+        Err(e) if source::is_retryable(&e) => return Ok(Response::text(503, format!("{:#}\n", e))),
+        Err(e) => return Err(e),
+    };
+
+    let page = &fills[cursor.min(fills.len())..(cursor.saturating_add(limit)).min(fills.len())];
+    let next_cursor = Some(cursor + page.len()).filter(|next| *next < fills.len());
+    let (content_type, body) = if ndjson {
+        let mut body = Vec::new();
+        for fill in page {
+            serde_json::to_writer(&mut body, fill)?;
+            body.push(b'\n');
+        }
+        ("application/x-ndjson", body)
+    } else {
+        let page = serde_json::json!({
+            "fills": page,
+            "next_cursor": next_cursor.map(|next| next.to_string()),
+        });
+        ("application/json", serde_json::to_vec(&page)?)
+    };
+    let cache_control = cache_control(end, Utc::now().timestamp());
+    let response = Response {
+        status: 200,
+        headers: vec![
+            ("Content-Type", content_type.to_string()),
+            ("Cache-Control", cache_control.to_string()),
+        ],
+        body,
+    };
+    Ok(match next_cursor {
+        Some(next) => response.with_header("X-Next-Cursor", next),
+        None => response,
+    })
+}
+
+/// Returns the Cache-Control of the fills of a window ending at `end` at the time `now`, in
+/// Unix seconds: a window that ended before the current hour holds fills that don't change
+/// any more, and one touching it fills still coming in
+fn cache_control(end: i64, now: i64) -> &'static str {
+    if end <= now - now.rem_euclid(3600) {
+        HISTORICAL_CACHE_CONTROL
+    } else {
+        "no-store"
+    }
+}
+
+/// Answers the query lines of a body, see `route`
+fn answer_lines(server: &Server, body: &[u8], priority: Priority) -> Response {
+    let mut answers = Vec::new();
+    socket::serve_connection(server, Cursor::new(body.to_vec()), &mut answers, priority);
+    let content_type = match server.config.output_format {
+        OutputFormat::Text => "text/plain; charset=utf-8",
+        OutputFormat::Msgpack => "application/octet-stream",
+        OutputFormat::Json => "application/x-ndjson",
+        OutputFormat::Csv => "text/csv; charset=utf-8",
+    };
+    Response {
+        status: 200,
+        headers: vec![("Content-Type", content_type.to_string())],
+        body: answers,
+    }
+}
+
+/// Reads a request: its line, headers and, by its Content-Length, its body
+pub fn read_request(reader: &mut impl BufRead) -> anyhow::Result<Request> {
+    let mut head = reader.take(MAX_HEAD_BYTES);
+    let mut line = String::new();
+    head.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(anyhow!("Invalid request line: {:?}", line.trim_end()));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(anyhow!("Unsupported HTTP version {}", version));
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            Ok((decode_component(name)?, decode_component(value)?))
+        })
+        .collect::<anyhow::Result<Vec<(String, String)>>>()?;
+    let (method, path) = (method.to_string(), decode_component(path)?);
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if head.read_line(&mut line)? == 0 {
+            return Err(anyhow!(
+                "Request headers cut short or over {} bytes",
+                MAX_HEAD_BYTES
+            ));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid header: {:?}", line))?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+
+    let mut request = Request {
+        method,
+        path,
+        params,
+        headers,
+        body: Vec::new(),
+    };
+    if let Some(length) = request.header("content-length") {
+        let length = length
+            .parse::<usize>()
+            .map_err(|_| anyhow!("Invalid Content-Length: {}", length))?;
+        if length > MAX_BODY_BYTES {
+            return Err(anyhow!(
+                "Request body of {} bytes, over the limit of {}",
+                length,
+                MAX_BODY_BYTES
+            ));
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        request.body = body;
+    }
+    Ok(request)
+}
+
+/// Decodes a percent-encoded component of a URL, with '+' for a space
+fn decode_component(component: &str) -> anyhow::Result<String> {
+    let mut bytes = Vec::with_capacity(component.len());
+    let mut rest = component.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = after
+                    .get(..2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| anyhow!("Invalid percent-encoding in {:?}", component))?;
+                bytes.push(hex);
+                rest = &after[2..];
+                continue;
+            }
+            byte => bytes.push(byte),
+        }
+        rest = after;
+    }
+    String::from_utf8(bytes).map_err(|_| anyhow!("Invalid UTF-8 in {:?}", component))
+}
+
+/// Writes the response, closing the connection after it
+pub fn write_response(writer: &mut impl Write, response: &Response) -> io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        reason(response.status),
+        response.body.len()
+    );
+    for (name, value) in &response.headers {
+        head += &format!("{}: {}\r\n", name, value);
+    }
+    head += "\r\n";
+    writer.write_all(head.as_bytes())?;
+    writer.write_all(&response.body)?;
+    writer.flush()
+}
+
+/// Reason phrase of the status codes the server answers with
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        429 => "Too Many Requests",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_parse_with_their_parameters_and_body() {
+        let raw = "POST /query?q=C+1700820000%201700830000&empty HTTP/1.1\r\n\
+                   Host: localhost\r\nContent-Length: 5\r\n\r\nhello trailing";
+        let request = read_request(&mut raw.as_bytes()).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/query");
+        assert_eq!(request.param("q"), Some("C 1700820000 1700830000"));
+        assert_eq!(request.param("empty"), Some(""));
+        assert_eq!(request.header("content-length"), Some("5"));
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn malformed_requests_fail_to_parse() {
+        for raw in [
+            "",
+            "GET /query\r\n\r\n",
+            "GET /query HTTP/2\r\n\r\n",
+            "GET /query?q=%zz HTTP/1.1\r\n\r\n",
+            "GET /query HTTP/1.1\r\nno colon\r\n\r\n",
+            "GET /query HTTP/1.1\r\nHost: cut short",
+            "POST /query HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort",
+        ] {
+            assert!(read_request(&mut raw.as_bytes()).is_err(), "{:?}", raw);
+        }
+        assert_eq!(decode_component("%E2%82%AC+1").unwrap(), "€ 1");
+        assert!(decode_component("%ff").is_err());
+    }
+
+    #[test]
+    fn only_windows_before_the_current_hour_are_cached() {
+        let hour = 1700820000;
+        assert_eq!(cache_control(hour, hour + 10), HISTORICAL_CACHE_CONTROL);
+        assert_eq!(
+            cache_control(hour - 3600, hour + 3599),
+            HISTORICAL_CACHE_CONTROL
+        );
+        assert_eq!(cache_control(hour + 1, hour + 10), "no-store");
+        assert_eq!(cache_control(hour + 3600, hour + 10), "no-store");
+    }
+}
@@ -0,0 +1,158 @@
+use std::fmt::Write;
+
+use super::Server;
+use crate::pool::{Priority, QueueStats};
+use crate::stats::{KindCounters, LATENCY_BUCKETS};
+
+/// Content type of the Prometheus text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Renders the metrics of the server in the Prometheus text exposition format: the queries
+/// the workers are answering, and per priority class the depth of the queue, the queries
+/// taken by a worker and the time they waited, in total and at most, and the queries
+/// refused for a full queue. Per query type, labeled like `type="C"`, it renders the
+/// queries the workers answered, those that failed, the hours served from the cache, not
+/// served from it and fetched, and a histogram of the time the queries took.
+pub fn render(server: &Server) -> String {
+    let stats = Priority::ALL.map(|priority| (priority, server.pool.queue_stats(priority)));
+    let per_class = |value: fn(&QueueStats) -> f64| {
+        stats.iter().map(move |(priority, stats)| {
+            (format!("priority=\"{}\"", priority.name()), value(stats))
+        })
+    };
+    let mut text = String::new();
+    metric(
+        &mut text,
+        "interview_in_flight",
+        "gauge",
+        "Queries the workers are answering",
+        [(String::new(), server.pool.in_flight() as f64)].into_iter(),
+    );
+    metric(
+        &mut text,
+        "interview_queue_depth",
+        "gauge",
+        "Queries waiting for a worker",
+        per_class(|stats| stats.depth as f64),
+    );
+    metric(
+        &mut text,
+        "interview_queue_started_total",
+        "counter",
+        "Queries taken by a worker",
+        per_class(|stats| stats.started as f64),
+    );
+    metric(
+        &mut text,
+        "interview_queue_wait_seconds_total",
+        "counter",
+        "Time the queries taken by a worker waited for it",
+        per_class(|stats| stats.wait.as_secs_f64()),
+    );
+    metric(
+        &mut text,
+        "interview_queue_wait_seconds_max",
+        "gauge",
+        "Longest time a query taken by a worker waited for it",
+        per_class(|stats| stats.max_wait.as_secs_f64()),
+    );
+    metric(
+        &mut text,
+        "interview_rejected_total",
+        "counter",
+        "Queries refused because the queue was full",
+        per_class(|stats| stats.rejected as f64),
+    );
+
+    let kinds = server.pool.kind_counters();
+    let per_type = |value: fn(&KindCounters) -> f64| {
+        kinds
+            .iter()
+            .map(move |(kind, counters)| (format!("type=\"{}\"", kind.code()), value(counters)))
+    };
+    for (name, help, value) in [
+        (
+            "interview_queries_total",
+            "Queries answered by the workers, failed ones included",
+            (|counters| counters.queries as f64) as fn(&KindCounters) -> f64,
+        ),
+        (
+            "interview_query_failures_total",
+            "Queries that failed after being parsed",
+            |counters| counters.failed as f64,
+        ),
+        (
+            "interview_cache_hits_total",
+            "Hours of the queries served from the cache",
+            |counters| counters.cache_hits as f64,
+        ),
+        (
+            "interview_cache_misses_total",
+            "Hours of the queries not served from the cache",
+            |counters| counters.misses as f64,
+        ),
+        (
+            "interview_api_calls_total",
+            "Hours of the queries fetched from the upstream and cached",
+            |counters| counters.api_calls as f64,
+        ),
+    ] {
+        metric(&mut text, name, "counter", help, per_type(value));
+    }
+
+    let name = "interview_query_duration_seconds";
+    let _ = writeln!(text, "# HELP {} Time the queries took to answer", name);
+    let _ = writeln!(text, "# TYPE {} histogram", name);
+    for (kind, counters) in &kinds {
+        let kind = kind.code();
+        let bounds = LATENCY_BUCKETS.map(|bound| bound.to_string());
+        let buckets = counters
+            .latency_buckets
+            .into_iter()
+            .chain([counters.queries]);
+        for (bound, queries) in bounds
+            .iter()
+            .map(String::as_str)
+            .chain(["+Inf"])
+            .zip(buckets)
+        {
+            let _ = writeln!(
+                text,
+                "{}_bucket{{type=\"{}\",le=\"{}\"}} {}",
+                name, kind, bound, queries
+            );
+        }
+        let _ = writeln!(
+            text,
+            "{}_sum{{type=\"{}\"}} {}",
+            name,
+            kind,
+            counters.latency_sum.as_secs_f64()
+        );
+        let _ = writeln!(
+            text,
+            "{}_count{{type=\"{}\"}} {}",
+            name, kind, counters.queries
+        );
+    }
+    text
+}
+
+/// Writes a metric with its samples, each with its labels, like `priority="batch"`
+fn metric(
+    text: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl Iterator<Item = (String, f64)>,
+) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(text, "{} {}", name, value);
+        } else {
+            let _ = writeln!(text, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
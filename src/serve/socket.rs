@@ -0,0 +1,199 @@
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use tracing::debug;
+
+use super::{binary, Server};
+use crate::output::{Answer, AnswerWriter};
+use crate::pipeline::{self, PooledLine};
+use crate::pool::{Priority, Reply};
+use crate::protocol;
+
+/// Lines of a connection read ahead of the answer being written
+const PENDING_LINES: usize = 256;
+
+/// Answer to a query line refused because the queue of the workers is full
+pub const BUSY: &str = "BUSY";
+
+/// What a query line of a connection is answered with, in the order of the lines
+enum Pending {
+    Reply(Receiver<Reply>),
+    Version(u32),
+    Priority(Priority),
+    /// Refused for a full queue
+    Busy,
+    Failed(anyhow::Error),
+}
+
+/// Serves the line protocol on one connection: every query line is answered by the next
+/// free worker, like the input of `query --workers`, and the answers are written in the
+/// order of the lines, in the configured output format. Unlike `query`, a line that fails
+/// is answered with its error (`ERROR <MESSAGE>` in text) and the connection carries on,
+/// and one refused because the queue of the workers is full with `BUSY`.
+/// Lines are read on a thread of their own, so a client may send lines ahead of their
+/// answers; the answers written are flushed whenever no more are ready.
+///
+/// A connection whose first byte is `binary::MAGIC` speaks the binary protocol instead,
+/// see `binary`.
+///
+/// The queries are queued in the class of `priority`, until a `PRIORITY <CLASS>` line sets
+/// the class of the lines after it, and is answered `PRIORITY <CLASS>`.
+pub fn serve_connection(
+    server: &Server,
+    reader: impl Read + Send,
+    writer: impl Write,
+    mut priority: Priority,
+) {
+    let mut reader = BufReader::new(reader);
+    match reader.fill_buf() {
+        Ok([binary::MAGIC, ..]) => {
+            reader.consume(1);
+            return binary::serve_connection(server, reader, writer, priority);
+        }
+        Ok(_) => {}
+        Err(e) => {
+            debug!("Failed to read a connection: {}", e);
+            return;
+        }
+    }
+    let (sender, receiver) = mpsc::sync_channel::<(usize, Option<String>, Pending)>(PENDING_LINES);
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            let mut version = protocol::DEFAULT_VERSION;
+            let mut query_lines = 0;
+            for (index, line) in reader.lines().enumerate() {
+                let Ok(line) = line else {
+                    break;
+                };
+                let Some(query) = pipeline::query_text(&line) else {
+                    continue;
+                };
+                query_lines += 1;
+                let pending = match priority_line(query) {
+                    Some(Ok(class)) => {
+                        priority = class;
+                        (None, Pending::Priority(class))
+                    }
+                    Some(Err(e)) => (None, Pending::Failed(e)),
+                    None => match pipeline::pooled_line(query, &mut version, query_lines) {
+                        Ok(PooledLine::Query { id, query }) => {
+                            match server.pool.submit(query, priority) {
+                                Ok(receiver) => (id, Pending::Reply(receiver)),
+                                Err(_) => (id, Pending::Busy),
+                            }
+                        }
+                        Ok(PooledLine::Version(version)) => (None, Pending::Version(version)),
+                        Err(e) => (None, Pending::Failed(e)),
+                    },
+                };
+                if sender.send((index + 1, pending.0, pending.1)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut writer = BufWriter::new(writer);
+        let mut output = AnswerWriter::new(
+            &mut writer,
+            server.config.output_format,
+            server.config.output_template.as_ref(),
+        );
+        let mut next = receiver.recv().map_err(|_| TryRecvError::Disconnected);
+        while let Ok((line, id, pending)) = next {
+            if let Err(e) = write_answer(&mut output, line, id.as_deref(), pending) {
+                debug!("Closing a connection that can't be written: {}", e);
+                return;
+            }
+            next = match receiver.try_recv() {
+                Err(TryRecvError::Empty) => {
+                    if output.flush().is_err() {
+                        return;
+                    }
+                    receiver.recv().map_err(|_| TryRecvError::Disconnected)
+                }
+                next => next,
+            };
+        }
+        let _ = output.flush();
+    });
+}
+
+/// Writes the answer to one query line, waiting for its worker
+fn write_answer(
+    output: &mut AnswerWriter<impl Write>,
+    line: usize,
+    id: Option<&str>,
+    pending: Pending,
+) -> std::io::Result<()> {
+    let reply = match pending {
+        Pending::Reply(receiver) => receiver.recv().map_err(|_| {
+            anyhow::anyhow!("The worker answering the query on line {} stopped", line)
+        }),
+        Pending::Version(version) => {
+            return output.write(Answer::Answer {
+                line,
+                id,
+                result: &format!("VERSION {}", version),
+                is_final: None,
+                latest_end_ms: None,
+                context: None,
+            })
+        }
+        Pending::Priority(priority) => {
+            return output.write(Answer::Answer {
+                line,
+                id,
+                result: &format!("PRIORITY {}", priority.name()),
+                is_final: None,
+                latest_end_ms: None,
+                context: None,
+            })
+        }
+        Pending::Busy => {
+            return output.write(Answer::Answer {
+                line,
+                id,
+                result: BUSY,
+                is_final: None,
+                latest_end_ms: None,
+                context: None,
+            })
+        }
+        Pending::Failed(e) => Err(e),
+    };
+    match reply {
+        Ok(Reply {
+            result: Ok(result),
+            is_final,
+            latest_end_ms,
+            context,
+        }) => output.write(Answer::Answer {
+            line,
+            id,
+            result: &result,
+            is_final: Some(is_final),
+            latest_end_ms,
+            context: Some(&context),
+        }),
+        Ok(Reply { result: Err(e), .. }) | Err(e) => output.write(Answer::Error {
+            line,
+            id,
+            error: &format!("{:#}", e),
+        }),
+    }
+}
+
+/// Parses a `PRIORITY <CLASS>` line, or returns `None` for any other line
+fn priority_line(query: &str) -> Option<anyhow::Result<Priority>> {
+    let mut tokens = query.split_whitespace();
+    if !tokens.next()?.eq_ignore_ascii_case("PRIORITY") {
+        return None;
+    }
+    Some(match (tokens.next(), tokens.next()) {
+        (Some(class), None) => class.parse(),
+        _ => Err(anyhow::anyhow!(
+            "Expected PRIORITY interactive or PRIORITY batch: {}",
+            query
+        )),
+    })
+}
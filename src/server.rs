@@ -3,30 +3,194 @@ use chrono::{DateTime, Utc};
 use lazy_static::lazy_static;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::{thread, time};
+use tracing::{debug, warn};
+
+use crate::source::UpstreamUnavailable;
+
+/// Symbol served from `./trades.csv`. Every other symbol is served from `./trades_<SYMBOL>.csv`.
+pub const DEFAULT_SYMBOL: &str = "DEFAULT";
 
 lazy_static! {
-    static ref FILLS: Vec<Fill> = {
-        let mut rdr = csv::Reader::from_path("./trades.csv").expect("Failed to find trades.csv");
-        rdr.deserialize()
-            .into_iter()
-            .filter_map(|result| result.ok())
-            .collect()
+    /// Trade data per symbol, loaded from disk the first time the symbol is requested
+    static ref TRADES: Mutex<HashMap<String, Arc<TradeData>>> = Mutex::new(HashMap::new());
+}
+
+/// Raw trade records of one symbol, deserialized into fills when they are served
+struct TradeData {
+    headers: csv::StringRecord,
+    /// Columns of the trades file that aren't fields of a fill, see `unknown_fields`
+    unknown_fields: Vec<String>,
+    records: Vec<RawFill>,
+}
+
+struct RawFill {
+    /// Line of the record in its trades file
+    line: u64,
+    time: DateTime<Utc>,
+    record: csv::StringRecord,
+}
+
+/// Fills returned by the API for one request
+pub struct ApiResponse {
+    pub fills: Vec<Fill>,
+    /// Number of records in the response that failed to parse and were skipped (lenient mode only)
+    pub malformed: usize,
+    /// Fields of the response's fills that aren't fields of `Fill`, ignored
+    pub unknown_fields: Vec<String>,
+}
+
+/// Fields of a fill, in the order they are serialized
+pub const FILL_FIELDS: [&str; 9] = [
+    "time",
+    "direction",
+    "price",
+    "quantity",
+    "sequence_number",
+    "liquidity",
+    "trade_id",
+    "fee",
+    "fee_currency",
+];
+
+/// Number of leading fields of `FILL_FIELDS` every fill has; the others are optional
+const REQUIRED_FILL_FIELDS: usize = 5;
+
+/// Returns the columns of the headers that aren't fields of a fill, which deserializing
+/// ignores, so fills keep parsing when the upstream adds fields.
+/// Fails when a field every fill has is missing, or, with `strict`, when there are
+/// unknown columns (see `--strict-schema`).
+pub fn unknown_fields(headers: &csv::StringRecord, strict: bool) -> anyhow::Result<Vec<String>> {
+    if let Some(missing) = FILL_FIELDS[..REQUIRED_FILL_FIELDS]
+        .iter()
+        .find(|field| !headers.iter().any(|header| header == **field))
+    {
+        return Err(anyhow!("No {} column", missing));
+    }
+    let unknown = headers
+        .iter()
+        .filter(|header| !FILL_FIELDS.contains(header))
+        .map(str::to_string)
+        .collect::<Vec<String>>();
+    if strict && !unknown.is_empty() {
+        return Err(anyhow!("Unknown fill fields: {}", unknown.join(", ")));
+    }
+    Ok(unknown)
+}
+
+fn load_trades(symbol: &str) -> anyhow::Result<Arc<TradeData>> {
+    let mut trades = TRADES
+        .lock()
+        .map_err(|_| anyhow!("Trade data lock poisoned"))?;
+    if let Some(data) = trades.get(symbol) {
+        return Ok(Arc::clone(data));
+    }
+
+    let path = if symbol == DEFAULT_SYMBOL {
+        "./trades.csv".to_string()
+    } else {
+        format!("./trades_{}.csv", symbol)
     };
+    // Flexible, so records with missing fields fail when served, with the request's context
+    let rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_path(&path)
+        .map_err(|e| {
+            UpstreamUnavailable(format!(
+                "No trade data for symbol {} ({}): {}",
+                symbol, path, e
+            ))
+        })?;
+    let data = Arc::new(read_trades(rdr, &path)?);
+    trades.insert(symbol.to_string(), Arc::clone(&data));
+    Ok(data)
 }
 
-mod date_string {
-    use chrono::{DateTime, NaiveDateTime, Utc};
+/// Reads the CSV trades data of the file at `path`, for the hours it is served for
+fn read_trades<R: std::io::Read>(mut rdr: csv::Reader<R>, path: &str) -> anyhow::Result<TradeData> {
+    let headers = rdr.headers()?.clone();
+    // Checked against `--strict-schema` when served, as the data is loaded once for every run
+    let unknown_fields =
+        unknown_fields(&headers, false).map_err(|e| anyhow!("{} in {}", e, path))?;
+    let time_column = headers
+        .iter()
+        .position(|header| header == "time")
+        .ok_or_else(|| anyhow!("No time column in {}", path))?;
+
+    // Records that can't be placed in time can't be served for any hour
+    let mut records = Vec::new();
+    for result in rdr.records() {
+        let record = match result {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("Skipping unreadable record in {}: {}", path, e);
+                continue;
+            }
+        };
+        let line = record.position().map_or(0, |position| position.line());
+        match record
+            .get(time_column)
+            .map(date_string::parse)
+            .unwrap_or_else(|| Err(anyhow!("missing time")))
+        {
+            Ok(time) => records.push(RawFill { line, time, record }),
+            Err(e) => warn!(
+                "Skipping record with unparseable time at {} line {}: {}: {}",
+                path,
+                line,
+                e,
+                snippet(&record)
+            ),
+        }
+    }
+
+    Ok(TradeData {
+        headers,
+        unknown_fields,
+        records,
+    })
+}
+
+/// Returns the record as CSV text, truncated for error messages
+fn snippet(record: &csv::StringRecord) -> String {
+    const MAX_LEN: usize = 80;
+    let text = record.iter().collect::<Vec<&str>>().join(",");
+    if text.chars().count() <= MAX_LEN {
+        text
+    } else {
+        format!("{}...", text.chars().take(MAX_LEN).collect::<String>())
+    }
+}
+
+/// Serde format of fill times: RFC3339 in UTC, like "2023-11-24T09:01:00Z", with the
+/// fractional seconds written only when the time has some. Times in the format hour files
+/// were written in before, and the trades data still is, UTC "YYYY-MM-DD HH:MM:SS" with
+/// optional fractional seconds, still parse.
+pub mod date_string {
+    use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
     use serde::{self, Deserialize, Deserializer, Serializer};
 
-    const FORMAT: &'static str = "%Y-%m-%d %H:%M:%S";
+    /// Format of the trades data and of earlier hour files; fractional seconds are optional
+    const LEGACY_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+
+    pub fn parse(s: &str) -> anyhow::Result<DateTime<Utc>> {
+        if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+            return Ok(time.with_timezone(&Utc));
+        }
+        let dt = NaiveDateTime::parse_from_str(s, LEGACY_FORMAT)?;
+        Ok(DateTime::<Utc>::from_naive_utc_and_offset(dt, Utc))
+    }
 
     pub fn serialize<S>(date: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let s = format!("{}", date.format(FORMAT));
-        serializer.serialize_str(&s)
+        serializer.serialize_str(&date.to_rfc3339_opts(SecondsFormat::AutoSi, true))
     }
 
     pub fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
@@ -34,45 +198,334 @@ mod date_string {
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        let dt = NaiveDateTime::parse_from_str(&s, FORMAT).map_err(serde::de::Error::custom)?;
-        Ok(DateTime::<Utc>::from_naive_utc_and_offset(dt, Utc))
+        parse(&s).map_err(serde::de::Error::custom)
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+/// Whether our side of a fill added (maker) or removed (taker) liquidity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Liquidity {
+    Maker,
+    Taker,
+}
+
+/// Code of the currency a fee is charged in, such as "USD" or "BTC": one to eight ASCII
+/// letters and digits. Kept inline so fills stay `Copy` and their size covers their memory.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Currency {
+    len: u8,
+    code: [u8; Currency::MAX_LEN],
+}
+
+impl Currency {
+    /// Longest currency code accepted
+    pub const MAX_LEN: usize = 8;
+
+    pub fn as_str(&self) -> &str {
+        // Only ASCII is ever stored
+        std::str::from_utf8(&self.code[..self.len as usize]).unwrap_or_default()
+    }
+}
+
+impl FromStr for Currency {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s.is_empty() || s.len() > Self::MAX_LEN || !s.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            return Err(anyhow!(
+                "Invalid currency \"{}\": expected 1 to {} ASCII letters and digits",
+                s,
+                Self::MAX_LEN
+            ));
+        }
+        let mut code = [0; Self::MAX_LEN];
+        code[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(Currency {
+            len: s.len() as u8,
+            code,
+        })
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl PartialOrd for Currency {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Currency {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Side of the taker trade a fill belongs to, as given by the fill's direction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    /// Market buy (direction 1)
+    Buy,
+    /// Market sell (direction 2, or -1 in older responses)
+    Sell,
+    /// Any other direction, such as the upstream's 0 for "unknown"
+    Unknown,
+}
+
+/// One fill of a taker trade, as served by the upstream.
+///
+/// The serialized form is the one of the trades data and of hour files, and is kept stable
+/// so recordings stay readable: fields in declaration order under these names, `time` as
+/// RFC3339 in UTC with fractional seconds only when present (see `date_string`),
+/// `price`, `quantity` and `fee` as decimal strings that keep their exact digits, and
+/// `liquidity`, `trade_id`, `fee` and `fee_currency` empty (`null` in JSON) when the upstream
+/// didn't send them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Fill {
+    /// Time of the fill, millisecond precision or finer
     #[serde(with = "date_string")]
     pub time: DateTime<Utc>,
+    /// 1 for a market buy, 2 (or -1 in older responses) for a market sell; see `side`
     pub direction: i32,
     pub price: Decimal,
     pub quantity: Decimal,
+    /// Upstream's sequence number of the taker trade, shared by its fills
     pub sequence_number: u64,
+    /// Liquidity flag, absent in recordings made before the upstream started sending it
+    #[serde(default)]
+    pub liquidity: Option<Liquidity>,
+    /// Venue's id of the taker trade, for venues that send one
+    #[serde(default)]
+    pub trade_id: Option<u64>,
+    /// Fee charged for the fill, in `fee_currency`, for venues that send one
+    #[serde(default)]
+    pub fee: Option<Decimal>,
+    #[serde(default)]
+    pub fee_currency: Option<Currency>,
+}
+
+impl Fill {
+    /// Returns the side of the fill's taker trade
+    pub fn side(&self) -> Side {
+        match self.direction {
+            1 => Side::Buy,
+            2 | -1 => Side::Sell,
+            _ => Side::Unknown,
+        }
+    }
 }
 
-#[must_use]
+/// Returns the fills of the symbol within (start, end].
+/// A record that fails to parse fails the whole request with the requested hour,
+/// the index of the record in the response and a snippet of it, unless `lenient`
+/// is set, in which case it is skipped and counted in the response.
+/// Fields the fills have beyond those of `Fill` are ignored and named in the response,
+/// unless `strict_schema` is set, in which case they fail the request.
 pub fn get_fills_api(
+    symbol: &str,
     start_timestamp_in_seconds: i64,
     end_timestamp_in_seconds: i64,
-) -> anyhow::Result<Vec<Fill>> {
+    lenient: bool,
+    strict_schema: bool,
+) -> anyhow::Result<ApiResponse> {
     let start_time = DateTime::from_timestamp(start_timestamp_in_seconds, 0)
         .ok_or_else(|| anyhow!("Invalid timestamp"))?;
     let end_time = DateTime::from_timestamp(end_timestamp_in_seconds, 0)
         .ok_or_else(|| anyhow!("Invalid timestamp"))?;
 
+    let data = load_trades(symbol)?;
+    if strict_schema && !data.unknown_fields.is_empty() {
+        return Err(anyhow!(
+            "Unknown fill fields in the {} response for hour {}: {}",
+            symbol,
+            start_timestamp_in_seconds,
+            data.unknown_fields.join(", ")
+        ));
+    }
+
     let interval_length = (end_timestamp_in_seconds - start_timestamp_in_seconds).max(0) as f64;
 
     // Fetching 1 day's worth of data should take around 1 second
     let sleep_time = time::Duration::from_secs_f64(interval_length * 0.00001);
     thread::sleep(sleep_time);
 
-    Ok(FILLS
+    let mut response = ApiResponse {
+        fills: Vec::new(),
+        malformed: 0,
+        unknown_fields: data.unknown_fields.clone(),
+    };
+    let records = data
+        .records
         .iter()
-        .filter_map(|fill| {
-            if fill.time <= start_time || fill.time > end_time {
-                None
-            } else {
-                Some(*fill)
+        .filter(|raw| raw.time > start_time && raw.time <= end_time);
+    for (index, raw) in records.enumerate() {
+        match raw.record.deserialize::<Fill>(Some(&data.headers)) {
+            Ok(fill) => response.fills.push(fill),
+            Err(e) => {
+                let context = format!(
+                    "Malformed fill #{} in the {} response for hour {} (line {}): {}: {}",
+                    index,
+                    symbol,
+                    start_timestamp_in_seconds,
+                    raw.line,
+                    e,
+                    snippet(&raw.record)
+                );
+                if !lenient {
+                    return Err(anyhow!(context));
+                }
+                debug!("Skipping {}", context);
+                response.malformed += 1;
             }
-        })
-        .collect())
+        }
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill() -> Fill {
+        Fill {
+            time: DateTime::from_timestamp_millis(1700816460250).unwrap(),
+            direction: 1,
+            price: "37000.50".parse().unwrap(),
+            quantity: "0.0010".parse().unwrap(),
+            sequence_number: 42,
+            liquidity: Some(Liquidity::Taker),
+            trade_id: Some(7),
+            fee: Some("-0.125".parse().unwrap()),
+            fee_currency: Some("USD".parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn fills_serialize_to_a_stable_form() {
+        assert_eq!(
+            serde_json::to_string(&fill()).unwrap(),
+            r#"{"time":"2023-11-24T09:01:00.250Z","direction":1,"price":"37000.50","quantity":"0.0010","sequence_number":42,"liquidity":"taker","trade_id":7,"fee":"-0.125","fee_currency":"USD"}"#
+        );
+        let bare = Fill {
+            time: DateTime::from_timestamp(1700816460, 0).unwrap(),
+            liquidity: None,
+            trade_id: None,
+            fee: None,
+            fee_currency: None,
+            ..fill()
+        };
+        assert_eq!(
+            serde_json::to_string(&bare).unwrap(),
+            r#"{"time":"2023-11-24T09:01:00Z","direction":1,"price":"37000.50","quantity":"0.0010","sequence_number":42,"liquidity":null,"trade_id":null,"fee":null,"fee_currency":null}"#
+        );
+
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(Vec::new());
+        writer.write_record(FILL_FIELDS).unwrap();
+        writer.serialize(fill()).unwrap();
+        writer.serialize(bare).unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner().unwrap()).unwrap(),
+            "time,direction,price,quantity,sequence_number,liquidity,trade_id,fee,fee_currency\n\
+             2023-11-24T09:01:00.250Z,1,37000.50,0.0010,42,taker,7,-0.125,USD\n\
+             2023-11-24T09:01:00Z,1,37000.50,0.0010,42,,,,\n"
+        );
+    }
+
+    #[test]
+    fn fills_round_trip_and_legacy_times_parse() {
+        let json = serde_json::to_string(&fill()).unwrap();
+        assert_eq!(serde_json::from_str::<Fill>(&json).unwrap(), fill());
+
+        let legacy = json.replace("2023-11-24T09:01:00.250Z", "2023-11-24 09:01:00.250");
+        assert_eq!(serde_json::from_str::<Fill>(&legacy).unwrap(), fill());
+        assert_eq!(
+            date_string::parse("2023-11-24 09:01:00").unwrap(),
+            DateTime::from_timestamp(1700816460, 0).unwrap()
+        );
+        // Offsets are honored
+        assert_eq!(
+            date_string::parse("2023-11-24T10:01:00.250+01:00").unwrap(),
+            fill().time
+        );
+        assert!(date_string::parse("2023-11-24").is_err());
+    }
+
+    /// Serves the CSV text as the trades data of the symbol
+    fn serve_trades(symbol: &str, csv: &str) {
+        let rdr = csv::ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(csv.as_bytes());
+        let data = read_trades(rdr, symbol).unwrap();
+        TRADES
+            .lock()
+            .unwrap()
+            .insert(symbol.to_string(), Arc::new(data));
+    }
+
+    #[test]
+    fn lenient_requests_skip_the_malformed_fills_and_keep_the_others() {
+        serve_trades(
+            "LENIENT",
+            "time,direction,price,quantity,sequence_number\n\
+             2023-11-24 09:01:00,1,10.5,1,1\n\
+             2023-11-24 09:02:00,1,ten,1,2\n\
+             2023-11-24 09:03:00,2,11,2\n\
+             not a time,1,12,1,4\n\
+             2023-11-24 09:05:00,sell,12,1,5\n\
+             2023-11-24 09:06:00,2,11.25,3,6\n\
+             2023-11-24 09:07:00,1,13,-,7\n",
+        );
+        let (start, end) = (1700816400, 1700820000);
+        let response = get_fills_api("LENIENT", start, end, true, false).unwrap();
+        assert_eq!(
+            response
+                .fills
+                .iter()
+                .map(|fill| (fill.sequence_number, fill.price.to_string()))
+                .collect::<Vec<(u64, String)>>(),
+            [(1, "10.5".to_string()), (6, "11.25".to_string())]
+        );
+        // The record without a readable time never makes it into a response
+        assert_eq!(response.malformed, 4);
+
+        // Without it the first one fails the request, with its place in the response
+        let strict = get_fills_api("LENIENT", start, end, false, false);
+        let e = strict.err().unwrap().to_string();
+        assert!(
+            e.starts_with(
+                "Malformed fill #1 in the LENIENT response for hour 1700816400 (line 3): "
+            ),
+            "{}",
+            e
+        );
+        assert!(e.ends_with(": 2023-11-24 09:02:00,1,ten,1,2"), "{}", e);
+    }
 }
@@ -0,0 +1,639 @@
+use anyhow::{anyhow, Context};
+use chrono::DateTime;
+use clap::ValueEnum;
+use flate2::Crc;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::server::{Currency, Fill, Liquidity};
+use crate::DuplicateFills;
+
+/// Layout of the snapshot file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SnapshotFormat {
+    /// One line of JSON per hour, readable with any JSON tool
+    #[default]
+    Json,
+    /// A versioned header followed by length-prefixed, checksummed blocks of fixed-width
+    /// fills, one block per hour, an order of magnitude faster to load
+    Binary,
+}
+
+/// Where and how often the cache is snapshotted
+#[derive(Debug, Clone)]
+pub struct SnapshotConfig {
+    /// File the snapshot segments are appended to and hours are loaded from at startup
+    pub path: PathBuf,
+    /// Snapshot once this many hours were fetched since the last snapshot
+    pub every_hours: usize,
+    /// Snapshot once this long has passed since the last snapshot, if any hour was fetched;
+    /// `None` snapshots by fetched hours only
+    pub interval: Option<Duration>,
+    /// Compact the file once it is larger than this many bytes
+    pub compact_bytes: u64,
+    /// Layout new snapshot files are written in. A file in the other layout is loaded as it
+    /// is and rewritten in this one.
+    pub format: SnapshotFormat,
+}
+
+/// One complete cached hour, a line of JSON in the snapshot file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HourRecord {
+    pub venue: String,
+    pub symbol: String,
+    pub hour: i64,
+    pub duplicates: DuplicateFills,
+    pub fills: Vec<Fill>,
+}
+
+/// Hours read from a snapshot file
+pub struct Loaded {
+    /// The hours in the order they were appended, so a later record of an hour
+    /// supersedes an earlier one
+    pub records: Vec<HourRecord>,
+    /// Number of bytes before a torn tail, or the size of the file
+    pub valid_bytes: u64,
+    /// Layout of the file, `None` when there is none yet
+    pub format: Option<SnapshotFormat>,
+}
+
+/// Reads the hours of a snapshot file, in either layout. Reading stops at the first record
+/// that doesn't parse, or whose checksum doesn't match in the binary layout: the torn tail
+/// of a segment cut short by a crash. A missing or empty file holds no hours.
+/// Fails on a binary file of another version, which this build can't read or safely append to.
+pub fn load(path: &Path) -> anyhow::Result<Loaded> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read snapshot {}", path.display()))
+        }
+    };
+    let loaded = if bytes.is_empty() {
+        Loaded {
+            records: Vec::new(),
+            valid_bytes: 0,
+            format: None,
+        }
+    } else if bytes.starts_with(MAGIC) {
+        load_binary(path, &bytes)?
+    } else {
+        load_json(path, &bytes)
+    };
+    Ok(loaded)
+}
+
+/// Reads the JSON lines of a snapshot file, stopping at the first one that doesn't parse
+fn load_json(path: &Path, bytes: &[u8]) -> Loaded {
+    let mut records = Vec::new();
+    let mut valid_bytes = 0;
+    for line in bytes.split_inclusive(|&byte| byte == b'\n') {
+        // A record is whole only once its newline is written
+        let record = match line.strip_suffix(b"\n") {
+            Some(json) => serde_json::from_slice::<HourRecord>(json).map_err(|e| e.to_string()),
+            None => Err("record has no end of line".to_string()),
+        };
+        match record {
+            Ok(record) => {
+                records.push(record);
+                valid_bytes += line.len() as u64;
+            }
+            Err(e) => {
+                torn_tail(path, records.len(), &e);
+                break;
+            }
+        }
+    }
+    Loaded {
+        records,
+        valid_bytes,
+        format: Some(SnapshotFormat::Json),
+    }
+}
+
+/// Reads the blocks of a binary snapshot file, stopping at the first one that is cut short,
+/// fails its checksum or doesn't decode
+fn load_binary(path: &Path, bytes: &[u8]) -> anyhow::Result<Loaded> {
+    check_version(bytes).with_context(|| format!("Snapshot {}", path.display()))?;
+
+    let mut records = Vec::new();
+    let mut rest = &bytes[HEADER_LEN..];
+    let mut valid_bytes = HEADER_LEN as u64;
+    while !rest.is_empty() {
+        match decode_block(rest) {
+            Ok((record, after)) => {
+                records.push(record);
+                valid_bytes += (rest.len() - after.len()) as u64;
+                rest = after;
+            }
+            Err(e) => {
+                torn_tail(path, records.len(), e);
+                break;
+            }
+        }
+    }
+    Ok(Loaded {
+        records,
+        valid_bytes,
+        format: Some(SnapshotFormat::Binary),
+    })
+}
+
+/// Fails unless the bytes start with the binary header of the version this build reads
+fn check_version(bytes: &[u8]) -> anyhow::Result<()> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(anyhow!("not in the binary layout"));
+    }
+    let version = bytes
+        .get(MAGIC.len()..HEADER_LEN)
+        .map(|version| u16::from_le_bytes([version[0], version[1]]));
+    if version != Some(VERSION) {
+        return Err(anyhow!(
+            "binary version {}, this build reads version {}",
+            version.map_or("?".to_string(), |version| version.to_string()),
+            VERSION
+        ));
+    }
+    Ok(())
+}
+
+/// Decodes the block at the start of the bytes and returns its hour with the bytes after it,
+/// or why the block is torn: cut short, failing its checksum or not decoding
+fn decode_block(bytes: &[u8]) -> Result<(HourRecord, &[u8]), &'static str> {
+    let mut block = Decoder(bytes);
+    let body = block
+        .u32()
+        .and_then(|len| block.take(len as usize))
+        .ok_or("block is cut short")?;
+    let checksum = block.u32().ok_or("block is cut short")?;
+    let mut crc = Crc::new();
+    crc.update(body);
+    if crc.sum() != checksum {
+        return Err("checksum mismatch");
+    }
+    let record = decode_record(body).ok_or("block doesn't decode")?;
+    Ok((record, block.0))
+}
+
+/// Returns the hour in the binary layout: the header and the hour's block, as a binary hour
+/// file holds it (see `hour_file`)
+pub fn encode_hour(record: &HourRecord) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN + 64 + record.fills.len() * FILL_LEN);
+    out.extend(MAGIC);
+    out.extend(VERSION.to_le_bytes());
+    encode_record(&mut out, record);
+    out
+}
+
+/// Decodes an hour written by `encode_hour`. Unlike the tail of a snapshot, which a crash may
+/// have torn, the hour has to be whole: a header of another version, a block cut short,
+/// failing its checksum or not decoding, or bytes after the block fail the decode.
+pub fn decode_hour(bytes: &[u8]) -> anyhow::Result<HourRecord> {
+    check_version(bytes)?;
+    let (record, rest) = decode_block(&bytes[HEADER_LEN..]).map_err(|e| anyhow!(e))?;
+    if !rest.is_empty() {
+        return Err(anyhow!("{} bytes after the hour's block", rest.len()));
+    }
+    Ok(record)
+}
+
+fn torn_tail(path: &Path, hours: usize, e: &str) {
+    warn!(
+        "Ignoring the torn tail of snapshot {} after {} hours: {}",
+        path.display(),
+        hours,
+        e
+    );
+}
+
+/// Start of every binary snapshot file, followed by the version of its layout
+const MAGIC: &[u8] = b"OBSNAP";
+
+/// Version of the binary layout, bumped whenever it changes
+const VERSION: u16 = 1;
+
+/// Length of the magic and version at the start of a binary snapshot
+const HEADER_LEN: usize = MAGIC.len() + 2;
+
+/// Appends the binary block of an hour: the length of its body, the body, and the body's
+/// CRC-32. The body holds the venue and the symbol, each prefixed by its length, the hour,
+/// the duplicate counts, the number of fills and the fills (see `encode_fill`),
+/// integers little-endian.
+fn encode_record(out: &mut Vec<u8>, record: &HourRecord) {
+    let mut body = Vec::with_capacity(64 + record.fills.len() * FILL_LEN);
+    for name in [&record.venue, &record.symbol] {
+        body.extend((name.len() as u16).to_le_bytes());
+        body.extend(name.as_bytes());
+    }
+    body.extend(record.hour.to_le_bytes());
+    body.extend((record.duplicates.identical as u64).to_le_bytes());
+    body.extend((record.duplicates.conflicting as u64).to_le_bytes());
+    body.extend((record.fills.len() as u32).to_le_bytes());
+    for fill in &record.fills {
+        encode_fill(&mut body, fill);
+    }
+    let mut crc = Crc::new();
+    crc.update(&body);
+    out.extend((body.len() as u32).to_le_bytes());
+    out.extend(&body);
+    out.extend(crc.sum().to_le_bytes());
+}
+
+/// Bytes of an encoded fill
+const FILL_LEN: usize = 12 + 4 + 16 + 16 + 8 + 1 + 9 + 17 + 9;
+
+/// Appends a fill in fixed width: its time as Unix seconds and nanoseconds, the direction,
+/// the price and quantity as `Decimal`'s 16 bytes, the sequence number, the liquidity
+/// (0 when absent, 1 maker, 2 taker), the trade id and the fee each behind a presence byte,
+/// and the fee currency as its length, 0 when absent, and 8 bytes of code
+fn encode_fill(out: &mut Vec<u8>, fill: &Fill) {
+    out.extend(fill.time.timestamp().to_le_bytes());
+    out.extend(fill.time.timestamp_subsec_nanos().to_le_bytes());
+    out.extend(fill.direction.to_le_bytes());
+    out.extend(fill.price.serialize());
+    out.extend(fill.quantity.serialize());
+    out.extend(fill.sequence_number.to_le_bytes());
+    out.push(match fill.liquidity {
+        None => 0,
+        Some(Liquidity::Maker) => 1,
+        Some(Liquidity::Taker) => 2,
+    });
+    out.push(fill.trade_id.is_some() as u8);
+    out.extend(fill.trade_id.unwrap_or_default().to_le_bytes());
+    out.push(fill.fee.is_some() as u8);
+    out.extend(fill.fee.unwrap_or_default().serialize());
+    let currency = fill.fee_currency.as_ref().map_or("", Currency::as_str);
+    let mut code = [0; Currency::MAX_LEN];
+    code[..currency.len()].copy_from_slice(currency.as_bytes());
+    out.push(currency.len() as u8);
+    out.extend(code);
+}
+
+/// Decodes the body of a block written by `encode_record`
+fn decode_record(body: &[u8]) -> Option<HourRecord> {
+    let mut body = Decoder(body);
+    let mut name = || {
+        let len = u16::from_le_bytes(body.array()?) as usize;
+        String::from_utf8(body.take(len)?.to_vec()).ok()
+    };
+    let (venue, symbol) = (name()?, name()?);
+    let hour = body.i64()?;
+    let duplicates = DuplicateFills {
+        identical: body.u64()? as usize,
+        conflicting: body.u64()? as usize,
+    };
+    let count = body.u32()? as usize;
+    let mut fills = Vec::with_capacity(count.min(body.0.len() / FILL_LEN));
+    for _ in 0..count {
+        fills.push(decode_fill(&mut body)?);
+    }
+    body.0.is_empty().then_some(HourRecord {
+        venue,
+        symbol,
+        hour,
+        duplicates,
+        fills,
+    })
+}
+
+/// Decodes a fill written by `encode_fill`
+fn decode_fill(body: &mut Decoder) -> Option<Fill> {
+    let fill = body.array::<FILL_LEN>()?;
+    let mut fill = Decoder(&fill);
+    let time = DateTime::from_timestamp(fill.i64()?, fill.u32()?)?;
+    let direction = i32::from_le_bytes(fill.array()?);
+    let price = Decimal::deserialize(fill.array()?);
+    let quantity = Decimal::deserialize(fill.array()?);
+    let sequence_number = fill.u64()?;
+    let [liquidity, has_trade_id] = fill.array()?;
+    let liquidity = match liquidity {
+        0 => None,
+        1 => Some(Liquidity::Maker),
+        2 => Some(Liquidity::Taker),
+        _ => return None,
+    };
+    let trade_id = fill.u64()?;
+    let [has_fee] = fill.array()?;
+    let fee = Decimal::deserialize(fill.array()?);
+    let [currency_len] = fill.array()?;
+    let code = fill.array::<{ Currency::MAX_LEN }>()?;
+    let fee_currency = match currency_len as usize {
+        0 => None,
+        len => Some(std::str::from_utf8(code.get(..len)?).ok()?.parse().ok()?),
+    };
+    Some(Fill {
+        time,
+        direction,
+        price,
+        quantity,
+        sequence_number,
+        liquidity,
+        trade_id: (has_trade_id == 1).then_some(trade_id),
+        fee: (has_fee == 1).then_some(fee),
+        fee_currency,
+    })
+}
+
+/// Reads the integers of a binary block in order; every read is `None` past its end
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.0.len() {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.take(N)?.try_into().ok()
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.array().map(u64::from_le_bytes)
+    }
+
+    fn i64(&mut self) -> Option<i64> {
+        self.array().map(i64::from_le_bytes)
+    }
+}
+
+/// Work for the snapshot writer thread
+enum Job {
+    /// Append the hours as a new segment
+    Append(Vec<HourRecord>),
+    /// Replace the file by the hours, written to a temporary file first
+    Compact(Vec<HourRecord>),
+}
+
+/// Writes snapshot segments on a thread of its own, so queries don't wait for the disk.
+/// Like the audit log, the first IO error disables snapshots with a warning instead of
+/// failing queries.
+pub struct Snapshotter {
+    sender: Option<SyncSender<Job>>,
+    thread: Option<JoinHandle<()>>,
+    /// Size of the snapshot file as of the last write
+    bytes: Arc<AtomicU64>,
+    /// Size of the file after the latest compaction, or as of the request of a pending one
+    compacted_bytes: Arc<AtomicU64>,
+}
+
+/// Number of segments waiting to be written before queries wait for the writer
+const PENDING_SEGMENTS: usize = 4;
+
+impl Snapshotter {
+    /// Starts the writer thread appending to the file in `file_format`, creating it if needed.
+    /// The file is cut to its first `valid_bytes` first, dropping a torn tail left by a crash,
+    /// so new segments start on a record of their own. Compactions write `format`, which
+    /// the appends after them follow.
+    pub fn start(
+        path: PathBuf,
+        valid_bytes: u64,
+        file_format: SnapshotFormat,
+        format: SnapshotFormat,
+    ) -> Self {
+        let bytes = Arc::new(AtomicU64::new(0));
+        let (sender, receiver) = mpsc::sync_channel::<Job>(PENDING_SEGMENTS);
+        let compacted_bytes = Arc::new(AtomicU64::new(0));
+        let thread_bytes = Arc::clone(&bytes);
+        let thread_compacted_bytes = Arc::clone(&compacted_bytes);
+        let thread = thread::spawn(move || {
+            let mut file_format = file_format;
+            let mut file = match open(&path, valid_bytes, file_format) {
+                Ok((file, size)) => {
+                    thread_bytes.store(size, Ordering::Relaxed);
+                    Some(file)
+                }
+                Err(e) => {
+                    warn!("Snapshots disabled: cannot open {}: {}", path.display(), e);
+                    None
+                }
+            };
+            for job in receiver {
+                let Some(writer) = &mut file else {
+                    continue;
+                };
+                let written = match job {
+                    Job::Append(hours) => append(writer, &hours, file_format).map(|written| {
+                        thread_bytes.fetch_add(written, Ordering::Relaxed);
+                        debug!("Appended {} hours to the snapshot", hours.len());
+                    }),
+                    Job::Compact(hours) => compact(&path, &hours, format).and_then(|size| {
+                        file_format = format;
+                        *writer = open(&path, size, format)?.0;
+                        thread_bytes.store(size, Ordering::Relaxed);
+                        thread_compacted_bytes.store(size, Ordering::Relaxed);
+                        info!(
+                            "Compacted the snapshot {} to {} hours ({} bytes)",
+                            path.display(),
+                            hours.len(),
+                            size
+                        );
+                        Ok(())
+                    }),
+                };
+                if let Err(e) = written {
+                    warn!("Snapshots disabled after a write error: {}", e);
+                    file = None;
+                }
+            }
+        });
+        Snapshotter {
+            sender: Some(sender),
+            thread: Some(thread),
+            bytes,
+            compacted_bytes,
+        }
+    }
+
+    /// Returns whether the file is over the threshold and has at least doubled since it was
+    /// last compacted, so a cache larger than the threshold isn't rewritten on every snapshot
+    pub fn needs_compaction(&self, threshold: u64) -> bool {
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        bytes > threshold && bytes > 2 * self.compacted_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Queues the hours to be appended
+    pub fn append(&self, hours: Vec<HourRecord>) {
+        self.send(Job::Append(hours));
+    }
+
+    /// Queues the file to be replaced by the hours
+    pub fn compact(&self, hours: Vec<HourRecord>) {
+        self.compacted_bytes
+            .store(self.bytes.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.send(Job::Compact(hours));
+    }
+
+    fn send(&self, job: Job) {
+        if let Some(sender) = &self.sender {
+            // The thread only goes away by panicking, which it reported already
+            let _ = sender.send(job);
+        }
+    }
+}
+
+impl Drop for Snapshotter {
+    /// Waits for the queued segments to be written
+    fn drop(&mut self) {
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Opens the file for appending after truncating it to `valid_bytes`, writing the header of
+/// the format to a file left empty, and returns it with its size
+fn open(
+    path: &Path,
+    valid_bytes: u64,
+    format: SnapshotFormat,
+) -> std::io::Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.set_len(valid_bytes)?;
+    let mut writer = BufWriter::new(file);
+    let size = match valid_bytes {
+        0 => write_header(&mut writer, format)?,
+        _ => valid_bytes,
+    };
+    Ok((writer, size))
+}
+
+/// Writes the header of a new file in the format, if it has one, and returns its length
+fn write_header(writer: &mut impl Write, format: SnapshotFormat) -> std::io::Result<u64> {
+    match format {
+        SnapshotFormat::Json => Ok(0),
+        SnapshotFormat::Binary => {
+            writer.write_all(MAGIC)?;
+            writer.write_all(&VERSION.to_le_bytes())?;
+            writer.flush()?;
+            Ok(HEADER_LEN as u64)
+        }
+    }
+}
+
+/// Writes the hours as records of the format and flushes them, returning the number of
+/// bytes written
+fn append(
+    writer: &mut impl Write,
+    hours: &[HourRecord],
+    format: SnapshotFormat,
+) -> std::io::Result<u64> {
+    let mut bytes = Vec::new();
+    for hour in hours {
+        match format {
+            SnapshotFormat::Json => {
+                serde_json::to_writer(&mut bytes, hour)?;
+                bytes.push(b'\n');
+            }
+            SnapshotFormat::Binary => encode_record(&mut bytes, hour),
+        }
+    }
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+    Ok(bytes.len() as u64)
+}
+
+/// Writes the hours to a temporary file next to the snapshot and renames it over the snapshot,
+/// so a crash leaves either file whole. Returns the size of the new snapshot.
+fn compact(path: &Path, hours: &[HourRecord], format: SnapshotFormat) -> std::io::Result<u64> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    let size = write_header(&mut writer, format)? + append(&mut writer, hours, format)?;
+    writer.into_inner()?.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{fill, HOUR};
+
+    fn records() -> Vec<HourRecord> {
+        (0..3)
+            .map(|i| HourRecord {
+                venue: "default".to_string(),
+                symbol: "BTC".to_string(),
+                hour: HOUR + i * 3600,
+                duplicates: DuplicateFills {
+                    identical: i as usize,
+                    conflicting: 1,
+                },
+                fills: vec![
+                    Fill {
+                        liquidity: Some(Liquidity::Maker),
+                        trade_id: Some(i as u64),
+                        fee: Some("0.01".parse().unwrap()),
+                        fee_currency: Some("USDC".parse().unwrap()),
+                        ..fill(HOUR + i * 3600 + 1, 1, 1, "10.50", "1")
+                    },
+                    fill(HOUR + i * 3600 + 2, 2, 0, "0.000000000000000001", "3"),
+                ],
+            })
+            .collect()
+    }
+
+    /// Returns the records as serialized, to compare decimals with their scale
+    fn json(records: &[HourRecord]) -> String {
+        serde_json::to_string(records).unwrap()
+    }
+
+    #[test]
+    fn snapshots_round_trip_in_both_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        for format in [SnapshotFormat::Json, SnapshotFormat::Binary] {
+            let path = dir.path().join(format!("{:?}", format));
+            let size = compact(&path, &records(), format).unwrap();
+            let loaded = load(&path).unwrap();
+            assert_eq!(json(&loaded.records), json(&records()), "{:?}", format);
+            assert_eq!(loaded.valid_bytes, size);
+            assert_eq!(loaded.format, Some(format));
+        }
+    }
+
+    #[test]
+    fn binary_snapshots_stop_at_a_damaged_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot");
+        compact(&path, &records(), SnapshotFormat::Binary).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let block_len = (bytes.len() - HEADER_LEN) / 3;
+
+        // A flipped bit in the second block keeps the first only
+        let mut flipped = bytes.clone();
+        flipped[HEADER_LEN + block_len + 20] ^= 1;
+        fs::write(&path, &flipped).unwrap();
+        let loaded = load(&path).unwrap();
+        assert_eq!(json(&loaded.records), json(&records()[..1]));
+        assert_eq!(loaded.valid_bytes, (HEADER_LEN + block_len) as u64);
+        // So does a file cut short in the second block
+        fs::write(&path, &bytes[..HEADER_LEN + block_len + 10]).unwrap();
+        assert_eq!(load(&path).unwrap().records.len(), 1);
+
+        // Another version isn't read at all
+        let mut newer = bytes;
+        newer[MAGIC.len()] = 2;
+        fs::write(&path, &newer).unwrap();
+        let error = load(&path).err().unwrap();
+        assert!(format!("{:#}", error).ends_with("binary version 2, this build reads version 1"));
+    }
+}
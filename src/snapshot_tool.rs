@@ -0,0 +1,329 @@
+use anyhow::{anyhow, Context};
+use clap::Args;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::{error, info};
+
+use crate::cli::InputCompression;
+use crate::clock::SystemClock;
+use crate::config::{Config, ConfigArgs};
+use crate::hour_file::{self, HourFormat};
+use crate::logging::{self, LogFormat};
+use crate::snapshot::{self, HourRecord, SnapshotConfig, SnapshotFormat};
+use crate::source::{SourceSpec, DEFAULT_VENUE};
+use crate::{build_source, check_output_flags, pipeline, run_queries, Processor};
+
+/// Flags of the `warm` command
+#[derive(Debug, Args)]
+pub struct WarmArgs {
+    /// Read the queries from FILE instead of stdin
+    #[arg(long, value_name = "FILE")]
+    input: Option<PathBuf>,
+    /// Compression of the query input; by default an --input file ending in .gz is gzip
+    /// and anything else is plain text
+    #[arg(long, value_enum, value_name = "COMPRESSION")]
+    input_compression: Option<InputCompression>,
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+/// Entry point of the `warm` command: answers the queries like `query` without printing
+/// the answers, so the hours they touch are loaded into the cache, and saves them to the
+/// snapshot file for the next run to start from
+pub fn warm(args: WarmArgs) -> anyhow::Result<()> {
+    let config = Config::from(args.config);
+    logging::init(config.log_format);
+    check_output_flags(&config)?;
+    let Some(path) = config
+        .snapshot
+        .as_ref()
+        .map(|snapshot| snapshot.path.clone())
+    else {
+        return Err(anyhow!(
+            "warm saves the hours it loads to a snapshot: give --snapshot-file PATH"
+        ));
+    };
+    let input = pipeline::open_input(args.input.as_deref(), args.input_compression)?;
+    let source = build_source(&config);
+    let mut processor = Processor::with_source(config, source, Box::new(SystemClock));
+    let loaded = processor.cache_stats().hours;
+
+    let result = run_queries(&mut processor, input, &mut io::sink());
+    processor.snapshot();
+    let metrics = processor.metrics();
+    let (queries, failed, fetched) = (metrics.queries, metrics.failed, metrics.api_calls);
+    let cached = processor.cache_stats().hours;
+    // Waits for the snapshot to be written
+    drop(processor);
+    writeln!(
+        io::stdout().lock(),
+        "Warmed {}: {} queries ({} failed), {} hours fetched, {} cached ({} loaded from the snapshot)",
+        path.display(),
+        queries,
+        failed,
+        fetched,
+        cached,
+        loaded
+    )?;
+    result
+}
+
+/// Flags of the `export` command
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+    /// Snapshot file to export, in either layout
+    snapshot: PathBuf,
+    /// Directory to write the hour files to, as `<DIR>/<SYMBOL>/<HOUR>.csv`,
+    /// or `<HOUR>.bin` in the binary format
+    #[arg(long, value_name = "DIR")]
+    out: PathBuf,
+    /// Format of the hour files
+    #[arg(long, value_enum, default_value = "csv")]
+    hour_format: HourFormat,
+    /// Venue whose hours are exported
+    #[arg(long, value_name = "VENUE", default_value = DEFAULT_VENUE)]
+    venue: String,
+}
+
+/// Entry point of the `export` command: writes every hour of the venue in the snapshot to
+/// an hour file, the layout `backfill` writes and disk sources read
+pub fn export(args: ExportArgs) -> anyhow::Result<()> {
+    logging::init(LogFormat::Text);
+    let hours = load_hours(&args.snapshot)?;
+    let mut written = 0;
+    for record in hours.values().filter(|record| record.venue == args.venue) {
+        let path = hour_file::hour_path(&args.out, &record.symbol, record.hour, args.hour_format);
+        hour_file::write(&path, &record.symbol, record.hour, &record.fills)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        written += 1;
+    }
+    info!(
+        "Exported {} into {}",
+        args.snapshot.display(),
+        args.out.display()
+    );
+    writeln!(
+        io::stdout().lock(),
+        "{} hours of venue {} written to {} ({} hours of other venues left out)",
+        written,
+        args.venue,
+        args.out.display(),
+        hours.len() - written
+    )?;
+    Ok(())
+}
+
+/// Flags of the `import` command
+#[derive(Debug, Args)]
+pub struct ImportArgs {
+    /// Directory of hour files, as `<DIR>/<SYMBOL>/<HOUR>.csv` or `.bin`, as written by
+    /// `backfill` or `export`
+    dir: PathBuf,
+    /// Snapshot file to write the hours to; hours it already holds are kept
+    #[arg(long, value_name = "PATH")]
+    out: PathBuf,
+    /// Layout of a new snapshot file
+    #[arg(long, value_enum, default_value = "json")]
+    snapshot_format: SnapshotFormat,
+    /// Venue the hours are imported as
+    #[arg(long, value_name = "VENUE", default_value = DEFAULT_VENUE)]
+    venue: String,
+    /// Fail on hour files with columns unknown to a fill instead of ignoring them
+    #[arg(long)]
+    strict_schema: bool,
+}
+
+/// Entry point of the `import` command: loads every hour file under the directory into a
+/// cache, checked and deduplicated like fetched hours, and appends the hours to the
+/// snapshot file. Hours the snapshot holds already are served from it and left as they
+/// are. A file that fails to load doesn't stop the import, but fails the command once
+/// every other file has been tried.
+pub fn import(args: ImportArgs) -> anyhow::Result<()> {
+    logging::init(LogFormat::Text);
+    let mut symbols = Vec::new();
+    for entry in
+        fs::read_dir(&args.dir).with_context(|| format!("Failed to list {}", args.dir.display()))?
+    {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            let hours = hour_file::hours(&entry.path())?;
+            symbols.push((entry.file_name().to_string_lossy().into_owned(), hours));
+        }
+    }
+    symbols.sort();
+
+    let mut config = Config {
+        strict_schema: args.strict_schema,
+        snapshot: Some(SnapshotConfig {
+            path: args.out.clone(),
+            // Each hour is appended as it loads, so none is lost to the cache's eviction
+            every_hours: 1,
+            interval: None,
+            compact_bytes: u64::MAX,
+            format: args.snapshot_format,
+        }),
+        ..Config::default()
+    };
+    let venue = if args.venue == DEFAULT_VENUE {
+        config.source = SourceSpec::Disk(args.dir.clone());
+        String::new()
+    } else {
+        config
+            .venues
+            .insert(args.venue.clone(), SourceSpec::Disk(args.dir.clone()));
+        format!("@{} ", args.venue)
+    };
+    let source = build_source(&config);
+    let mut processor = Processor::with_source(config, source, Box::new(SystemClock));
+
+    let mut failures = 0;
+    for (symbol, hours) in &symbols {
+        for hour in hours {
+            // A window of the hour's seconds, whose bounds touch no neighbouring hour
+            let query = format!("C {}(] {} {} {}", venue, symbol, hour, hour + 3599);
+            if let Err(e) = processor.process_query(query) {
+                error!("Failed to import {} hour {}: {:#}", symbol, hour, e);
+                failures += 1;
+            }
+        }
+    }
+    processor.snapshot();
+    let metrics = processor.metrics();
+    let (imported, present) = (metrics.api_calls, metrics.cache_hits);
+    drop(processor);
+
+    let hours = symbols.iter().map(|(_, hours)| hours.len()).sum::<usize>();
+    writeln!(
+        io::stdout().lock(),
+        "{} hours of {} symbols: {} imported into {}, {} already present, {} failed",
+        hours,
+        symbols.len(),
+        imported,
+        args.out.display(),
+        present,
+        failures
+    )?;
+    if failures > 0 {
+        return Err(anyhow!("{} of {} hours failed", failures, hours));
+    }
+    Ok(())
+}
+
+/// Flags of the `stats` command
+#[derive(Debug, Args)]
+pub struct StatsArgs {
+    /// Snapshot file to describe, in either layout
+    snapshot: PathBuf,
+}
+
+/// What a snapshot holds of one venue and symbol
+#[derive(Debug, Default)]
+struct MarketStats {
+    hours: usize,
+    first_hour: i64,
+    last_hour: i64,
+    fills: usize,
+    duplicates: usize,
+}
+
+/// Entry point of the `stats` command: loads the snapshot file like a processor at startup
+/// and prints its layout and size, and the hours, time range, fills and duplicates it holds
+/// per venue and symbol
+pub fn stats(args: StatsArgs) -> anyhow::Result<()> {
+    logging::init(LogFormat::Text);
+    let path = &args.snapshot;
+    let size = fs::metadata(path)
+        .with_context(|| format!("Failed to read snapshot {}", path.display()))?
+        .len();
+    let loaded = snapshot::load(path)?;
+    let records = loaded.records.len();
+    let format = loaded.format.map_or("empty", |format| match format {
+        SnapshotFormat::Json => "json",
+        SnapshotFormat::Binary => "binary",
+    });
+    let valid_bytes = loaded.valid_bytes;
+    let hours = latest(loaded.records);
+
+    let mut markets = BTreeMap::<(&str, &str), MarketStats>::new();
+    for record in hours.values() {
+        let stats = markets
+            .entry((&record.venue, &record.symbol))
+            .or_insert(MarketStats {
+                first_hour: record.hour,
+                ..MarketStats::default()
+            });
+        stats.hours += 1;
+        stats.first_hour = stats.first_hour.min(record.hour);
+        stats.last_hour = stats.last_hour.max(record.hour);
+        stats.fills += record.fills.len();
+        stats.duplicates += record.duplicates.identical + record.duplicates.conflicting;
+    }
+
+    let mut stdout = io::stdout().lock();
+    writeln!(
+        stdout,
+        "Snapshot {}: {} layout, {} bytes, {} records of {} hours ({} superseded)",
+        path.display(),
+        format,
+        size,
+        records,
+        hours.len(),
+        records - hours.len()
+    )?;
+    if valid_bytes < size {
+        writeln!(
+            stdout,
+            "Torn tail: {} bytes after the last whole record, dropped on the next write",
+            size - valid_bytes
+        )?;
+    }
+    writeln!(
+        stdout,
+        "    {:<16} {:<16} {:>6} {:>11} {:>11} {:>10} {:>10}",
+        "VENUE", "SYMBOL", "HOURS", "FIRST_HOUR", "LAST_HOUR", "FILLS", "DUPLICATES"
+    )?;
+    for ((venue, symbol), stats) in &markets {
+        writeln!(
+            stdout,
+            "    {:<16} {:<16} {:>6} {:>11} {:>11} {:>10} {:>10}",
+            venue,
+            symbol,
+            stats.hours,
+            stats.first_hour,
+            stats.last_hour,
+            stats.fills,
+            stats.duplicates
+        )?;
+    }
+    writeln!(
+        stdout,
+        "Total: {} hours, {} fills",
+        hours.len(),
+        markets.values().map(|stats| stats.fills).sum::<usize>()
+    )?;
+    Ok(())
+}
+
+/// Reads the hours of an existing snapshot file, see `latest`
+fn load_hours(path: &Path) -> anyhow::Result<BTreeMap<(String, String, i64), HourRecord>> {
+    if !path.exists() {
+        return Err(anyhow!("No snapshot at {}", path.display()));
+    }
+    Ok(latest(snapshot::load(path)?.records))
+}
+
+/// Returns the latest record of each venue, symbol and hour, which supersedes the earlier
+/// ones like it does when a processor loads the snapshot
+fn latest(records: Vec<HourRecord>) -> BTreeMap<(String, String, i64), HourRecord> {
+    records
+        .into_iter()
+        .map(|record| {
+            (
+                (record.venue.clone(), record.symbol.clone(), record.hour),
+                record,
+            )
+        })
+        .collect()
+}
@@ -0,0 +1,241 @@
+use anyhow::anyhow;
+use chrono::Utc;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::hour_file::{self, HourFormat};
+use crate::server::{get_fills_api, ApiResponse};
+
+/// Venue of queries without an "@VENUE" prefix, served by the `--source` upstream
+pub const DEFAULT_VENUE: &str = "default";
+
+/// Upstream that hours of fills are fetched from on cache misses.
+/// Sources are `Send`, so fetches can run on threads of their own under a query timeout.
+pub trait FillSource: Send {
+    /// Returns the fills of the symbol within (start, end]
+    fn get_fills(
+        &mut self,
+        symbol: &str,
+        start_timestamp_in_seconds: i64,
+        end_timestamp_in_seconds: i64,
+    ) -> anyhow::Result<ApiResponse>;
+
+    /// Returns whether the source serves ranges within an hour, as differential refreshes
+    /// request, and not only whole hours
+    fn serves_partial_hours(&self) -> bool {
+        true
+    }
+
+    /// Returns the start of the newest hour the source has fills of the symbol for, in Unix
+    /// seconds, when it can tell without fetching
+    fn newest_hour(&self, _symbol: &str) -> Option<i64> {
+        None
+    }
+}
+
+/// The trades API, see `get_fills_api`
+pub struct ApiSource {
+    /// Skip fills that fail to parse instead of failing the fetch
+    pub lenient: bool,
+    /// Fail requests whose fills have fields unknown to `Fill` instead of ignoring them
+    pub strict_schema: bool,
+}
+
+impl FillSource for ApiSource {
+    fn get_fills(
+        &mut self,
+        symbol: &str,
+        start_timestamp_in_seconds: i64,
+        end_timestamp_in_seconds: i64,
+    ) -> anyhow::Result<ApiResponse> {
+        get_fills_api(
+            symbol,
+            start_timestamp_in_seconds,
+            end_timestamp_in_seconds,
+            self.lenient,
+            self.strict_schema,
+        )
+    }
+
+    // The trades API is live: it has the hour in progress
+    fn newest_hour(&self, _symbol: &str) -> Option<i64> {
+        let now = Utc::now().timestamp();
+        Some(now - now.rem_euclid(3600))
+    }
+}
+
+/// Error of a disk source asked for an hour it has no file for
+#[derive(Debug)]
+pub struct HourNotAvailable {
+    pub symbol: String,
+    pub hour: i64,
+    pub path: PathBuf,
+}
+
+impl fmt::Display for HourNotAvailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Hour {} of {} is not available on disk ({})",
+            self.hour,
+            self.symbol,
+            self.path.display()
+        )
+    }
+}
+
+impl std::error::Error for HourNotAvailable {}
+
+/// Error of an upstream that couldn't be read at all, as opposed to one that served
+/// bad data; the same request may succeed later
+#[derive(Debug)]
+pub struct UpstreamUnavailable(pub String);
+
+impl fmt::Display for UpstreamUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Upstream unavailable: {}", self.0)
+    }
+}
+
+impl std::error::Error for UpstreamUnavailable {}
+
+/// Returns whether the error was caused by an unavailable upstream
+pub fn is_retryable(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<UpstreamUnavailable>())
+}
+
+/// Serves whole hours from hour files, as written by `backfill` and `fixture record`,
+/// reading each file when its hour is requested, the binary file when an hour has both
+pub struct DiskSource {
+    pub dir: PathBuf,
+    /// Fail reads of hour files with columns unknown to `Fill` instead of ignoring them
+    pub strict_schema: bool,
+}
+
+impl FillSource for DiskSource {
+    fn get_fills(
+        &mut self,
+        symbol: &str,
+        start_timestamp_in_seconds: i64,
+        end_timestamp_in_seconds: i64,
+    ) -> anyhow::Result<ApiResponse> {
+        if start_timestamp_in_seconds % 3600 != 0
+            || end_timestamp_in_seconds != start_timestamp_in_seconds + 3600
+        {
+            return Err(anyhow!(
+                "Hour files only hold whole hours, not ({}, {}]",
+                start_timestamp_in_seconds,
+                end_timestamp_in_seconds
+            ));
+        }
+
+        let Some(path) = hour_file::find(&self.dir, symbol, start_timestamp_in_seconds) else {
+            return Err(HourNotAvailable {
+                symbol: symbol.to_string(),
+                hour: start_timestamp_in_seconds,
+                path: hour_file::hour_path(
+                    &self.dir,
+                    symbol,
+                    start_timestamp_in_seconds,
+                    HourFormat::Csv,
+                ),
+            }
+            .into());
+        };
+        let file = hour_file::read(&path, self.strict_schema)?;
+        Ok(ApiResponse {
+            fills: file.fills,
+            malformed: 0,
+            unknown_fields: file.unknown_fields,
+        })
+    }
+
+    fn serves_partial_hours(&self) -> bool {
+        false
+    }
+
+    fn newest_hour(&self, symbol: &str) -> Option<i64> {
+        hour_file::hours(&self.dir.join(symbol))
+            .ok()?
+            .last()
+            .copied()
+    }
+}
+
+/// Which upstream to fetch hours from, given as `api` or `disk:<DIR>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceSpec {
+    /// The trades API
+    Api,
+    /// Hour files under the directory
+    Disk(PathBuf),
+}
+
+impl FromStr for SourceSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.split_once(':') {
+            None if s == "api" => Ok(SourceSpec::Api),
+            Some(("disk", dir)) if !dir.is_empty() => Ok(SourceSpec::Disk(PathBuf::from(dir))),
+            _ => Err(anyhow!(
+                "Invalid source: {} (expected api or disk:<DIR>)",
+                s
+            )),
+        }
+    }
+}
+
+impl SourceSpec {
+    /// Creates the source, skipping fills that fail to parse when `lenient` is set,
+    /// and failing on fills with unknown fields when `strict_schema` is set
+    pub fn build(&self, lenient: bool, strict_schema: bool) -> Box<dyn FillSource> {
+        match self {
+            SourceSpec::Api => Box::new(ApiSource {
+                lenient,
+                strict_schema,
+            }),
+            SourceSpec::Disk(dir) => Box::new(DiskSource {
+                dir: dir.clone(),
+                strict_schema,
+            }),
+        }
+    }
+}
+
+/// A named venue and the upstream serving it, given as `NAME=SOURCE`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VenueSpec {
+    pub name: String,
+    pub source: SourceSpec,
+}
+
+impl FromStr for VenueSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (name, source) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid venue: {} (expected NAME=SOURCE)", s))?;
+        if !is_venue_name(name) || name == DEFAULT_VENUE {
+            return Err(anyhow!(
+                "Invalid venue name: {} (expected letters, digits, '-' or '_', other than {})",
+                name,
+                DEFAULT_VENUE
+            ));
+        }
+        Ok(VenueSpec {
+            name: name.to_string(),
+            source: source.parse()?,
+        })
+    }
+}
+
+/// Returns whether the name can name a venue: non-empty letters, digits, '-' and '_'
+pub fn is_venue_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
@@ -0,0 +1,692 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+
+use crate::clock::Instant;
+use crate::config::Config;
+use crate::endpoint::EndpointStats;
+use crate::intern::HourKey;
+use crate::query::{self, Query, QueryKind};
+use crate::{DuplicateFills, Processor};
+
+/// Counters of the queries processed so far, in total and per query type, and of what the
+/// cache and the upstreams did for them
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Processed queries, failed ones included
+    pub queries: usize,
+    /// Processed queries that failed, to parse or after
+    pub failed: usize,
+    /// Processing time of every query, in the order processed
+    latencies: Vec<Duration>,
+    /// Hours served from the cache
+    pub cache_hits: usize,
+    /// Hours fetched from the upstream and cached
+    pub api_calls: usize,
+    /// Counters per query type; queries that failed to parse have no type and aren't included
+    pub by_kind: BTreeMap<QueryKind, KindMetrics>,
+    /// Counters per venue, like those per query type
+    pub by_venue: BTreeMap<String, VenueMetrics>,
+    /// Queries answered TIMEOUT
+    pub timed_out_queries: usize,
+    /// Fetched fills skipped because they failed to parse (lenient mode)
+    pub malformed_fills: usize,
+    /// Number of fetched hours whose fills had each field unknown to `Fill`, by field
+    pub unknown_fields: BTreeMap<String, usize>,
+    /// Fills dropped for a non-positive price or quantity, per venue, symbol and hour,
+    /// as of the latest fetch of the hour
+    pub invalid_fills: BTreeMap<HourKey, usize>,
+    /// Duplicate taker trades per venue, symbol and hour that had any,
+    /// as of the latest fetch of the hour
+    pub duplicate_fills: BTreeMap<HourKey, DuplicateFills>,
+    /// Fetched fills whose direction is neither buy nor sell
+    pub unknown_direction_fills: usize,
+    /// Fetched or loaded fills dropped for a time outside the hour they came with
+    pub out_of_hour_fills: usize,
+    /// Verified queries whose fresh answer differed from the cached answer
+    pub verify_mismatches: usize,
+    /// Refetches of a cached hour, stale or verified, that returned other fills than it
+    /// was cached with
+    pub changed_hours: usize,
+    /// Requests made to the upstreams, including verification fetches
+    pub upstream_requests: usize,
+    /// Requests of each endpoint of the default venue by its name, "live" and, with
+    /// `--archive-source`, "archive", counted by the endpoints as they run
+    pub endpoints: Vec<(&'static str, Arc<EndpointStats>)>,
+    /// Fetched hours over the streaming threshold, whose fills weren't cached
+    pub streamed_hours: usize,
+    /// Hours fetched ahead of the queries needing them
+    pub prefetched_hours: usize,
+    /// Stale hours refreshed by fetching only the fills after the cached ones
+    pub differential_refreshes: usize,
+    /// Cached fills differential refreshes didn't fetch again
+    pub fills_not_refetched: usize,
+    /// Differential refreshes that fell back to a full refetch after a gap
+    pub refresh_gaps: usize,
+    /// Fetched hours with more fills than the cache admits, kept in the jumbo slot instead
+    pub jumbo_hours: usize,
+    /// Hours evicted to keep the cache within its byte budget
+    pub budget_evictions: usize,
+}
+
+/// Upper bounds, in seconds, of the buckets of the query latency histograms
+pub const LATENCY_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// Counters of the queries of one type
+#[derive(Debug, Default)]
+pub struct KindMetrics {
+    /// Processed queries, failed ones included
+    pub queries: usize,
+    /// Queries that failed after being parsed
+    pub failed: usize,
+    /// Hours served from the cache
+    pub cache_hits: usize,
+    /// Hours not served from the cache, whether or not their fetch succeeded
+    pub misses: usize,
+    /// Hours fetched from the upstream and cached
+    pub api_calls: usize,
+    /// Processing time of every query, in the order processed
+    latencies: Vec<Duration>,
+    /// Queries that took at most each bound of `LATENCY_BUCKETS`
+    latency_buckets: [usize; LATENCY_BUCKETS.len()],
+    /// Processing time of the queries, in total
+    latency_sum: Duration,
+}
+
+/// The counters of `KindMetrics` that add up across processors, for the metrics of a pool
+/// of workers: the latencies are a histogram rather than every one of them
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct KindCounters {
+    pub queries: usize,
+    pub failed: usize,
+    pub cache_hits: usize,
+    pub misses: usize,
+    pub api_calls: usize,
+    /// Queries that took at most each bound of `LATENCY_BUCKETS`
+    pub latency_buckets: [usize; LATENCY_BUCKETS.len()],
+    /// Processing time of the queries, in total
+    pub latency_sum: Duration,
+}
+
+impl KindCounters {
+    /// Adds what the counters `after` counted since they were `before`
+    pub fn add_since(&mut self, after: &KindCounters, before: &KindCounters) {
+        self.queries += after.queries - before.queries;
+        self.failed += after.failed - before.failed;
+        self.cache_hits += after.cache_hits - before.cache_hits;
+        self.misses += after.misses - before.misses;
+        self.api_calls += after.api_calls - before.api_calls;
+        for (bucket, (after, before)) in self
+            .latency_buckets
+            .iter_mut()
+            .zip(after.latency_buckets.iter().zip(before.latency_buckets))
+        {
+            *bucket += after - before;
+        }
+        self.latency_sum += after.latency_sum - before.latency_sum;
+    }
+}
+
+/// Counters of the queries of one venue
+#[derive(Debug, Default)]
+pub struct VenueMetrics {
+    /// Processed queries, failed ones included
+    pub queries: usize,
+    /// Queries that failed after being parsed
+    pub failed: usize,
+    /// Hours served from the cache
+    pub cache_hits: usize,
+    /// Hours not served from the cache, whether or not their fetch succeeded
+    pub misses: usize,
+    /// Hours fetched from the upstream and cached
+    pub api_calls: usize,
+}
+
+impl Metrics {
+    /// Returns the requests the endpoint of the given name sent, 0 for an unknown one
+    pub fn endpoint_requests(&self, name: &str) -> usize {
+        self.endpoints
+            .iter()
+            .find(|(endpoint, _)| *endpoint == name)
+            .map_or(0, |(_, stats)| stats.counts().0)
+    }
+
+    /// Returns the counters of the query's venue
+    fn venue(&mut self, parsed: &Query) -> &mut VenueMetrics {
+        if !self.by_venue.contains_key(&parsed.venue) {
+            self.by_venue
+                .insert(parsed.venue.clone(), VenueMetrics::default());
+        }
+        self.by_venue.get_mut(&parsed.venue).unwrap()
+    }
+
+    /// Counts an hour of the query served from the cache
+    pub fn record_hit(&mut self, parsed: &Query) {
+        self.cache_hits += 1;
+        self.by_kind.entry(parsed.kind).or_default().cache_hits += 1;
+        self.venue(parsed).cache_hits += 1;
+    }
+
+    /// Counts an hour of the query not served from the cache
+    pub fn record_miss(&mut self, parsed: &Query) {
+        self.by_kind.entry(parsed.kind).or_default().misses += 1;
+        self.venue(parsed).misses += 1;
+    }
+
+    /// Counts an hour of the query fetched from the upstream and cached
+    pub fn record_api_call(&mut self, parsed: &Query) {
+        self.api_calls += 1;
+        self.by_kind.entry(parsed.kind).or_default().api_calls += 1;
+        self.venue(parsed).api_calls += 1;
+    }
+
+    /// Counts a processed query and how long it took, under its type and venue when it parsed
+    pub fn record_query(&mut self, parsed: Option<&Query>, duration: Duration, failed: bool) {
+        self.queries += 1;
+        self.failed += failed as usize;
+        self.latencies.push(duration);
+        let Some(parsed) = parsed else {
+            return;
+        };
+        let metrics = self.by_kind.entry(parsed.kind).or_default();
+        metrics.queries += 1;
+        metrics.failed += failed as usize;
+        metrics.latencies.push(duration);
+        metrics.latency_sum += duration;
+        for (bucket, bound) in metrics.latency_buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            *bucket += (duration.as_secs_f64() <= bound) as usize;
+        }
+        let metrics = self.venue(parsed);
+        metrics.queries += 1;
+        metrics.failed += failed as usize;
+    }
+
+    /// Formats the per-type counters as a table, one row per query type seen
+    pub fn kind_table(&self) -> String {
+        let mut table = format!(
+            "\nPer-type statistics:\n    {:<4} {:>8} {:>7} {:>8} {:>7} {:>9} {:>8} {:>9} {:>9} {:>9}",
+            "TYPE", "QUERIES", "FAILED", "HITS", "MISSES", "API_CALLS", "HIT_RATE", "P50_MS", "P90_MS",
+            "P99_MS"
+        );
+        for (kind, metrics) in &self.by_kind {
+            let [p50, p90, p99] = [50, 90, 99].map(|p| metrics.latency_percentile(p));
+            table += &format!(
+                "\n    {:<4} {:>8} {:>7} {:>8} {:>7} {:>9} {:>7.2}% {:>9.3} {:>9.3} {:>9.3}",
+                kind.code(),
+                metrics.queries,
+                metrics.failed,
+                metrics.cache_hits,
+                metrics.misses,
+                metrics.api_calls,
+                hit_rate(metrics.cache_hits, metrics.misses),
+                p50.as_secs_f64() * 1000.0,
+                p90.as_secs_f64() * 1000.0,
+                p99.as_secs_f64() * 1000.0
+            );
+        }
+        table
+    }
+
+    /// Returns the counters of every query type seen, see `KindCounters`
+    pub fn kind_counters(&self) -> BTreeMap<QueryKind, KindCounters> {
+        self.by_kind
+            .iter()
+            .map(|(kind, metrics)| (*kind, metrics.counters()))
+            .collect()
+    }
+
+    /// Formats the counters for the answer of a `STATS` line, on one line: the totals, then a
+    /// `TYPE:NAME=VALUE,...` token per query type seen, with the columns of `kind_table`
+    pub fn stats_line(&self) -> String {
+        let milliseconds = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let mut line = format!(
+            "queries={} failed={} hits={} api_calls={} hit_rate={:.2} p50_ms={:.3} p90_ms={:.3} p99_ms={:.3}",
+            self.queries,
+            self.failed,
+            self.cache_hits,
+            self.api_calls,
+            hit_rate(self.cache_hits, self.api_calls),
+            milliseconds(self.latency_percentile(50)),
+            milliseconds(self.latency_percentile(90)),
+            milliseconds(self.latency_percentile(99))
+        );
+        for (kind, metrics) in &self.by_kind {
+            line += &format!(
+                " {}:queries={},failed={},hits={},misses={},api_calls={},hit_rate={:.2},p50_ms={:.3},p90_ms={:.3},p99_ms={:.3}",
+                kind.code(),
+                metrics.queries,
+                metrics.failed,
+                metrics.cache_hits,
+                metrics.misses,
+                metrics.api_calls,
+                hit_rate(metrics.cache_hits, metrics.misses),
+                milliseconds(metrics.latency_percentile(50)),
+                milliseconds(metrics.latency_percentile(90)),
+                milliseconds(metrics.latency_percentile(99))
+            );
+        }
+        line
+    }
+
+    /// Formats the per-venue counters as a table, one row per venue queried
+    pub fn venue_table(&self) -> String {
+        let mut table = format!(
+            "\nPer-venue statistics:\n    {:<16} {:>8} {:>7} {:>8} {:>7} {:>9} {:>8}",
+            "VENUE", "QUERIES", "FAILED", "HITS", "MISSES", "API_CALLS", "HIT_RATE"
+        );
+        for (venue, metrics) in &self.by_venue {
+            table += &format!(
+                "\n    {:<16} {:>8} {:>7} {:>8} {:>7} {:>9} {:>7.2}%",
+                venue,
+                metrics.queries,
+                metrics.failed,
+                metrics.cache_hits,
+                metrics.misses,
+                metrics.api_calls,
+                hit_rate(metrics.cache_hits, metrics.misses)
+            );
+        }
+        table
+    }
+}
+
+impl Metrics {
+    /// Nearest-rank percentile of the latencies of all queries, zero when none was processed
+    pub fn latency_percentile(&self, percentile: usize) -> Duration {
+        self::percentile(&self.latencies, percentile)
+    }
+}
+
+/// Size of a set of cache entries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    /// Number of hours cached
+    pub hours: usize,
+    /// Total number of fills stored
+    pub fills: usize,
+    /// Maximum number of fills in a single hour
+    pub max_hour_fills: usize,
+    /// Approximate memory usage in bytes
+    pub bytes: usize,
+}
+
+impl KindMetrics {
+    /// Nearest-rank percentile of the query latencies, zero when no query was processed
+    pub fn latency_percentile(&self, percentile: usize) -> Duration {
+        self::percentile(&self.latencies, percentile)
+    }
+
+    pub fn counters(&self) -> KindCounters {
+        KindCounters {
+            queries: self.queries,
+            failed: self.failed,
+            cache_hits: self.cache_hits,
+            misses: self.misses,
+            api_calls: self.api_calls,
+            latency_buckets: self.latency_buckets,
+            latency_sum: self.latency_sum,
+        }
+    }
+}
+
+/// Nearest-rank percentile of the durations, zero when there are none
+pub fn percentile(durations: &[Duration], percentile: usize) -> Duration {
+    let mut durations = durations.to_vec();
+    durations.sort_unstable();
+    let rank = (durations.len() * percentile).div_ceil(100);
+    durations
+        .get(rank.saturating_sub(1))
+        .copied()
+        .unwrap_or_default()
+}
+
+/// Periodically logs the cache/API statistics during long runs, every N processed queries
+/// and/or every T seconds, along with the deltas since the previous dump.
+/// The clock is checked between queries, so an idle input emits no dumps.
+pub struct StatsReporter {
+    every_queries: Option<usize>,
+    interval: Option<Duration>,
+    last_dump: Instant,
+    queries: usize,
+    last_queries: usize,
+    last_cache_hits: usize,
+    last_api_calls: usize,
+}
+
+/// Cache hit rate as a percentage, zero when nothing was looked up
+pub fn hit_rate(cache_hits: usize, api_calls: usize) -> f64 {
+    if cache_hits + api_calls == 0 {
+        return 0.0;
+    }
+    (cache_hits as f64 / (cache_hits + api_calls) as f64) * 100.0
+}
+
+impl StatsReporter {
+    pub fn new(config: &Config) -> Self {
+        StatsReporter {
+            every_queries: config.stats_every_queries,
+            interval: config.stats_interval,
+            last_dump: Instant::now(),
+            queries: 0,
+            last_queries: 0,
+            last_cache_hits: 0,
+            last_api_calls: 0,
+        }
+    }
+
+    /// Records one processed query and dumps the statistics if a reporting period has elapsed
+    pub fn record_query(&mut self, processor: &Processor) {
+        self.queries += 1;
+
+        let queries_due = self
+            .every_queries
+            .is_some_and(|n| self.queries - self.last_queries >= n);
+        let interval_due = self
+            .interval
+            .is_some_and(|interval| self.last_dump.elapsed() >= interval);
+        if queries_due || interval_due {
+            self.dump(processor);
+        }
+    }
+
+    fn dump(&mut self, processor: &Processor) {
+        let cache_hits = processor.metrics().cache_hits;
+        let api_calls = processor.metrics().api_calls;
+        let window_queries = self.queries - self.last_queries;
+        let window_hits = cache_hits - self.last_cache_hits;
+        let window_api_calls = api_calls - self.last_api_calls;
+        let CacheStats {
+            hours: hours_cached,
+            bytes: cache_bytes,
+            ..
+        } = processor.cache_stats();
+
+        info!(
+            queries = self.queries,
+            window_queries,
+            window_hit_rate = hit_rate(window_hits, window_api_calls),
+            window_cache_hits = window_hits,
+            window_api_calls,
+            hit_rate = hit_rate(cache_hits, api_calls),
+            cache_hits,
+            api_calls,
+            hours_cached,
+            cache_bytes,
+            "Periodic statistics after {} queries: last {} queries hit rate {:.2}% ({} hits, {} API calls); \
+             cumulative hit rate {:.2}% ({} hits, {} API calls); {} hours cached ({} bytes)",
+            self.queries,
+            window_queries,
+            hit_rate(window_hits, window_api_calls),
+            window_hits,
+            window_api_calls,
+            hit_rate(cache_hits, api_calls),
+            cache_hits,
+            api_calls,
+            hours_cached,
+            cache_bytes
+        );
+
+        self.last_dump = Instant::now();
+        self.last_queries = self.queries;
+        self.last_cache_hits = cache_hits;
+        self.last_api_calls = api_calls;
+    }
+}
+
+/// Logs the statistics of a finished run: the cache contents, the hit rate, the per-type
+/// and per-venue tables, the phase times when profiling, and the counters of what the
+/// features in use did
+pub fn log_run_stats(processor: &Processor) {
+    let metrics = processor.metrics();
+    info!("{}", processor.print_cache_stats());
+    let cache_hits = metrics.cache_hits;
+    let api_calls = metrics.api_calls;
+    let hit_rate = hit_rate(cache_hits, api_calls);
+    info!(hit_rate, "Cache hit rate: {:.2}%", hit_rate);
+    info!(cache_hits, "Cache hits: {}", cache_hits);
+    info!(api_calls, "API calls: {}", api_calls);
+    info!("{}", metrics.kind_table());
+    info!("{}", metrics.venue_table());
+    if let Some(profile) = processor.profile() {
+        info!("{}", profile.table());
+    }
+    info!(
+        malformed_fills = metrics.malformed_fills,
+        "Malformed fills skipped: {}", metrics.malformed_fills
+    );
+    if !metrics.unknown_fields.is_empty() {
+        info!(
+            "Unknown fill fields ignored, by hours sending them:{}",
+            processor
+                .metrics()
+                .unknown_fields
+                .iter()
+                .map(|(field, hours)| format!("\n    {}: {}", field, hours))
+                .collect::<String>()
+        );
+    }
+    info!(
+        unknown_direction_fills = metrics.unknown_direction_fills,
+        "Fills of unknown direction: {}", metrics.unknown_direction_fills
+    );
+    info!(
+        timed_out_queries = metrics.timed_out_queries,
+        "Queries timed out: {}", metrics.timed_out_queries
+    );
+    info!(
+        out_of_hour_fills = metrics.out_of_hour_fills,
+        "Fills outside their hour dropped: {}", metrics.out_of_hour_fills
+    );
+    let invalid_fills = &metrics.invalid_fills;
+    let invalid_total = invalid_fills.values().sum::<usize>();
+    info!(
+        invalid_fills = invalid_total,
+        "Invalid fills dropped: {}{}",
+        invalid_total,
+        invalid_fills
+            .iter()
+            .map(|((venue, symbol, hour), count)| {
+                format!(
+                    "\n    {} hour {}: {}",
+                    query::market_name(venue, symbol),
+                    hour,
+                    count
+                )
+            })
+            .collect::<String>()
+    );
+    let duplicate_fills = &metrics.duplicate_fills;
+    let identical_total = duplicate_fills.values().map(|d| d.identical).sum::<usize>();
+    let conflicting_total = duplicate_fills
+        .values()
+        .map(|d| d.conflicting)
+        .sum::<usize>();
+    info!(
+        identical_duplicate_fills = identical_total,
+        conflicting_duplicate_fills = conflicting_total,
+        "Duplicate fills: {} identical, {} conflicting{}",
+        identical_total,
+        conflicting_total,
+        duplicate_fills
+            .iter()
+            .map(|((venue, symbol, hour), duplicates)| {
+                format!(
+                    "\n    {} hour {}: {} identical, {} conflicting",
+                    query::market_name(venue, symbol),
+                    hour,
+                    duplicates.identical,
+                    duplicates.conflicting
+                )
+            })
+            .collect::<String>()
+    );
+    if processor.config().stream_threshold.is_some() {
+        info!(
+            streamed_hours = metrics.streamed_hours,
+            "Hours summarized instead of cached: {}", metrics.streamed_hours
+        );
+    }
+    if processor.config().differential_refresh {
+        info!(
+            differential_refreshes = metrics.differential_refreshes,
+            fills_not_refetched = metrics.fills_not_refetched,
+            refresh_gaps = metrics.refresh_gaps,
+            "Differential refreshes: {}, skipping {} cached fills; {} fell back to a full refetch",
+            metrics.differential_refreshes,
+            metrics.fills_not_refetched,
+            metrics.refresh_gaps
+        );
+    }
+    if processor.config().archive.is_some() {
+        for (name, stats) in &metrics.endpoints {
+            let (requests, failures, retries) = stats.counts();
+            info!(
+                endpoint = name,
+                requests,
+                failures,
+                retries,
+                "Requests to the {} endpoint: {} ({} failed, {} retries)",
+                name,
+                requests,
+                failures,
+                retries
+            );
+        }
+    }
+    if processor.config().lookahead.is_some() {
+        info!(
+            prefetched_hours = metrics.prefetched_hours,
+            "Hours prefetched for upcoming queries: {}", metrics.prefetched_hours
+        );
+    }
+    if let Some(budget) = processor.cache_budget() {
+        info!(
+            budget_evictions = metrics.budget_evictions,
+            "Hours evicted to keep the cache within {} bytes: {}", budget, metrics.budget_evictions
+        );
+    }
+    if processor.config().max_cached_hour_fills.is_some() {
+        info!(
+            jumbo_hours = metrics.jumbo_hours,
+            "Hours kept out of the cache for their size: {}", metrics.jumbo_hours
+        );
+    }
+    if let Some(budget) = processor.config().max_api_calls {
+        info!(
+            upstream_requests = metrics.upstream_requests,
+            "API call budget: {} of {} calls used", metrics.upstream_requests, budget
+        );
+    }
+    info!(
+        changed_hours = metrics.changed_hours,
+        "Hours whose content changed after their initial fetch: {}", metrics.changed_hours
+    );
+    if processor.config().verify_sample > 0.0 {
+        info!(
+            verify_mismatches = metrics.verify_mismatches,
+            "Verification mismatches: {}", metrics.verify_mismatches
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    fn parsed(query: &str) -> Query {
+        let now = DateTime::from_timestamp(1800000000, 0).unwrap();
+        Query::parse(query, "BTC", Default::default(), now).unwrap()
+    }
+
+    #[test]
+    fn hit_rate_is_zero_without_lookups() {
+        assert_eq!(hit_rate(0, 0), 0.0);
+        assert_eq!(Metrics::default().latency_percentile(99), Duration::ZERO);
+        assert_eq!(hit_rate(3, 1), 75.0);
+    }
+
+    #[test]
+    fn counters_follow_the_recorded_hits_and_misses() {
+        let mut metrics = Metrics::default();
+        let count = parsed("C 1700816400 1700819999");
+        let volume = parsed("V @other 1700816400 1700819999");
+        metrics.record_miss(&count);
+        metrics.record_api_call(&count);
+        metrics.record_query(Some(&count), Duration::from_millis(4), false);
+        metrics.record_hit(&volume);
+        metrics.record_query(Some(&volume), Duration::from_millis(2), false);
+        metrics.record_miss(&count);
+        metrics.record_query(Some(&count), Duration::from_millis(6), true);
+        metrics.record_query(None, Duration::from_millis(1), true);
+
+        assert_eq!((metrics.queries, metrics.failed), (4, 2));
+        assert_eq!((metrics.cache_hits, metrics.api_calls), (1, 1));
+        let kind = &metrics.by_kind[&QueryKind::Count];
+        assert_eq!(
+            (
+                kind.queries,
+                kind.failed,
+                kind.cache_hits,
+                kind.misses,
+                kind.api_calls
+            ),
+            (2, 1, 0, 2, 1)
+        );
+        assert_eq!(kind.latency_percentile(50), Duration::from_millis(4));
+        assert_eq!(kind.latency_percentile(100), Duration::from_millis(6));
+        let venue = &metrics.by_venue["other"];
+        assert_eq!((venue.queries, venue.cache_hits, venue.misses), (1, 1, 0));
+        assert_eq!(metrics.latency_percentile(50), Duration::from_millis(2));
+
+        assert_eq!(
+            metrics.stats_line(),
+            "queries=4 failed=2 hits=1 api_calls=1 hit_rate=50.00 p50_ms=2.000 p90_ms=6.000 \
+             p99_ms=6.000 \
+             C:queries=2,failed=1,hits=0,misses=2,api_calls=1,hit_rate=0.00,p50_ms=4.000,\
+             p90_ms=6.000,p99_ms=6.000 \
+             V:queries=1,failed=0,hits=1,misses=0,api_calls=0,hit_rate=100.00,p50_ms=2.000,\
+             p90_ms=2.000,p99_ms=2.000"
+        );
+    }
+
+    #[test]
+    fn kind_counters_add_up_across_processors() {
+        let count = parsed("C 1700816400 1700819999");
+        let mut first = Metrics::default();
+        first.record_hit(&count);
+        first.record_query(Some(&count), Duration::from_millis(2), false);
+        let before = first.kind_counters()[&QueryKind::Count];
+        assert_eq!(before.latency_buckets[..4], [0, 0, 1, 1]);
+        assert_eq!(before.latency_buckets[11], 1);
+        first.record_miss(&count);
+        first.record_api_call(&count);
+        first.record_query(Some(&count), Duration::from_millis(300), true);
+        let mut second = Metrics::default();
+        second.record_query(Some(&count), Duration::from_secs(3), false);
+
+        let mut total = KindCounters::default();
+        total.add_since(&first.kind_counters()[&QueryKind::Count], &before);
+        total.add_since(
+            &second.kind_counters()[&QueryKind::Count],
+            &KindCounters::default(),
+        );
+        assert_eq!(
+            (
+                total.queries,
+                total.failed,
+                total.cache_hits,
+                total.misses,
+                total.api_calls
+            ),
+            (2, 1, 0, 1, 1)
+        );
+        assert_eq!(total.latency_buckets[..9], [0; 9]);
+        assert_eq!(total.latency_buckets[9..], [1, 1, 1]);
+        assert_eq!(total.latency_sum, Duration::from_millis(3300));
+    }
+}
@@ -0,0 +1,149 @@
+use anyhow::anyhow;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::clock::Instant;
+
+/// A standing-query command on an input line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command<'a> {
+    /// "SUB INTERVAL_SECONDS QUERY": answer the query now and every interval after
+    Subscribe { interval: Duration, query: &'a str },
+    /// "UNSUB ID": stop re-answering the subscription
+    Unsubscribe(u64),
+}
+
+impl<'a> Command<'a> {
+    /// Parses the command on a query line, or returns `None` when the line is a plain query
+    pub fn parse(line: &'a str) -> Option<anyhow::Result<Self>> {
+        let (word, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim_start();
+        if word.eq_ignore_ascii_case("SUB") {
+            let (interval, query) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            let command = match interval.parse::<u64>() {
+                Ok(seconds) if seconds > 0 && !query.trim().is_empty() => Ok(Command::Subscribe {
+                    interval: Duration::from_secs(seconds),
+                    query: query.trim(),
+                }),
+                _ => Err(anyhow!(
+                    "Invalid subscription: {} (expected SUB INTERVAL_SECONDS QUERY)",
+                    line
+                )),
+            };
+            Some(command)
+        } else if word.eq_ignore_ascii_case("UNSUB") {
+            Some(
+                rest.parse::<u64>()
+                    .map(Command::Unsubscribe)
+                    .map_err(|_| anyhow!("Invalid unsubscription: {} (expected UNSUB ID)", line)),
+            )
+        } else {
+            None
+        }
+    }
+}
+
+/// A standing query answered again every interval
+struct Subscription {
+    query: String,
+    interval: Duration,
+    next_due: Instant,
+}
+
+/// Registry of the standing queries, keyed by subscription id
+#[derive(Default)]
+pub struct Subscriptions {
+    subscriptions: BTreeMap<u64, Subscription>,
+    next_id: u64,
+}
+
+impl Subscriptions {
+    /// Registers a query first answered at `now` and returns its id, counting from 1
+    pub fn add(&mut self, query: &str, interval: Duration, now: Instant) -> u64 {
+        self.next_id += 1;
+        self.subscriptions.insert(
+            self.next_id,
+            Subscription {
+                query: query.to_string(),
+                interval,
+                next_due: now + interval,
+            },
+        );
+        self.next_id
+    }
+
+    /// Cancels a subscription, failing when there is none with the id
+    pub fn remove(&mut self, id: u64) -> anyhow::Result<()> {
+        self.subscriptions
+            .remove(&id)
+            .map(|_| ())
+            .ok_or_else(|| anyhow!("No subscription {}", id))
+    }
+
+    /// Returns when the next subscription is due, if there is any
+    pub fn next_due(&self) -> Option<Instant> {
+        self.subscriptions
+            .values()
+            .map(|subscription| subscription.next_due)
+            .min()
+    }
+
+    /// Returns the id and query of every subscription due at `now`, in id order, and schedules
+    /// their next evaluation. A subscription that fell behind skips the intervals it missed
+    /// rather than catching up, so slow evaluations don't pile up output.
+    pub fn take_due(&mut self, now: Instant) -> Vec<(u64, String)> {
+        let mut due = Vec::new();
+        for (&id, subscription) in &mut self.subscriptions {
+            if subscription.next_due > now {
+                continue;
+            }
+            due.push((id, subscription.query.clone()));
+            subscription.next_due += subscription.interval;
+            if subscription.next_due <= now {
+                subscription.next_due = now + subscription.interval;
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscriptions_come_due_every_interval_until_cancelled() {
+        let start = Instant::now();
+        let at = |seconds: u64| start + Duration::from_secs(seconds);
+        let mut subscriptions = Subscriptions::default();
+        let id = subscriptions.add("C NOW-60 NOW", Duration::from_secs(10), start);
+        let other = subscriptions.add("V NOW-60 NOW", Duration::from_secs(25), start);
+        assert_eq!((id, other), (1, 2));
+        assert_eq!(subscriptions.next_due(), Some(at(10)));
+
+        let due = |subscriptions: &mut Subscriptions, seconds| {
+            subscriptions
+                .take_due(at(seconds))
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect::<Vec<u64>>()
+        };
+        assert!(due(&mut subscriptions, 9).is_empty());
+        assert_eq!(due(&mut subscriptions, 10), [1]);
+        assert!(due(&mut subscriptions, 15).is_empty());
+        assert_eq!(due(&mut subscriptions, 20), [1]);
+        assert_eq!(due(&mut subscriptions, 25), [2]);
+        // Fallen behind, it is answered once and rescheduled from then
+        assert_eq!(due(&mut subscriptions, 47), [1]);
+        assert_eq!(subscriptions.next_due(), Some(at(50)));
+        assert_eq!(due(&mut subscriptions, 50), [2]);
+        assert_eq!(due(&mut subscriptions, 57), [1]);
+
+        subscriptions.remove(id).unwrap();
+        assert_eq!(due(&mut subscriptions, 100), [2]);
+        assert!(subscriptions.remove(id).is_err());
+        subscriptions.remove(other).unwrap();
+        assert_eq!(subscriptions.next_due(), None);
+        assert!(due(&mut subscriptions, 1000).is_empty());
+    }
+}
@@ -0,0 +1,197 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::stats::{self, CacheStats};
+use crate::Processor;
+
+/// Version of the summary layout, bumped when a field changes meaning or goes away
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A query that failed, by the line of the input it was on
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueryFailure {
+    /// Line number in the input, counting from 1
+    pub line: usize,
+    pub query: String,
+    pub error: String,
+}
+
+/// Query latencies in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Latencies {
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+}
+
+impl Latencies {
+    fn of(percentile: impl Fn(usize) -> Duration) -> Self {
+        let [p50_ms, p90_ms, p99_ms] = [50, 90, 99].map(|p| percentile(p).as_secs_f64() * 1000.0);
+        Latencies {
+            p50_ms,
+            p90_ms,
+            p99_ms,
+        }
+    }
+}
+
+/// Counters of the queries of one type
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KindSummary {
+    pub queries: usize,
+    pub failed: usize,
+    pub cache_hits: usize,
+    pub misses: usize,
+    pub api_calls: usize,
+    pub latency: Latencies,
+}
+
+/// Cache contents at the end of the run and how often it was hit
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheSummary {
+    #[serde(flatten)]
+    pub stats: CacheStats,
+    pub hits: usize,
+    /// Percentage of the hours served from the cache or fetched that were served from the cache
+    pub hit_rate: f64,
+}
+
+/// End-of-run summary written by `--summary-json`, one JSON document per run
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    pub schema_version: u32,
+    /// Processed queries, failed ones included
+    pub queries: usize,
+    pub failed_queries: usize,
+    /// Counters per query type code, of the queries that parsed
+    pub by_kind: BTreeMap<String, KindSummary>,
+    /// Failed queries in the order they failed; retried queries only once they failed for good
+    pub failures: Vec<QueryFailure>,
+    pub cache: CacheSummary,
+    /// Hours fetched from the upstream and cached
+    pub api_calls: usize,
+    /// Requests made to the upstream, verification fetches included
+    pub upstream_requests: usize,
+    /// Requests sent to the archive upstream of `--archive-source`, its pages and retries
+    /// included
+    pub archive_requests: usize,
+    /// Refetches of cached hours that returned other fills than the hours were cached with
+    pub changed_hours: usize,
+    pub latency: Latencies,
+    /// Wall-clock time of the run
+    pub duration_ms: f64,
+    /// Exit code of the process: 0 when the run succeeded, 1 when it stopped on an error
+    pub exit_code: i32,
+}
+
+impl Summary {
+    pub fn new(processor: &Processor, duration: Duration, exit_code: i32) -> Self {
+        let metrics = processor.metrics();
+        Summary {
+            schema_version: SCHEMA_VERSION,
+            queries: metrics.queries,
+            failed_queries: metrics.failed,
+            by_kind: metrics
+                .by_kind
+                .iter()
+                .map(|(kind, metrics)| {
+                    let summary = KindSummary {
+                        queries: metrics.queries,
+                        failed: metrics.failed,
+                        cache_hits: metrics.cache_hits,
+                        misses: metrics.misses,
+                        api_calls: metrics.api_calls,
+                        latency: Latencies::of(|p| metrics.latency_percentile(p)),
+                    };
+                    (kind.code().to_string(), summary)
+                })
+                .collect(),
+            failures: processor.failures().to_vec(),
+            cache: CacheSummary {
+                stats: processor.cache_stats(),
+                hits: metrics.cache_hits,
+                hit_rate: stats::hit_rate(metrics.cache_hits, metrics.api_calls),
+            },
+            api_calls: metrics.api_calls,
+            upstream_requests: metrics.upstream_requests,
+            archive_requests: metrics.endpoint_requests("archive"),
+            changed_hours: metrics.changed_hours,
+            latency: Latencies::of(|p| metrics.latency_percentile(p)),
+            duration_ms: duration.as_secs_f64() * 1000.0,
+            exit_code,
+        }
+    }
+
+    /// Writes the summary as one line of JSON to the file, or to stderr when the path is "-"
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let mut writer: Box<dyn Write> = if path == Path::new("-") {
+            Box::new(io::stderr().lock())
+        } else {
+            Box::new(
+                File::create(path)
+                    .with_context(|| format!("Failed to create summary file {}", path.display()))?,
+            )
+        };
+        serde_json::to_writer(&mut writer, self)?;
+        writeln!(writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::testing::{fill, MemorySource, TestClock, HOUR};
+
+    #[test]
+    fn summaries_round_trip_through_their_json() {
+        let source = MemorySource::new(vec![
+            fill(HOUR + 60, 1, 1, "10", "1"),
+            fill(HOUR + 120, 2, 2, "11", "2"),
+        ]);
+        let clock = Box::new(TestClock::at(HOUR + 86400));
+        let mut processor = Processor::with_source(Config::default(), Box::new(source), clock);
+        for query in [
+            format!("C {} {}", HOUR, HOUR + 3599),
+            format!("V {} {}", HOUR, HOUR + 3599),
+        ] {
+            processor.process_query(query).unwrap();
+        }
+        let query = format!("C {} {}", HOUR + 3599, HOUR);
+        let error = processor.process_query(query.clone()).unwrap_err();
+        processor.record_failure(3, &query, format!("{:#}", error));
+
+        let summary = Summary::new(&processor, Duration::from_millis(1500), 1);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("summary.json");
+        summary.write(&path).unwrap();
+        let json = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(json.lines().count(), 1);
+        assert_eq!(serde_json::from_str::<Summary>(&json).unwrap(), summary);
+
+        // The field names are the schema scrapers read
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["schema_version"], SCHEMA_VERSION);
+        assert_eq!(
+            (value["queries"].clone(), value["failed_queries"].clone()),
+            (3.into(), 1.into())
+        );
+        // The reversed window fails to parse, so it has no type
+        assert_eq!(value["by_kind"]["C"]["queries"], 1);
+        assert_eq!(value["by_kind"]["V"]["cache_hits"], 1);
+        assert!(value["by_kind"]["C"]["latency"]["p99_ms"].is_f64());
+        assert_eq!(value["failures"][0]["line"], 3);
+        assert_eq!(value["cache"]["hours"], 1);
+        assert_eq!(value["cache"]["hit_rate"], 50.0);
+        assert_eq!(value["api_calls"], 1);
+        assert_eq!(value["duration_ms"], 1500.0);
+        assert_eq!(value["exit_code"], 1);
+    }
+}
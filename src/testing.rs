@@ -0,0 +1,221 @@
+//! Sources, clocks and fills for the unit tests
+
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::clock::Clock;
+use crate::memory::{MemoryProbe, SystemMemory};
+use crate::server::{ApiResponse, Fill};
+use crate::source::{FillSource, UpstreamUnavailable};
+
+/// First hour of the test data, 2023-11-24 09:00 UTC
+pub const HOUR: i64 = 1700816400;
+
+/// Returns a fill at the time in Unix seconds, with the given sequence number, direction,
+/// price and quantity (as decimal strings)
+pub fn fill(time: i64, sequence_number: u64, direction: i32, price: &str, quantity: &str) -> Fill {
+    Fill {
+        time: DateTime::from_timestamp(time, 0).unwrap(),
+        direction,
+        price: price.parse::<Decimal>().unwrap(),
+        quantity: quantity.parse::<Decimal>().unwrap(),
+        sequence_number,
+        liquidity: None,
+        trade_id: None,
+        fee: None,
+        fee_currency: None,
+    }
+}
+
+/// Serves the fills it holds, which the test may change between requests, and counts
+/// the requests it served
+#[derive(Clone, Default)]
+pub struct MemorySource {
+    pub fills: Arc<Mutex<Vec<Fill>>>,
+    pub requests: Arc<AtomicUsize>,
+}
+
+impl MemorySource {
+    pub fn new(fills: Vec<Fill>) -> Self {
+        MemorySource {
+            fills: Arc::new(Mutex::new(fills)),
+            requests: Arc::default(),
+        }
+    }
+
+    /// Returns the number of requests served so far
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+}
+
+impl FillSource for MemorySource {
+    fn get_fills(
+        &mut self,
+        _symbol: &str,
+        start_timestamp_in_seconds: i64,
+        end_timestamp_in_seconds: i64,
+    ) -> anyhow::Result<ApiResponse> {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        let fills = self
+            .fills
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|fill| {
+                let time = fill.time.timestamp_millis();
+                time > start_timestamp_in_seconds * 1000 && time <= end_timestamp_in_seconds * 1000
+            })
+            .copied()
+            .collect();
+        Ok(ApiResponse {
+            fills,
+            malformed: 0,
+            unknown_fields: Vec::new(),
+        })
+    }
+}
+
+/// Fails its first requests with an unavailable upstream, then serves its fills
+pub struct FlakySource {
+    pub failures: usize,
+    pub source: MemorySource,
+}
+
+impl FillSource for FlakySource {
+    fn get_fills(&mut self, symbol: &str, start: i64, end: i64) -> anyhow::Result<ApiResponse> {
+        if self.failures > 0 {
+            self.failures -= 1;
+            return Err(UpstreamUnavailable("503".to_string()).into());
+        }
+        self.source.get_fills(symbol, start, end)
+    }
+}
+
+/// Serves the fills of its memory source after a delay, as a slow upstream would
+pub struct SlowSource {
+    pub latency: Duration,
+    pub source: MemorySource,
+}
+
+impl FillSource for SlowSource {
+    fn get_fills(&mut self, symbol: &str, start: i64, end: i64) -> anyhow::Result<ApiResponse> {
+        thread::sleep(self.latency);
+        self.source.get_fills(symbol, start, end)
+    }
+}
+
+/// Input of lines, each read only after its delay, as typed by someone at a terminal
+pub struct PacedInput(VecDeque<(Duration, String)>);
+
+impl PacedInput {
+    /// Returns an input of the lines, each given with the delay before it is read
+    pub fn new(lines: &[(u64, &str)]) -> Self {
+        PacedInput(
+            lines
+                .iter()
+                .map(|&(delay_ms, line)| (Duration::from_millis(delay_ms), format!("{}\n", line)))
+                .collect(),
+        )
+    }
+}
+
+impl Read for PacedInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some((delay, line)) = self.0.front_mut() else {
+            return Ok(0);
+        };
+        thread::sleep(std::mem::take(delay));
+        let read = line.len().min(buf.len());
+        buf[..read].copy_from_slice(&line.as_bytes()[..read]);
+        line.drain(..read);
+        if line.is_empty() {
+            self.0.pop_front();
+        }
+        Ok(read)
+    }
+}
+
+/// A clock the test sets, shared with the processor reading it
+#[derive(Clone)]
+pub struct TestClock(pub Arc<Mutex<DateTime<Utc>>>);
+
+impl TestClock {
+    /// Returns a clock at the time in Unix seconds
+    pub fn at(time: i64) -> Self {
+        TestClock(Arc::new(Mutex::new(
+            DateTime::from_timestamp(time, 0).unwrap(),
+        )))
+    }
+
+    /// Moves the clock to the time in Unix seconds
+    pub fn set(&self, time: i64) {
+        *self.0.lock().unwrap() = DateTime::from_timestamp(time, 0).unwrap();
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Log output kept for the test to read
+#[derive(Clone, Default)]
+pub struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs `f` with what it logs on this thread captured, and returns its result and the
+/// log lines
+pub fn capture_logs<R>(f: impl FnOnce() -> R) -> (R, String) {
+    let buffer = LogBuffer::default();
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let result = tracing::subscriber::with_default(subscriber, f);
+    let logs = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    (result, logs)
+}
+
+/// Reports the memory it holds, which the test may change between queries
+#[derive(Clone)]
+pub struct StubMemory(pub Arc<Mutex<Option<SystemMemory>>>);
+
+impl StubMemory {
+    pub fn new(total: usize, available: usize) -> Self {
+        StubMemory(Arc::new(Mutex::new(Some(SystemMemory {
+            total,
+            available,
+        }))))
+    }
+
+    pub fn set_available(&self, available: usize) {
+        if let Some(memory) = self.0.lock().unwrap().as_mut() {
+            memory.available = available;
+        }
+    }
+}
+
+impl MemoryProbe for StubMemory {
+    fn probe(&self) -> Option<SystemMemory> {
+        *self.0.lock().unwrap()
+    }
+}
@@ -0,0 +1,156 @@
+use anyhow::{anyhow, Context};
+use clap::Args;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{error, info};
+
+use crate::clock::SystemClock;
+use crate::config::{Config, ConfigArgs};
+use crate::{build_source, check_output_flags, logging, run_queries, Processor};
+
+/// Subdirectory of the spool directory that processed query files are moved to
+const DONE_DIR: &str = "done";
+/// Subdirectory of the spool directory that query files which failed are moved to
+const FAILED_DIR: &str = "failed";
+
+/// Flags of the `watch` command
+#[derive(Debug, Args)]
+pub struct WatchArgs {
+    /// Spool directory to watch for query files
+    dir: PathBuf,
+    /// Time between two scans of the directory for new query files
+    #[arg(long, value_name = "MS", default_value_t = 500, value_parser = clap::value_parser!(u64).range(1..))]
+    poll_interval_ms: u64,
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+/// Entry point of the `watch` command: polls the spool directory for query files and
+/// answers each through the same pipeline as `query`, with one processor, so the cache
+/// stays warm from file to file. Only complete files are picked up: writers create a file
+/// under another name and rename it to `<NAME>.txt` once written (see `query_files`).
+/// The answers of `<NAME>.txt` are written to `<NAME>.out` next to it, atomically, and the
+/// file is then moved to `done/`, or to `failed/` when a query stopped it. Runs until killed.
+pub fn main(args: WatchArgs) -> anyhow::Result<()> {
+    let config = Config::from(args.config);
+    logging::init(config.log_format);
+    check_output_flags(&config)?;
+    for subdir in [DONE_DIR, FAILED_DIR] {
+        let path = args.dir.join(subdir);
+        fs::create_dir_all(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+    }
+    let source = build_source(&config);
+    let mut processor = Processor::with_source(config, source, Box::new(SystemClock));
+
+    info!("Watching {} for query files", args.dir.display());
+    let interval = Duration::from_millis(args.poll_interval_ms);
+    loop {
+        for path in query_files(&args.dir)? {
+            process_file(&mut processor, &args.dir, &path)?;
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// Returns the query files waiting in the directory in arrival order: by the time they
+/// were last written, then by name. Query files are the `*.txt` files not starting with
+/// '.', so files still being written under another name, or hidden, are left alone.
+fn query_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to list {}", dir.display()))? {
+        let entry = entry?;
+        let path = entry.path();
+        let is_query_file = path.extension().is_some_and(|extension| extension == "txt")
+            && !entry.file_name().to_string_lossy().starts_with('.');
+        if !is_query_file || !entry.file_type()?.is_file() {
+            continue;
+        }
+        let modified = entry
+            .metadata()?
+            .modified()
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        files.push((modified, path));
+    }
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Answers the queries of one file into its `.out` file, moves it to `done/` or `failed/`,
+/// and logs a summary of it. Fails only when the output can't be written or the file
+/// can't be moved, as the directory couldn't be watched on.
+fn process_file(processor: &mut Processor, dir: &Path, path: &Path) -> anyhow::Result<()> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Not a file: {}", path.display()))?;
+    let start = Instant::now();
+    let metrics = processor.metrics();
+    let before = (
+        metrics.queries,
+        metrics.failed,
+        metrics.cache_hits,
+        metrics.api_calls,
+    );
+
+    let mut output = Vec::new();
+    let result = File::open(path)
+        .with_context(|| format!("Failed to open query file {}", path.display()))
+        .and_then(|file| run_queries(processor, BufReader::new(file), &mut output));
+    // The run never ends, so the hours fetched are saved after every file
+    processor.snapshot();
+    let out_path = path.with_extension("out");
+    write_atomically(&out_path, &output)?;
+    let subdir = if result.is_ok() { DONE_DIR } else { FAILED_DIR };
+    let moved = dir.join(subdir).join(name);
+    fs::rename(path, &moved)
+        .with_context(|| format!("Failed to move {} to {}", path.display(), moved.display()))?;
+
+    let metrics = processor.metrics();
+    let queries = metrics.queries - before.0;
+    let failed = metrics.failed - before.1;
+    let cache_hits = metrics.cache_hits - before.2;
+    let api_calls = metrics.api_calls - before.3;
+    let duration_ms = start.elapsed().as_millis() as u64;
+    match result {
+        Ok(()) => info!(
+            queries,
+            failed,
+            cache_hits,
+            api_calls,
+            duration_ms,
+            "Processed {}: {} queries ({} failed), {} hours from the cache, {} API calls in {}ms, answers in {}",
+            name.to_string_lossy(),
+            queries,
+            failed,
+            cache_hits,
+            api_calls,
+            duration_ms,
+            out_path.display()
+        ),
+        Err(e) => error!(
+            queries,
+            failed,
+            cache_hits,
+            api_calls,
+            duration_ms,
+            "Failed {} after {} queries, moved it to {}: {:#}",
+            name.to_string_lossy(),
+            queries,
+            moved.display(),
+            e
+        ),
+    }
+    Ok(())
+}
+
+/// Writes the bytes to the file, replacing it atomically, so readers never see part of it
+fn write_atomically(path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
+    let tmp_path = path.with_extension("out.tmp");
+    fs::write(&tmp_path, bytes)
+        .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, path).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use crate::rng::SplitMix64;
+
+/// Query types a workload draws from, each answered from the fills of the hours it touches
+const KINDS: [&str; 8] = ["C", "B", "S", "V", "WB", "WS", "SD", "E"];
+
+/// Shape of a synthetic query workload, see `generate`
+#[derive(Debug, Clone)]
+pub struct WorkloadConfig {
+    /// Seed of the generator; the same seed and parameters always produce the same queries
+    pub seed: u64,
+    /// Number of queries
+    pub queries: usize,
+    /// Hours the queries are spread over
+    pub hours: Vec<i64>,
+    /// Probability that a query returns to an hour an earlier query touched instead of
+    /// moving on to one no query touched yet
+    pub hit_ratio: f64,
+}
+
+/// Returns the queries of the workload, in order, over the default symbol. Each query is of
+/// a random type over a random window within one hour, so it touches that hour only.
+/// The first query, and with probability `1 - hit_ratio` each later one, takes the next hour
+/// no query touched yet, in a shuffled order; the others take one of the hours already
+/// touched, uniformly. Once every hour was touched, every query returns to one.
+pub fn generate(config: &WorkloadConfig) -> Vec<String> {
+    let mut rng = SplitMix64(config.seed);
+    let mut fresh = config.hours.clone();
+    for i in (1..fresh.len()).rev() {
+        fresh.swap(i, rng.below(i as u64 + 1) as usize);
+    }
+    let mut touched = Vec::with_capacity(fresh.len());
+    let mut queries = Vec::with_capacity(config.queries);
+    for _ in 0..config.queries {
+        let revisit = !touched.is_empty() && (fresh.is_empty() || rng.unit() < config.hit_ratio);
+        let hour = if revisit {
+            touched[rng.below(touched.len() as u64) as usize]
+        } else if let Some(hour) = fresh.pop() {
+            touched.push(hour);
+            hour
+        } else {
+            break;
+        };
+
+        // Within (hour, hour + 3599], as an end at the next hour would touch it too
+        let start = rng.below(3599) as i64;
+        let end = start + 1 + rng.below(3599 - start as u64) as i64;
+        let kind = KINDS[rng.below(KINDS.len() as u64) as usize];
+        queries.push(format!("{} {} {}", kind, hour + start, hour + end));
+    }
+    queries
+}
@@ -0,0 +1,47 @@
+//! Runs the binary to backfill hour files in the binary format from recorded CSV ones,
+//! and to answer queries from them
+
+use std::fs::{self, File};
+use std::process::{Command, Stdio};
+
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/multi-hour-range");
+
+fn interview() -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_interview"));
+    command.stderr(Stdio::null());
+    command
+}
+
+#[test]
+fn binary_backfill_answers_like_the_files_it_came_from() {
+    let out = tempfile::tempdir().unwrap();
+    let status = interview()
+        .args(["backfill", "--from", "1700820000", "--to", "1700830000"])
+        .arg("--out")
+        .arg(out.path())
+        .args(["--hour-format", "binary", "--source"])
+        .arg(format!("disk:{}/hours", FIXTURE))
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let mut files = fs::read_dir(out.path().join("DEFAULT"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<String>>();
+    files.sort();
+    assert_eq!(
+        files,
+        ["1700820000.bin", "1700823600.bin", "1700827200.bin"]
+    );
+
+    let output = interview()
+        .arg("--source")
+        .arg(format!("disk:{}", out.path().display()))
+        .stdin(File::open(format!("{}/queries.txt", FIXTURE)).unwrap())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let expected = fs::read_to_string(format!("{}/expected.txt", FIXTURE)).unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
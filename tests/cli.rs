@@ -0,0 +1,341 @@
+//! Runs the binary for its help, and for the snapshot commands, query workers and gzipped
+//! and CRLF query inputs, and replays every fixture
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{self, File};
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/multi-hour-range");
+
+fn interview() -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_interview"));
+    command.stderr(Stdio::null());
+    command
+}
+
+fn stdout(output: Output) -> String {
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn expected() -> String {
+    fs::read_to_string(format!("{}/expected.txt", FIXTURE)).unwrap()
+}
+
+#[test]
+fn help_lists_the_commands_and_the_environment_fallbacks() {
+    let help = stdout(interview().arg("--help").output().unwrap());
+    for command in [
+        "query", "backfill", "bench", "fixture", "watch", "serve", "warm", "export", "import",
+        "stats",
+    ]
+    .into_iter()
+    .filter(|command| cfg!(feature = "net") || *command != "serve")
+    {
+        assert!(
+            help.lines()
+                .any(|line| line.trim_start().starts_with(command)),
+            "{} is not listed:\n{}",
+            command,
+            help
+        );
+    }
+    for command in ["query", "warm"] {
+        let help = stdout(interview().args([command, "--help"]).output().unwrap());
+        assert!(help.contains("--default-symbol <SYMBOL>"), "{}", help);
+        assert!(help.contains("[env: DEFAULT_SYMBOL="), "{}", help);
+    }
+    let help = stdout(interview().args(["query", "--help"]).output().unwrap());
+    for flag in ["--output <FORMAT>", "--workers <N>", "--cache-hours <N>"] {
+        assert!(help.contains(flag), "{}", help);
+    }
+}
+
+#[test]
+fn workers_answer_in_input_order() {
+    let output = interview()
+        .args(["query", "--workers", "3", "--source"])
+        .arg(format!("disk:{}/hours", FIXTURE))
+        .stdin(File::open(format!("{}/queries.txt", FIXTURE)).unwrap())
+        .output()
+        .unwrap();
+    assert_eq!(stdout(output), expected());
+
+    // Commands acting on one cache need a single worker
+    let dir = tempfile::tempdir().unwrap();
+    let queries = dir.path().join("queries.txt");
+    fs::write(&queries, "DQ 1700820000 1700830000\n").unwrap();
+    let output = interview()
+        .args(["--workers", "2", "--source"])
+        .arg(format!("disk:{}/hours", FIXTURE))
+        .arg("--input")
+        .arg(&queries)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn snapshots_import_describe_and_export_hour_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let snapshot = dir.path().join("snapshot.bin");
+    let import = || {
+        stdout(
+            interview()
+                .arg("import")
+                .arg(format!("{}/hours", FIXTURE))
+                .arg("--out")
+                .arg(&snapshot)
+                .args(["--snapshot-format", "binary"])
+                .output()
+                .unwrap(),
+        )
+    };
+    assert!(import().starts_with("3 hours of 1 symbols: 3 imported"));
+    // Hours the snapshot holds are left as they are
+    assert!(import().starts_with("3 hours of 1 symbols: 0 imported"));
+
+    let stats = stdout(interview().arg("stats").arg(&snapshot).output().unwrap());
+    assert!(stats.contains("binary layout"), "{}", stats);
+    assert!(
+        stats.contains("3 records of 3 hours (0 superseded)"),
+        "{}",
+        stats
+    );
+    let row = stats
+        .lines()
+        .find(|line| line.trim_start().starts_with("default"))
+        .unwrap();
+    assert_eq!(
+        row.split_whitespace().collect::<Vec<&str>>(),
+        [
+            "default",
+            "DEFAULT",
+            "3",
+            "1700820000",
+            "1700827200",
+            "6116",
+            "5"
+        ]
+    );
+
+    let out = dir.path().join("hours");
+    stdout(
+        interview()
+            .arg("export")
+            .arg(&snapshot)
+            .arg("--out")
+            .arg(&out)
+            .output()
+            .unwrap(),
+    );
+    let output = interview()
+        .arg("--source")
+        .arg(format!("disk:{}", out.display()))
+        .stdin(File::open(format!("{}/queries.txt", FIXTURE)).unwrap())
+        .output()
+        .unwrap();
+    assert_eq!(stdout(output), expected());
+}
+
+#[test]
+fn warm_saves_the_hours_of_the_queries() {
+    let dir = tempfile::tempdir().unwrap();
+    let snapshot = dir.path().join("snapshot.json");
+    let warmed = stdout(
+        interview()
+            .arg("warm")
+            .arg("--source")
+            .arg(format!("disk:{}/hours", FIXTURE))
+            .arg("--snapshot-file")
+            .arg(&snapshot)
+            .stdin(File::open(format!("{}/queries.txt", FIXTURE)).unwrap())
+            .output()
+            .unwrap(),
+    );
+    assert!(warmed.contains("3 hours fetched"), "{}", warmed);
+
+    // The next run answers from the snapshot alone
+    let empty = dir.path().join("empty");
+    fs::create_dir(&empty).unwrap();
+    let output = interview()
+        .arg("--source")
+        .arg(format!("disk:{}", empty.display()))
+        .arg("--snapshot-file")
+        .arg(&snapshot)
+        .stdin(File::open(format!("{}/queries.txt", FIXTURE)).unwrap())
+        .output()
+        .unwrap();
+    assert_eq!(stdout(output), expected());
+
+    let without_snapshot = interview().arg("warm").stdin(Stdio::null()).output();
+    assert!(!without_snapshot.unwrap().status.success());
+}
+
+fn gzipped(text: &str) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(text.as_bytes()).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[test]
+fn gzipped_inputs_answer_like_plain_ones() {
+    let dir = tempfile::tempdir().unwrap();
+    let queries = fs::read_to_string(format!("{}/queries.txt", FIXTURE)).unwrap();
+    let input = dir.path().join("queries.txt.gz");
+    fs::write(&input, gzipped(&queries)).unwrap();
+    let source = format!("disk:{}/hours", FIXTURE);
+
+    // By its extension
+    let output = interview()
+        .args(["--source", &source, "--input"])
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(stdout(output), expected());
+
+    // From stdin, as told
+    let output = interview()
+        .args(["--source", &source, "--input-compression", "gzip"])
+        .stdin(File::open(&input).unwrap())
+        .output()
+        .unwrap();
+    assert_eq!(stdout(output), expected());
+
+    // Errors name the line of the decompressed input, comments and blank lines counted
+    fs::write(&input, gzipped("# A comment\n\nX 1700820000 1700830000\n")).unwrap();
+    let output = interview()
+        .args(["--source", &source, "--input"])
+        .arg(&input)
+        .stderr(Stdio::piped())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Query on line 3 failed"), "{}", stderr);
+
+    // A plain file read as gzip fails rather than answering garbage
+    let plain = dir.path().join("queries.txt");
+    fs::write(&plain, &queries).unwrap();
+    let output = interview()
+        .args([
+            "--source",
+            &source,
+            "--input-compression",
+            "gzip",
+            "--input",
+        ])
+        .arg(&plain)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn crlf_inputs_answer_like_plain_ones() {
+    let queries = fs::read_to_string(format!("{}/queries.txt", FIXTURE)).unwrap();
+    // Windows line endings, with a blank line of only a carriage return and a comment
+    let crlf = format!(
+        "# Saved on Windows\r\n\r\n{}",
+        queries.replace('\n', "\r\n")
+    );
+    let mut child = interview()
+        .arg("--source")
+        .arg(format!("disk:{}/hours", FIXTURE))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(crlf.as_bytes())
+        .unwrap();
+    assert_eq!(stdout(child.wait_with_output().unwrap()), expected());
+}
+
+#[test]
+fn every_fixture_replays_its_expected_output() {
+    let mut fixtures = fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect::<Vec<_>>();
+    fixtures.sort();
+    assert!(fixtures.len() > 20, "{:?}", fixtures);
+    let output = Command::new(env!("CARGO_BIN_EXE_interview"))
+        .args(["fixture", "run"])
+        .args(&fixtures)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    for fixture in &fixtures {
+        let line = format!("{}: ok", fixture.display());
+        assert!(
+            stdout.lines().any(|l| l == line),
+            "{} missing:\n{}",
+            line,
+            stdout
+        );
+    }
+    assert_eq!(stdout.lines().count(), fixtures.len(), "{}", stdout);
+}
+
+#[test]
+fn fixtures_replay_the_hours_their_args_point_to() {
+    let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+    let output = interview()
+        .args(["fixture", "run"])
+        .arg(format!("{}/lookahead", fixtures))
+        .arg(format!("{}/format-csv", fixtures))
+        .output()
+        .unwrap();
+    assert_eq!(
+        stdout(output),
+        format!("{0}/lookahead: ok\n{0}/format-csv: ok\n", fixtures)
+    );
+
+    // A fixture without hour files of its own can't be replayed without them
+    let dir = tempfile::tempdir().unwrap();
+    for file in ["queries.txt", "expected.txt"] {
+        fs::copy(
+            format!("{}/lookahead/{}", fixtures, file),
+            dir.path().join(file),
+        )
+        .unwrap();
+    }
+    fs::write(
+        dir.path().join("args.txt"),
+        "--lookahead 3 --cache-hours 2\n",
+    )
+    .unwrap();
+    let output = interview()
+        .args(["fixture", "run"])
+        .arg(dir.path())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    fs::write(
+        dir.path().join("args.txt"),
+        format!(
+            "--lookahead 3 --cache-hours 2 --hours {}/multi-hour-range/hours\n",
+            fixtures
+        ),
+    )
+    .unwrap();
+    let output = interview()
+        .args(["fixture", "run"])
+        .arg(dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+}
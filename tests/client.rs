@@ -0,0 +1,150 @@
+//! Answers the fixture's queries with `ProxyClient` over every transport, against a server
+//! started in the process
+#![cfg(feature = "net")]
+
+use clap::Parser;
+use rust_decimal::Decimal;
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use interview::cli::{Cli, Command};
+use interview::client::{Backoff, ClientError, ProxyClient};
+use interview::pool::Priority;
+use interview::query::QueryKind;
+use interview::serve::binary::{self, Value};
+use interview::serve::{self, RunningServer};
+use interview::server::Side;
+
+const HOURS: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/fixtures/multi-hour-range/hours"
+);
+
+/// Starts a server on the fixture's hour files with the listener flags
+fn start(listeners: &[&str]) -> RunningServer {
+    let args = ["interview", "serve", "--workers", "2", "--source"]
+        .into_iter()
+        .map(str::to_string)
+        .chain([format!("disk:{}", HOURS)])
+        .chain(listeners.iter().map(|flag| flag.to_string()));
+    let Some(Command::Serve(args)) = Cli::try_parse_from(args).unwrap().command else {
+        unreachable!()
+    };
+    serve::start(args).unwrap()
+}
+
+/// Returns an address no one listens on, for now
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+fn answers_the_fixture(client: &mut ProxyClient) {
+    assert_eq!(client.count(1700820000, 1700830000).unwrap(), 4509);
+    assert_eq!(
+        client.volume(1700820000, 1700830000).unwrap(),
+        "1813528.229735".parse::<Decimal>().unwrap()
+    );
+    assert_eq!(
+        client.vwap(Side::Buy, 1700820000, 1700830000).unwrap(),
+        Some("58.17551691".parse().unwrap())
+    );
+    assert_eq!(
+        client.vwap(Side::Sell, 1700820000, 1700830000).unwrap(),
+        Some("58.1647306".parse().unwrap())
+    );
+    assert_eq!(
+        client.vwap(Side::Buy, 1700820000, 1700820001).unwrap(),
+        None
+    );
+    let response = client
+        .query(QueryKind::RawCount, 1700820000, 1700830000)
+        .unwrap();
+    assert_eq!(response.value, Value::Integer(5908));
+    assert!(response.is_final);
+    // The fixture has no hour files after its third hour
+    match client.count(1700820000, 1700840000) {
+        Err(ClientError::Query(message)) => assert!(message.contains("1700830800"), "{}", message),
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn clients_answer_queries_over_every_transport() {
+    let dir = tempfile::tempdir().unwrap();
+    let unix = dir.path().join("interview.sock");
+    let server = start(&[
+        "--http",
+        "127.0.0.1:0",
+        "--tcp",
+        "127.0.0.1:0",
+        "--unix",
+        unix.to_str().unwrap(),
+    ]);
+
+    answers_the_fixture(&mut ProxyClient::connect_tcp(server.tcp.unwrap()).unwrap());
+    answers_the_fixture(&mut ProxyClient::connect_unix(&unix).unwrap());
+    let mut http = ProxyClient::http(server.http.unwrap()).with_priority(Priority::Batch);
+    answers_the_fixture(&mut http);
+
+    let fills = http.fills_in_range(1700820000, 1700830000).unwrap();
+    // The fill count of the window's E answer, as with GET /fills
+    assert_eq!(fills.len(), 5908);
+    assert!(fills.windows(2).all(|pair| pair[0].time <= pair[1].time));
+    let mut tcp = ProxyClient::connect_tcp(server.tcp.unwrap()).unwrap();
+    assert!(matches!(
+        tcp.fills_in_range(1700820000, 1700830000),
+        Err(ClientError::Unsupported(_))
+    ));
+}
+
+#[test]
+fn socket_clients_reconnect_with_backoff() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing.sock");
+    assert!(matches!(
+        ProxyClient::connect_unix(Path::new(&missing)),
+        Err(ClientError::Connection(_))
+    ));
+
+    // A server starting after the client, which waits for it
+    let addr = free_addr();
+    let starting = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        start(&["--tcp", &addr.to_string()])
+    });
+    let mut client = ProxyClient::connect_tcp(addr).unwrap();
+    let server = starting.join().unwrap();
+    assert_eq!(client.count(1700820000, 1700830000).unwrap(), 4509);
+
+    // A connection dropped before its answer: the first is closed once the request is
+    // read, and the next relayed to the server
+    let relay = TcpListener::bind("127.0.0.1:0").unwrap();
+    let relay_addr = relay.local_addr().unwrap();
+    let tcp = server.tcp.unwrap();
+    thread::spawn(move || {
+        let (mut first, _) = relay.accept().unwrap();
+        let mut request = [0; 1 + 4 + binary::REQUEST_BYTES];
+        first.read_exact(&mut request).unwrap();
+        drop(first);
+        let (mut client, _) = relay.accept().unwrap();
+        let mut server = TcpStream::connect(tcp).unwrap();
+        let (mut client_reader, mut server_writer) =
+            (client.try_clone().unwrap(), server.try_clone().unwrap());
+        thread::spawn(move || io::copy(&mut client_reader, &mut server_writer));
+        let _ = io::copy(&mut server, &mut client);
+    });
+    let mut client = ProxyClient::connect_tcp(relay_addr)
+        .unwrap()
+        .with_backoff(Backoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(10),
+            retries: 3,
+        });
+    assert_eq!(client.count(1700820000, 1700830000).unwrap(), 4509);
+}
@@ -0,0 +1,186 @@
+//! Runs the binary end to end against the mock source, which answers the same queries the
+//! same way on every run for a seed
+#![cfg(feature = "mock-source")]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Returns the answers of the binary to the queries, run with the flags
+fn answers(flags: &[&str], queries: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_interview"))
+        .args(flags)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(queries.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn mock_answers_match_the_golden_run() {
+    let queries = "C 1700816400 1700820000\nV 1700816400 1700823600\nB 1700816400 1700820000\n";
+    let golden = "1109\n1055646.7262712\n565\n";
+    assert_eq!(answers(&["--mock"], queries), golden);
+    assert_eq!(answers(&["--mock-seed", "0"], queries), golden);
+    assert_eq!(
+        answers(&["--mock-seed", "7"], "C 1700816400 1700820000\n"),
+        "1353\n"
+    );
+}
+
+/// Starts a server with the flags and returns it with the address of its only listener
+fn serve(flags: &[&str]) -> (Child, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_interview"))
+        .arg("serve")
+        .args(flags)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(child.stdout.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    let addr = line.trim().rsplit(' ').next().unwrap().to_string();
+    (child, addr)
+}
+
+/// Sends a GET request and returns the response, head and body
+fn get(addr: &str, target: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\n\r\n", target).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+/// Sends the lines on a connection and returns the answers with the time each arrived
+fn timed_answers(addr: &str, lines: &str) -> Vec<(String, Instant)> {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(lines.as_bytes()).unwrap();
+    BufReader::new(stream)
+        .lines()
+        .take(lines.lines().count())
+        .map(|line| (line.unwrap(), Instant::now()))
+        .collect()
+}
+
+#[test]
+fn interactive_queries_jump_a_slow_batch_on_the_server() {
+    let (mut child, addr) = serve(&[
+        "--tcp",
+        "127.0.0.1:0",
+        "--workers",
+        "1",
+        "--mock-latency-ms",
+        "100",
+    ]);
+
+    // Ten hours of batch queries, each fetched in 100 ms by the single worker
+    let batch = (0..10)
+        .map(|hour| {
+            let start = 1700816400 + hour * 3600;
+            format!("C {} {}\n", start, start + 3599)
+        })
+        .collect::<String>();
+    let batch = {
+        let addr = addr.clone();
+        thread::spawn(move || timed_answers(&addr, &format!("PRIORITY batch\n{}", batch)))
+    };
+    thread::sleep(Duration::from_millis(250));
+    let interactive = timed_answers(&addr, "C 1700780400 1700783999\n");
+    let batch = batch.join().unwrap();
+    let _ = child.kill();
+    let _ = child.wait();
+
+    assert_eq!(batch[0].0, "PRIORITY batch");
+    assert_eq!(batch.len(), 11);
+    // The interactive query was answered while most of the batch still waited
+    let answered_after = batch
+        .iter()
+        .filter(|(_, at)| *at > interactive[0].1)
+        .count();
+    assert!(
+        answered_after >= 5,
+        "{} batch answers after",
+        answered_after
+    );
+}
+
+#[test]
+fn the_server_caps_the_queries_it_runs_and_queues() {
+    let (mut child, addr) = serve(&[
+        "--http",
+        "127.0.0.1:0",
+        "--workers",
+        "2",
+        "--queue-depth",
+        "2",
+        "--mock-latency-ms",
+        "300",
+    ]);
+
+    // A burst of cold queries, each on an hour of its own
+    let burst = (0..10)
+        .map(|hour| {
+            let addr = addr.clone();
+            thread::spawn(move || {
+                let start = 1700816400 + hour * 3600;
+                get(&addr, &format!("/query?q=C+{}+{}", start, start + 3599))
+            })
+        })
+        .collect::<Vec<_>>();
+    let deadline = Instant::now() + Duration::from_millis(500);
+    while Instant::now() < deadline {
+        let metrics = get(&addr, "/metrics");
+        let sample = |name: &str| -> f64 {
+            metrics
+                .lines()
+                .filter(|line| line.starts_with(name))
+                .map(|line| line.rsplit(' ').next().unwrap().parse::<f64>().unwrap())
+                .sum()
+        };
+        assert!(sample("interview_in_flight ") <= 2.0, "{}", metrics);
+        assert!(sample("interview_queue_depth{") <= 2.0, "{}", metrics);
+        thread::sleep(Duration::from_millis(10));
+    }
+    let responses = burst
+        .into_iter()
+        .map(|request| request.join().unwrap())
+        .collect::<Vec<_>>();
+    let metrics = get(&addr, "/metrics");
+    let _ = child.kill();
+    let _ = child.wait();
+
+    let answered = responses
+        .iter()
+        .filter(|response| response.starts_with("HTTP/1.1 200 "))
+        .count();
+    let refused = responses
+        .iter()
+        .filter(|response| response.starts_with("HTTP/1.1 429 "))
+        .inspect(|response| assert!(response.contains("\r\nRetry-After: 1\r\n"), "{}", response))
+        .count();
+    assert_eq!(answered + refused, 10, "{:?}", responses);
+    assert!(answered >= 2 && refused >= 1, "{:?}", responses);
+    assert!(
+        metrics.contains(&format!(
+            "\ninterview_rejected_total{{priority=\"interactive\"}} {}\n",
+            refused
+        )),
+        "{}",
+        metrics
+    );
+}
@@ -0,0 +1,47 @@
+//! Runs the Python tests of the bindings in `python/` against the library built with them
+#![cfg(feature = "python")]
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn python_bindings_answer_queries() {
+    // Built again, as the test run leaves the library of another run's features in place
+    let target_dir = Path::new(env!("CARGO_BIN_EXE_interview"))
+        .parent()
+        .and_then(Path::parent)
+        .unwrap();
+    let status = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(["build", "--lib", "--features", "python", "--target-dir"])
+        .arg(target_dir)
+        .status()
+        .expect("cargo should run");
+    assert!(status.success(), "the library build failed");
+    let library = Path::new(env!("CARGO_BIN_EXE_interview")).with_file_name("libinterview.so");
+    assert!(library.exists(), "{} wasn't built", library.display());
+
+    let output = Command::new("python3")
+        .arg(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/python/tests/test_processor.py"
+        ))
+        .env("INTERVIEW_LIB", &library)
+        .output()
+        .expect("python3 should run");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        stdout
+            .lines()
+            .filter(|line| line.starts_with("ok "))
+            .count(),
+        5
+    );
+}
@@ -0,0 +1,513 @@
+//! Runs the binary's `serve` command and answers the fixture's queries over HTTP, TCP and
+//! a Unix socket, in the line protocol and the binary one
+#![cfg(feature = "net")]
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use interview::pool::Priority;
+use interview::query::QueryKind;
+use interview::serve::binary;
+use serde_json::Value;
+
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/multi-hour-range");
+
+/// A running server, killed once dropped
+struct Server {
+    child: Child,
+    http: String,
+    tcp: String,
+}
+
+impl Server {
+    fn start(unix: &Path) -> Self {
+        Server::start_with(unix, Path::new(&format!("{}/hours", FIXTURE)), &[])
+    }
+
+    /// Starts a server on the hour files of the directory, with the flags
+    fn start_with(unix: &Path, hours: &Path, flags: &[&str]) -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_interview"))
+            .args([
+                "serve",
+                "--http",
+                "127.0.0.1:0",
+                "--tcp",
+                "127.0.0.1:0",
+                "--workers",
+                "2",
+            ])
+            .arg("--unix")
+            .arg(unix)
+            .arg("--source")
+            .arg(format!("disk:{}", hours.display()))
+            .args(flags)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        let (mut http, mut tcp) = (None, None);
+        for _ in 0..3 {
+            let line = lines.next().unwrap().unwrap();
+            let mut words = line.strip_prefix("Listening on ").unwrap().split(' ');
+            let (protocol, addr) = (words.next().unwrap(), words.next().unwrap().to_string());
+            match protocol {
+                "http" => http = Some(addr),
+                "tcp" => tcp = Some(addr),
+                _ => assert_eq!(Path::new(&addr), unix),
+            }
+        }
+        Server {
+            child,
+            http: http.unwrap(),
+            tcp: tcp.unwrap(),
+        }
+    }
+
+    /// Sends the request and returns the response's head and body
+    fn http(&self, request: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(&self.http).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.to_string(), body.to_string())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn queries() -> String {
+    fs::read_to_string(format!("{}/queries.txt", FIXTURE)).unwrap()
+}
+
+fn expected() -> String {
+    fs::read_to_string(format!("{}/expected.txt", FIXTURE)).unwrap()
+}
+
+/// Sends the queries on a line protocol connection and reads an answer per query line
+fn answers(mut stream: impl Read + Write) -> String {
+    stream.write_all(queries().as_bytes()).unwrap();
+    let query_lines = expected().lines().count();
+    let mut reader = BufReader::new(stream);
+    let mut answers = String::new();
+    for _ in 0..query_lines {
+        reader.read_line(&mut answers).unwrap();
+    }
+    answers
+}
+
+#[test]
+fn sockets_answer_query_lines_in_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let unix = dir.path().join("interview.sock");
+    let server = Server::start(&unix);
+    assert_eq!(
+        answers(TcpStream::connect(&server.tcp).unwrap()),
+        expected()
+    );
+    assert_eq!(answers(UnixStream::connect(&unix).unwrap()), expected());
+
+    // A failing line is answered with its error, and the connection carries on
+    let mut stream = TcpStream::connect(&server.tcp).unwrap();
+    stream
+        .write_all(b"VERSION 2\nC 1700830000 1700820000\nq1: C 1700820000 1700830000\n")
+        .unwrap();
+    let mut lines = BufReader::new(stream).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "VERSION 2");
+    assert!(lines.next().unwrap().unwrap().starts_with("ERROR "));
+    assert_eq!(lines.next().unwrap().unwrap(), "q1: 4509");
+
+    // A connection's queries are interactive until a PRIORITY line says otherwise
+    let mut stream = TcpStream::connect(&server.tcp).unwrap();
+    stream
+        .write_all(b"PRIORITY batch\nPRIORITY urgent\nC 1700820000 1700830000\n")
+        .unwrap();
+    let mut lines = BufReader::new(stream).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "PRIORITY batch");
+    assert!(lines.next().unwrap().unwrap().starts_with("ERROR "));
+    assert_eq!(lines.next().unwrap().unwrap(), "4509");
+}
+
+#[test]
+fn http_answers_queries() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = Server::start(&dir.path().join("interview.sock"));
+
+    let (head, body) = server.http("GET /query?q=C+1700820000+1700830000 HTTP/1.1\r\n\r\n");
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+    assert!(head.contains("\r\nX-Final: true"), "{}", head);
+    assert_eq!(body, "4509\n");
+
+    let queries = queries();
+    let (head, body) = server.http(&format!(
+        "POST /query HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+        queries.len(),
+        queries
+    ));
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+    assert_eq!(body, expected());
+
+    let (head, _) = server.http("GET /query?q=C+1700830000+1700820000 HTTP/1.1\r\n\r\n");
+    assert!(head.starts_with("HTTP/1.1 400 "), "{}", head);
+    let (head, _) =
+        server.http("GET /query?q=C+1700820000+1700830000&priority=urgent HTTP/1.1\r\n\r\n");
+    assert!(head.starts_with("HTTP/1.1 400 "), "{}", head);
+    let (head, body) =
+        server.http("GET /query?q=C+1700820000+1700830000&priority=batch HTTP/1.1\r\n\r\n");
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+    assert_eq!(body, "4509\n");
+    let (_, metrics) = server.http("GET /metrics HTTP/1.1\r\n\r\n");
+    assert!(
+        metrics.contains("\ninterview_queue_started_total{priority=\"batch\"} 1\n"),
+        "{}",
+        metrics
+    );
+    assert!(
+        metrics.contains("\ninterview_queue_depth{priority=\"interactive\"} 0\n"),
+        "{}",
+        metrics
+    );
+    // Per query type, of every worker: the two GET queries and those of the body
+    let sample = |name: &str| {
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .unwrap_or_else(|| panic!("No {} in {}", name, metrics))
+            .parse::<f64>()
+            .unwrap()
+    };
+    let lines = queries
+        .lines()
+        .filter(|line| line.starts_with("C "))
+        .count();
+    assert_eq!(
+        sample("interview_queries_total{type=\"C\"} "),
+        (lines + 2) as f64
+    );
+    assert_eq!(
+        sample("interview_query_duration_seconds_count{type=\"C\"} "),
+        (lines + 2) as f64
+    );
+    assert_eq!(
+        sample("interview_query_duration_seconds_bucket{type=\"C\",le=\"+Inf\"} "),
+        (lines + 2) as f64
+    );
+    assert!(sample("interview_cache_hits_total{type=\"C\"} ") > 0.0);
+    let (head, _) = server.http("GET /nowhere HTTP/1.1\r\n\r\n");
+    assert!(head.starts_with("HTTP/1.1 404 "), "{}", head);
+}
+
+#[test]
+fn binary_connections_answer_frames_and_close_on_malformed_ones() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = Server::start(&dir.path().join("interview.sock"));
+    let mut stream = TcpStream::connect(&server.tcp).unwrap();
+    stream.write_all(&[binary::MAGIC]).unwrap();
+    for (kind, start, end) in [
+        (QueryKind::Count, 1700820000, 1700830000),
+        (QueryKind::Volume, 1700820000, 1700830000),
+        (QueryKind::Count, 1700830000, 1700820000),
+    ] {
+        let request = binary::Request {
+            kind,
+            priority: Priority::Interactive,
+            start,
+            end,
+        };
+        binary::write_frame(&mut stream, &request.encode()).unwrap();
+    }
+    binary::write_frame(&mut stream, b"not a request").unwrap();
+
+    let mut responses = Vec::new();
+    while let Some(payload) = binary::read_frame(&mut stream).unwrap() {
+        responses.push(binary::Response::decode(&payload).unwrap());
+    }
+    assert_eq!(responses.len(), 4);
+    assert_eq!(
+        responses[0],
+        binary::Response {
+            status: binary::Status::Ok,
+            is_final: true,
+            value: binary::Value::Integer(4509),
+        }
+    );
+    assert_eq!(
+        responses[1].value,
+        binary::Value::Decimal("1813528.229735".parse().unwrap())
+    );
+    assert_eq!(responses[2].status, binary::Status::Error);
+    assert_eq!(
+        responses[3].value,
+        binary::Value::Text("Request of 13 bytes, expected 19".to_string())
+    );
+}
+
+#[test]
+fn readiness_waits_for_the_upstream_probe() {
+    let dir = tempfile::tempdir().unwrap();
+    let hours = dir.path().join("hours");
+    fs::create_dir_all(hours.join("DEFAULT")).unwrap();
+    let server = Server::start_with(
+        &dir.path().join("interview.sock"),
+        &hours,
+        &["--probe-hour", "1700820000", "--probe-interval-ms", "20"],
+    );
+    let status = |path: &str| {
+        let (head, body) = server.http(&format!("GET {} HTTP/1.1\r\n\r\n", path));
+        (head[9..12].to_string(), body)
+    };
+    assert_eq!(status("/healthz").0, "200");
+    // The hour probed isn't on disk yet
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(
+        status("/readyz"),
+        ("503".to_string(), "waiting for upstream\n".to_string())
+    );
+
+    fs::copy(
+        format!("{}/hours/DEFAULT/1700820000.csv", FIXTURE),
+        hours.join("DEFAULT/1700820000.csv"),
+    )
+    .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while status("/readyz").0 != "200" {
+        assert!(Instant::now() < deadline, "{:?}", status("/readyz"));
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(status("/readyz").1, "ready\n");
+    assert_eq!(status("/healthz").0, "200");
+}
+
+#[test]
+fn full_queues_refuse_queries_on_every_protocol() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = Server::start_with(
+        &dir.path().join("interview.sock"),
+        Path::new(&format!("{}/hours", FIXTURE)),
+        &["--queue-depth", "0"],
+    );
+    let (head, _) = server.http("GET /query?q=C+1700820000+1700830000 HTTP/1.1\r\n\r\n");
+    assert!(head.starts_with("HTTP/1.1 429 "), "{}", head);
+    assert!(head.contains("\r\nRetry-After: 1"), "{}", head);
+
+    let mut stream = TcpStream::connect(&server.tcp).unwrap();
+    stream
+        .write_all(b"VERSION 2\nq1: C 1700820000 1700830000\n")
+        .unwrap();
+    let mut lines = BufReader::new(stream).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "VERSION 2");
+    assert_eq!(lines.next().unwrap().unwrap(), "q1: BUSY");
+
+    let mut stream = TcpStream::connect(&server.tcp).unwrap();
+    stream.write_all(&[binary::MAGIC]).unwrap();
+    let request = binary::Request {
+        kind: QueryKind::Count,
+        priority: Priority::Batch,
+        start: 1700820000,
+        end: 1700830000,
+    };
+    binary::write_frame(&mut stream, &request.encode()).unwrap();
+    let payload = binary::read_frame(&mut stream).unwrap().unwrap();
+    assert_eq!(
+        binary::Response::decode(&payload).unwrap().status,
+        binary::Status::Busy
+    );
+
+    let (_, metrics) = server.http("GET /metrics HTTP/1.1\r\n\r\n");
+    assert!(
+        metrics.contains("\ninterview_rejected_total{priority=\"interactive\"} 2\n"),
+        "{}",
+        metrics
+    );
+    assert!(metrics.contains("\ninterview_in_flight 0\n"), "{}", metrics);
+}
+
+#[test]
+fn fills_come_in_pages_of_the_window() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = Server::start(&dir.path().join("interview.sock"));
+    let window = "start=1700820000&end=1700830000";
+
+    let (head, body) = server.http(&format!("GET /fills?{} HTTP/1.1\r\n\r\n", window));
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+    assert!(
+        head.contains("\r\nContent-Type: application/json"),
+        "{}",
+        head
+    );
+    assert!(
+        head.contains("\r\nCache-Control: public, max-age=31536000, immutable"),
+        "{}",
+        head
+    );
+    let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let fills = page["fills"].as_array().unwrap();
+    // The fill count of the window's E answer
+    assert_eq!(fills.len(), 5908);
+    assert_eq!(page["next_cursor"], serde_json::Value::Null);
+    assert!(fills[0]["time"]
+        .as_str()
+        .unwrap()
+        .starts_with("2023-11-24T10:00:03"));
+
+    let mut cursor = 0;
+    let mut lines = Vec::new();
+    loop {
+        let (head, body) = server.http(&format!(
+            "GET /fills?{}&format=ndjson&limit=2500&cursor={} HTTP/1.1\r\n\r\n",
+            window, cursor
+        ));
+        assert!(
+            head.contains("\r\nContent-Type: application/x-ndjson"),
+            "{}",
+            head
+        );
+        lines.extend(body.lines().map(str::to_string));
+        match head
+            .lines()
+            .find_map(|line| line.strip_prefix("X-Next-Cursor: "))
+        {
+            Some(next) => cursor = next.parse().unwrap(),
+            None => break,
+        }
+    }
+    assert_eq!(lines.len(), 5908);
+    let fills = fills
+        .iter()
+        .map(|fill| fill.to_string())
+        .collect::<Vec<_>>();
+    let lines = lines
+        .iter()
+        .map(|line| {
+            serde_json::from_str::<serde_json::Value>(line)
+                .unwrap()
+                .to_string()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(lines, fills);
+
+    for target in [
+        "/fills?start=1700820000&end=1700920000",
+        "/fills?start=1700830000&end=1700820000",
+        "/fills?start=1700820000",
+        "/fills?start=1700820000&end=1700830000&format=xml",
+        "/fills?start=1700820000&end=1700830000&limit=0",
+    ] {
+        let (head, _) = server.http(&format!("GET {} HTTP/1.1\r\n\r\n", target));
+        assert!(head.starts_with("HTTP/1.1 400 "), "{}: {}", target, head);
+    }
+}
+
+/// Returns the value of the span's attribute, in its OTLP encoding
+fn attribute<'a>(span: &'a Value, key: &str) -> Option<&'a Value> {
+    span["attributes"]
+        .as_array()?
+        .iter()
+        .find(|attribute| attribute["key"] == key)
+        .map(|attribute| &attribute["value"])
+}
+
+#[test]
+fn query_spans_are_exported_in_the_trace_of_their_request() {
+    // A collector taking every export and passing on its spans
+    let collector = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", collector.local_addr().unwrap());
+    let (exported, spans) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in collector.incoming() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let mut head = Vec::new();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                head.push(line.trim_end().to_string());
+                line.clear();
+            }
+            let length = head
+                .iter()
+                .find_map(|header| header.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse::<usize>()
+                .unwrap();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            assert_eq!(head[0], "POST /v1/traces HTTP/1.1");
+            assert!(head.contains(&"Content-Type: application/json".to_string()));
+            let request = serde_json::from_slice::<Value>(&body).unwrap();
+            for span in request["resourceSpans"][0]["scopeSpans"][0]["spans"]
+                .as_array()
+                .unwrap()
+            {
+                let _ = exported.send(span.clone());
+            }
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+        }
+    });
+    let dir = tempfile::tempdir().unwrap();
+    let server = Server::start_with(
+        &dir.path().join("interview.sock"),
+        Path::new(&format!("{}/hours", FIXTURE)),
+        &["--otlp-endpoint", &endpoint],
+    );
+
+    let trace_id = "0af7651916cd43dd8448eb211c80319c";
+    let (_, body) = server.http(&format!(
+        "GET /query?q=C+1700820000+1700830000 HTTP/1.1\r\ntraceparent: 00-{}-b7ad6b7169203331-01\r\n\r\n",
+        trace_id
+    ));
+    assert_eq!(body, "4509\n");
+
+    // The request's span, its query's and the query's three hours
+    let mut trace = Vec::new();
+    while trace.len() < 5 {
+        let span = spans.recv_timeout(Duration::from_secs(20)).unwrap();
+        if span["traceId"] == trace_id {
+            trace.push(span);
+        }
+    }
+    let named = |name: &str| {
+        trace
+            .iter()
+            .filter(|span| span["name"] == name)
+            .collect::<Vec<_>>()
+    };
+    let [http] = named("http")[..] else {
+        panic!("{:?}", trace)
+    };
+    assert_eq!(http["parentSpanId"], "b7ad6b7169203331");
+    assert_eq!(http["kind"], 2);
+    assert_eq!(attribute(http, "path").unwrap()["stringValue"], "/query");
+    assert_eq!(attribute(http, "traceparent"), None);
+    let [query] = named("query")[..] else {
+        panic!("{:?}", trace)
+    };
+    assert_eq!(query["parentSpanId"], http["spanId"]);
+    assert_eq!(
+        attribute(query, "query").unwrap()["stringValue"],
+        "C 1700820000 1700830000"
+    );
+    let hours = named("hour");
+    assert_eq!(hours.len(), 3);
+    for hour in hours {
+        assert_eq!(hour["parentSpanId"], query["spanId"]);
+        assert_eq!(attribute(hour, "cache_hit").unwrap()["boolValue"], false);
+        let start = hour["startTimeUnixNano"].as_str().unwrap().parse::<u128>();
+        let end = hour["endTimeUnixNano"].as_str().unwrap().parse::<u128>();
+        assert!(start.unwrap() <= end.unwrap());
+    }
+}
@@ -0,0 +1,49 @@
+//! Builds the library for `wasm32-unknown-unknown` and runs the node tests of its
+//! JavaScript wrapper in `wasm/` against it
+#![cfg(feature = "wasm")]
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn wasm_build_answers_queries_with_a_javascript_fetch() {
+    let target_dir = Path::new(env!("CARGO_BIN_EXE_interview"))
+        .parent()
+        .and_then(Path::parent)
+        .unwrap();
+    let status = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(["build", "--lib", "--target", "wasm32-unknown-unknown"])
+        .args([
+            "--no-default-features",
+            "--features",
+            "wasm",
+            "--target-dir",
+        ])
+        .arg(target_dir)
+        .status()
+        .expect("cargo should run");
+    assert!(status.success(), "the wasm build failed");
+
+    let module = target_dir.join("wasm32-unknown-unknown/debug/interview.wasm");
+    let output = Command::new("node")
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/wasm/test.mjs"))
+        .env("INTERVIEW_WASM", &module)
+        .output()
+        .expect("node should run");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        stdout
+            .lines()
+            .filter(|line| line.starts_with("ok "))
+            .count(),
+        5
+    );
+}
@@ -0,0 +1,103 @@
+//! Runs the binary's `watch` command on a spool directory and drops query files into it
+
+use std::fs::{self, File};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/multi-hour-range");
+
+/// A running watcher, killed once dropped
+struct Watcher(Child);
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Writes a query file under a hidden name and renames it into the spool directory, as
+/// writers do
+fn drop_file(spool: &Path, name: &str, queries: &str) {
+    let hidden = spool.join(format!(".{}", name));
+    fs::write(&hidden, queries).unwrap();
+    fs::rename(&hidden, spool.join(name)).unwrap();
+}
+
+/// Waits for the file to exist
+fn wait_for(path: &Path) {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while !path.exists() {
+        assert!(
+            Instant::now() < deadline,
+            "{} never appeared",
+            path.display()
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn query_files_are_answered_with_a_warm_cache_and_moved() {
+    let dir = tempfile::tempdir().unwrap();
+    let spool = dir.path().join("spool");
+    fs::create_dir(&spool).unwrap();
+    let log = dir.path().join("watch.log");
+    let _watcher = Watcher(
+        Command::new(env!("CARGO_BIN_EXE_interview"))
+            .arg("watch")
+            .arg(&spool)
+            .args(["--poll-interval-ms", "20", "--source"])
+            .arg(format!("disk:{}/hours", FIXTURE))
+            .env("RUST_LOG", "info")
+            .stdout(Stdio::null())
+            .stderr(File::create(&log).unwrap())
+            .spawn()
+            .unwrap(),
+    );
+    let queries = fs::read_to_string(format!("{}/queries.txt", FIXTURE)).unwrap();
+    let expected = fs::read_to_string(format!("{}/expected.txt", FIXTURE)).unwrap();
+
+    // A file still being written is left alone
+    fs::write(spool.join(".partial.txt"), "C 1700820000").unwrap();
+    drop_file(&spool, "first.txt", &queries);
+    wait_for(&spool.join("done/first.txt"));
+    assert_eq!(
+        fs::read_to_string(spool.join("first.out")).unwrap(),
+        expected
+    );
+    assert!(!spool.join("first.txt").exists());
+
+    // The next file finds the hours of the first in the cache
+    drop_file(&spool, "second.txt", "C 1700820000 1700830000\n");
+    wait_for(&spool.join("done/second.txt"));
+    assert_eq!(
+        fs::read_to_string(spool.join("second.out")).unwrap(),
+        "4509\n"
+    );
+
+    // A query that fails stops its file, which keeps the answers before it
+    drop_file(
+        &spool,
+        "bad.txt",
+        "C 1700820000 1700830000\nX 1700820000 1700830000\nC 1700820000 1700830000\n",
+    );
+    wait_for(&spool.join("failed/bad.txt"));
+    assert_eq!(fs::read_to_string(spool.join("bad.out")).unwrap(), "4509\n");
+    assert!(spool.join(".partial.txt").exists());
+    assert!(!spool.join("done/.partial.txt").exists());
+
+    let log = fs::read_to_string(&log).unwrap();
+    let second = log
+        .lines()
+        .find(|line| line.contains("Processed second.txt"))
+        .unwrap_or_else(|| panic!("{}", log));
+    assert!(
+        second.contains("3 hours from the cache, 0 API calls"),
+        "{}",
+        second
+    );
+    assert!(log.contains("Failed bad.txt after 2 queries"), "{}", log);
+}
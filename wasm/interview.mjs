@@ -0,0 +1,250 @@
+// JavaScript wrapper of the WebAssembly build of the processor, for browsers and node:
+//
+//     cargo build --release --lib --target wasm32-unknown-unknown --no-default-features --features wasm
+//
+// builds target/wasm32-unknown-unknown/release/interview.wasm, the module `load` takes:
+//
+//     import { load } from "./interview.mjs";
+//
+//     const lib = await load(await (await fetch("interview.wasm")).arrayBuffer());
+//     const processor = lib.processor({ cacheHours: 24 }, async (symbol, start, end) =>
+//       (await fetch(`/fills?symbol=${symbol}&start=${start}&end=${end}`)).json());
+//     await processor.query("V 1700820000 1700830000"); // "1813528.229735"
+//
+// The fetch function returns the fills of `symbol` within (start, end] in Unix seconds, as
+// objects with the fields of `fixture record`'s JSON (decimals as strings), or a Promise of
+// them. It may throw or reject with `Unavailable` for an upstream that is down. Answers and
+// decimals cross the boundary as strings, the library's own text, so no digit is lost.
+//
+// The library runs synchronously, so a fetch that returns a Promise can't be awaited where
+// the library asks for it. Before a query, the processor fetches the hours the query misses
+// all at once and awaits them; a fetch the library still asks for is started, the query
+// fails as pending, and it runs again once the fetch settled, finding its fills.
+
+const OK = 0;
+const FAILED = 1;
+const UNAVAILABLE = 2;
+const PENDING = 3;
+
+const encoder = new TextEncoder();
+const decoder = new TextDecoder();
+
+/** An upstream that is unavailable, as opposed to one whose fills are bad */
+export class Unavailable extends Error {}
+
+/** A query or setting the processor rejected, with the processor's error */
+export class ProcessorError extends Error {}
+
+/** Instantiates the module, given as its bytes or compiled, and returns its library */
+export async function load(module) {
+  const library = new Library();
+  const imports = {
+    env: {
+      interview_now_ms: () => Date.now(),
+      interview_fetch: (user, symbol, symbolLen, start, end, sink) =>
+        library.processors.get(user).fetchFor(
+          library.readString(symbol, symbolLen),
+          Number(start),
+          Number(end),
+          sink,
+        ),
+    },
+  };
+  const instance =
+    module instanceof WebAssembly.Module
+      ? await WebAssembly.instantiate(module, imports)
+      : (await WebAssembly.instantiate(module, imports)).instance;
+  library.exports = instance.exports;
+  return library;
+}
+
+class Library {
+  constructor() {
+    this.exports = null;
+    /** Processors by the `user` id the library passes to `interview_fetch` */
+    this.processors = new Map();
+    this.nextId = 1;
+  }
+
+  /** Creates a processor: settings `cacheHours`, `symbol`, and the fetch function */
+  processor(settings, fetch) {
+    const id = this.nextId++;
+    const config = JSON.stringify({
+      ...(settings.cacheHours !== undefined && { cache_hours: settings.cacheHours }),
+      ...(settings.symbol !== undefined && { symbol: settings.symbol }),
+    });
+    const handle = this.withString(config, (ptr, len) =>
+      this.exports.interview_new(ptr, len, 0, id),
+    );
+    if (handle === 0) {
+      throw new ProcessorError(this.error());
+    }
+    const processor = new Processor(this, id, handle, fetch);
+    this.processors.set(id, processor);
+    return processor;
+  }
+
+  readString(ptr, len) {
+    return decoder.decode(new Uint8Array(this.exports.memory.buffer, ptr >>> 0, len >>> 0));
+  }
+
+  /** Calls `f` with the pointer and length of the string copied into the module's memory */
+  withString(text, f) {
+    const bytes = encoder.encode(text);
+    const ptr = this.exports.interview_alloc(bytes.length);
+    try {
+      new Uint8Array(this.exports.memory.buffer, ptr >>> 0, bytes.length).set(bytes);
+      return f(ptr, bytes.length);
+    } finally {
+      this.exports.interview_dealloc(ptr, bytes.length);
+    }
+  }
+
+  error() {
+    return this.readString(this.exports.interview_error_ptr(), this.exports.interview_error_len());
+  }
+}
+
+/** Returns the status and text of a fetch that threw or rejected */
+function failure(error) {
+  return {
+    status: error instanceof Unavailable ? UNAVAILABLE : FAILED,
+    text: String(error?.message ?? error),
+  };
+}
+
+class Processor {
+  constructor(library, id, handle, fetch) {
+    this.library = library;
+    this.id = id;
+    this.handle = handle;
+    this.fetch = fetch;
+    /** Settled fetches, by request, waiting for the library to ask for them */
+    this.provided = new Map();
+    /** Fetches in flight, by request */
+    this.pending = new Map();
+  }
+
+  /** Starts fetching, returning the settled fetch or a Promise of it */
+  start(symbol, start, end) {
+    let fills;
+    try {
+      fills = this.fetch(symbol, start, end);
+    } catch (error) {
+      return failure(error);
+    }
+    if (typeof fills?.then !== "function") {
+      return { status: OK, text: JSON.stringify(fills) };
+    }
+    return fills.then((fills) => ({ status: OK, text: JSON.stringify(fills) }), failure);
+  }
+
+  /** Answers the library's `interview_fetch` */
+  fetchFor(symbol, start, end, sink) {
+    const key = `${symbol} ${start} ${end}`;
+    let fetched = this.provided.get(key);
+    this.provided.delete(key);
+    if (fetched === undefined && !this.pending.has(key)) {
+      fetched = this.start(symbol, start, end);
+      if (typeof fetched.then === "function") {
+        this.pending.set(key, fetched);
+        fetched = undefined;
+      }
+    }
+    const { status, text } = fetched ?? { status: PENDING, text: "pending" };
+    const exports = this.library.exports;
+    this.library.withString(text, (ptr, len) => exports.interview_sink_write(sink, ptr, len));
+    return status;
+  }
+
+  /** Starts fetching the hours the line misses, so they are fetched concurrently */
+  prefetch(line) {
+    const exports = this.library.exports;
+    const status = this.library.withString(line, (ptr, len) =>
+      exports.interview_missing_hours(this.handle, ptr, len),
+    );
+    // A line that fails to plan fails alike when it runs
+    if (status !== OK) {
+      return;
+    }
+    for (const [symbol, start, end] of JSON.parse(this.result())) {
+      const key = `${symbol} ${start} ${end}`;
+      if (!this.provided.has(key) && !this.pending.has(key)) {
+        const fetched = this.start(symbol, start, end);
+        if (typeof fetched.then === "function") {
+          this.pending.set(key, fetched);
+        } else {
+          this.provided.set(key, fetched);
+        }
+      }
+    }
+  }
+
+  /** Makes the call until it no longer waits for a fetch, and returns its answer */
+  async run(call) {
+    for (;;) {
+      await this.settle();
+      if (call() === OK) {
+        return this.result();
+      }
+      if (this.pending.size === 0) {
+        throw new ProcessorError(this.library.error());
+      }
+    }
+  }
+
+  /** Awaits the fetches in flight */
+  async settle() {
+    const pending = [...this.pending];
+    this.pending.clear();
+    for (const [key, fetched] of pending) {
+      this.provided.set(key, await fetched);
+    }
+  }
+
+  result() {
+    const exports = this.library.exports;
+    return this.library.readString(
+      exports.interview_result_ptr(this.handle),
+      exports.interview_result_len(this.handle),
+    );
+  }
+
+  /** Answers a query line, like a line of `query`'s input, with its text */
+  async query(line) {
+    this.prefetch(line);
+    const exports = this.library.exports;
+    return this.run(() =>
+      this.library.withString(line, (ptr, len) => exports.interview_query(this.handle, ptr, len)),
+    );
+  }
+
+  /** Returns the fills of the symbol (the default one when left out) within (start, end]
+   * in Unix seconds through the cache, as a query of the window sees them */
+  async fillsInRange(start, end, symbol = "") {
+    const window = `(] ${symbol} ${start} ${end}`;
+    this.prefetch(`SD ${window}`);
+    const exports = this.library.exports;
+    const fills = await this.run(() =>
+      this.library.withString(symbol, (ptr, len) =>
+        exports.interview_fills_in_range(this.handle, ptr, len, BigInt(start), BigInt(end)),
+      ),
+    );
+    return JSON.parse(fills);
+  }
+
+  /** Returns the cached hours, fills and bytes and the hit and API call counts */
+  cacheStats() {
+    if (this.library.exports.interview_cache_stats(this.handle) !== OK) {
+      throw new ProcessorError(this.library.error());
+    }
+    return JSON.parse(this.result());
+  }
+
+  /** Frees the processor; it can't be used after */
+  free() {
+    this.library.exports.interview_free(this.handle);
+    this.library.processors.delete(this.id);
+    this.handle = 0;
+  }
+}
@@ -0,0 +1,147 @@
+// Runs the multi-hour-range fixture's queries through the WebAssembly build, with a stubbed
+// fetch serving the fixture's hour files, and checks the answers against its expected
+// output. Run by `cargo test --features wasm`, or directly with the module built:
+//
+//     INTERVIEW_WASM=target/wasm32-unknown-unknown/debug/interview.wasm node wasm/test.mjs
+
+import assert from "node:assert/strict";
+import { readFileSync } from "node:fs";
+import { dirname, join } from "node:path";
+import { fileURLToPath } from "node:url";
+
+import { load, ProcessorError, Unavailable } from "./interview.mjs";
+
+const FIXTURE = join(dirname(fileURLToPath(import.meta.url)), "../fixtures/multi-hour-range");
+const HOUR = 1700816400;
+
+/** Returns the fills of the fixture's hour file in the JSON of `fixture record` */
+function hourFile(hour) {
+  let lines;
+  try {
+    lines = readFileSync(join(FIXTURE, `hours/DEFAULT/${hour}.csv`), "utf8").trim().split("\n");
+  } catch {
+    return [];
+  }
+  const [header, ...rows] = lines.map((line) => line.split(","));
+  return rows.map((row) => {
+    const fill = {};
+    header.forEach((field, i) => {
+      if (row[i] !== "" && row[i] !== undefined) {
+        fill[field] = row[i];
+      }
+    });
+    fill.time = `${fill.time.replace(" ", "T")}Z`;
+    fill.direction = Number(fill.direction);
+    fill.sequence_number = Number(fill.sequence_number);
+    return fill;
+  });
+}
+
+/** Returns a fetch serving the fixture's hours after a delay, the requests it got, and
+ * the most it had in flight at once */
+function stubbedFetch() {
+  const stub = { requests: [], maxInFlight: 0 };
+  let inFlight = 0;
+  stub.fetch = (symbol, start, end) => {
+    stub.requests.push([symbol, start, end]);
+    inFlight++;
+    stub.maxInFlight = Math.max(stub.maxInFlight, inFlight);
+    return new Promise((resolve) =>
+      setTimeout(() => {
+        inFlight--;
+        resolve(hourFile(start));
+      }, 5),
+    );
+  };
+  return stub;
+}
+
+const tests = {
+  async "answers match the fixture's expected output"(lib) {
+    const stub = stubbedFetch();
+    const processor = lib.processor({ cacheHours: 24 }, stub.fetch);
+    const queries = readFileSync(join(FIXTURE, "queries.txt"), "utf8")
+      .split("\n")
+      .filter((line) => line.trim() !== "" && !line.startsWith("#"));
+    const answers = [];
+    for (const query of queries) {
+      answers.push(await processor.query(query));
+    }
+    assert.equal(`${answers.join("\n")}\n`, readFileSync(join(FIXTURE, "expected.txt"), "utf8"));
+
+    // The three hours were fetched once, at the same time
+    assert.deepEqual(stub.requests, [
+      ["DEFAULT", 1700820000, 1700823600],
+      ["DEFAULT", 1700823600, 1700827200],
+      ["DEFAULT", 1700827200, 1700830800],
+    ]);
+    assert.equal(stub.maxInFlight, 3);
+    const stats = processor.cacheStats();
+    assert.equal(stats.hours, 3);
+    assert.equal(stats.api_calls, 3);
+    assert.ok(stats.cache_hits > 0);
+    processor.free();
+  },
+
+  async "a fetch found pending is awaited and the query run again"(lib) {
+    const stub = stubbedFetch();
+    const processor = lib.processor({}, stub.fetch);
+    // Without the prefetch, each hour the query reaches is found pending in turn
+    const line = "C 1700820000 1700830000";
+    const answer = await processor.run(() =>
+      lib.withString(line, (ptr, len) => lib.exports.interview_query(processor.handle, ptr, len)),
+    );
+    assert.equal(answer, "4509");
+    assert.equal(stub.requests.length, 3);
+    assert.equal(stub.maxInFlight, 1);
+    assert.equal(processor.cacheStats().api_calls, 3);
+  },
+
+  async "fills in range are the fetched fills"(lib) {
+    const processor = lib.processor({}, stubbedFetch().fetch);
+    const fills = await processor.fillsInRange(1700820000, 1700820004);
+    const fields = (fill) => [fill.time, fill.price, fill.sequence_number];
+    const expected = hourFile(1700820000).filter((fill) => fill.time <= "2023-11-24T10:00:04Z");
+    assert.equal(expected.length, 5);
+    assert.deepEqual(fills.map(fields), expected.map(fields));
+  },
+
+  async "a synchronous fetch is used as it returns"(lib) {
+    const requests = [];
+    const processor = lib.processor({ symbol: "BTC" }, (symbol, start, end) => {
+      requests.push([symbol, start, end]);
+      return start === HOUR
+        ? [
+            { time: "2023-11-24T09:00:10Z", direction: 1, price: "100.5", quantity: "2", sequence_number: 1 },
+            { time: "2023-11-24T09:00:20Z", direction: 2, price: "101", quantity: "1", sequence_number: 2 },
+          ]
+        : [];
+    });
+    assert.equal(await processor.query("C 1700816400 1700820000"), "2");
+    assert.equal(await processor.query("V 1700816400 1700820000"), "302.0");
+    assert.equal(await processor.query("WB 1700816415 1700820000"), "NONE");
+    assert.deepEqual(requests, [
+      ["BTC", HOUR, HOUR + 3600],
+      ["BTC", HOUR + 3600, HOUR + 7200],
+    ]);
+  },
+
+  async "failed fetches fail their query"(lib) {
+    const processor = lib.processor({}, async (symbol) => {
+      throw new Unavailable(`no fills for ${symbol}`);
+    });
+    await assert.rejects(processor.query("C 1700816400 1700820000"), (error) => {
+      assert.ok(error instanceof ProcessorError);
+      assert.match(error.message, /no fills for DEFAULT/);
+      return true;
+    });
+    await assert.rejects(processor.query("X 1700816400 1700820000"), ProcessorError);
+    assert.throws(() => lib.processor({ cacheHours: -1 }, () => []), ProcessorError);
+  },
+};
+
+const lib = await load(readFileSync(process.env.INTERVIEW_WASM));
+for (const [name, test] of Object.entries(tests)) {
+  await test(lib);
+  console.log("ok", name);
+}